@@ -0,0 +1,217 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::unistd::{fchownat, FchownatFlags, Uid, User};
+use tracing::{span, Span};
+use walkdir::WalkDir;
+
+use crate::action::{
+    shell_command, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction,
+};
+
+/** Give `user` ownership of `path` and everything beneath it, without following symlinks
+
+Revert hands the tree back to `root`, so whatever reverts after it removes it as it would any
+other install.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ChangeOwnership {
+    path: PathBuf,
+    user: String,
+}
+
+impl ChangeOwnership {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        path: impl AsRef<Path>,
+        user: String,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        // The user must exist when planning, not only when executing
+        uid_of(&user).map_err(Self::error)?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            user,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "change_ownership")]
+impl Action for ChangeOwnership {
+    fn action_tag() -> ActionTag {
+        ActionTag("change_ownership")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Give `{}` ownership of `{}`",
+            self.user,
+            self.path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "change_ownership",
+            path = tracing::field::display(self.path.display()),
+            user = self.user,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![format!(
+                "Everything beneath `{}` is changed too, so `{}` can use Nix without a daemon",
+                self.path.display(),
+                self.user
+            )],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let uid = uid_of(&self.user).map_err(Self::error)?;
+        chown_tree(&self.path, uid).map_err(Self::error)?;
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        Some(vec![shell_command([
+            OsStr::new("chown"),
+            OsStr::new("-R"),
+            OsStr::new("-h"),
+            self.user.as_ref(),
+            self.path.as_ref(),
+        ])])
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        // A fresh store holds tens of thousands of files
+        Duration::from_secs(2)
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Give `root` ownership of `{}`", self.path.display()),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        chown_tree(&self.path, Uid::from_raw(0)).map_err(Self::error)?;
+        Ok(())
+    }
+}
+
+fn uid_of(user: &str) -> Result<Uid, ActionErrorKind> {
+    Ok(User::from_name(user)
+        .map_err(|e| ActionErrorKind::GettingUserId(user.to_string(), e))?
+        .ok_or_else(|| ActionErrorKind::NoUser(user.to_string()))?
+        .uid)
+}
+
+/// Change the owner of `path` and its contents, changing symlinks themselves rather than what they point at
+fn chown_tree(path: &Path, uid: Uid) -> Result<(), ActionErrorKind> {
+    for entry in WalkDir::new(path) {
+        let entry = entry.map_err(|e| {
+            let path = e.path().unwrap_or(path).to_path_buf();
+            ActionErrorKind::Read(path, e.into())
+        })?;
+        fchownat(
+            None,
+            entry.path(),
+            Some(uid),
+            None,
+            FchownatFlags::NoFollowSymlink,
+        )
+        .map_err(|e| ActionErrorKind::Chown(entry.path().to_path_buf(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use nix::unistd::getuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn changes_and_restores_ownership_without_following_symlinks() -> eyre::Result<()> {
+        // Only root can give files away
+        let Some(nobody) = User::from_name("nobody")? else {
+            return Ok(());
+        };
+        if !getuid().is_root() {
+            return Ok(());
+        }
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        let outside = temp_dir.path().join("outside");
+        tokio::fs::create_dir_all(tree.join("nested")).await?;
+        tokio::fs::write(tree.join("nested/file"), "").await?;
+        tokio::fs::write(&outside, "").await?;
+        tokio::fs::symlink(&outside, tree.join("link")).await?;
+        let paths = [
+            tree.clone(),
+            tree.join("nested"),
+            tree.join("nested/file"),
+            tree.join("link"),
+        ];
+
+        let mut action = ChangeOwnership::plan(&tree, "nobody".to_string()).await?;
+        action.try_execute().await?;
+        for path in &paths {
+            assert_eq!(
+                tokio::fs::symlink_metadata(path).await?.uid(),
+                nobody.uid.as_raw(),
+                "`{}` should be owned by `nobody`",
+                path.display()
+            );
+        }
+        assert_eq!(tokio::fs::metadata(&outside).await?.uid(), 0);
+
+        action.try_revert().await?;
+        for path in &paths {
+            assert_eq!(
+                tokio::fs::symlink_metadata(path).await?.uid(),
+                0,
+                "`{}` should be owned by `root` again",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plan_requires_the_user() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        assert!(
+            ChangeOwnership::plan(temp_dir.path(), "nix-installer-no-such-user".to_string())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revert_of_a_removed_tree_succeeds() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let mut action = ChangeOwnership::plan(temp_dir.path().join("removed"), "root".to_string())
+            .await?
+            .action;
+        action.revert().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+/// Below this, the Nix daemon and the first builds are likely to be killed by the OOM killer
+pub const DEFAULT_MINIMUM_MEMORY_MIB: u64 = 1024;
+const MIB: u64 = 1024 * 1024;
+
+/**
+Check the system has enough memory to run the Nix daemon and its builds, does nothing on revert
+
+This runs when planning and as a preflight check of a dry run. Without an enforced minimum,
+insufficient memory, or memory which can't be determined, is only warned about.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CheckMemory {
+    /// In bytes
+    required: u64,
+    enforce: bool,
+}
+
+impl CheckMemory {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        minimum_memory_mib: Option<u64>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let this = Self {
+            required: minimum_memory_mib.unwrap_or(DEFAULT_MINIMUM_MEMORY_MIB) * MIB,
+            enforce: minimum_memory_mib.is_some(),
+        };
+        this.check().await?;
+
+        Ok(this.into())
+    }
+
+    async fn check(&self) -> Result<(), ActionError> {
+        self.check_available(available_memory().await)
+    }
+
+    /// Compare the `available` memory, or why it could not be determined, against the minimum
+    fn check_available(&self, available: Result<u64, ActionErrorKind>) -> Result<(), ActionError> {
+        let err = match available {
+            Ok(available) if available >= self.required => return Ok(()),
+            Ok(available) => ActionErrorKind::from(CheckMemoryError::Insufficient {
+                available,
+                required: self.required,
+            }),
+            Err(err) => err,
+        };
+        if self.enforce {
+            Err(Self::error(err))
+        } else {
+            tracing::warn!("{err}, builds may be killed for running out of memory");
+            Ok(())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "check_memory")]
+impl Action for CheckMemory {
+    fn action_tag() -> ActionTag {
+        ActionTag("check_memory")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Check at least {} MiB of memory is available",
+            self.required / MIB
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "check_memory",
+            required = self.required,
+            enforce = self.enforce,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                "The Nix daemon and its builds are likely to run out of memory on smaller systems"
+                    .to_string(),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.check().await
+    }
+
+    async fn preflight(&self) -> Result<(), ActionError> {
+        self.check().await
+    }
+
+    fn depends_on(&self) -> Option<Vec<ActionTag>> {
+        // Only reads the system
+        Some(vec![])
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        // Only a warning unless enforced, so there is nothing to run
+        if !self.enforce {
+            return Some(vec![]);
+        }
+        #[cfg(target_os = "linux")]
+        let available = "$(( $(awk '/^MemAvailable:/ { print $2 }' /proc/meminfo) * 1024 ))";
+        #[cfg(target_os = "macos")]
+        let available = "$(vm_stat | awk '/page size of/ { size = $8 } /^Pages (free|inactive|speculative):/ { pages += $NF } END { printf \"%d\", pages * size }')";
+        Some(vec![format!(
+            "[ \"{available}\" -ge {required} ] || {{ echo 'At least {} MiB of memory is required' >&2; exit 1; }}",
+            self.required / MIB,
+            required = self.required,
+        )])
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![/* Deliberately empty -- this is a noop */]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+/// The memory available to new processes, in bytes
+#[cfg(target_os = "linux")]
+async fn available_memory() -> Result<u64, ActionErrorKind> {
+    const MEMINFO: &str = "/proc/meminfo";
+    let meminfo = tokio::fs::read_to_string(MEMINFO)
+        .await
+        .map_err(|e| ActionErrorKind::Read(MEMINFO.into(), e))?;
+    meminfo_available(&meminfo)
+}
+
+/// The `MemAvailable` of a `/proc/meminfo`, in bytes
+#[cfg(any(target_os = "linux", test))]
+fn meminfo_available(meminfo: &str) -> Result<u64, ActionErrorKind> {
+    let available_kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| {
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .ok_or(CheckMemoryError::UnknownAvailable)?;
+
+    Ok(available_kib * 1024)
+}
+
+/// The memory available to new processes without swapping, in bytes
+#[cfg(target_os = "macos")]
+async fn available_memory() -> Result<u64, ActionErrorKind> {
+    let output = crate::execute_command(
+        tokio::process::Command::new("/usr/bin/vm_stat")
+            .process_group(0)
+            .stdin(std::process::Stdio::null()),
+    )
+    .await?;
+    vm_stat_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The free, inactive and speculative pages of a `vm_stat` output, in bytes
+///
+/// macOS keeps otherwise unused memory as inactive pages, which it hands out without swapping.
+#[cfg(any(target_os = "macos", test))]
+fn vm_stat_available(vm_stat: &str) -> Result<u64, ActionErrorKind> {
+    let page_size = vm_stat
+        .lines()
+        .next()
+        .and_then(|line| line.split_once("page size of "))
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .and_then(|size| size.parse::<u64>().ok())
+        .ok_or(CheckMemoryError::UnknownAvailable)?;
+    let mut pages = 0;
+    for key in ["Pages free:", "Pages inactive:", "Pages speculative:"] {
+        pages += vm_stat
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| value.trim().trim_end_matches('.').parse::<u64>().ok())
+            .ok_or(CheckMemoryError::UnknownAvailable)?;
+    }
+
+    Ok(pages * page_size)
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CheckMemoryError {
+    #[error(
+        "Only {} MiB of memory is available, at least {} MiB is required to run the Nix daemon and its builds",
+        available / MIB,
+        required / MIB
+    )]
+    Insufficient { available: u64, required: u64 },
+    #[error("Could not determine the available memory")]
+    UnknownAvailable,
+}
+
+impl From<CheckMemoryError> for ActionErrorKind {
+    fn from(v: CheckMemoryError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MEMINFO: &str = "\
+MemTotal:        2014256 kB
+MemFree:          104812 kB
+MemAvailable:     524288 kB
+Buffers:           39640 kB
+";
+
+    fn check_memory(minimum_memory_mib: Option<u64>) -> CheckMemory {
+        CheckMemory {
+            required: minimum_memory_mib.unwrap_or(DEFAULT_MINIMUM_MEMORY_MIB) * MIB,
+            enforce: minimum_memory_mib.is_some(),
+        }
+    }
+
+    #[test]
+    fn meminfo_available_reads_mem_available() -> eyre::Result<()> {
+        assert_eq!(meminfo_available(MEMINFO)?, 512 * MIB);
+        assert!(meminfo_available("MemTotal: 2014256 kB\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_stat_available_counts_reclaimable_pages() -> eyre::Result<()> {
+        let vm_stat = "\
+Mach Virtual Memory Statistics: (page size of 16384 bytes)
+Pages free:                                8192.
+Pages active:                            100000.
+Pages inactive:                           16384.
+Pages speculative:                         8192.
+";
+        assert_eq!(vm_stat_available(vm_stat)?, 32768 * 16384);
+        assert!(vm_stat_available("Pages free: 8192.\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn only_warns_without_a_minimum() {
+        let check_memory = check_memory(None);
+        assert!(check_memory
+            .check_available(meminfo_available(MEMINFO))
+            .is_ok());
+        assert!(check_memory
+            .check_available(meminfo_available("garbage"))
+            .is_ok());
+    }
+
+    #[test]
+    fn fails_below_an_enforced_minimum() {
+        assert!(check_memory(Some(256))
+            .check_available(meminfo_available(MEMINFO))
+            .is_ok());
+
+        let check_memory = check_memory(Some(1024));
+        assert!(check_memory
+            .check_available(meminfo_available(MEMINFO))
+            .is_err());
+        assert!(check_memory
+            .check_available(meminfo_available("garbage"))
+            .is_err());
+    }
+}
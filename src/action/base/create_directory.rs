@@ -1,18 +1,26 @@
+use std::ffi::OsStr;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
 use nix::unistd::{chown, Group, User};
 
-use tokio::fs::{create_dir, remove_dir_all};
+use tokio::fs::{create_dir, remove_dir};
 use tracing::{span, Span};
 
-use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
+use super::remove_tree::remove_tree;
+use super::{set_selinux_context, shell_set_ownership, shell_set_selinux_context};
+use crate::action::{shell_command, Action, ActionDescription, ActionErrorKind, ActionState};
 use crate::action::{ActionError, StatefulAction};
 
 /** Create a directory at the given location, optionally with an owning user, group, and mode.
 
 If `force_prune_on_revert` is set, the folder will always be deleted on
-[`revert`](CreateDirectory::revert).
+[`revert`](CreateDirectory::revert), except for any paths planned with
+[`plan_preserving`](CreateDirectory::plan_preserving).
+
+On systems with SELinux, the created directory is labeled with its
+[`with_selinux_context`](StatefulAction::<CreateDirectory>::with_selinux_context), or else by the
+loaded policy.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateDirectory {
@@ -21,6 +29,10 @@ pub struct CreateDirectory {
     group: Option<String>,
     mode: Option<u32>,
     force_prune_on_revert: bool,
+    #[serde(default)]
+    preserve_on_revert: Vec<PathBuf>,
+    #[serde(default)]
+    selinux_context: Option<String>,
 }
 
 impl CreateDirectory {
@@ -37,53 +49,10 @@ impl CreateDirectory {
         let group = group.into();
         let mode = mode.into();
 
-        let action_state = if path.exists() {
-            let metadata = tokio::fs::metadata(&path)
-                .await
-                .map_err(|e| ActionErrorKind::GettingMetadata(path.clone(), e))
-                .map_err(Self::error)?;
-            if !metadata.is_dir() {
-                return Err(Self::error(ActionErrorKind::PathWasNotDirectory(
-                    path.to_owned(),
-                )));
-            }
-
-            // Does it have the right user/group?
-            if let Some(user) = &user {
-                // If the file exists, the user must also exist to be correct.
-                let expected_uid = User::from_name(user.as_str())
-                    .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))
-                    .map_err(Self::error)?
-                    .uid;
-                let found_uid = metadata.uid();
-                if found_uid != expected_uid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathUserMismatch(
-                        path.clone(),
-                        found_uid,
-                        expected_uid.as_raw(),
-                    )));
-                }
-            }
-            if let Some(group) = &group {
-                // If the file exists, the group must also exist to be correct.
-                let expected_gid = Group::from_name(group.as_str())
-                    .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(group.clone()))
-                    .map_err(Self::error)?
-                    .gid;
-                let found_gid = metadata.gid();
-                if found_gid != expected_gid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathGroupMismatch(
-                        path.clone(),
-                        found_gid,
-                        expected_gid.as_raw(),
-                    )));
-                }
-            }
-
+        let action_state = if Self::check_existing(&path, &user, &group)
+            .await
+            .map_err(Self::error)?
+        {
             tracing::debug!("Creating directory `{}` already complete", path.display(),);
             ActionState::Completed
         } else {
@@ -97,10 +66,137 @@ impl CreateDirectory {
                 group,
                 mode,
                 force_prune_on_revert,
+                preserve_on_revert: vec![],
+                selinux_context: None,
             },
             state: action_state,
+            timeout: None,
         })
     }
+
+    /// Whether a directory is already at `path`, erroring if it is something else or has a different owner
+    async fn check_existing(
+        path: &Path,
+        user: &Option<String>,
+        group: &Option<String>,
+    ) -> Result<bool, ActionErrorKind> {
+        if !path.exists() {
+            return Ok(false);
+        }
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.to_owned(), e))?;
+        if !metadata.is_dir() {
+            return Err(ActionErrorKind::PathWasNotDirectory(path.to_owned()));
+        }
+
+        // Does it have the right user/group?
+        if let Some(user) = user {
+            // If the file exists, the user must also exist to be correct.
+            let expected_uid = User::from_name(user.as_str())
+                .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))?
+                .uid;
+            let found_uid = metadata.uid();
+            if found_uid != expected_uid.as_raw() {
+                return Err(ActionErrorKind::PathUserMismatch(
+                    path.to_owned(),
+                    found_uid,
+                    expected_uid.as_raw(),
+                ));
+            }
+        }
+        if let Some(group) = group {
+            // If the file exists, the group must also exist to be correct.
+            let expected_gid = Group::from_name(group.as_str())
+                .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoGroup(group.clone()))?
+                .gid;
+            let found_gid = metadata.gid();
+            if found_gid != expected_gid.as_raw() {
+                return Err(ActionErrorKind::PathGroupMismatch(
+                    path.to_owned(),
+                    found_gid,
+                    expected_gid.as_raw(),
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Plan a directory which is pruned on revert, apart from `preserve` and the directories leading to them
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_preserving(
+        path: impl AsRef<Path>,
+        user: impl Into<Option<String>>,
+        group: impl Into<Option<String>>,
+        mode: impl Into<Option<u32>>,
+        preserve: Vec<PathBuf>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let path = path.as_ref();
+        for preserved in &preserve {
+            if !preserved.is_absolute()
+                || preserved == path
+                || !preserved.starts_with(path)
+                || preserved
+                    .components()
+                    .any(|c| c == std::path::Component::ParentDir)
+            {
+                return Err(Self::error(CreateDirectoryError::PreservedPathOutside(
+                    preserved.clone(),
+                    path.to_path_buf(),
+                )));
+            }
+        }
+
+        let mut this = Self::plan(path, user, group, mode, true).await?;
+        this.action.preserve_on_revert = preserve;
+        Ok(this)
+    }
+}
+
+impl StatefulAction<CreateDirectory> {
+    /// Label the created directory with the SELinux `context`, like `system_u:object_r:etc_t:s0`, on systems with SELinux
+    pub fn with_selinux_context(mut self, context: impl Into<Option<String>>) -> Self {
+        self.action.selinux_context = context.into();
+        self
+    }
+}
+
+/// Find everything in `dir` to remove, apart from `preserve` and the directories leading to them, returning what is preserved
+async fn prunable_except(
+    dir: &Path,
+    preserve: &[PathBuf],
+    prunable: &mut Vec<PathBuf>,
+) -> Result<Vec<PathBuf>, ActionErrorKind> {
+    let mut preserved = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .map_err(|e| ActionErrorKind::Read(dir.clone(), e))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| ActionErrorKind::Read(dir.clone(), e))?
+        {
+            let entry_path = entry.path();
+            if preserve.contains(&entry_path) {
+                preserved.push(entry_path);
+                continue;
+            }
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| ActionErrorKind::GettingMetadata(entry_path.clone(), e))?;
+            if file_type.is_dir() && preserve.iter().any(|p| p.starts_with(&entry_path)) {
+                dirs.push(entry_path);
+            } else {
+                prunable.push(entry_path);
+            }
+        }
+    }
+    Ok(preserved)
 }
 
 #[async_trait::async_trait]
@@ -138,8 +234,19 @@ impl Action for CreateDirectory {
             group,
             mode,
             force_prune_on_revert: _,
+            preserve_on_revert: _,
+            selinux_context,
         } = self;
 
+        // Someone, such as an earlier run of the installer, may have created it since planning
+        if Self::check_existing(path, user, group)
+            .await
+            .map_err(Self::error)?
+        {
+            tracing::debug!("Creating directory `{}` already complete", path.display());
+            return Ok(());
+        }
+
         let gid = if let Some(group) = group {
             Some(
                 Group::from_name(group.as_str())
@@ -180,9 +287,28 @@ impl Action for CreateDirectory {
                 .map_err(Self::error)?;
         }
 
+        set_selinux_context(path, selinux_context.as_deref())
+            .await
+            .map_err(Self::error)?;
+
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![shell_command([OsStr::new("mkdir"), self.path.as_ref()])];
+        commands.extend(shell_set_ownership(
+            &self.path,
+            &self.user,
+            &self.group,
+            self.mode,
+        ));
+        commands.extend(shell_set_selinux_context(
+            &self.path,
+            self.selinux_context.as_deref(),
+        ));
+        Some(commands)
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             path,
@@ -190,18 +316,25 @@ impl Action for CreateDirectory {
             group: _,
             mode: _,
             force_prune_on_revert,
+            preserve_on_revert,
+            selinux_context: _,
         } = &self;
         vec![ActionDescription::new(
             format!(
                 "Remove the directory `{}`{}",
                 path.display(),
-                if *force_prune_on_revert {
-                    ""
-                } else {
+                if !*force_prune_on_revert {
                     " if no other contents exists"
+                } else if !preserve_on_revert.is_empty() {
+                    ", except preserved paths"
+                } else {
+                    ""
                 }
             ),
-            vec![],
+            preserve_on_revert
+                .iter()
+                .map(|p| format!("Preserve `{}`", p.display()))
+                .collect(),
         )]
     }
 
@@ -213,8 +346,34 @@ impl Action for CreateDirectory {
             group: _,
             mode: _,
             force_prune_on_revert,
+            preserve_on_revert,
+            selinux_context: _,
         } = self;
 
+        if !path.exists() {
+            tracing::debug!("Directory `{}` already removed, skipping", path.display());
+            return Ok(());
+        }
+
+        if *force_prune_on_revert {
+            // For `/nix` this is mostly the store, which is far quicker to remove in parallel
+            let mut prunable = vec![];
+            let preserved = prunable_except(path, preserve_on_revert, &mut prunable)
+                .await
+                .map_err(Self::error)?;
+            remove_tree(path, prunable).await.map_err(Self::error)?;
+            for preserved in &preserved {
+                tracing::info!("Preserved `{}`", preserved.display());
+            }
+            if preserved.is_empty() {
+                remove_dir(&path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
+                    .map_err(Self::error)?;
+            }
+            return Ok(());
+        }
+
         let is_empty = path
             .read_dir()
             .map_err(|e| ActionErrorKind::Read(path.clone(), e))
@@ -222,20 +381,32 @@ impl Action for CreateDirectory {
             .next()
             .is_none();
 
-        match (is_empty, force_prune_on_revert) {
-            (true, _) | (false, true) => remove_dir_all(path.clone())
+        if is_empty {
+            remove_dir(&path)
                 .await
                 .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
-                .map_err(Self::error)?,
-            (false, false) => {
-                tracing::debug!("Not removing `{}`, the folder is not empty", path.display());
-            },
-        };
+                .map_err(Self::error)?;
+        } else {
+            tracing::debug!("Not removing `{}`, the folder is not empty", path.display());
+        }
 
         Ok(())
     }
 }
 
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateDirectoryError {
+    #[error("Cannot preserve `{0}`, only paths under `{1}` can be preserved")]
+    PreservedPathOutside(PathBuf, PathBuf),
+}
+
+impl From<CreateDirectoryError> for ActionErrorKind {
+    fn from(v: CreateDirectoryError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -295,4 +466,85 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prunes_all_but_preserved_paths() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_dir = temp_dir.path().join("prunes_all_but_preserved_paths");
+        let kept = test_dir.join("store").join("kept");
+        let mut action =
+            CreateDirectory::plan_preserving(&test_dir, None, None, None, vec![kept.clone()])
+                .await?;
+
+        action.try_execute().await?;
+
+        tokio::fs::create_dir_all(&kept).await?;
+        tokio::fs::write(kept.join("stub"), "More content").await?;
+        tokio::fs::create_dir_all(test_dir.join("store").join("removed")).await?;
+        tokio::fs::write(test_dir.join("stub"), "More content").await?;
+
+        action.try_revert().await?;
+
+        assert!(kept.join("stub").exists(), "Preserved path should remain");
+        assert!(!test_dir.join("store").join("removed").exists());
+        assert!(!test_dir.join("stub").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_if_preserved_path_is_outside_directory() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_dir = temp_dir
+            .path()
+            .join("errors_if_preserved_path_is_outside_directory");
+
+        assert!(CreateDirectory::plan_preserving(
+            &test_dir,
+            None,
+            None,
+            None,
+            vec![temp_dir.path().join("elsewhere")]
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn completes_if_directory_appeared_since_planning() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_dir = temp_dir
+            .path()
+            .join("completes_if_directory_appeared_since_planning");
+        let mut action = CreateDirectory::plan(test_dir.clone(), None, None, None, false).await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+
+        tokio::fs::create_dir(&test_dir).await?;
+        action.try_execute().await?;
+        assert_eq!(action.state, ActionState::Completed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_if_file_appeared_since_planning() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_path = temp_dir
+            .path()
+            .join("errors_if_file_appeared_since_planning");
+        let mut action = CreateDirectory::plan(test_path.clone(), None, None, None, false).await?;
+
+        tokio::fs::write(&test_path, "Not a directory").await?;
+        match action.try_execute().await {
+            Err(err) => assert!(matches!(
+                err.kind(),
+                ActionErrorKind::PathWasNotDirectory(_)
+            )),
+            Ok(()) => panic!("Executing should have failed"),
+        }
+
+        Ok(())
+    }
 }
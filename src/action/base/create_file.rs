@@ -1,24 +1,42 @@
 use nix::unistd::{chown, Group, User};
+use rand::Rng;
 use tracing::{span, Span};
 
 use std::{
+    ffi::OsStr,
     os::{unix::fs::MetadataExt, unix::fs::PermissionsExt},
     path::{Path, PathBuf},
 };
 use tokio::{
-    fs::{remove_file, File, OpenOptions},
+    fs::{hard_link, remove_file, rename, File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
 };
 
+use super::{
+    set_selinux_context, shell_set_ownership, shell_set_selinux_context, shell_write,
+    OriginalMetadata,
+};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    shell_command, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction,
 };
 
 /** Create a file at the given location with the provided `buf`,
 optionally with an owning user, group, and mode.
 
+If the file already exists with different content, it is moved to
+`<path>.nix-installer.bak` and restored on revert, along with its
+original owner, group, and mode.
+
 If `force` is set, the file will always be overwritten (and deleted)
-regardless of its presence prior to install.
+regardless of its presence prior to install, without a backup.
+
+The file is written in full and synced beside `path`, then renamed into place, so readers see
+either the previous file or the complete new one.
+
+On systems with SELinux, the created file is labeled with its
+[`with_selinux_context`](StatefulAction::<CreateFile>::with_selinux_context), or
+else by the loaded policy.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateFile {
@@ -28,6 +46,13 @@ pub struct CreateFile {
     mode: Option<u32>,
     buf: String,
     force: bool,
+    #[serde(default)]
+    backup: Option<PathBuf>,
+    #[serde(default)]
+    selinux_context: Option<String>,
+    /// The owner, group and mode of the file moved to `backup`, restored on revert
+    #[serde(default)]
+    original: Option<OriginalMetadata>,
 }
 
 impl CreateFile {
@@ -44,101 +69,115 @@ impl CreateFile {
         let mode = mode.into();
         let user = user.into();
         let group = group.into();
-        let this = Self {
+        let mut this = Self {
             path,
             user,
             group,
             mode,
             buf,
             force,
+            backup: None,
+            selinux_context: None,
+            original: None,
         };
 
         if this.path.exists() {
             // If the path exists, perhaps we can just skip this
-            let mut file = File::open(&this.path)
-                .await
-                .map_err(|e| ActionErrorKind::Open(this.path.clone(), e))
-                .map_err(Self::error)?;
+            if !this.matches_existing().await.map_err(Self::error)? {
+                if !this.force {
+                    let backup = backup_path(&this.path);
+                    if backup.exists() {
+                        return Err(Self::error(ActionErrorKind::FileExists(backup)));
+                    }
+                    this.backup = Some(backup);
+                }
+                return Ok(StatefulAction::uncompleted(this));
+            }
 
-            let metadata = file
-                .metadata()
-                .await
-                .map_err(|e| ActionErrorKind::GettingMetadata(this.path.clone(), e))
-                .map_err(Self::error)?;
+            tracing::debug!("Creating file `{}` already complete", this.path.display());
+            return Ok(StatefulAction::completed(this));
+        }
 
-            if !metadata.is_file() {
-                return Err(Self::error(ActionErrorKind::PathWasNotFile(this.path)));
-            }
+        Ok(StatefulAction::uncompleted(this))
+    }
 
-            if let Some(mode) = mode {
-                // Does the file have the right permissions?
-                let discovered_mode = metadata.permissions().mode();
-                // We only care about user-group-other permissions
-                let discovered_mode = discovered_mode & 0o777;
-
-                if discovered_mode != mode {
-                    return Err(Self::error(ActionErrorKind::PathModeMismatch(
-                        this.path.clone(),
-                        discovered_mode,
-                        mode,
-                    )));
-                }
-            }
+    /// If the file at `path` already holds `buf`, erroring if it is not a file or has a different mode or owner
+    async fn matches_existing(&self) -> Result<bool, ActionErrorKind> {
+        let mut file = File::open(&self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Open(self.path.clone(), e))?;
 
-            // Does it have the right user/group?
-            if let Some(user) = &this.user {
-                // If the file exists, the user must also exist to be correct.
-                let expected_uid = User::from_name(user.as_str())
-                    .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))
-                    .map_err(Self::error)?
-                    .uid;
-                let found_uid = metadata.uid();
-                if found_uid != expected_uid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathUserMismatch(
-                        this.path.clone(),
-                        found_uid,
-                        expected_uid.as_raw(),
-                    )));
-                }
-            }
-            if let Some(group) = &this.group {
-                // If the file exists, the group must also exist to be correct.
-                let expected_gid = Group::from_name(group.as_str())
-                    .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(group.clone()))
-                    .map_err(Self::error)?
-                    .gid;
-                let found_gid = metadata.gid();
-                if found_gid != expected_gid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathGroupMismatch(
-                        this.path.clone(),
-                        found_gid,
-                        expected_gid.as_raw(),
-                    )));
-                }
-            }
+        let metadata = file
+            .metadata()
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(self.path.clone(), e))?;
 
-            // Does it have the right content?
-            let mut discovered_buf = String::new();
-            file.read_to_string(&mut discovered_buf)
-                .await
-                .map_err(|e| ActionErrorKind::Read(this.path.clone(), e))
-                .map_err(Self::error)?;
+        if !metadata.is_file() {
+            return Err(ActionErrorKind::PathWasNotFile(self.path.clone()));
+        }
 
-            if discovered_buf != this.buf {
-                return Err(Self::error(ActionErrorKind::DifferentContent(
-                    this.path.clone(),
-                )));
+        if let Some(mode) = self.mode {
+            // Does the file have the right permissions?
+            let discovered_mode = metadata.permissions().mode();
+            // We only care about user-group-other permissions
+            let discovered_mode = discovered_mode & 0o777;
+
+            if discovered_mode != mode {
+                return Err(ActionErrorKind::PathModeMismatch(
+                    self.path.clone(),
+                    discovered_mode,
+                    mode,
+                ));
             }
+        }
 
-            tracing::debug!("Creating file `{}` already complete", this.path.display());
-            return Ok(StatefulAction::completed(this));
+        // Does it have the right user/group?
+        if let Some(user) = &self.user {
+            // If the file exists, the user must also exist to be correct.
+            let expected_uid = User::from_name(user.as_str())
+                .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))?
+                .uid;
+            let found_uid = metadata.uid();
+            if found_uid != expected_uid.as_raw() {
+                return Err(ActionErrorKind::PathUserMismatch(
+                    self.path.clone(),
+                    found_uid,
+                    expected_uid.as_raw(),
+                ));
+            }
+        }
+        if let Some(group) = &self.group {
+            // If the file exists, the group must also exist to be correct.
+            let expected_gid = Group::from_name(group.as_str())
+                .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoGroup(group.clone()))?
+                .gid;
+            let found_gid = metadata.gid();
+            if found_gid != expected_gid.as_raw() {
+                return Err(ActionErrorKind::PathGroupMismatch(
+                    self.path.clone(),
+                    found_gid,
+                    expected_gid.as_raw(),
+                ));
+            }
         }
 
-        Ok(StatefulAction::uncompleted(this))
+        // Does it have the right content?
+        let mut discovered_buf = String::new();
+        file.read_to_string(&mut discovered_buf)
+            .await
+            .map_err(|e| ActionErrorKind::Read(self.path.clone(), e))?;
+
+        Ok(discovered_buf == self.buf)
+    }
+}
+
+impl StatefulAction<CreateFile> {
+    /// Label the created file with the SELinux `context`, like `system_u:object_r:etc_t:s0`, on systems with SELinux
+    pub fn with_selinux_context(mut self, context: impl Into<Option<String>>) -> Self {
+        self.action.selinux_context = context.into();
+        self
     }
 }
 
@@ -172,18 +211,38 @@ impl Action for CreateFile {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if let Some(backup) = &self.backup {
+            explanation.push(format!(
+                "Move the existing `{}` to `{}`",
+                self.path.display(),
+                backup.display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        // Someone, such as an earlier run of the installer, may have written it since planning
+        if self.backup.is_none() && !self.force && self.path.exists() {
+            if self.matches_existing().await.map_err(Self::error)? {
+                tracing::debug!("Creating file `{}` already complete", self.path.display());
+                return Ok(());
+            }
+            return Err(Self::error(ActionErrorKind::FileExists(self.path.clone())));
+        }
+
         let Self {
             path,
             user,
             group,
             mode,
             buf,
-            force: _,
+            force,
+            backup,
+            selinux_context,
+            original,
         } = self;
 
         if tracing::enabled!(tracing::Level::TRACE) {
@@ -191,6 +250,15 @@ impl Action for CreateFile {
             span.record("buf", &buf);
         }
 
+        // The complete file is written beside `path`, then renamed over it, so a crash never
+        // leaves a torn file at `path`
+        let parent_dir = path
+            .parent()
+            .ok_or_else(|| Self::error(ActionErrorKind::NoParentDirectory(path.clone())))?;
+        let temp_file_path = parent_dir.join(format!(
+            "nix-installer-tmp.{}",
+            rand::thread_rng().gen::<u32>()
+        ));
         let mut options = OpenOptions::new();
         options.create_new(true).write(true).read(true);
 
@@ -199,15 +267,45 @@ impl Action for CreateFile {
         }
 
         let mut file = options
-            .open(&path)
+            .open(&temp_file_path)
             .await
-            .map_err(|e| ActionErrorKind::Open(path.to_owned(), e))
+            .map_err(|e| ActionErrorKind::Open(temp_file_path.clone(), e))
             .map_err(Self::error)?;
+        let written = async {
+            file.write_all(buf.as_bytes())
+                .await
+                .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))?;
+            file.sync_all()
+                .await
+                .map_err(|e| ActionErrorKind::Sync(temp_file_path.clone(), e))
+        }
+        .await;
+        drop(file);
+        if let Err(e) = written {
+            let _ = remove_file(&temp_file_path).await;
+            return Err(Self::error(e));
+        }
 
-        file.write_all(buf.as_bytes())
+        if let Some(backup) = backup {
+            *original = OriginalMetadata::of(path).await.map_err(Self::error)?;
+            // Linking keeps the original at `path` until the new file replaces it
+            hard_link(&path, &backup)
+                .await
+                .map_err(|e| ActionErrorKind::Copy(path.to_owned(), backup.to_owned(), e))
+                .map_err(Self::error)?;
+        } else if !*force && path.exists() {
+            let _ = remove_file(&temp_file_path).await;
+            return Err(Self::error(ActionErrorKind::FileExists(path.to_owned())));
+        }
+
+        rename(&temp_file_path, &path)
             .await
-            .map_err(|e| ActionErrorKind::Write(path.to_owned(), e))
+            .map_err(|e| ActionErrorKind::Rename(temp_file_path.clone(), path.to_owned(), e))
             .map_err(Self::error)?;
+        if let Ok(parent_dir) = File::open(parent_dir).await {
+            // Also persist the rename itself, failing to is no worse than before the rename
+            let _ = parent_dir.sync_all().await;
+        }
 
         let gid = if let Some(group) = group {
             Some(
@@ -237,9 +335,54 @@ impl Action for CreateFile {
             .map_err(|e| ActionErrorKind::Chown(path.clone(), e))
             .map_err(Self::error)?;
 
+        set_selinux_context(path, selinux_context.as_deref())
+            .await
+            .map_err(Self::error)?;
+
+        super::verify_written(path, buf, *mode)
+            .await
+            .map_err(Self::error)?;
+
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        if let Some(backup) = &self.backup {
+            commands.push(shell_command([
+                OsStr::new("ln"),
+                self.path.as_ref(),
+                backup.as_ref(),
+            ]));
+        }
+        let mut temp_file_path = self.path.as_os_str().to_owned();
+        temp_file_path.push(".nix-installer-tmp");
+        commands.push(shell_write(Path::new(&temp_file_path), &self.buf));
+        commands.push(shell_command([
+            OsStr::new("mv"),
+            "-f".as_ref(),
+            temp_file_path.as_os_str(),
+            self.path.as_ref(),
+        ]));
+        commands.extend(shell_set_ownership(
+            &self.path,
+            &self.user,
+            &self.group,
+            self.mode,
+        ));
+        commands.extend(shell_set_selinux_context(
+            &self.path,
+            self.selinux_context.as_deref(),
+        ));
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.path.clone()];
+        paths.extend(self.backup.clone());
+        paths
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             path,
@@ -248,11 +391,22 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            backup,
+            selinux_context: _,
+            original: _,
         } = &self;
 
+        let mut explanation = vec![format!("Delete file `{}`", path.display())];
+        if let Some(backup) = backup {
+            explanation.push(format!(
+                "Restore `{}` to `{}`",
+                backup.display(),
+                path.display()
+            ));
+        }
         vec![ActionDescription::new(
             format!("Delete file `{}`", path.display()),
-            vec![format!("Delete file `{}`", path.display())],
+            explanation,
         )]
     }
 
@@ -265,20 +419,51 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            backup,
+            selinux_context: _,
+            original,
         } = self;
 
-        remove_file(&path)
-            .await
-            .map_err(|e| ActionErrorKind::Remove(path.to_owned(), e))
-            .map_err(Self::error)?;
+        match remove_file(&path).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("File `{}` already removed, skipping", path.display())
+            },
+            Err(e) => return Err(Self::error(ActionErrorKind::Remove(path.to_owned(), e))),
+        }
+
+        if let Some(backup) = backup {
+            if !backup.exists() {
+                tracing::debug!(
+                    "Backup `{}` already restored or removed, skipping",
+                    backup.display()
+                );
+                return Ok(());
+            }
+            rename(&backup, &path)
+                .await
+                .map_err(|e| ActionErrorKind::Rename(backup.to_owned(), path.to_owned(), e))
+                .map_err(Self::error)?;
+            if let Some(original) = original {
+                original.restore(path).await.map_err(Self::error)?;
+            }
+        }
 
         Ok(())
     }
 }
 
+/// The location an existing file is moved to before being replaced, eg `/etc/bashrc.nix-installer.bak`
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".nix-installer.bak");
+    PathBuf::from(backup)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::action::ActionState;
     use color_eyre::eyre::eyre;
     use tokio::fs::write;
 
@@ -298,6 +483,119 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn writing_without_a_parent_directory_fails() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut action = CreateFile::plan(
+            temp_dir.path().join("file"),
+            None,
+            None,
+            None,
+            "Test".into(),
+            false,
+        )
+        .await?;
+        action.action.path = PathBuf::from("/");
+
+        let err = action
+            .try_execute()
+            .await
+            .expect_err("`/` has no parent directory");
+        assert_eq!(err.code(), "create-file.no-parent-directory");
+        Ok(())
+    }
+
+    #[test]
+    fn error_codes_name_the_failing_action_and_cause() {
+        let exists = CreateFile::error(ActionErrorKind::FileExists("/etc/nix/nix.conf".into()));
+        assert_eq!(exists.code(), "create-file.eexist");
+
+        let denied = CreateFile::error(ActionErrorKind::Write(
+            "/etc/nix/nix.conf".into(),
+            std::io::Error::from_raw_os_error(nix::libc::EACCES),
+        ));
+        let grouped = crate::action::base::CreateDirectory::error(denied);
+        assert_eq!(grouped.code(), "create-file.eacces");
+        assert_eq!(
+            crate::NixInstallerError::Action(grouped).code(),
+            "create-file.eacces"
+        );
+        assert_eq!(
+            crate::NixInstallerError::Cancelled.code(),
+            "installer.cancelled"
+        );
+    }
+
+    #[tokio::test]
+    async fn reverts_file_already_deleted() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("reverts_file_already_deleted");
+        let mut action =
+            CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
+
+        action.try_execute().await?;
+
+        tokio::fs::remove_file(&test_file).await?;
+
+        action.try_revert().await?;
+
+        assert!(!test_file.exists(), "File should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaces_file_atomically_without_leftovers() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("replaces_file_atomically");
+        write(test_file.as_path(), "Original").await?;
+        let mut action =
+            CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
+
+        action.try_execute().await?;
+        assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Test");
+        let mut entries = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                OsStr::new("replaces_file_atomically").to_owned(),
+                OsStr::new("replaces_file_atomically.nix-installer.bak").to_owned(),
+            ]
+        );
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Original");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_file_with_selinux_context() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("creates_file_with_selinux_context");
+        let mut action =
+            CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false)
+                .await?
+                .with_selinux_context("system_u:object_r:etc_t:s0".to_string());
+
+        let shell = action.to_shell().unwrap_or_default();
+        assert!(shell
+            .iter()
+            .any(|command| command.contains("chcon system_u:object_r:etc_t:s0")));
+
+        // Without SELinux, the context is left alone
+        if !Path::new("/sys/fs/selinux").exists() {
+            action.try_execute().await?;
+            assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Test");
+            action.try_revert().await?;
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn creates_and_deletes_file_even_if_edited() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -348,14 +646,90 @@ mod test {
     }
 
     #[tokio::test]
-    async fn recognizes_existing_different_files_and_errors() -> eyre::Result<()> {
+    async fn backs_up_existing_different_files_and_restores_them() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
         let test_file = temp_dir
             .path()
-            .join("recognizes_existing_different_files_and_errors");
+            .join("backs_up_existing_different_files_and_restores_them");
+        let backup_file = backup_path(&test_file);
 
         write(test_file.as_path(), "Some content").await?;
 
+        let mut action = CreateFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Some different content".into(),
+            false,
+        )
+        .await?;
+
+        action.try_execute().await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "Some different content"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(&backup_file).await?,
+            "Some content"
+        );
+
+        action.try_revert().await?;
+
+        assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Some content");
+        assert!(!backup_file.exists(), "Backup should have been restored");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forced_overwrite_of_different_files_skips_backup() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("forced_overwrite_of_different_files_skips_backup");
+
+        write(test_file.as_path(), "Some content").await?;
+
+        let mut action = CreateFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Some different content".into(),
+            true,
+        )
+        .await?;
+
+        action.try_execute().await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "Some different content"
+        );
+        assert!(
+            !backup_path(&test_file).exists(),
+            "No backup should have been made"
+        );
+
+        action.try_revert().await?;
+
+        assert!(!test_file.exists(), "File should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_if_backup_already_exists() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("errors_if_backup_already_exists");
+        let backup_file = backup_path(&test_file);
+
+        write(test_file.as_path(), "Some content").await?;
+        write(backup_file.as_path(), "Some older content").await?;
+
         match CreateFile::plan(
             test_file.clone(),
             None,
@@ -367,16 +741,16 @@ mod test {
         .await
         {
             Err(error) => match error.kind() {
-                ActionErrorKind::DifferentContent(path) => assert_eq!(path, test_file.as_path()),
+                ActionErrorKind::FileExists(path) => assert_eq!(path, backup_file.as_path()),
                 _ => {
                     return Err(eyre!(
-                        "Should have returned an ActionErrorKind::Exists error"
+                        "Should have returned an ActionErrorKind::FileExists error"
                     ))
                 },
             },
             _ => {
                 return Err(eyre!(
-                    "Should have returned an ActionErrorKind::Exists error"
+                    "Should have returned an ActionErrorKind::FileExists error"
                 ))
             },
         };
@@ -491,4 +865,48 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn completes_if_exact_file_appeared_since_planning() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("completes_if_exact_file_appeared_since_planning");
+
+        let test_content = "Some content";
+        let mut action = CreateFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            test_content.into(),
+            false,
+        )
+        .await?;
+
+        write(test_file.as_path(), test_content).await?;
+        action.try_execute().await?;
+        assert_eq!(action.state, ActionState::Completed);
+
+        // A different file is not ours to overwrite
+        write(test_file.as_path(), "Some different content").await?;
+        action.state = ActionState::Uncompleted;
+        match action.try_execute().await {
+            Err(err) => match err.kind() {
+                ActionErrorKind::FileExists(path) => assert_eq!(path, &test_file),
+                _ => {
+                    return Err(eyre!(
+                        "Should have returned an ActionErrorKind::FileExists error"
+                    ))
+                },
+            },
+            _ => {
+                return Err(eyre!(
+                    "Should have returned an ActionErrorKind::FileExists error"
+                ))
+            },
+        }
+
+        Ok(())
+    }
 }
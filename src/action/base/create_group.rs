@@ -1,10 +1,13 @@
-use nix::unistd::Group;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{Gid, Group};
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::action::{shell_command, ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::settings::{default_target_root, in_target_root, HOST_ROOT};
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -15,18 +18,35 @@ Create an operating system level user group
 pub struct CreateGroup {
     name: String,
     gid: u32,
+    #[serde(default = "default_target_root")]
+    target_root: PathBuf,
 }
 
 impl CreateGroup {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn plan(name: String, gid: u32) -> Result<StatefulAction<Self>, ActionError> {
+    pub fn plan(
+        name: String,
+        gid: u32,
+        target_root: PathBuf,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         let this = Self {
             name: name.clone(),
             gid,
+            target_root: target_root.clone(),
         };
+        let is_host_root = target_root == Path::new(HOST_ROOT);
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
+            // Only `groupadd` and `groupdel` support `--root`
+            _ if !is_host_root => {
+                if which::which("groupadd").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
+                }
+                if which::which("groupdel").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingGroupDeletionCommand));
+                }
+            },
             _ => {
                 if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
@@ -38,14 +58,11 @@ impl CreateGroup {
         }
 
         // Ensure group does not exists
-        if let Some(group) = Group::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingGroupId(name.clone(), e))
-            .map_err(Self::error)?
-        {
-            if group.gid.as_raw() != gid {
+        if let Some(existing_gid) = this.existing_gid().map_err(Self::error)? {
+            if existing_gid != gid {
                 return Err(Self::error(ActionErrorKind::GroupGidMismatch(
                     name.clone(),
-                    group.gid.as_raw(),
+                    existing_gid,
                     gid,
                 )));
             }
@@ -55,6 +72,17 @@ impl CreateGroup {
         }
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// The GID of the group named `name`, if it exists in the target root
+    fn existing_gid(&self) -> Result<Option<u32>, ActionErrorKind> {
+        if self.target_root == Path::new(HOST_ROOT) {
+            Ok(Group::from_name(self.name.as_str())
+                .map_err(|e| ActionErrorKind::GettingGroupId(self.name.clone(), e))?
+                .map(|group| group.gid.as_raw()))
+        } else {
+            gid_in_target_root(&self.target_root, &self.name)
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -67,7 +95,11 @@ impl Action for CreateGroup {
         format!("Create group `{}` (GID {})", self.name, self.gid)
     }
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { name: _, gid: _ } = &self;
+        let Self {
+            name: _,
+            gid: _,
+            target_root: _,
+        } = &self;
         vec![ActionDescription::new(
             self.tracing_synopsis(),
             vec![format!(
@@ -85,9 +117,42 @@ impl Action for CreateGroup {
         )
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn preflight(&self) -> Result<(), ActionError> {
+        let Self {
+            name,
+            gid,
+            target_root,
+        } = self;
+
+        // The group itself was checked during planning, but another group may hold the GID
+        let existing_name = if *target_root == Path::new(HOST_ROOT) {
+            Group::from_gid(Gid::from_raw(*gid))
+                .map_err(|e| ActionErrorKind::GettingGroupId(name.clone(), e))
+                .map_err(Self::error)?
+                .map(|group| group.name)
+        } else {
+            groups_in_target_root(target_root)
+                .map_err(Self::error)?
+                .into_iter()
+                .find(|(_, existing_gid)| existing_gid == gid)
+                .map(|(existing_name, _)| existing_name)
+        };
+        match existing_name {
+            Some(existing_name) if existing_name != *name => {
+                Err(Self::error(ActionErrorKind::GidInUse(*gid, existing_name)))
+            },
+            _ => Ok(()),
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid } = self;
+        let Self {
+            name,
+            gid,
+            target_root,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -116,8 +181,12 @@ impl Action for CreateGroup {
             },
             _ => {
                 if which::which("groupadd").is_ok() {
+                    let mut command = Command::new("groupadd");
+                    if *target_root != Path::new(HOST_ROOT) {
+                        command.arg("--root").arg(&target_root);
+                    }
                     execute_command(
-                        Command::new("groupadd")
+                        command
                             .process_group(0)
                             .args(["-g", &gid.to_string(), "--system", name])
                             .stdin(std::process::Stdio::null()),
@@ -142,8 +211,47 @@ impl Action for CreateGroup {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let Self {
+            name,
+            gid,
+            target_root,
+        } = self;
+        let gid = gid.to_string();
+
+        let command = match OperatingSystem::host() {
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => shell_command([
+                "/usr/sbin/dseditgroup",
+                "-o",
+                "create",
+                "-r",
+                "Nix build group for nix-daemon",
+                "-i",
+                &gid,
+                name,
+            ]),
+            _ if which::which("groupadd").is_ok() => {
+                let mut args = vec!["groupadd".to_string()];
+                if *target_root != Path::new(HOST_ROOT) {
+                    args.extend(["--root".to_string(), target_root.display().to_string()]);
+                }
+                args.extend(["-g".to_string(), gid, "--system".to_string(), name.clone()]);
+                shell_command(args)
+            },
+            _ if which::which("addgroup").is_ok() => {
+                shell_command(["addgroup", "-g", &gid, "--system", name])
+            },
+            _ => return None,
+        };
+        Some(vec![command])
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
-        let Self { name, gid } = &self;
+        let Self {
+            name,
+            gid,
+            target_root: _,
+        } = &self;
         vec![ActionDescription::new(
             format!("Delete group `{name}` (GID {gid})"),
             vec![format!(
@@ -154,7 +262,17 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid: _ } = self;
+        // Someone, such as an earlier uninstall, may have deleted it already
+        if self.existing_gid().map_err(Self::error)?.is_none() {
+            tracing::debug!("Group `{}` already deleted", self.name);
+            return Ok(());
+        }
+
+        let Self {
+            name,
+            gid: _,
+            target_root,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -174,8 +292,12 @@ impl Action for CreateGroup {
             },
             _ => {
                 if which::which("groupdel").is_ok() {
+                    let mut command = Command::new("groupdel");
+                    if *target_root != Path::new(HOST_ROOT) {
+                        command.arg("--root").arg(&target_root);
+                    }
                     execute_command(
-                        Command::new("groupdel")
+                        command
                             .process_group(0)
                             .arg(name)
                             .stdin(std::process::Stdio::null()),
@@ -200,3 +322,58 @@ impl Action for CreateGroup {
         Ok(())
     }
 }
+
+/// Look up the GID of a group in the `/etc/group` of an alternate target root, which the host's NSS does not see
+fn gid_in_target_root(target_root: &Path, name: &str) -> Result<Option<u32>, ActionErrorKind> {
+    Ok(groups_in_target_root(target_root)?
+        .into_iter()
+        .find(|(group_name, _)| group_name == name)
+        .map(|(_, gid)| gid))
+}
+
+/// The name and GID of each group in the `/etc/group` of an alternate target root
+fn groups_in_target_root(target_root: &Path) -> Result<Vec<(String, u32)>, ActionErrorKind> {
+    let group_file = in_target_root(target_root, "/etc/group");
+    if !group_file.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(&group_file)
+        .map_err(|e| ActionErrorKind::Read(group_file.clone(), e))?;
+    let mut groups = Vec::new();
+    for line in contents.lines() {
+        let mut fields = line.split(':');
+        let name = fields.next();
+        // Skip the password field
+        let gid = fields.nth(1).and_then(|v| v.parse().ok());
+        if let (Some(name), Some(gid)) = (name, gid) {
+            groups.push((name.to_string(), gid));
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn revert_of_a_deleted_group_succeeds() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let group_file = temp_dir.path().join("etc/group");
+        tokio::fs::create_dir_all(temp_dir.path().join("etc")).await?;
+        tokio::fs::write(&group_file, "root:x:0:\n").await?;
+        let mut action = CreateGroup {
+            name: "nixbld".to_string(),
+            gid: 30000,
+            target_root: temp_dir.path().to_path_buf(),
+        };
+        assert_eq!(action.existing_gid()?, None);
+
+        action.revert().await?;
+        assert_eq!(tokio::fs::read_to_string(&group_file).await?, "root:x:0:\n");
+
+        tokio::fs::write(&group_file, "root:x:0:\nnixbld:x:30000:\n").await?;
+        assert_eq!(action.existing_gid()?, Some(30000));
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 use nix::unistd::{chown, Group, User};
 
+use super::OriginalMetadata;
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    shell_quote, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
 use rand::Rng;
 use std::{
@@ -21,11 +22,22 @@ pub enum Position {
     End,
 }
 
+/// Comment lines delimiting the inserted `buf` when `markers` is set
+const MARKER_START: &str = "# >>> nix-installer >>>";
+const MARKER_END: &str = "# <<< nix-installer <<<";
+
 /** Create a file at the given location with the provided `buf` as
 contents, optionally with an owning user, group, and mode.
 
 If the file exists, the provided `buf` will be inserted at its
 beginning or end, depending on the position field.
+
+If `markers` is set, `buf` is wrapped in `# >>> nix-installer >>>` and
+`# <<< nix-installer <<<` lines, and revert removes exactly that block
+even if the user edited the file around it. Only use it for files where
+`#` starts a comment.
+
+Revert restores the owner, group, and mode an existing file had before.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateOrInsertIntoFile {
@@ -35,6 +47,14 @@ pub struct CreateOrInsertIntoFile {
     mode: Option<u32>,
     buf: String,
     position: Position,
+    #[serde(default)]
+    markers: bool,
+    /// The SHA-256 of the whole file once it contained `buf`, used to detect later modification
+    #[serde(default)]
+    content_hash: Option<String>,
+    /// The owner, group and mode of the existing file, restored on revert
+    #[serde(default)]
+    original: Option<OriginalMetadata>,
 }
 
 impl CreateOrInsertIntoFile {
@@ -46,6 +66,7 @@ impl CreateOrInsertIntoFile {
         mode: impl Into<Option<u32>>,
         buf: String,
         position: Position,
+        markers: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
         let mode = mode.into();
@@ -58,6 +79,9 @@ impl CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            markers,
+            content_hash: None,
+            original: None,
         };
         if this.path.exists() {
             // If the path exists, perhaps we can just skip this
@@ -137,7 +161,10 @@ impl CreateOrInsertIntoFile {
 
             if discovered_buf.contains(&this.buf) {
                 tracing::debug!("Inserting into `{}` already complete", this.path.display(),);
-                return Ok(StatefulAction::completed(this));
+                return Ok(StatefulAction::completed(Self {
+                    content_hash: Some(content_hash(discovered_buf.as_bytes())),
+                    ..this
+                }));
             }
 
             // If not, we can't skip this, so we still do it
@@ -145,6 +172,19 @@ impl CreateOrInsertIntoFile {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// The text actually written into the file
+    fn inserted(&self) -> String {
+        if self.markers {
+            let maybe_newline = if self.buf.ends_with('\n') { "" } else { "\n" };
+            format!(
+                "{MARKER_START}\n{buf}{maybe_newline}{MARKER_END}\n",
+                buf = self.buf
+            )
+        } else {
+            self.buf.clone()
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -182,15 +222,21 @@ impl Action for CreateOrInsertIntoFile {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        let inserted = self.inserted();
         let Self {
             path,
             user,
             group,
             mode,
-            buf,
+            buf: _,
             position,
+            markers,
+            content_hash: recorded_content_hash,
+            original,
         } = self;
 
+        *original = OriginalMetadata::of(path).await.map_err(Self::error)?;
+
         let mut orig_file = match OpenOptions::new().read(true).open(&path).await {
             Ok(f) => Some(f),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
@@ -229,11 +275,20 @@ impl Action for CreateOrInsertIntoFile {
                         ActionErrorKind::Copy(path.to_owned(), temp_file_path.to_owned(), e)
                     })
                     .map_err(Self::error)?;
+
+                // The start marker must begin its own line
+                if *markers && !ends_with_newline(orig_file, path).await? {
+                    temp_file
+                        .write_all(b"\n")
+                        .await
+                        .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))
+                        .map_err(Self::error)?;
+                }
             }
         }
 
         temp_file
-            .write_all(buf.as_bytes())
+            .write_all(inserted.as_bytes())
             .await
             .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))
             .map_err(Self::error)?;
@@ -308,9 +363,50 @@ impl Action for CreateOrInsertIntoFile {
             .map_err(|e| ActionErrorKind::Rename(path.to_owned(), temp_file_path.to_owned(), e))
             .map_err(Self::error)?;
 
+        let written = tokio::fs::read(&path)
+            .await
+            .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
+            .map_err(Self::error)?;
+        *recorded_content_hash = Some(content_hash(&written));
+
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let path = shell_quote(&self.path);
+        let inserted = shell_quote(self.inserted());
+        let mut commands = vec![];
+        match self.position {
+            Position::End => {
+                // The start marker must begin its own line
+                if self.markers {
+                    commands.push(format!(
+                        "if [ -s {path} ] && [ -n \"$(tail -c 1 {path})\" ]; then echo >> {path}; fi"
+                    ));
+                }
+                commands.push(format!("printf '%s' {inserted} >> {path}"));
+            },
+            Position::Beginning => {
+                let temp_path = shell_quote(format!("{}.nix-installer-tmp", self.path.display()));
+                commands.push(format!(
+                    "{{ printf '%s' {inserted}; cat {path} 2>/dev/null || true; }} > {temp_path}"
+                ));
+                commands.push(format!("mv {temp_path} {path}"));
+            },
+        }
+        commands.extend(super::shell_set_ownership(
+            &self.path,
+            &self.user,
+            &self.group,
+            self.mode,
+        ));
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             path,
@@ -319,6 +415,9 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            markers: _,
+            content_hash: _,
+            original: _,
         } = &self;
         vec![ActionDescription::new(
             format!("Delete Nix related fragment from file `{}`", path.display()),
@@ -338,6 +437,9 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            markers,
+            content_hash: _,
+            original,
         } = self;
         let mut file = OpenOptions::new()
             .create(false)
@@ -354,7 +456,18 @@ impl Action for CreateOrInsertIntoFile {
             .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
             .map_err(Self::error)?;
 
-        if let Some(start) = file_contents.rfind(buf.as_str()) {
+        if *markers {
+            match marked_block(&file_contents) {
+                Some(range) => file_contents.replace_range(range, ""),
+                None => {
+                    tracing::warn!(
+                        "Could not find the `{MARKER_START}` and `{MARKER_END}` lines in `{}`, it was left unchanged",
+                        path.display()
+                    );
+                    return Ok(());
+                },
+            }
+        } else if let Some(start) = file_contents.rfind(buf.as_str()) {
             let end = start + buf.len();
             file_contents.replace_range(start..end, "")
         }
@@ -381,9 +494,64 @@ impl Action for CreateOrInsertIntoFile {
                 .await
                 .map_err(|e| ActionErrorKind::Flush(path.to_owned(), e))
                 .map_err(Self::error)?;
+            if let Some(original) = original {
+                original.restore(path).await.map_err(Self::error)?;
+            }
         }
         Ok(())
     }
+
+    fn check_drift(&self) -> Vec<PathBuf> {
+        let Some(recorded) = &self.content_hash else {
+            return Vec::new();
+        };
+        match std::fs::read(&self.path) {
+            Ok(found) if content_hash(&found) == *recorded => Vec::new(),
+            // A removed file has drifted too
+            _ => vec![self.path.clone()],
+        }
+    }
+}
+
+/// The byte range of the last marker delimited block, including both marker lines
+fn marked_block(contents: &str) -> Option<std::ops::Range<usize>> {
+    let start = contents.rfind(&format!("{MARKER_START}\n"))?;
+    let end_marker = contents[start..].find(MARKER_END)? + start;
+    let mut end = end_marker + MARKER_END.len();
+    if contents[end..].starts_with('\n') {
+        end += 1;
+    }
+    Some(start..end)
+}
+
+async fn ends_with_newline(file: &mut File, path: &Path) -> Result<bool, ActionError> {
+    let len = file
+        .metadata()
+        .await
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))
+        .map_err(CreateOrInsertIntoFile::error)?
+        .len();
+    if len == 0 {
+        return Ok(true);
+    }
+    file.seek(SeekFrom::End(-1))
+        .await
+        .map_err(|e| ActionErrorKind::Seek(path.to_owned(), e))
+        .map_err(CreateOrInsertIntoFile::error)?;
+    let mut last = [0u8];
+    file.read_exact(&mut last)
+        .await
+        .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
+        .map_err(CreateOrInsertIntoFile::error)?;
+    Ok(last[0] == b'\n')
+}
+
+fn content_hash(buf: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, buf)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
 }
 
 #[cfg(test)]
@@ -392,6 +560,35 @@ mod test {
     use color_eyre::eyre::eyre;
     use tokio::fs::{read_to_string, write};
 
+    #[tokio::test]
+    async fn restores_original_mode_on_revert() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("restores_original_mode_on_revert");
+        write(test_file.as_path(), "Original\n").await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o640)).await?;
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            0o644,
+            "Test\n".into(),
+            Position::End,
+            false,
+        )
+        .await?;
+
+        action.try_execute().await?;
+        let mode = tokio::fs::metadata(&test_file).await?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        action.try_revert().await?;
+        assert_eq!(read_to_string(&test_file).await?, "Original\n");
+        let mode = tokio::fs::metadata(&test_file).await?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o640, "The original mode should be restored");
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn creates_and_deletes_file() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -403,6 +600,7 @@ mod test {
             None,
             "Test".into(),
             Position::Beginning,
+            false,
         )
         .await?;
 
@@ -415,6 +613,31 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn detects_drift_after_execute() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("detects_drift_after_execute");
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "Test".into(),
+            Position::Beginning,
+            false,
+        )
+        .await?;
+
+        assert!(action.action.check_drift().is_empty());
+        action.try_execute().await?;
+        assert!(action.action.check_drift().is_empty());
+
+        write(&test_file, "Something else").await?;
+        assert_eq!(action.action.check_drift(), vec![test_file.clone()]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn edits_and_reverts_file() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -432,6 +655,7 @@ mod test {
             None,
             "Test".into(),
             Position::Beginning,
+            false,
         )
         .await?;
 
@@ -473,6 +697,7 @@ mod test {
                 None,
                 expected_content.into(),
                 position,
+                false,
             )
             .await?;
 
@@ -508,6 +733,7 @@ mod test {
             Some(expected_mode),
             "Some different content".into(),
             Position::End,
+            false,
         )
         .await?;
 
@@ -537,6 +763,7 @@ mod test {
             Some(initial_mode),
             "Some content".into(),
             Position::End,
+            false,
         )
         .await?;
 
@@ -549,6 +776,85 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn reverts_marked_block_when_surrounding_lines_were_modified() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("reverts_marked_block_when_surrounding_lines_were_modified");
+
+        // We test all `Position` options
+        let positions = [Position::Beginning, Position::End];
+        for position in positions {
+            write(test_file.as_path(), "export FOO=1").await?;
+
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                "export NIX=1".into(),
+                position.clone(),
+                true,
+            )
+            .await?;
+
+            action.try_execute().await?;
+
+            let executed_content = read_to_string(&test_file).await?;
+            let block = format!("{MARKER_START}\nexport NIX=1\n{MARKER_END}\n");
+            assert!(executed_content.contains(&block));
+
+            let edited_content = match position {
+                Position::Beginning => {
+                    format!("# A user comment\n{executed_content}\nexport BAR=1\n")
+                },
+                Position::End => {
+                    executed_content.replace("export FOO=1", "export FOO=2") + "export BAR=1\n"
+                },
+            };
+            write(test_file.as_path(), &edited_content).await?;
+
+            action.try_revert().await?;
+
+            let after_revert_content = read_to_string(&test_file).await?;
+            assert_eq!(after_revert_content, edited_content.replace(&block, ""));
+            assert!(!after_revert_content.contains("NIX"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_revert_when_markers_were_removed() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("skips_revert_when_markers_were_removed");
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "export NIX=1\n".into(),
+            Position::Beginning,
+            true,
+        )
+        .await?;
+
+        action.try_execute().await?;
+
+        let edited_content = "export NIX=1\nexport FOO=1\n";
+        write(test_file.as_path(), edited_content).await?;
+
+        action.try_revert().await?;
+
+        assert_eq!(read_to_string(&test_file).await?, edited_content);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn errors_on_dir() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -560,6 +866,7 @@ mod test {
             None,
             "Some different content".into(),
             Position::End,
+            false,
         )
         .await
         {
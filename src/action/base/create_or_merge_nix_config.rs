@@ -12,15 +12,30 @@ use tokio::{
 use tracing::{span, Span};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    shell_quote, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
 
 /// The `nix.conf` configuration names that are safe to merge.
 // FIXME(@cole-h): make configurable by downstream users?
 const MERGEABLE_CONF_NAMES: &[&str] = &["experimental-features"];
-const NIX_CONF_MODE: u32 = 0o664;
+pub(crate) const NIX_CONF_MODE: u32 = 0o664;
 const NIX_CONF_COMMENT_CHAR: char = '#';
 
+/// The settings of `nix_config`, under a comment noting they were written by the installer
+fn generated_config(nix_config: &NixConfig) -> String {
+    let mut buf = format!(
+        "# Generated by https://github.com/DeterminateSystems/nix-installer, version {version}.\n",
+        version = env!("CARGO_PKG_VERSION"),
+    );
+    for (name, value) in nix_config.settings() {
+        buf.push_str(name);
+        buf.push_str(" = ");
+        buf.push_str(value);
+        buf.push('\n');
+    }
+    buf
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum CreateOrMergeNixConfigError {
@@ -46,6 +61,9 @@ impl Into<ActionErrorKind> for CreateOrMergeNixConfigError {
 pub struct CreateOrMergeNixConfig {
     pub(crate) path: PathBuf,
     pending_nix_config: NixConfig,
+    /// Replace the values of settings the existing file already has, rather than refusing to merge them
+    #[serde(default)]
+    replace_existing: bool,
 }
 
 impl CreateOrMergeNixConfig {
@@ -54,16 +72,37 @@ impl CreateOrMergeNixConfig {
         path: impl AsRef<Path>,
         pending_nix_config: NixConfig,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let path = path.as_ref().to_path_buf();
+        Self::plan_inner(path.as_ref().to_path_buf(), pending_nix_config, false).await
+    }
 
+    /// Like [`plan`](Self::plan), but giving the settings of `pending_nix_config` their new values
+    /// where the existing file already sets them, such as when reconfiguring an install
+    ///
+    /// The other settings and comments of the existing file are kept.
+    pub(crate) async fn plan_replacing(
+        path: impl AsRef<Path>,
+        pending_nix_config: NixConfig,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_inner(path.as_ref().to_path_buf(), pending_nix_config, true).await
+    }
+
+    async fn plan_inner(
+        path: PathBuf,
+        pending_nix_config: NixConfig,
+        replace_existing: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         let this = Self {
             path,
             pending_nix_config,
+            replace_existing,
         };
 
         if this.path.exists() {
-            let (merged_nix_config, _) =
-                Self::validate_existing_nix_config(&this.pending_nix_config, &this.path)?;
+            let (merged_nix_config, _) = Self::validate_existing_nix_config(
+                &this.pending_nix_config,
+                &this.path,
+                this.replace_existing,
+            )?;
 
             if !merged_nix_config.settings().is_empty() {
                 return Ok(StatefulAction::uncompleted(this));
@@ -83,6 +122,7 @@ impl CreateOrMergeNixConfig {
         pending_nix_config: &NixConfig,
         existing_nix_config: &NixConfig,
         path: &Path,
+        replace_existing: bool,
     ) -> Result<(NixConfig, NixConfig), CreateOrMergeNixConfigError> {
         let mut merged_nix_config = NixConfig::new();
         let mut unmergeable_config_names = Vec::new();
@@ -113,6 +153,10 @@ impl CreateOrMergeNixConfig {
                     merged_nix_config
                         .settings_mut()
                         .insert(pending_conf_name.to_owned(), merged_conf_value.to_owned());
+                } else if replace_existing {
+                    merged_nix_config
+                        .settings_mut()
+                        .insert(pending_conf_name.to_owned(), pending_conf_value.join(" "));
                 } else {
                     unmergeable_config_names.push(pending_conf_name.to_owned());
                 }
@@ -136,6 +180,7 @@ impl CreateOrMergeNixConfig {
     fn validate_existing_nix_config(
         pending_nix_config: &NixConfig,
         path: &Path,
+        replace_existing: bool,
     ) -> Result<(NixConfig, NixConfig), ActionError> {
         let path = path.to_path_buf();
         let metadata = path
@@ -167,6 +212,7 @@ impl CreateOrMergeNixConfig {
             &pending_nix_config,
             &existing_nix_config,
             &path,
+            replace_existing,
         )
         .map_err(Self::error)?;
 
@@ -232,6 +278,7 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config,
+            replace_existing,
         } = self;
 
         if tracing::enabled!(tracing::Level::TRACE) {
@@ -273,7 +320,7 @@ impl Action for CreateOrMergeNixConfig {
 
         let (mut merged_nix_config, mut existing_nix_config) = if path.exists() {
             let (merged_nix_config, existing_nix_config) =
-                Self::validate_existing_nix_config(&pending_nix_config, &path)?;
+                Self::validate_existing_nix_config(&pending_nix_config, &path, *replace_existing)?;
             (merged_nix_config, Some(existing_nix_config))
         } else {
             (pending_nix_config.clone(), None)
@@ -409,17 +456,7 @@ impl Action for CreateOrMergeNixConfig {
             new_config.push('\n');
         }
 
-        new_config.push_str(&format!(
-            "# Generated by https://github.com/DeterminateSystems/nix-installer, version {version}.\n",
-            version = env!("CARGO_PKG_VERSION"),
-        ));
-
-        for (name, value) in merged_nix_config.settings() {
-            new_config.push_str(name);
-            new_config.push_str(" = ");
-            new_config.push_str(value);
-            new_config.push('\n');
-        }
+        new_config.push_str(&generated_config(&merged_nix_config));
 
         temp_file
             .write_all(new_config.as_bytes())
@@ -448,13 +485,33 @@ impl Action for CreateOrMergeNixConfig {
                 ))
             })?;
 
+        super::verify_written(path, &new_config, Some(NIX_CONF_MODE))
+            .await
+            .map_err(Self::error)?;
+
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        // Merging into an existing configuration depends on its contents when the script runs
+        if self.path.exists() {
+            return None;
+        }
+        Some(vec![
+            super::shell_write(&self.path, &generated_config(&self.pending_nix_config)),
+            format!("chmod {NIX_CONF_MODE:o} {}", shell_quote(&self.path)),
+        ])
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             path,
             pending_nix_config: _,
+            replace_existing: _,
         } = &self;
 
         vec![ActionDescription::new(
@@ -468,6 +525,7 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config: _,
+            replace_existing: _,
         } = self;
 
         remove_file(&path)
@@ -651,6 +709,48 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn replaces_existing_settings_in_place() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("replaces_existing_settings_in_place");
+
+        write(
+            test_file.as_path(),
+            "warn-dirty = false\nmax-jobs = 4 # a comment\n",
+        )
+        .await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(NIX_CONF_MODE)).await?;
+
+        for max_jobs in ["8", "16"] {
+            let mut nix_config = NixConfig::new();
+            nix_config
+                .settings_mut()
+                .insert("max-jobs".into(), max_jobs.into());
+            assert!(CreateOrMergeNixConfig::plan(&test_file, nix_config.clone())
+                .await
+                .is_err());
+            let mut action = CreateOrMergeNixConfig::plan_replacing(&test_file, nix_config).await?;
+
+            action.try_execute().await?;
+
+            let parsed = NixConfig::parse_file(&test_file)?;
+            assert_eq!(
+                parsed.settings().get("max-jobs").map(String::as_str),
+                Some(max_jobs)
+            );
+            assert_eq!(
+                parsed.settings().get("warn-dirty").map(String::as_str),
+                Some("false")
+            );
+            let s = std::fs::read_to_string(&test_file)?;
+            assert!(s.contains(&format!("max-jobs = {max_jobs} # a comment")));
+            assert_eq!(s.matches("max-jobs").count(), 1);
+            assert_eq!(s.matches("# Generated by").count(), 1);
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn preserves_comments() -> eyre::Result<()> {
         let temp_dir = tempfile::TempDir::new()?;
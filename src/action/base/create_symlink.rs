@@ -0,0 +1,337 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs::{remove_file, rename, symlink};
+use tracing::{span, Span};
+
+use super::create_file::backup_path;
+use crate::action::{
+    shell_command, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction,
+};
+
+/** Create a symlink at `target` pointing to `source`
+
+If `target` is already a symlink to `source`, this is already complete. If something else is
+at `target` and `force` is set, it is moved to `<target>.nix-installer.bak` and restored on
+revert, otherwise planning fails.
+
+Revert only removes `target` if it still points to `source`.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CreateSymlink {
+    source: PathBuf,
+    target: PathBuf,
+    force: bool,
+    #[serde(default)]
+    backup: Option<PathBuf>,
+}
+
+impl CreateSymlink {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        source: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut this = Self {
+            source: source.as_ref().to_path_buf(),
+            target: target.as_ref().to_path_buf(),
+            force,
+            backup: None,
+        };
+
+        let metadata = match tokio::fs::symlink_metadata(&this.target).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(StatefulAction::uncompleted(this))
+            },
+            Err(e) => {
+                return Err(Self::error(ActionErrorKind::GettingMetadata(
+                    this.target.clone(),
+                    e,
+                )))
+            },
+        };
+
+        if metadata.is_symlink() {
+            let link_source = tokio::fs::read_link(&this.target)
+                .await
+                .map_err(|e| ActionErrorKind::ReadSymlink(this.target.clone(), e))
+                .map_err(Self::error)?;
+            if link_source == this.source {
+                tracing::debug!(
+                    "Creating symlink `{}` already complete",
+                    this.target.display()
+                );
+                return Ok(StatefulAction::completed(this));
+            }
+        } else if metadata.is_dir() {
+            return Err(Self::error(ActionErrorKind::DirExists(this.target)));
+        }
+
+        if !this.force {
+            return Err(Self::error(if metadata.is_symlink() {
+                ActionErrorKind::SymlinkExists(this.target)
+            } else {
+                ActionErrorKind::FileExists(this.target)
+            }));
+        }
+
+        let backup = backup_path(&this.target);
+        if tokio::fs::symlink_metadata(&backup).await.is_ok() {
+            return Err(Self::error(ActionErrorKind::FileExists(backup)));
+        }
+        this.backup = Some(backup);
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_symlink")]
+impl Action for CreateSymlink {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_symlink")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Symlink `{}` to `{}`",
+            self.target.display(),
+            self.source.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_symlink",
+            source = tracing::field::display(self.source.display()),
+            target = tracing::field::display(self.target.display()),
+            force = self.force,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![];
+        if let Some(backup) = &self.backup {
+            explanation.push(format!(
+                "Move the existing `{}` to `{}`",
+                self.target.display(),
+                backup.display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let Self {
+            source,
+            target,
+            force: _,
+            backup,
+        } = self;
+
+        if let Some(backup) = backup {
+            rename(&target, &backup)
+                .await
+                .map_err(|e| ActionErrorKind::Rename(target.clone(), backup.clone(), e))
+                .map_err(Self::error)?;
+        }
+
+        symlink(&source, &target)
+            .await
+            .map_err(|e| ActionErrorKind::Symlink(source.clone(), target.clone(), e))
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        if let Some(backup) = &self.backup {
+            commands.push(shell_command([
+                OsStr::new("mv"),
+                self.target.as_ref(),
+                backup.as_ref(),
+            ]));
+        }
+        commands.push(shell_command([
+            OsStr::new("ln"),
+            "-s".as_ref(),
+            self.source.as_ref(),
+            self.target.as_ref(),
+        ]));
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.target.clone()];
+        paths.extend(self.backup.clone());
+        paths
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Delete `{}` if it still points to `{}`",
+            self.target.display(),
+            self.source.display()
+        )];
+        if let Some(backup) = &self.backup {
+            explanation.push(format!(
+                "Restore `{}` to `{}`",
+                backup.display(),
+                self.target.display()
+            ));
+        }
+        vec![ActionDescription::new(
+            format!("Delete symlink `{}`", self.target.display()),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let Self {
+            source,
+            target,
+            force: _,
+            backup,
+        } = self;
+
+        match tokio::fs::read_link(&target).await {
+            Ok(link_source) if link_source == *source => {
+                remove_file(&target)
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(target.clone(), e))
+                    .map_err(Self::error)?;
+            },
+            Ok(_) => tracing::warn!(
+                "Not removing `{}`, it no longer points to `{}`",
+                target.display(),
+                source.display()
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("Symlink `{}` already removed, skipping", target.display())
+            },
+            // Something other than a symlink replaced it
+            Err(_) => tracing::warn!(
+                "Not removing `{}`, it is no longer a symlink",
+                target.display()
+            ),
+        }
+
+        if let Some(backup) = backup {
+            if tokio::fs::symlink_metadata(&backup).await.is_err() {
+                tracing::debug!(
+                    "Backup `{}` already restored or removed, skipping",
+                    backup.display()
+                );
+                return Ok(());
+            }
+            if tokio::fs::symlink_metadata(&target).await.is_ok() {
+                return Err(Self::error(ActionErrorKind::FileExists(target.clone())));
+            }
+            rename(&backup, &target)
+                .await
+                .map_err(|e| ActionErrorKind::Rename(backup.clone(), target.clone(), e))
+                .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    #[tokio::test]
+    async fn creates_and_deletes_symlink() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        let mut action = CreateSymlink::plan(&source, &target, false).await?;
+
+        action.try_execute().await?;
+        assert_eq!(tokio::fs::read_link(&target).await?, source);
+
+        action.try_revert().await?;
+        assert!(tokio::fs::symlink_metadata(&target).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recognizes_existing_symlink() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        symlink(&source, &target).await?;
+
+        let action = CreateSymlink::plan(&source, &target, false).await?;
+        assert_eq!(action.state, crate::action::ActionState::Completed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_on_existing_file_unless_forced() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        tokio::fs::write(&target, "Some content").await?;
+
+        match CreateSymlink::plan(&source, &target, false).await {
+            Err(err) => match err.kind() {
+                ActionErrorKind::FileExists(path) => assert_eq!(path, &target),
+                _ => {
+                    return Err(eyre!(
+                        "Should have returned an ActionErrorKind::FileExists error"
+                    ))
+                },
+            },
+            _ => {
+                return Err(eyre!(
+                    "Should have returned an ActionErrorKind::FileExists error"
+                ))
+            },
+        }
+
+        let mut action = CreateSymlink::plan(&source, &target, true).await?;
+        action.try_execute().await?;
+        assert_eq!(tokio::fs::read_link(&target).await?, source);
+        assert_eq!(
+            tokio::fs::read_to_string(backup_path(&target)).await?,
+            "Some content"
+        );
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read_to_string(&target).await?, "Some content");
+        assert!(!backup_path(&target).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_repointed_symlink_on_revert() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let other = temp_dir.path().join("other");
+        let target = temp_dir.path().join("target");
+        let mut action = CreateSymlink::plan(&source, &target, false).await?;
+
+        action.try_execute().await?;
+        remove_file(&target).await?;
+        symlink(&other, &target).await?;
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read_link(&target).await?, other);
+
+        Ok(())
+    }
+}
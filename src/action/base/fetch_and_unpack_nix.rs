@@ -1,16 +1,226 @@
-use std::path::PathBuf;
+use std::{
+    future::Future,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use bytes::{Buf, Bytes};
-use reqwest::Url;
+use base64::Engine;
+use bytes::{Buf, Bytes, BytesMut};
+use rand::Rng;
+use reqwest::{
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    StatusCode, Url,
+};
+use tokio::{io::AsyncWriteExt, task::JoinSet};
 use tracing::{span, Span};
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        context::ActionContext, shell_quote, Action, ActionDescription, ActionError,
+        ActionErrorKind, ActionTag, StatefulAction,
+    },
     parse_ssl_cert,
+    plan::InstallEvent,
+    settings::{BytesPerSec, DEFAULT_USER_AGENT},
 };
 
+/// The prefix of the SRI style hashes (as used by Nix) accepted for `expected_hash`
+const SHA256_PREFIX: &str = "sha256-";
+/// Any clock before the release of the default Nix package (April 2023) is certainly wrong
+const EARLIEST_PLAUSIBLE_TIME: Duration = Duration::from_secs(1_680_307_200);
+/// Any clock after 2100 is almost certainly wrong
+const LATEST_PLAUSIBLE_TIME: Duration = Duration::from_secs(4_102_444_800);
+/// The delay before the first retry of a download, doubled for every following retry
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+/// The smallest range fetched on its own, smaller downloads are not worth splitting further
+const MIN_RANGE_LENGTH: u64 = 1024 * 1024;
+/// How many bytes are downloaded between progress reports
+const PROGRESS_STEP: u64 = 1024 * 1024;
+/// The assumed size of a Nix tarball which is not on disk yet, for [`Action::estimated_duration`]
+pub(crate) const ESTIMATED_TARBALL_BYTES: u64 = 30 * 1024 * 1024;
+/// How many times larger a Nix tarball is once unpacked, for [`Action::required_disk_space`]
+pub(crate) const UNPACKED_SIZE_RATIO: u64 = 6;
+/// The assumed time taken to unpack a Nix tarball, for [`Action::estimated_duration`]
+const ESTIMATED_UNPACK_DURATION: Duration = Duration::from_secs(5);
+/// The directory in `dest` keeping the ranges of an unfinished download, so a retry or a resumed install continues them
+const PARTS_DIR: &str = "nix.tar.xz.parts";
+/// The file in [`PARTS_DIR`] keeping the [`RangeSupport::validator`] the parts were fetched with
+const PARTS_VALIDATOR: &str = "validator";
+
+/// A minisign signature the Nix tarball fetched by [`FetchAndUnpackNix`] must carry
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct NixSignature {
+    /// Where to fetch the `.minisig` signature of the tarball from
+    pub signature_url: Url,
+    /// The trusted minisign public key, in base64 as on the last line of a `minisign.pub`
+    pub trusted_pubkey: String,
+}
+
 /**
-Fetch a URL to the given path
+Fetches the Nix tarball over `http(s)://` for [`FetchAndUnpackNix`]
+
+By default, a [`reqwest::Client`] configured with the proxy, certificates and user agent of the
+action is used. Another implementation, such as one serving fixture bytes in tests or using a
+custom transport, can be set with [`FetchAndUnpackNix::with_downloader`].
+*/
+#[async_trait::async_trait]
+pub trait NixDownloader: std::fmt::Debug + Send + Sync {
+    /// Fetch the whole body of `url`
+    ///
+    /// A [`FetchUrlError::Reqwest`] which may succeed if tried again (like a timeout or a server
+    /// error) is retried, any other error fails the download immediately.
+    async fn fetch(&self, url: &Url) -> Result<Bytes, FetchUrlError>;
+
+    /// Fetch the whole body of `url`, piece by piece as it arrives
+    ///
+    /// Only used when the download is rate limited, so the body can be read no faster than the limit.
+    async fn fetch_stream(&self, url: &Url) -> Result<Box<dyn RangeBody>, FetchUrlError> {
+        Ok(Box::new(Some(self.fetch(url).await?)))
+    }
+
+    /// The length of `url` and its validator, if its server accepts range requests
+    ///
+    /// Only then is `url` fetched in parallel ranges with [`fetch_range`](Self::fetch_range),
+    /// otherwise it is fetched in a single stream with [`fetch`](Self::fetch).
+    async fn range_support(&self, _url: &Url) -> Result<Option<RangeSupport>, FetchUrlError> {
+        Ok(None)
+    }
+
+    /// Fetch `range` of the body of `url`, piece by piece as it arrives
+    ///
+    /// If a `validator` is given, the range must be of the body it was taken from, like with an
+    /// `If-Range` header.
+    async fn fetch_range(
+        &self,
+        url: &Url,
+        range: Range<u64>,
+        _validator: Option<&str>,
+    ) -> Result<Box<dyn RangeBody>, FetchUrlError> {
+        let bytes = self.fetch(url).await?;
+        let end = usize::try_from(range.end).map_or(bytes.len(), |end| end.min(bytes.len()));
+        let start = usize::try_from(range.start).map_or(end, |start| start.min(end));
+        Ok(Box::new(Some(bytes.slice(start..end))))
+    }
+}
+
+/// How the server of a URL serves ranges of it, see [`NixDownloader::range_support`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeSupport {
+    /// The length of the whole body
+    pub length: u64,
+    /// A strong `ETag`, or else the `Last-Modified` date, which changes along with the body
+    ///
+    /// Ranges fetched with a different validator are of another body, so they are fetched again
+    /// rather than resumed.
+    pub validator: Option<String>,
+}
+
+/// The body of a [`NixDownloader::fetch_range`]
+#[async_trait::async_trait]
+pub trait RangeBody: Send {
+    /// The next piece of the body, or `None` once all of it was read
+    async fn next_piece(&mut self) -> Result<Option<Bytes>, FetchUrlError>;
+}
+
+#[async_trait::async_trait]
+impl RangeBody for reqwest::Response {
+    async fn next_piece(&mut self) -> Result<Option<Bytes>, FetchUrlError> {
+        Ok(self.chunk().await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl RangeBody for Option<Bytes> {
+    async fn next_piece(&mut self) -> Result<Option<Bytes>, FetchUrlError> {
+        Ok(self.take())
+    }
+}
+
+#[async_trait::async_trait]
+impl NixDownloader for reqwest::Client {
+    async fn fetch(&self, url: &Url) -> Result<Bytes, FetchUrlError> {
+        Ok(self
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?)
+    }
+
+    async fn fetch_stream(&self, url: &Url) -> Result<Box<dyn RangeBody>, FetchUrlError> {
+        Ok(Box::new(
+            self.get(url.clone()).send().await?.error_for_status()?,
+        ))
+    }
+
+    async fn range_support(&self, url: &Url) -> Result<Option<RangeSupport>, FetchUrlError> {
+        let res = self.head(url.clone()).send().await?.error_for_status()?;
+        let headers = res.headers();
+        let accepts_ranges = headers
+            .get(ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        // The body of a `HEAD` response is empty, so only the header has the length
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        // `If-Range` only accepts a strong `ETag`
+        let validator = headers
+            .get(ETAG)
+            .filter(|value| !value.as_bytes().starts_with(b"W/"))
+            .or_else(|| headers.get(LAST_MODIFIED))
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        Ok(length
+            .filter(|_| accepts_ranges)
+            .map(|length| RangeSupport { length, validator }))
+    }
+
+    async fn fetch_range(
+        &self,
+        url: &Url,
+        range: Range<u64>,
+        validator: Option<&str>,
+    ) -> Result<Box<dyn RangeBody>, FetchUrlError> {
+        let mut req = self
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+        // A body changed since the validator was taken is sent whole, rather than mixed with the old one
+        if let Some(validator) = validator {
+            req = req.header(IF_RANGE, validator);
+        }
+        let res = req.send().await?.error_for_status()?;
+        // A server ignoring the range sends the whole body instead
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(FetchUrlError::RangeIgnored(url.clone()));
+        }
+        Ok(Box::new(res))
+    }
+}
+
+/**
+Fetch a URL to the given path, optionally verifying its SHA-256 before unpacking
+
+If `local_tarball` is set, it is unpacked instead and nothing is downloaded.
+
+If the server accepts range requests, up to `parallelism` ranges of the tarball are fetched at
+once. Each range is kept in `dest` until the whole tarball is fetched, so a retry, or an install
+resumed from its receipt, only fetches what is missing. Otherwise, the tarball is fetched in a
+single stream.
+
+If a `rate_limit` is set, the body is read no faster than it, all ranges together.
+
+Transient download failures (connection errors, timeouts, and server errors) are retried up to
+`max_retries` times with exponential backoff.
+
+If a [`NixSignature`] is given, the tarball must also carry a valid minisign signature by its
+trusted public key, checked before anything is unpacked.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct FetchAndUnpackNix {
@@ -18,6 +228,71 @@ pub struct FetchAndUnpackNix {
     dest: PathBuf,
     proxy: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    #[serde(default)]
+    expected_hash: Option<String>,
+    #[serde(default)]
+    skip_clock_check: bool,
+    #[serde(default = "crate::settings::default_max_retries")]
+    max_retries: u32,
+    #[serde(default)]
+    local_tarball: Option<PathBuf>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    parallelism: u32,
+    #[serde(default = "crate::settings::default_assumed_bandwidth_mbps")]
+    assumed_bandwidth_mbps: u32,
+    #[serde(default)]
+    signature: Option<NixSignature>,
+    #[serde(default)]
+    rate_limit: Option<BytesPerSec>,
+    #[serde(skip)]
+    downloader: Option<Arc<dyn NixDownloader>>,
+}
+
+/// How [`FetchAndUnpackNix`] fetches and checks the Nix package, defaulting as [`CommonSettings`](crate::settings::CommonSettings) does
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// The proxy to fetch through, `http`, `https` or `socks5`
+    pub proxy: Option<Url>,
+    /// A certificate bundle to trust, instead of the system one
+    pub ssl_cert_file: Option<PathBuf>,
+    /// The `sha256-` hash the tarball must have
+    pub expected_hash: Option<String>,
+    /// Fetch over `https` even if the clock looks wrong
+    pub skip_clock_check: bool,
+    /// How often a transient failure is retried
+    pub max_retries: u32,
+    /// A tarball to unpack instead of fetching the URL
+    pub local_tarball: Option<PathBuf>,
+    /// The `User-Agent` header to send
+    pub user_agent: Option<String>,
+    /// The most ranges fetched at once, `1` fetches in a single stream
+    pub parallelism: u32,
+    /// The download speed assumed when estimating the duration
+    pub assumed_bandwidth_mbps: u32,
+    /// The signature the tarball must carry
+    pub signature: Option<NixSignature>,
+    /// The most bytes read per second, all ranges together
+    pub rate_limit: Option<BytesPerSec>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            ssl_cert_file: None,
+            expected_hash: None,
+            skip_clock_check: false,
+            max_retries: crate::settings::default_max_retries(),
+            local_tarball: None,
+            user_agent: None,
+            parallelism: crate::settings::default_download_parallelism(),
+            assumed_bandwidth_mbps: crate::settings::default_assumed_bandwidth_mbps(),
+            signature: None,
+            rate_limit: None,
+        }
+    }
 }
 
 impl FetchAndUnpackNix {
@@ -25,10 +300,22 @@ impl FetchAndUnpackNix {
     pub async fn plan(
         url: Url,
         dest: PathBuf,
-        proxy: Option<Url>,
-        ssl_cert_file: Option<PathBuf>,
+        options: FetchOptions,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        // TODO(@hoverbear): Check URL exists?
+        let FetchOptions {
+            proxy,
+            ssl_cert_file,
+            expected_hash,
+            skip_clock_check,
+            max_retries,
+            local_tarball,
+            user_agent,
+            parallelism,
+            assumed_bandwidth_mbps,
+            signature,
+            rate_limit,
+        } = options;
+
         // TODO(@hoverbear): Check tempdir exists
 
         match url.scheme() {
@@ -47,13 +334,431 @@ impl FetchAndUnpackNix {
             parse_ssl_cert(&ssl_cert_file).await.map_err(Self::error)?;
         }
 
-        Ok(Self {
+        if let Some(user_agent) = &user_agent {
+            if reqwest::header::HeaderValue::from_str(user_agent).is_err() {
+                return Err(Self::error(FetchUrlError::InvalidUserAgent(
+                    user_agent.clone(),
+                )));
+            }
+        }
+
+        if let Some(local_tarball) = &local_tarball {
+            check_local_tarball(local_tarball)
+                .await
+                .map_err(Self::error)?;
+        }
+
+        if let Some(expected_hash) = &expected_hash {
+            let is_sha256 = expected_hash
+                .strip_prefix(SHA256_PREFIX)
+                .and_then(|digest| {
+                    base64::engine::general_purpose::STANDARD
+                        .decode(digest)
+                        .ok()
+                })
+                .map(|digest| digest.len() == ring::digest::SHA256_OUTPUT_LEN)
+                .unwrap_or(false);
+            if !is_sha256 {
+                return Err(Self::error(FetchUrlError::InvalidHash(
+                    expected_hash.clone(),
+                )));
+            }
+        }
+
+        if let Some(signature) = &signature {
+            match signature.signature_url.scheme() {
+                "https" | "http" | "file" => (),
+                _ => return Err(Self::error(FetchUrlError::UnknownUrlScheme)),
+            };
+            minisign_verify::PublicKey::from_base64(&signature.trusted_pubkey)
+                .map_err(|e| {
+                    FetchUrlError::InvalidTrustedPubkey(signature.trusted_pubkey.clone(), e)
+                })
+                .map_err(Self::error)?;
+        }
+
+        let this = Self {
             url,
             dest,
             proxy,
             ssl_cert_file,
+            expected_hash,
+            skip_clock_check,
+            max_retries,
+            local_tarball,
+            user_agent,
+            parallelism,
+            assumed_bandwidth_mbps,
+            signature,
+            rate_limit,
+            downloader: None,
+        };
+        this.check_clock()?;
+
+        Ok(this.into())
+    }
+
+    /// Fetch `http(s)://` URLs with `downloader` instead of a [`reqwest::Client`]
+    ///
+    /// The downloader is not serialized, a plan loaded from a receipt uses a [`reqwest::Client`] again.
+    pub fn with_downloader(mut self, downloader: impl NixDownloader + 'static) -> Self {
+        self.downloader = Some(Arc::new(downloader));
+        self
+    }
+
+    /// TLS certificates can't be validated with a wrong clock, which is common on fresh VMs and containers
+    fn check_clock(&self) -> Result<(), ActionError> {
+        if self.skip_clock_check || self.local_tarball.is_some() || self.url.scheme() != "https" {
+            return Ok(());
+        }
+        match implausible_clock() {
+            Some(now) => Err(Self::error(FetchUrlError::ImplausibleClock(now))),
+            None => Ok(()),
+        }
+    }
+
+    async fn client(&self) -> Result<reqwest::Client, ActionError> {
+        // Without an explicit proxy, `reqwest` already honors `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY`
+        let mut buildable_client = reqwest::Client::builder()
+            .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+        if let Some(proxy) = &self.proxy {
+            buildable_client = buildable_client.proxy(
+                reqwest::Proxy::all(proxy.clone())
+                    .map_err(FetchUrlError::Reqwest)
+                    .map_err(Self::error)?
+                    .no_proxy(reqwest::NoProxy::from_env()),
+            )
+        }
+        if let Some(ssl_cert_file) = &self.ssl_cert_file {
+            let ssl_cert = parse_ssl_cert(&ssl_cert_file).await.map_err(Self::error)?;
+            buildable_client = buildable_client.add_root_certificate(ssl_cert);
+        }
+        buildable_client
+            .build()
+            .map_err(FetchUrlError::Reqwest)
+            .map_err(Self::error)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %self.url, bytes = tracing::field::Empty))]
+    async fn download(&self) -> Result<Bytes, ActionError> {
+        if let Some(local_tarball) = &self.local_tarball {
+            let buf = tokio::fs::read(local_tarball)
+                .await
+                .map_err(|e| ActionErrorKind::Read(local_tarball.clone(), e))
+                .map_err(Self::error)?;
+            tracing::Span::current().record("bytes", buf.len());
+            return Ok(Bytes::from(buf));
+        }
+
+        let bytes = match self.url.scheme() {
+            "https" | "http" => {
+                let downloader = match &self.downloader {
+                    Some(downloader) => downloader.clone(),
+                    None => Arc::new(self.client().await?),
+                };
+                // A server which can't tell the length up front is fetched in a single stream
+                let range_support = if self.parallelism > 1 {
+                    downloader
+                        .range_support(&self.url)
+                        .await
+                        .unwrap_or_else(|e| {
+                            tracing::debug!("Not fetching `{}` in ranges: {e}", self.url);
+                            None
+                        })
+                        .filter(|range_support| range_support.length > 0)
+                } else {
+                    None
+                };
+                // One bucket for every range, so the limit holds for the whole download
+                let rate_limiter = self.rate_limit.map(|rate_limit| {
+                    tracing::debug!("Limiting the download of `{}` to {rate_limit}/s", self.url);
+                    RateLimiter::new(rate_limit)
+                });
+                let start = Instant::now();
+                let bytes = match range_support {
+                    Some(RangeSupport { length, validator }) => {
+                        let progress = DownloadProgress::new(self.url.clone(), Some(length));
+                        let parts = self.parts(length);
+                        self.with_retries(|| {
+                            self.fetch_parts(
+                                &downloader,
+                                &parts,
+                                validator.as_deref(),
+                                &progress,
+                                &rate_limiter,
+                            )
+                        })
+                        .await?;
+                        self.join_parts(&parts).await.map_err(Self::error)?
+                    },
+                    None => {
+                        let progress = DownloadProgress::new(self.url.clone(), None);
+                        let bytes = match &rate_limiter {
+                            Some(rate_limiter) => {
+                                self.with_retries(|| {
+                                    fetch_throttled(&*downloader, &self.url, rate_limiter)
+                                })
+                                .await?
+                            },
+                            None => self.with_retries(|| downloader.fetch(&self.url)).await?,
+                        };
+                        progress.add(bytes.len() as u64);
+                        bytes
+                    },
+                };
+                let elapsed = start.elapsed().as_secs_f64();
+                if elapsed > 0.0 {
+                    tracing::debug!(
+                        "Fetched `{}` at {:.0} bytes/s",
+                        self.url,
+                        bytes.len() as f64 / elapsed
+                    );
+                }
+                bytes
+            },
+            "file" => {
+                let buf = tokio::fs::read(self.url.path())
+                    .await
+                    .map_err(|e| ActionErrorKind::Read(PathBuf::from(self.url.path()), e))
+                    .map_err(Self::error)?;
+                Bytes::from(buf)
+            },
+            _ => return Err(Self::error(FetchUrlError::UnknownUrlScheme)),
+        };
+
+        tracing::Span::current().record("bytes", bytes.len());
+        Ok(bytes)
+    }
+
+    /// Run `fetch` until it succeeds, retrying transient failures up to `max_retries` times
+    async fn with_retries<T, F, Fut>(&self, mut fetch: F) -> Result<T, ActionError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, FetchUrlError>>,
+    {
+        let mut retries = 0;
+        loop {
+            match fetch().await {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) if retries < self.max_retries && is_transient(&e) => {
+                    retries += 1;
+                    let delay = retry_delay(retries);
+                    tracing::warn!(
+                        "Fetching `{}` failed, retrying in {:.1}s ({retries}/{}): {e}",
+                        self.url,
+                        delay.as_secs_f32(),
+                        self.max_retries,
+                    );
+                    tokio::time::sleep(delay).await;
+                },
+                Err(e) => {
+                    if let Some(now) = implausible_clock() {
+                        tracing::warn!(
+                            "The system clock reads {} seconds since the Unix epoch, which is likely why fetching `{}` failed, consider correcting the clock",
+                            now.as_secs(),
+                            self.url,
+                        );
+                    }
+                    return Err(Self::error(e));
+                },
+            }
+        }
+    }
+
+    /// Split a download of `length` bytes into at most `parallelism` ranges, each kept in its own file
+    fn parts(&self, length: u64) -> Vec<(Range<u64>, PathBuf)> {
+        let parts_dir = self.dest.join(PARTS_DIR);
+        let range_length = length
+            .div_ceil(u64::from(self.parallelism.max(1)))
+            .max(MIN_RANGE_LENGTH);
+        (0..length)
+            .step_by(range_length as usize)
+            .map(|start| {
+                let end = (start + range_length).min(length);
+                // Naming a part by its range keeps a part of another split from being mistaken for it
+                (start..end, parts_dir.join(format!("{start}-{end}")))
+            })
+            .collect()
+    }
+
+    /// Fetch what is still missing of each of `parts`, all at once
+    ///
+    /// Parts fetched earlier with another `validator` are of another body, so they are discarded.
+    async fn fetch_parts(
+        &self,
+        downloader: &Arc<dyn NixDownloader>,
+        parts: &[(Range<u64>, PathBuf)],
+        validator: Option<&str>,
+        progress: &DownloadProgress,
+        rate_limiter: &Option<RateLimiter>,
+    ) -> Result<(), FetchUrlError> {
+        let parts_dir = self.dest.join(PARTS_DIR);
+        let validator_path = parts_dir.join(PARTS_VALIDATOR);
+        let previous_validator = match tokio::fs::read_to_string(&validator_path).await {
+            Ok(previous_validator) => Some(previous_validator),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(FetchUrlError::WritingPart(validator_path, e)),
+        };
+        if previous_validator.as_deref() != validator {
+            if previous_validator.is_some() {
+                tracing::debug!(
+                    "`{}` changed since it was partly fetched, fetching it again",
+                    self.url
+                );
+            }
+            match tokio::fs::remove_dir_all(&parts_dir).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(FetchUrlError::WritingPart(parts_dir, e)),
+            }
+        }
+        tokio::fs::create_dir_all(&parts_dir)
+            .await
+            .map_err(|e| FetchUrlError::WritingPart(parts_dir.clone(), e))?;
+        if let Some(validator) = validator {
+            tokio::fs::write(&validator_path, validator)
+                .await
+                .map_err(|e| FetchUrlError::WritingPart(validator_path, e))?;
+        }
+
+        let mut fetched = 0;
+        let mut missing = vec![];
+        for (range, path) in parts {
+            let existing = match tokio::fs::metadata(path).await {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(FetchUrlError::WritingPart(path.clone(), e)),
+            };
+            let expected = range.end - range.start;
+            let existing = if existing > expected {
+                tokio::fs::remove_file(path)
+                    .await
+                    .map_err(|e| FetchUrlError::WritingPart(path.clone(), e))?;
+                0
+            } else {
+                existing
+            };
+            fetched += existing;
+            if existing < expected {
+                missing.push(((range.start + existing)..range.end, path.clone()));
+            }
+        }
+        if fetched > 0 {
+            tracing::debug!("Resuming `{}` after {fetched} bytes", self.url);
+        }
+        progress.reset(fetched);
+
+        let mut set = JoinSet::new();
+        for (range, path) in missing {
+            let downloader = downloader.clone();
+            let url = self.url.clone();
+            let progress = progress.clone();
+            let rate_limiter = rate_limiter.clone();
+            let validator = validator.map(String::from);
+            set.spawn(async move {
+                fetch_part(
+                    &*downloader,
+                    &url,
+                    range,
+                    validator.as_deref(),
+                    &path,
+                    &progress,
+                    &rate_limiter,
+                )
+                .await
+            });
+        }
+
+        let mut first_error = None;
+        while let Some(result) = set.join_next().await {
+            let result = result.unwrap_or_else(|e| Err(FetchUrlError::Join(e)));
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
         }
-        .into())
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Join the fetched `parts` into the whole tarball, removing them
+    async fn join_parts(&self, parts: &[(Range<u64>, PathBuf)]) -> Result<Bytes, ActionErrorKind> {
+        let mut bytes =
+            BytesMut::with_capacity(parts.last().map_or(0, |(range, _)| range.end) as usize);
+        for (_, path) in parts {
+            let part = tokio::fs::read(path)
+                .await
+                .map_err(|e| ActionErrorKind::Read(path.clone(), e))?;
+            bytes.extend_from_slice(&part);
+        }
+        let parts_dir = self.dest.join(PARTS_DIR);
+        tokio::fs::remove_dir_all(&parts_dir)
+            .await
+            .map_err(|e| ActionErrorKind::Remove(parts_dir, e))?;
+        // Leave nothing behind if verifying the tarball fails, unpacking creates `dest` again
+        let _ = tokio::fs::remove_dir(&self.dest).await;
+        Ok(bytes.freeze())
+    }
+
+    /// Check `bytes` carry a valid signature by the trusted public key, fetching the signature first
+    #[tracing::instrument(level = "debug", skip_all, fields(signature_url = %signature.signature_url))]
+    async fn verify_signature(
+        &self,
+        signature: &NixSignature,
+        bytes: &[u8],
+    ) -> Result<(), ActionError> {
+        let signature_url = &signature.signature_url;
+        let buf = match signature_url.scheme() {
+            "https" | "http" => {
+                let downloader = match &self.downloader {
+                    Some(downloader) => downloader.clone(),
+                    None => Arc::new(self.client().await?),
+                };
+                self.with_retries(|| downloader.fetch(signature_url))
+                    .await?
+                    .to_vec()
+            },
+            "file" => tokio::fs::read(signature_url.path())
+                .await
+                .map_err(|e| ActionErrorKind::Read(PathBuf::from(signature_url.path()), e))
+                .map_err(Self::error)?,
+            _ => return Err(Self::error(FetchUrlError::UnknownUrlScheme)),
+        };
+
+        // Checked when planning, but a receipt may have been edited since
+        let public_key = minisign_verify::PublicKey::from_base64(&signature.trusted_pubkey)
+            .map_err(|e| FetchUrlError::InvalidTrustedPubkey(signature.trusted_pubkey.clone(), e))
+            .map_err(Self::error)?;
+        let decoded = std::str::from_utf8(&buf)
+            .ok()
+            .and_then(|buf| minisign_verify::Signature::decode(buf).ok())
+            .ok_or_else(|| FetchUrlError::InvalidSignature(signature_url.clone()))
+            .map_err(Self::error)?;
+        public_key
+            .verify(bytes, &decoded, false)
+            .map_err(|e| FetchUrlError::SignatureVerification(signature_url.clone(), e))
+            .map_err(Self::error)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(dest = %self.dest.display()))]
+    fn unpack(&self, bytes: Bytes) -> Result<(), ActionError> {
+        // TODO(@Hoverbear): Pick directory
+        tracing::trace!("Unpacking tar.xz");
+        let dest_clone = self.dest.clone();
+
+        let decoder = xz2::read::XzDecoder::new(bytes.reader());
+        let mut archive = tar::Archive::new(decoder);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(true);
+        archive
+            .unpack(&dest_clone)
+            .map_err(FetchUrlError::Unarchive)
+            .map_err(Self::error)?;
+
+        Ok(())
     }
 }
 
@@ -64,7 +769,14 @@ impl Action for FetchAndUnpackNix {
         ActionTag("fetch_and_unpack_nix")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Fetch `{}` to `{}`", self.url, self.dest.display())
+        match &self.local_tarball {
+            Some(local_tarball) => format!(
+                "Unpack the local `{}` to `{}` without downloading",
+                local_tarball.display(),
+                self.dest.display()
+            ),
+            None => format!("Fetch `{}` to `{}`", self.url, self.dest.display()),
+        }
     }
 
     fn tracing_span(&self) -> Span {
@@ -89,69 +801,208 @@ impl Action for FetchAndUnpackNix {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if self.local_tarball.is_some() {
+            explanation.push(format!(
+                "No network access is needed, `{}` is not fetched",
+                self.url
+            ));
+        }
+        if let Some(proxy) = self.proxy.as_ref().filter(|_| self.local_tarball.is_none()) {
+            explanation.push(format!("Download through the proxy `{proxy}`"));
+        }
+        if let Some(ssl_cert_file) = &self.ssl_cert_file {
+            explanation.push(format!(
+                "Trust the certificates in `{}`",
+                ssl_cert_file.display()
+            ));
+        }
+        if self.parallelism > 1 && self.local_tarball.is_none() {
+            explanation.push(format!(
+                "Fetch up to {} ranges at once if the server accepts range requests, resuming them if interrupted",
+                self.parallelism
+            ));
+        }
+        if let Some(rate_limit) = self.rate_limit.filter(|_| self.local_tarball.is_none()) {
+            explanation.push(format!("Download at no more than {rate_limit}/s"));
+        }
+        if self.max_retries > 0 && self.local_tarball.is_none() {
+            explanation.push(format!(
+                "Retry up to {} times if the download fails due to a network or server error",
+                self.max_retries
+            ));
+        }
+        if let Some(expected_hash) = &self.expected_hash {
+            explanation.push(format!(
+                "Verify the download has the hash `{expected_hash}` before unpacking"
+            ));
+        }
+        if let Some(signature) = &self.signature {
+            explanation.push(format!(
+                "Verify the download is signed by the trusted minisign key `{}`, with the signature from `{}`",
+                signature.trusted_pubkey, signature.signature_url
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
-    async fn execute(&mut self) -> Result<(), ActionError> {
-        let bytes = match self.url.scheme() {
+    async fn preflight(&self) -> Result<(), ActionError> {
+        if let Some(local_tarball) = &self.local_tarball {
+            return check_local_tarball(local_tarball)
+                .await
+                .map_err(Self::error);
+        }
+        self.check_clock()?;
+        match self.url.scheme() {
+            // A custom downloader may not reach the URL the way a `reqwest::Client` would
+            "https" | "http" if self.downloader.is_some() => (),
             "https" | "http" => {
-                let mut buildable_client = reqwest::Client::builder();
-                if let Some(proxy) = &self.proxy {
-                    buildable_client = buildable_client.proxy(
-                        reqwest::Proxy::all(proxy.clone())
-                            .map_err(FetchUrlError::Reqwest)
-                            .map_err(Self::error)?,
-                    )
-                }
-                if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                    let ssl_cert = parse_ssl_cert(&ssl_cert_file).await.map_err(Self::error)?;
-                    buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                }
-                let client = buildable_client
-                    .build()
-                    .map_err(FetchUrlError::Reqwest)
-                    .map_err(Self::error)?;
-                let req = client
-                    .get(self.url.clone())
-                    .build()
-                    .map_err(FetchUrlError::Reqwest)
-                    .map_err(Self::error)?;
-                let res = client
-                    .execute(req)
+                // Only ask for the headers, the tarball is fetched during execution
+                let client = self.client().await?;
+                client
+                    .head(self.url.clone())
+                    .send()
                     .await
+                    .and_then(|res| res.error_for_status())
                     .map_err(FetchUrlError::Reqwest)
                     .map_err(Self::error)?;
-                res.bytes()
-                    .await
-                    .map_err(FetchUrlError::Reqwest)
-                    .map_err(Self::error)?
             },
             "file" => {
-                let buf = tokio::fs::read(self.url.path())
+                tokio::fs::metadata(self.url.path())
                     .await
-                    .map_err(|e| ActionErrorKind::Read(PathBuf::from(self.url.path()), e))
+                    .map_err(|e| {
+                        ActionErrorKind::GettingMetadata(PathBuf::from(self.url.path()), e)
+                    })
                     .map_err(Self::error)?;
-                Bytes::from(buf)
             },
             _ => return Err(Self::error(FetchUrlError::UnknownUrlScheme)),
-        };
+        }
+        Ok(())
+    }
 
-        // TODO(@Hoverbear): Pick directory
-        tracing::trace!("Unpacking tar.xz");
-        let dest_clone = self.dest.clone();
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let bytes = self.download().await?;
 
-        let decoder = xz2::read::XzDecoder::new(bytes.reader());
-        let mut archive = tar::Archive::new(decoder);
-        archive.set_preserve_permissions(true);
-        archive.set_preserve_mtime(true);
-        archive.set_unpack_xattrs(true);
-        archive
-            .unpack(&dest_clone)
-            .map_err(FetchUrlError::Unarchive)
-            .map_err(Self::error)?;
+        // Verify before unpacking anything, so a mismatch leaves nothing behind
+        if let Some(expected_hash) = &self.expected_hash {
+            let got = format!(
+                "{SHA256_PREFIX}{}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(ring::digest::digest(&ring::digest::SHA256, &bytes))
+            );
+            if got != *expected_hash {
+                return Err(Self::error(FetchUrlError::HashMismatch {
+                    expected: expected_hash.clone(),
+                    got,
+                }));
+            }
+            tracing::debug!("Verified `{}` has hash `{got}`", self.url);
+        }
+        if let Some(signature) = &self.signature {
+            self.verify_signature(signature, &bytes).await?;
+            tracing::debug!(
+                "Verified `{}` is signed by the trusted key `{}`",
+                self.url,
+                signature.trusted_pubkey
+            );
+        }
 
-        Ok(())
+        self.unpack(bytes)
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let dest = shell_quote(&self.dest);
+        let mut commands = vec![format!("mkdir -p {dest}")];
+        let tarball = match &self.local_tarball {
+            Some(local_tarball) => shell_quote(local_tarball),
+            None => {
+                let tarball = shell_quote(self.dest.join("nix.tar.xz"));
+                let mut curl = format!(
+                    "curl --fail --location --retry {} --output {tarball}",
+                    self.max_retries
+                );
+                if let Some(proxy) = &self.proxy {
+                    curl.push_str(&format!(" --proxy {}", shell_quote(proxy.as_str())));
+                }
+                if let Some(rate_limit) = self.rate_limit {
+                    curl.push_str(&format!(" --limit-rate {rate_limit}"));
+                }
+                curl.push_str(&format!(
+                    " --user-agent {}",
+                    shell_quote(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+                ));
+                if let Some(ssl_cert_file) = &self.ssl_cert_file {
+                    curl.push_str(&format!(" --cacert {}", shell_quote(ssl_cert_file)));
+                }
+                curl.push_str(&format!(" {}", shell_quote(self.url.as_str())));
+                commands.push(curl);
+                tarball
+            },
+        };
+        if let Some(expected_hash) = &self.expected_hash {
+            let expected = shell_quote(expected_hash.trim_start_matches(SHA256_PREFIX));
+            commands.push(format!(
+                "[ \"$(openssl dgst -sha256 -binary {tarball} | base64)\" = {expected} ] || {{ echo {} >&2; exit 1; }}",
+                shell_quote(format!("{tarball} does not have the hash `{expected_hash}`"))
+            ));
+        }
+        if let Some(signature) = &self.signature {
+            let minisig = shell_quote(self.dest.join("nix.tar.xz.minisig"));
+            let mut curl = format!("curl --fail --location --output {minisig}");
+            if let Some(ssl_cert_file) = &self.ssl_cert_file {
+                curl.push_str(&format!(" --cacert {}", shell_quote(ssl_cert_file)));
+            }
+            curl.push_str(&format!(
+                " {}",
+                shell_quote(signature.signature_url.as_str())
+            ));
+            commands.push(curl);
+            commands.push(format!(
+                "minisign -V -m {tarball} -x {minisig} -P {}",
+                shell_quote(&signature.trusted_pubkey)
+            ));
+            commands.push(format!("rm {minisig}"));
+        }
+        commands.push(format!("tar -xJf {tarball} -C {dest}"));
+        if self.local_tarball.is_none() {
+            commands.push(format!("rm {tarball}"));
+        }
+        Some(commands)
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        // A tarball already on disk is only unpacked
+        if self.local_tarball.is_some() || self.url.scheme() == "file" {
+            return ESTIMATED_UNPACK_DURATION;
+        }
+        let mut bytes_per_second = u64::from(self.assumed_bandwidth_mbps.max(1)) * 1_000_000 / 8;
+        if let Some(rate_limit) = self.rate_limit {
+            bytes_per_second = bytes_per_second.min(rate_limit.get());
+        }
+        Duration::from_secs(ESTIMATED_TARBALL_BYTES.div_ceil(bytes_per_second))
+            + ESTIMATED_UNPACK_DURATION
+    }
+
+    fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+        let local_tarball = match &self.local_tarball {
+            Some(local_tarball) => Some(local_tarball.clone()),
+            None if self.url.scheme() == "file" => Some(PathBuf::from(self.url.path())),
+            None => None,
+        };
+        let required = match local_tarball {
+            // A tarball already on disk is only unpacked
+            Some(local_tarball) => {
+                std::fs::metadata(local_tarball)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(ESTIMATED_TARBALL_BYTES)
+                    * UNPACKED_SIZE_RATIO
+            },
+            // The download is kept in `dest` until it is unpacked
+            None => ESTIMATED_TARBALL_BYTES * (1 + UNPACKED_SIZE_RATIO),
+        };
+        vec![(self.dest.clone(), required)]
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
@@ -164,6 +1015,217 @@ impl Action for FetchAndUnpackNix {
     }
 }
 
+/// Fetch `range` of `url` onto the end of the part at `path`
+async fn fetch_part(
+    downloader: &dyn NixDownloader,
+    url: &Url,
+    range: Range<u64>,
+    validator: Option<&str>,
+    path: &Path,
+    progress: &DownloadProgress,
+    rate_limiter: &Option<RateLimiter>,
+) -> Result<(), FetchUrlError> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| FetchUrlError::WritingPart(path.to_path_buf(), e))?;
+    let expected = range.end - range.start;
+    let mut written = 0;
+    let mut body = downloader.fetch_range(url, range, validator).await?;
+    if let Some(rate_limiter) = rate_limiter {
+        body = Box::new(Throttled {
+            body,
+            rate_limiter: rate_limiter.clone(),
+        });
+    }
+    while let Some(piece) = body.next_piece().await? {
+        file.write_all(&piece)
+            .await
+            .map_err(|e| FetchUrlError::WritingPart(path.to_path_buf(), e))?;
+        written += piece.len() as u64;
+        progress.add(piece.len() as u64);
+    }
+    file.flush()
+        .await
+        .map_err(|e| FetchUrlError::WritingPart(path.to_path_buf(), e))?;
+    if written != expected {
+        return Err(FetchUrlError::PartLength {
+            path: path.to_path_buf(),
+            expected,
+            got: written,
+        });
+    }
+    Ok(())
+}
+
+/// Fetch the whole body of `url` through `rate_limiter`
+async fn fetch_throttled(
+    downloader: &dyn NixDownloader,
+    url: &Url,
+    rate_limiter: &RateLimiter,
+) -> Result<Bytes, FetchUrlError> {
+    let mut body = Throttled {
+        body: downloader.fetch_stream(url).await?,
+        rate_limiter: rate_limiter.clone(),
+    };
+    let mut bytes = BytesMut::new();
+    while let Some(piece) = body.next_piece().await? {
+        bytes.extend_from_slice(&piece);
+    }
+    Ok(bytes.freeze())
+}
+
+/**
+A token bucket shared by every body read through it, so together they read no faster than the rate
+
+A bucket holds up to a second of bytes. Reading more than the bucket holds borrows from the
+future, and the reader waits until the bucket would have refilled.
+*/
+#[derive(Clone)]
+struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(rate_limit: BytesPerSec) -> Self {
+        let bytes_per_second = rate_limit.get() as f64;
+        Self {
+            bytes_per_second,
+            bucket: Arc::new(Mutex::new((bytes_per_second, Instant::now()))),
+        }
+    }
+
+    /// How long to wait before reading `bytes` more
+    fn take(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.bytes_per_second)
+            .min(self.bytes_per_second);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.bytes_per_second)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// A [`RangeBody`] read through a [`RateLimiter`]
+struct Throttled {
+    body: Box<dyn RangeBody>,
+    rate_limiter: RateLimiter,
+}
+
+#[async_trait::async_trait]
+impl RangeBody for Throttled {
+    async fn next_piece(&mut self) -> Result<Option<Bytes>, FetchUrlError> {
+        let piece = self.body.next_piece().await?;
+        if let Some(piece) = &piece {
+            let wait = self.rate_limiter.take(piece.len());
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        Ok(piece)
+    }
+}
+
+/// Reports the bytes downloaded so far as [`InstallEvent::DownloadProgress`], every [`PROGRESS_STEP`] bytes
+#[derive(Clone)]
+struct DownloadProgress {
+    url: Url,
+    total: Option<u64>,
+    bytes: Arc<AtomicU64>,
+    context: ActionContext,
+}
+
+impl DownloadProgress {
+    fn new(url: Url, total: Option<u64>) -> Self {
+        Self {
+            url,
+            total,
+            bytes: Default::default(),
+            context: ActionContext::current(),
+        }
+    }
+
+    /// Start counting again from `bytes`, such as the ones already fetched by an earlier attempt
+    fn reset(&self, bytes: u64) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.report(bytes);
+    }
+
+    fn add(&self, bytes: u64) {
+        let before = self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        let after = before + bytes;
+        if before / PROGRESS_STEP != after / PROGRESS_STEP || Some(after) == self.total {
+            self.report(after);
+        }
+    }
+
+    fn report(&self, bytes: u64) {
+        tracing::trace!("Fetched {bytes} bytes of `{}`", self.url);
+        self.context.send(InstallEvent::DownloadProgress {
+            url: self.url.clone(),
+            bytes,
+            total: self.total,
+        });
+    }
+}
+
+/// The time since the Unix epoch, if it is outside of the plausible range
+fn implausible_clock() -> Option<Duration> {
+    // A clock before the Unix epoch is as implausible as it gets
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    if now < EARLIEST_PLAUSIBLE_TIME || now > LATEST_PLAUSIBLE_TIME {
+        Some(now)
+    } else {
+        None
+    }
+}
+
+async fn check_local_tarball(path: &Path) -> Result<(), ActionErrorKind> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+    if !metadata.is_file() {
+        return Err(ActionErrorKind::PathWasNotFile(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Whether a failed download might succeed if tried again, a `404` or an invalid certificate won't
+fn is_transient(err: &FetchUrlError) -> bool {
+    let err = match err {
+        FetchUrlError::Reqwest(err) => err,
+        // A body cut short is fetched again from where it stopped
+        FetchUrlError::PartLength { .. } => return true,
+        _ => return false,
+    };
+    match err.status() {
+        Some(status) => is_transient_status(status),
+        None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Exponential backoff with up to 50% jitter, so many machines installing at once don't retry in lockstep
+fn retry_delay(retry: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY * 2u32.pow(retry.saturating_sub(1).min(6));
+    let jitter = rand::thread_rng().gen_range(0.0..0.5);
+    delay.mul_f64(1.0 + jitter)
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum FetchUrlError {
@@ -179,6 +1241,32 @@ pub enum FetchUrlError {
     UnknownUrlScheme,
     #[error("Unknown proxy scheme, `https://`, `socks5://`, and `http://` supported")]
     UnknownProxyScheme,
+    #[error("User agent `{0}` is not a valid HTTP header value, it may only contain visible ASCII characters, spaces and tabs")]
+    InvalidUserAgent(String),
+    #[error("Expected hash `{0}` is not a SHA-256 hash in the form `sha256-<base64>`")]
+    InvalidHash(String),
+    #[error("Downloaded Nix has hash `{got}`, but `{expected}` was expected, the download may be corrupt or tampered with")]
+    HashMismatch { expected: String, got: String },
+    #[error("Trusted public key `{0}` is not a minisign public key in base64")]
+    InvalidTrustedPubkey(String, #[source] minisign_verify::Error),
+    #[error("`{0}` is not a minisign signature")]
+    InvalidSignature(Url),
+    #[error("Downloaded Nix is not signed by the trusted public key according to `{0}`, the download may have been tampered with")]
+    SignatureVerification(Url, #[source] minisign_verify::Error),
+    #[error("Server of `{0}` advertised range requests, but ignored one, or the download changed since its earlier ranges were fetched")]
+    RangeIgnored(Url),
+    #[error("Writing part of the download to `{0}`")]
+    WritingPart(PathBuf, #[source] std::io::Error),
+    #[error("Part `{path}` of the download should have {expected} bytes, but got {got}")]
+    PartLength {
+        path: PathBuf,
+        expected: u64,
+        got: u64,
+    },
+    #[error("Joining a download task")]
+    Join(#[source] tokio::task::JoinError),
+    #[error("The system clock reads {} seconds since the Unix epoch, which is not plausibly current and will cause TLS certificate validation to fail, consider correcting the clock (for example with `timedatectl set-ntp true`) or pass `--skip-clock-check`", .0.as_secs())]
+    ImplausibleClock(Duration),
 }
 
 impl Into<ActionErrorKind> for FetchUrlError {
@@ -186,3 +1274,461 @@ impl Into<ActionErrorKind> for FetchUrlError {
         ActionErrorKind::Custom(Box::new(self))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_server_errors_are_transient() {
+        assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn local_tarball_is_used_instead_of_url() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let local_tarball = temp_dir.path().join("nix.tar.xz");
+        tokio::fs::write(&local_tarball, fixture_tarball()?).await?;
+        // Nothing listens on the discard port, so any fetch of the URL fails
+        let url: Url = "http://127.0.0.1:9/nix.tar.xz".parse()?;
+        let dest = temp_dir.path().join("dest");
+
+        let mut action = FetchAndUnpackNix::plan(
+            url.clone(),
+            dest.clone(),
+            FetchOptions {
+                local_tarball: Some(local_tarball.clone()),
+                parallelism: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert!(action
+            .tracing_synopsis()
+            .contains(&format!("`{}`", local_tarball.display())));
+        action.try_preflight().await?;
+        action.try_execute().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(dest.join("nix-fixture/README")).await?,
+            "Nix"
+        );
+
+        let missing = FetchAndUnpackNix::plan(
+            url,
+            temp_dir.path().join("dest"),
+            FetchOptions {
+                local_tarball: Some(temp_dir.path().join("missing.tar.xz")),
+                parallelism: 1,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(
+            missing.map_err(|e| e.kind().to_string()),
+            Err(message) if message.contains("missing.tar.xz")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_user_agent_is_rejected() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let url: Url = crate::settings::NIX_X64_64_LINUX_URL.parse()?;
+
+        let invalid = FetchAndUnpackNix::plan(
+            url,
+            temp_dir.path().join("dest"),
+            FetchOptions {
+                skip_clock_check: true,
+                user_agent: Some("nix-installer\n(ops)".into()),
+                parallelism: 1,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(
+            invalid.map_err(|e| e.kind().to_string()),
+            Err(message) if message.contains("not a valid HTTP header value")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expected_hash_must_be_sha256() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let url: Url = crate::settings::NIX_X64_64_LINUX_URL.parse()?;
+
+        for invalid in [
+            "0".repeat(64),
+            "sha512-AAAA".to_string(),
+            format!("{SHA256_PREFIX}not base64"),
+            format!("{SHA256_PREFIX}AAAA"),
+        ] {
+            let planned = FetchAndUnpackNix::plan(
+                url.clone(),
+                temp_dir.path().join("dest"),
+                FetchOptions {
+                    expected_hash: Some(invalid.clone()),
+                    skip_clock_check: true,
+                    parallelism: 1,
+                    ..Default::default()
+                },
+            )
+            .await;
+            assert!(
+                matches!(
+                    planned.map_err(|e| e.kind().to_string()),
+                    Err(message) if message.contains("is not a SHA-256 hash")
+                ),
+                "{invalid}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signature_is_verified_against_trusted_pubkey() -> eyre::Result<()> {
+        // From the tests of `minisign-verify`, a prehashed signature of `test`
+        const TRUSTED_PUBKEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+        const MINISIG: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+        let temp_dir = tempfile::tempdir()?;
+        let minisig = temp_dir.path().join("nix.tar.xz.minisig");
+        tokio::fs::write(&minisig, MINISIG).await?;
+        let signature = NixSignature {
+            signature_url: Url::from_file_path(&minisig).unwrap(),
+            trusted_pubkey: TRUSTED_PUBKEY.into(),
+        };
+
+        let action = FetchAndUnpackNix::plan(
+            crate::settings::NIX_X64_64_LINUX_URL.parse()?,
+            temp_dir.path().join("dest"),
+            FetchOptions {
+                skip_clock_check: true,
+                parallelism: 1,
+                signature: Some(signature.clone()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        action.action.verify_signature(&signature, b"test").await?;
+        let err = action
+            .action
+            .verify_signature(&signature, b"Test")
+            .await
+            .unwrap_err();
+        assert!(err.kind().to_string().contains("not signed by the trusted"));
+
+        let untrusted = FetchAndUnpackNix::plan(
+            crate::settings::NIX_X64_64_LINUX_URL.parse()?,
+            temp_dir.path().join("dest"),
+            FetchOptions {
+                skip_clock_check: true,
+                parallelism: 1,
+                signature: Some(NixSignature {
+                    trusted_pubkey: "not a key".into(),
+                    ..signature
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(
+            untrusted.map_err(|e| e.kind().to_string()),
+            Err(message) if message.contains("not a minisign public key")
+        ));
+
+        Ok(())
+    }
+
+    /// Serves `tarball` for every URL, counting the fetches
+    #[derive(Debug)]
+    struct FixtureDownloader {
+        tarball: Bytes,
+        fetches: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl NixDownloader for FixtureDownloader {
+        async fn fetch(&self, _url: &Url) -> Result<Bytes, FetchUrlError> {
+            self.fetches
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(self.tarball.clone())
+        }
+    }
+
+    fn fixture_tarball() -> eyre::Result<Bytes> {
+        let contents = b"Nix";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 6));
+        builder.append_data(&mut header, "nix-fixture/README", &contents[..])?;
+        Ok(Bytes::from(builder.into_inner()?.finish()?))
+    }
+
+    #[tokio::test]
+    async fn injected_downloader_is_verified_and_unpacked() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let url: Url = crate::settings::NIX_X64_64_LINUX_URL.parse()?;
+        let tarball = fixture_tarball()?;
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let hash_of = |bytes: &[u8]| {
+            format!(
+                "{SHA256_PREFIX}{}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(ring::digest::digest(&ring::digest::SHA256, bytes))
+            )
+        };
+
+        for (dest, expected_hash) in [
+            ("matching", hash_of(&tarball)),
+            ("mismatched", hash_of(b"Not Nix")),
+        ] {
+            let dest = temp_dir.path().join(dest);
+            let mut action = FetchAndUnpackNix::plan(
+                url.clone(),
+                dest.clone(),
+                FetchOptions {
+                    expected_hash: Some(expected_hash),
+                    skip_clock_check: true,
+                    parallelism: 1,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            action.action = action.action.with_downloader(FixtureDownloader {
+                tarball: tarball.clone(),
+                fetches: fetches.clone(),
+            });
+            action.try_preflight().await?;
+
+            if dest.ends_with("matching") {
+                action.try_execute().await?;
+                assert_eq!(
+                    tokio::fs::read_to_string(dest.join("nix-fixture/README")).await?,
+                    "Nix"
+                );
+            } else {
+                let err = action.try_execute().await.unwrap_err();
+                assert!(err.kind().to_string().contains("was expected"));
+                assert!(!dest.exists());
+            }
+        }
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        Ok(())
+    }
+
+    /// Serves `tarball` in ranges, cutting the first range it serves short if `cut_short` is set
+    #[derive(Debug)]
+    struct RangedDownloader {
+        tarball: Bytes,
+        validator: Option<String>,
+        cut_short: bool,
+        ranges: Arc<std::sync::Mutex<Vec<Range<u64>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl NixDownloader for RangedDownloader {
+        async fn fetch(&self, _url: &Url) -> Result<Bytes, FetchUrlError> {
+            unreachable!("Only ranges are fetched")
+        }
+
+        async fn range_support(&self, _url: &Url) -> Result<Option<RangeSupport>, FetchUrlError> {
+            Ok(Some(RangeSupport {
+                length: self.tarball.len() as u64,
+                validator: self.validator.clone(),
+            }))
+        }
+
+        async fn fetch_range(
+            &self,
+            _url: &Url,
+            range: Range<u64>,
+            validator: Option<&str>,
+        ) -> Result<Box<dyn RangeBody>, FetchUrlError> {
+            assert_eq!(validator, self.validator.as_deref());
+            let mut ranges = self.ranges.lock().unwrap();
+            let end = if self.cut_short && ranges.is_empty() {
+                range.start + (range.end - range.start) / 2
+            } else {
+                range.end
+            };
+            ranges.push(range.clone());
+            Ok(Box::new(Some(
+                self.tarball.slice(range.start as usize..end as usize),
+            )))
+        }
+    }
+
+    /// A tarball of random contents, which don't compress, so it is split into several ranges
+    fn ranged_tarball() -> eyre::Result<(Vec<u8>, Bytes)> {
+        let mut contents = vec![0; 3 * MIN_RANGE_LENGTH as usize];
+        rand::thread_rng().fill(&mut contents[..]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 0));
+        builder.append_data(&mut header, "nix-fixture/store", &contents[..])?;
+        let tarball = Bytes::from(builder.into_inner()?.finish()?);
+        Ok((contents, tarball))
+    }
+
+    #[tokio::test]
+    async fn ranges_are_resumed_and_reported() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dest = temp_dir.path().join("dest");
+        let url: Url = crate::settings::NIX_X64_64_LINUX_URL.parse()?;
+        let (contents, tarball) = ranged_tarball()?;
+
+        let ranges = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut action = FetchAndUnpackNix::plan(
+            url,
+            dest.clone(),
+            FetchOptions {
+                skip_clock_check: true,
+                max_retries: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+        action.action = action.action.with_downloader(RangedDownloader {
+            tarball: tarball.clone(),
+            validator: Some("\"v1\"".into()),
+            cut_short: true,
+            ranges: ranges.clone(),
+        });
+
+        let (event_channel, mut events) = tokio::sync::broadcast::channel(1024);
+        ActionContext {
+            event_channel: Some(event_channel),
+            ..Default::default()
+        }
+        .scope(action.try_execute())
+        .await?;
+
+        assert_eq!(
+            tokio::fs::read(dest.join("nix-fixture/store")).await?,
+            contents
+        );
+        assert!(!dest.join(PARTS_DIR).exists());
+
+        // The range cut short is fetched again from where it stopped
+        let ranges = ranges.lock().unwrap().clone();
+        let parts = action.action.parts(tarball.len() as u64);
+        assert_eq!(ranges.len(), parts.len() + 1);
+        assert!(ranges.iter().any(|range| parts
+            .iter()
+            .any(|(part, _)| *range == ((part.start + part.end) / 2..part.end))));
+
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            if let InstallEvent::DownloadProgress { bytes, total, .. } = event {
+                assert_eq!(total, Some(tarball.len() as u64));
+                last = Some(bytes);
+            }
+        }
+        assert_eq!(last, Some(tarball.len() as u64));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parts_of_a_changed_download_are_fetched_again() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dest = temp_dir.path().join("dest");
+        let url: Url = crate::settings::NIX_X64_64_LINUX_URL.parse()?;
+        let (contents, tarball) = ranged_tarball()?;
+
+        let ranges = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut action = FetchAndUnpackNix::plan(
+            url,
+            dest.clone(),
+            FetchOptions {
+                skip_clock_check: true,
+                max_retries: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+        action.action = action.action.with_downloader(RangedDownloader {
+            tarball: tarball.clone(),
+            validator: Some("\"v2\"".into()),
+            cut_short: false,
+            ranges: ranges.clone(),
+        });
+
+        // Half of the first part of the previous version of the tarball
+        let parts = action.action.parts(tarball.len() as u64);
+        let (first_range, first_part) = &parts[0];
+        let parts_dir = dest.join(PARTS_DIR);
+        tokio::fs::create_dir_all(&parts_dir).await?;
+        tokio::fs::write(parts_dir.join(PARTS_VALIDATOR), "\"v1\"").await?;
+        tokio::fs::write(first_part, vec![0; (first_range.end / 2) as usize]).await?;
+
+        action.try_execute().await?;
+
+        assert_eq!(
+            tokio::fs::read(dest.join("nix-fixture/store")).await?,
+            contents
+        );
+        // Fetched all at once, so in whichever order they were started
+        let mut ranges = ranges.lock().unwrap().clone();
+        ranges.sort_by_key(|range| range.start);
+        assert_eq!(
+            ranges,
+            parts
+                .into_iter()
+                .map(|(range, _)| range)
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn retry_delay_backs_off_exponentially() {
+        for retry in 1..=4 {
+            let base = RETRY_BASE_DELAY * 2u32.pow(retry - 1);
+            let delay = retry_delay(retry);
+            assert!(delay >= base && delay < base.mul_f64(1.5), "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn rate_limiter_is_shared_by_its_clones() -> eyre::Result<()> {
+        let rate_limit = "1K".parse::<BytesPerSec>()?;
+        assert_eq!(rate_limit.get(), 1024);
+        assert_eq!(rate_limit.to_string(), "1K");
+        assert!("0".parse::<BytesPerSec>().is_err());
+        assert!("2X".parse::<BytesPerSec>().is_err());
+
+        let rate_limiter = RateLimiter::new(rate_limit);
+        // A full bucket lets a second of bytes through at once
+        assert_eq!(rate_limiter.take(1024), Duration::ZERO);
+        // Whichever range reads next waits for the bucket to refill
+        let wait = rate_limiter.clone().take(512);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "{wait:?}"
+        );
+        let wait = rate_limiter.take(512);
+        assert!(
+            wait > Duration::from_millis(900) && wait <= Duration::from_secs(1),
+            "{wait:?}"
+        );
+        Ok(())
+    }
+}
@@ -1,23 +1,192 @@
 //! Base [`Action`](crate::action::Action)s that themselves have no other actions as dependencies
 
+pub(crate) mod change_ownership;
+pub(crate) mod check_memory;
 pub(crate) mod create_directory;
 pub(crate) mod create_file;
 pub(crate) mod create_group;
 pub(crate) mod create_or_insert_into_file;
 pub(crate) mod create_or_merge_nix_config;
+pub(crate) mod create_symlink;
 pub(crate) mod delete_user;
 pub(crate) mod fetch_and_unpack_nix;
 pub(crate) mod move_unpacked_nix;
 pub(crate) mod remove_directory;
+pub(crate) mod remove_stale_temp_roots;
+pub(crate) mod remove_tree;
 pub(crate) mod setup_default_profile;
+pub(crate) mod verify_nix_on_path;
 
-pub use create_directory::CreateDirectory;
+pub use change_ownership::ChangeOwnership;
+pub use check_memory::{CheckMemory, CheckMemoryError};
+pub use create_directory::{CreateDirectory, CreateDirectoryError};
 pub use create_file::CreateFile;
 pub use create_group::CreateGroup;
 pub use create_or_insert_into_file::CreateOrInsertIntoFile;
 pub use create_or_merge_nix_config::CreateOrMergeNixConfig;
+pub use create_symlink::CreateSymlink;
 pub use delete_user::DeleteUser;
-pub use fetch_and_unpack_nix::{FetchAndUnpackNix, FetchUrlError};
+pub use fetch_and_unpack_nix::{
+    FetchAndUnpackNix, FetchOptions, FetchUrlError, NixDownloader, NixSignature, RangeBody,
+    RangeSupport,
+};
 pub use move_unpacked_nix::{MoveUnpackedNix, MoveUnpackedNixError};
-pub use remove_directory::RemoveDirectory;
+pub use remove_directory::{RemoveDirectory, RemoveDirectoryError};
+pub use remove_stale_temp_roots::RemoveStaleTempRoots;
+pub use remove_tree::RemoveTreeError;
 pub use setup_default_profile::{SetupDefaultProfile, SetupDefaultProfileError};
+pub use verify_nix_on_path::{VerifyNixOnPath, VerifyNixOnPathError};
+
+use std::{
+    ffi::OsStr,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use nix::unistd::{chown, Gid, Uid};
+use tokio::process::Command;
+
+use crate::{
+    action::{shell_command, shell_quote, ActionErrorKind},
+    execute_command,
+};
+
+/// Where the SELinux filesystem is mounted when SELinux is enabled
+const SELINUX_FS: &str = "/sys/fs/selinux";
+
+/// Re-read a freshly written file, ensuring the content (and mode, if given) on disk is what was written
+pub(crate) async fn verify_written(
+    path: &Path,
+    buf: &str,
+    mode: Option<u32>,
+) -> Result<(), ActionErrorKind> {
+    let discovered_buf = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| ActionErrorKind::Read(path.to_path_buf(), e))?;
+    if discovered_buf != buf {
+        return Err(ActionErrorKind::WriteVerificationFailed(path.to_path_buf()));
+    }
+
+    if let Some(mode) = mode {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+        // We only care about user-group-other permissions
+        if metadata.permissions().mode() & 0o777 != mode & 0o777 {
+            return Err(ActionErrorKind::WriteVerificationFailed(path.to_path_buf()));
+        }
+    }
+
+    Ok(())
+}
+
+/// The metadata of a file an action replaces or edits, recorded on execute so revert can restore it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct OriginalMetadata {
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl OriginalMetadata {
+    /// The metadata of `path`, if it exists
+    pub(crate) async fn of(path: &Path) -> Result<Option<Self>, ActionErrorKind> {
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => Ok(Some(Self {
+                mode: metadata.permissions().mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ActionErrorKind::GettingMetadata(path.to_path_buf(), e)),
+        }
+    }
+
+    /// Give `path` this owner, group and mode
+    pub(crate) async fn restore(&self, path: &Path) -> Result<(), ActionErrorKind> {
+        let Self { mode, uid, gid } = *self;
+        // Ownership first, so a setuid mode never applies to the wrong owner
+        chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+            .map_err(|e| ActionErrorKind::Chown(path.to_path_buf(), e))?;
+        tokio::fs::set_permissions(path, PermissionsExt::from_mode(mode))
+            .await
+            .map_err(|e| ActionErrorKind::SetPermissions(mode, path.to_path_buf(), e))?;
+        Ok(())
+    }
+}
+
+/// The shell commands giving `path` its owner, group, and mode, as the file actions do after writing it
+pub(crate) fn shell_set_ownership(
+    path: &Path,
+    user: &Option<String>,
+    group: &Option<String>,
+    mode: Option<u32>,
+) -> Vec<String> {
+    let mut commands = vec![];
+    let owner = match (user, group) {
+        (Some(user), Some(group)) => Some(format!("{user}:{group}")),
+        (Some(user), None) => Some(user.clone()),
+        (None, Some(group)) => Some(format!(":{group}")),
+        (None, None) => None,
+    };
+    if let Some(owner) = owner {
+        commands.push(shell_command([
+            OsStr::new("chown"),
+            owner.as_ref(),
+            path.as_ref(),
+        ]));
+    }
+    if let Some(mode) = mode {
+        commands.push(shell_command([
+            OsStr::new("chmod"),
+            format!("{mode:o}").as_ref(),
+            path.as_ref(),
+        ]));
+    }
+    commands
+}
+
+/// The shell command writing exactly `buf` to `path`, replacing it
+pub(crate) fn shell_write(path: &Path, buf: &str) -> String {
+    format!("printf '%s' {} > {}", shell_quote(buf), shell_quote(path))
+}
+
+/// Label a freshly created `path` for SELinux, with `context` if given, or else by the loaded policy
+///
+/// Does nothing on systems without SELinux. Without a `context`, failing to relabel only warns,
+/// since the policy for Nix may not be loaded yet.
+pub(crate) async fn set_selinux_context(
+    path: &Path,
+    context: Option<&str>,
+) -> Result<(), ActionErrorKind> {
+    if !Path::new(SELINUX_FS).exists() {
+        return Ok(());
+    }
+    match context {
+        Some(context) => {
+            execute_command(Command::new("chcon").arg(context).arg(path)).await?;
+        },
+        None if which::which("restorecon").is_ok() => {
+            if let Err(err) = execute_command(Command::new("restorecon").arg(path)).await {
+                tracing::warn!("Relabeling `{}` for SELinux: {err}", path.display());
+            }
+        },
+        None => {
+            tracing::debug!(
+                "Not relabeling `{}` for SELinux, `restorecon` is not installed",
+                path.display()
+            );
+        },
+    }
+    Ok(())
+}
+
+/// The shell command giving `path` its SELinux `context`, if any, when SELinux is enabled
+pub(crate) fn shell_set_selinux_context(path: &Path, context: Option<&str>) -> Option<String> {
+    context.map(|context| {
+        format!(
+            "if [ -d {SELINUX_FS} ]; then {}; fi",
+            shell_command([OsStr::new("chcon"), context.as_ref(), path.as_ref()])
+        )
+    })
+}
@@ -1,31 +1,79 @@
 use std::{
     fs::Permissions,
-    os::unix::prelude::PermissionsExt,
+    os::unix::prelude::{MetadataExt, PermissionsExt},
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use tracing::{span, Span};
 use walkdir::WalkDir;
 
+use crate::action::base::fetch_and_unpack_nix::{ESTIMATED_TARBALL_BYTES, UNPACKED_SIZE_RATIO};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    shell_quote, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
+use crate::settings::CopyStrategy;
 
 pub(crate) const DEST: &str = "/nix/";
 
 /**
-Move an unpacked Nix at `src` to `/nix`
+Move an unpacked Nix at `src` to `dest` (usually `/nix`)
+
+With [`CopyStrategy::Auto`], store paths are renamed when `src` and `dest` are on the same
+filesystem, and otherwise reflinked file by file where the filesystem supports it (such as btrfs,
+XFS, or APFS) and copied where it does not.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct MoveUnpackedNix {
     unpacked_path: PathBuf,
+    #[serde(default = "default_dest")]
+    dest: PathBuf,
+    #[serde(default)]
+    copy_strategy: CopyStrategy,
 }
 
 impl MoveUnpackedNix {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(
+        unpacked_path: PathBuf,
+        dest: PathBuf,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         // Note: Do NOT try to check for the src/dest since the installer creates those
-        Ok(Self { unpacked_path }.into())
+        Ok(Self {
+            unpacked_path,
+            dest,
+            copy_strategy: Default::default(),
+        }
+        .into())
+    }
+}
+
+impl StatefulAction<MoveUnpackedNix> {
+    /// Move the store paths with `copy_strategy` rather than [`CopyStrategy::Auto`]
+    pub fn with_copy_strategy(mut self, copy_strategy: CopyStrategy) -> Self {
+        self.action.copy_strategy = copy_strategy;
+        self
+    }
+}
+
+fn default_dest() -> PathBuf {
+    PathBuf::from(DEST)
+}
+
+impl MoveUnpackedNix {
+    /// If the store paths will be copied rather than renamed, so take space in `dest` as well
+    fn copies(&self) -> bool {
+        match self.copy_strategy {
+            CopyStrategy::Copy | CopyStrategy::Reflink => true,
+            // Neither path need exist before the install, so their closest existing parents are compared
+            CopyStrategy::Auto => {
+                let existing_device = |path: &Path| {
+                    path.ancestors()
+                        .find_map(|ancestor| device_of(ancestor).ok())
+                };
+                existing_device(&self.unpacked_path) != existing_device(&self.dest)
+            },
+        }
     }
 }
 
@@ -36,7 +84,7 @@ impl Action for MoveUnpackedNix {
         ActionTag("move_unpacked_nix")
     }
     fn tracing_synopsis(&self) -> String {
-        "Move the downloaded Nix into `/nix`".to_string()
+        format!("Move the downloaded Nix into `{}`", self.dest.display())
     }
 
     fn tracing_span(&self) -> Span {
@@ -44,23 +92,33 @@ impl Action for MoveUnpackedNix {
             tracing::Level::DEBUG,
             "mount_unpacked_nix",
             src = tracing::field::display(self.unpacked_path.display()),
-            dest = DEST,
+            dest = tracing::field::display(self.dest.display()),
         )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(
-            format!("Move the downloaded Nix into `/nix`"),
-            vec![format!(
-                "Nix is being downloaded to `{}` and should be in `/nix`",
-                self.unpacked_path.display(),
-            )],
-        )]
+        let mut explanation = vec![format!(
+            "Nix is being downloaded to `{}` and should be in `{}`",
+            self.unpacked_path.display(),
+            self.dest.display(),
+        )];
+        match self.copy_strategy {
+            CopyStrategy::Auto => (),
+            CopyStrategy::Copy => explanation.push("Every file is copied".to_string()),
+            CopyStrategy::Reflink => explanation.push("Every file is reflinked".to_string()),
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { unpacked_path } = self;
+        let Self {
+            unpacked_path,
+            dest,
+            copy_strategy,
+        } = self;
+        let copy_strategy = *copy_strategy;
+        let started = Instant::now();
 
         // This is the `nix-$VERSION` folder which unpacks from the tarball, not a nix derivation
         let found_nix_paths = glob::glob(&format!("{}/nix-*", unpacked_path.display()))
@@ -76,7 +134,7 @@ impl Action for MoveUnpackedNix {
             .await
             .map_err(|e| ActionErrorKind::ReadDir(src_store.clone(), e))
             .map_err(Self::error)?;
-        let dest_store = Path::new(DEST).join("store");
+        let dest_store = dest.join("store");
         if dest_store.exists() {
             if !dest_store.is_dir() {
                 return Err(Self::error(ActionErrorKind::PathWasNotDirectory(
@@ -89,7 +147,10 @@ impl Action for MoveUnpackedNix {
                 .map_err(|e| ActionErrorKind::CreateDirectory(dest_store.clone(), e))
                 .map_err(Self::error)?;
         }
+        let same_filesystem = device_of(&src_store).map_err(Self::error)?
+            == device_of(&dest_store).map_err(Self::error)?;
 
+        let mut transferred = Transferred::default();
         while let Some(entry) = src_store_listing
             .next_entry()
             .await
@@ -104,13 +165,34 @@ impl Action for MoveUnpackedNix {
                     .map_err(|e| ActionErrorKind::Remove(entry_dest.clone(), e))
                     .map_err(Self::error)?;
             }
-            tracing::trace!(src = %entry.path().display(), dest = %entry_dest.display(), "Renaming");
-            tokio::fs::rename(&entry.path(), &entry_dest)
-                .await
-                .map_err(|e| {
-                    ActionErrorKind::Rename(entry.path().clone(), entry_dest.to_owned(), e)
-                })
-                .map_err(Self::error)?;
+            let renamed = if copy_strategy == CopyStrategy::Auto && same_filesystem {
+                tracing::trace!(src = %entry.path().display(), dest = %entry_dest.display(), "Renaming");
+                match tokio::fs::rename(&entry.path(), &entry_dest).await {
+                    Ok(()) => true,
+                    // Separate mounts of one filesystem share a device, but cannot be renamed across
+                    Err(e) if e.raw_os_error() == Some(nix::libc::EXDEV) => false,
+                    Err(e) => {
+                        return Err(Self::error(ActionErrorKind::Rename(
+                            entry.path(),
+                            entry_dest.to_owned(),
+                            e,
+                        )))
+                    },
+                }
+            } else {
+                false
+            };
+            if renamed {
+                transferred.renamed += 1;
+            } else {
+                tracing::trace!(src = %entry.path().display(), dest = %entry_dest.display(), %copy_strategy, "Copying");
+                copy_tree(&entry.path(), &entry_dest, copy_strategy, &mut transferred)
+                    .map_err(Self::error)?;
+                tokio::fs::remove_dir_all(&entry.path())
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(entry.path(), e))
+                    .map_err(Self::error)?;
+            }
 
             let perms: Permissions = PermissionsExt::from_mode(0o555);
             for entry_item in WalkDir::new(&entry_dest)
@@ -140,9 +222,71 @@ impl Action for MoveUnpackedNix {
                 .map_err(Self::error)?;
         }
 
+        tracing::debug!(
+            renamed_store_paths = transferred.renamed,
+            reflinked_files = transferred.reflinked,
+            copied_files = transferred.copied,
+            elapsed = ?started.elapsed(),
+            "Moved the unpacked Nix into `{}`",
+            dest_store.display(),
+        );
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let unpacked_path = shell_quote(&self.unpacked_path);
+        let dest_store = shell_quote(self.dest.join("store"));
+        let transfer = match self.copy_strategy {
+            CopyStrategy::Auto => "mv \"$entry\" \"$entry_dest\"",
+            CopyStrategy::Copy => "cp -RP \"$entry\" \"$entry_dest\" && rm -rf \"$entry\"",
+            #[cfg(target_os = "macos")]
+            CopyStrategy::Reflink => "cp -cRP \"$entry\" \"$entry_dest\" && rm -rf \"$entry\"",
+            #[cfg(not(target_os = "macos"))]
+            CopyStrategy::Reflink => {
+                "cp -RP --reflink=always \"$entry\" \"$entry_dest\" && rm -rf \"$entry\""
+            },
+        };
+        Some(vec![
+            format!("mkdir -p {dest_store}"),
+            format!(
+                "for entry in {unpacked_path}/nix-*/store/*; do \
+                    entry_dest={dest_store}/\"$(basename \"$entry\")\"; \
+                    rm -rf \"$entry_dest\"; \
+                    {transfer}; \
+                    find \"$entry_dest\" ! -type l -exec chmod 555 {{}} +; \
+                    ln -s \"$entry_dest\" \"$entry\"; \
+                done"
+            ),
+        ])
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        // Moving the store paths is quick, unless they are copied to another filesystem
+        match self.copy_strategy {
+            CopyStrategy::Copy => Duration::from_secs(5),
+            CopyStrategy::Auto | CopyStrategy::Reflink => Duration::from_secs(2),
+        }
+    }
+
+    fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+        if !self.copies() {
+            return Vec::new();
+        }
+        // Reflinks may still fall back to copies, and the unpacked Nix is only removed once copied
+        let unpacked_bytes = if self.unpacked_path.exists() {
+            WalkDir::new(&self.unpacked_path)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        } else {
+            ESTIMATED_TARBALL_BYTES * UNPACKED_SIZE_RATIO
+        };
+        vec![(self.dest.clone(), unpacked_bytes)]
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![/* Deliberately empty -- this is a noop */]
     }
@@ -169,6 +313,8 @@ pub enum MoveUnpackedNixError {
         #[source]
         glob::GlobError,
     ),
+    #[error("Reflinking `{}` to `{}`, the filesystem may not support reflinks, `--copy-strategy auto` copies instead", .0.display(), .1.display())]
+    Reflink(PathBuf, PathBuf, #[source] std::io::Error),
 }
 
 impl Into<ActionErrorKind> for MoveUnpackedNixError {
@@ -176,3 +322,197 @@ impl Into<ActionErrorKind> for MoveUnpackedNixError {
         ActionErrorKind::Custom(Box::new(self))
     }
 }
+
+/// What moving the store paths took, logged to show which way they were moved
+#[derive(Debug, Default)]
+struct Transferred {
+    renamed: usize,
+    reflinked: usize,
+    copied: usize,
+}
+
+fn device_of(path: &Path) -> Result<u64, ActionErrorKind> {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.dev())
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))
+}
+
+/// Recreate `src` at `dest`, reflinking or copying its files as `copy_strategy` allows
+fn copy_tree(
+    src: &Path,
+    dest: &Path,
+    copy_strategy: CopyStrategy,
+    transferred: &mut Transferred,
+) -> Result<(), ActionErrorKind> {
+    for entry in WalkDir::new(src) {
+        let entry = entry.map_err(|e| {
+            let path = e.path().unwrap_or(src).to_path_buf();
+            ActionErrorKind::Read(path, e.into())
+        })?;
+        let entry_dest = match entry.path().strip_prefix(src) {
+            Ok(relative) if !relative.as_os_str().is_empty() => dest.join(relative),
+            _ => dest.to_path_buf(),
+        };
+        // A store path which is itself a symlink is recreated, rather than followed
+        let file_type = if entry.depth() == 0 {
+            std::fs::symlink_metadata(entry.path())
+                .map_err(|e| ActionErrorKind::GettingMetadata(entry.path().to_path_buf(), e))?
+                .file_type()
+        } else {
+            entry.file_type()
+        };
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(entry.path())
+                .map_err(|e| ActionErrorKind::ReadSymlink(entry.path().to_path_buf(), e))?;
+            std::os::unix::fs::symlink(&target, &entry_dest)
+                .map_err(|e| ActionErrorKind::Symlink(target, entry_dest.clone(), e))?;
+            if entry.depth() == 0 {
+                break;
+            }
+        } else if file_type.is_dir() {
+            std::fs::create_dir(&entry_dest)
+                .map_err(|e| ActionErrorKind::CreateDirectory(entry_dest.clone(), e))?;
+        } else {
+            copy_file(entry.path(), &entry_dest, copy_strategy, transferred)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_file(
+    src: &Path,
+    dest: &Path,
+    copy_strategy: CopyStrategy,
+    transferred: &mut Transferred,
+) -> Result<(), ActionErrorKind> {
+    if copy_strategy != CopyStrategy::Copy {
+        match reflink(src, dest) {
+            Ok(()) => {
+                transferred.reflinked += 1;
+                return Ok(());
+            },
+            Err(e) if copy_strategy == CopyStrategy::Reflink => {
+                return Err(
+                    MoveUnpackedNixError::Reflink(src.to_path_buf(), dest.to_path_buf(), e).into(),
+                )
+            },
+            Err(e) => {
+                tracing::trace!(src = %src.display(), dest = %dest.display(), "Could not reflink, copying instead: {e}")
+            },
+        }
+    }
+    std::fs::copy(src, dest)
+        .map_err(|e| ActionErrorKind::Copy(src.to_path_buf(), dest.to_path_buf(), e))?;
+    transferred.copied += 1;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Share the extents of `src` with a new file at `dest`, through `FICLONE`
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = std::fs::File::open(src)?;
+    let dest_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dest)?;
+    // SAFETY: Both file descriptors stay open for the duration of the call
+    unsafe { ficlone(dest_file.as_raw_fd(), src_file.as_raw_fd() as _) }?;
+    Ok(())
+}
+
+/// Clone `src` to a new file at `dest`, through `clonefile(2)`
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest = CString::new(dest.as_os_str().as_bytes())?;
+    // SAFETY: Both paths are valid, nul terminated strings
+    if unsafe { nix::libc::clonefile(src.as_ptr(), dest.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn copies_store_paths_and_links_back() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let unpacked = temp_dir.path().join("unpacked");
+        let src_store_path = unpacked.join("nix-2.15.0-x86_64-linux/store/abc-hello");
+        tokio::fs::create_dir_all(src_store_path.join("bin")).await?;
+        tokio::fs::write(src_store_path.join("bin/hello"), "#!/bin/sh\necho hello\n").await?;
+        tokio::fs::symlink("bin/hello", src_store_path.join("hello")).await?;
+        let dest = temp_dir.path().join("nix");
+        tokio::fs::create_dir_all(&dest).await?;
+
+        let mut action = MoveUnpackedNix::plan(unpacked, dest.clone())
+            .await?
+            .with_copy_strategy(CopyStrategy::Copy);
+        action.try_execute().await?;
+
+        let dest_store_path = dest.join("store/abc-hello");
+        assert_eq!(
+            tokio::fs::read_to_string(dest_store_path.join("bin/hello")).await?,
+            "#!/bin/sh\necho hello\n"
+        );
+        assert_eq!(
+            tokio::fs::read_link(dest_store_path.join("hello")).await?,
+            Path::new("bin/hello")
+        );
+        let mode = tokio::fs::metadata(dest_store_path.join("bin/hello"))
+            .await?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o555);
+        // The unpacked store path is replaced by a link to where it was moved
+        assert_eq!(
+            tokio::fs::read_link(&src_store_path).await?,
+            dest_store_path
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copies_require_disk_space_in_dest() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let unpacked = temp_dir.path().join("unpacked");
+        let src_store_path = unpacked.join("nix-2.15.0-x86_64-linux/store/abc-hello");
+        tokio::fs::create_dir_all(&src_store_path).await?;
+        tokio::fs::write(src_store_path.join("hello"), [0; 1024]).await?;
+        let dest = temp_dir.path().join("nix");
+
+        // Both are on the same filesystem, so the store paths are renamed
+        let action = MoveUnpackedNix::plan(unpacked.clone(), dest.clone()).await?;
+        assert!(action.required_disk_space().is_empty());
+
+        let action = MoveUnpackedNix::plan(unpacked, dest.clone())
+            .await?
+            .with_copy_strategy(CopyStrategy::Copy);
+        assert_eq!(action.required_disk_space(), vec![(dest.clone(), 1024)]);
+
+        // Before the download, the unpacked size is estimated
+        let action = MoveUnpackedNix::plan(temp_dir.path().join("missing"), dest.clone())
+            .await?
+            .with_copy_strategy(CopyStrategy::Reflink);
+        assert_eq!(
+            action.required_disk_space(),
+            vec![(dest, ESTIMATED_TARBALL_BYTES * UNPACKED_SIZE_RATIO)]
+        );
+        Ok(())
+    }
+}
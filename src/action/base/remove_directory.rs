@@ -1,30 +1,81 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-use tokio::fs::remove_dir_all;
+use tokio::fs::{remove_dir, remove_dir_all, remove_file};
 use tracing::{span, Span};
 
-use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
+use crate::action::{shell_quote, Action, ActionDescription, ActionErrorKind, ActionState};
 use crate::action::{ActionError, StatefulAction};
 
 /** Remove a directory, does nothing on revert.
+
+A directory is never removed if it is `/`, relative, or reached through `..`. With
+[`plan_guarded`](Self::plan_guarded), it must also be strictly inside one of the allowed prefixes
+once symlinks are resolved, and, if `require_empty` is set, must have nothing in it. If the
+path is itself a symlink, only the link is removed.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct RemoveDirectory {
     path: PathBuf,
+    #[serde(default)]
+    require_empty: bool,
+    #[serde(default)]
+    allowed_prefixes: Vec<PathBuf>,
 }
 
 impl RemoveDirectory {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(path: impl AsRef<Path>) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_guarded(path, false, vec![]).await
+    }
+
+    /// Remove `path` only if it is inside one of `allowed_prefixes`, and only if it is empty when `require_empty` is set
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_guarded(
+        path: impl AsRef<Path>,
+        require_empty: bool,
+        allowed_prefixes: Vec<PathBuf>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        let this = Self {
+            path,
+            require_empty,
+            allowed_prefixes,
+        };
+        this.check_path(&this.path).map_err(Self::error)?;
 
         Ok(StatefulAction {
-            action: Self {
-                path: path.to_path_buf(),
-            },
+            action: this,
             state: ActionState::Uncompleted,
+            timeout: None,
         })
     }
+
+    /// Refuse to remove `path` if it is unsafe, or outside of the allowed prefixes
+    fn check_path(&self, path: &Path) -> Result<(), RemoveDirectoryError> {
+        if !path.is_absolute()
+            || path.parent().is_none()
+            || path.components().any(|c| c == Component::ParentDir)
+        {
+            return Err(RemoveDirectoryError::UnsafePath(path.to_path_buf()));
+        }
+        if self.allowed_prefixes.is_empty() {
+            return Ok(());
+        }
+        let allowed = self.allowed_prefixes.iter().any(|prefix| {
+            // A resolved path is also compared to the resolved prefix, so a symlinked `/nix` still matches
+            let resolved = std::fs::canonicalize(prefix).unwrap_or_else(|_| prefix.clone());
+            [prefix, &resolved]
+                .iter()
+                .any(|prefix| path != prefix.as_path() && path.starts_with(prefix))
+        });
+        if !allowed {
+            return Err(RemoveDirectoryError::OutsideAllowedPrefixes(
+                path.to_path_buf(),
+                self.allowed_prefixes.clone(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -42,31 +93,110 @@ impl Action for RemoveDirectory {
             tracing::Level::DEBUG,
             "remove_directory",
             path = tracing::field::display(self.path.display()),
+            require_empty = self.require_empty,
         )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if self.require_empty {
+            explanation.push("Only if it is empty".to_string());
+        }
+        if !self.allowed_prefixes.is_empty() {
+            explanation.push(format!(
+                "Only if it is inside {}",
+                self.allowed_prefixes
+                    .iter()
+                    .map(|prefix| format!("`{}`", prefix.display()))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        if self.path.exists() {
-            if !self.path.is_dir() {
-                return Err(Self::error(ActionErrorKind::PathWasNotDirectory(
+        let metadata = match tokio::fs::symlink_metadata(&self.path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("Directory `{}` not present, skipping", self.path.display(),);
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(Self::error(ActionErrorKind::GettingMetadata(
                     self.path.clone(),
+                    e,
+                )))
+            },
+        };
+        if !self.path.is_dir() {
+            return Err(Self::error(ActionErrorKind::PathWasNotDirectory(
+                self.path.clone(),
+            )));
+        }
+
+        // A symlink along the way could point anywhere, so check where the path really is
+        let resolved = tokio::fs::canonicalize(&self.path)
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(self.path.clone(), e))
+            .map_err(Self::error)?;
+        self.check_path(&resolved).map_err(Self::error)?;
+
+        // Only the link itself is removed, never the directory it points at
+        if metadata.file_type().is_symlink() {
+            tracing::debug!(
+                "Directory `{}` is a symlink to `{}`, removing only the link",
+                self.path.display(),
+                resolved.display()
+            );
+            remove_file(&self.path)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.clone(), e)))?;
+            return Ok(());
+        }
+
+        if self.require_empty {
+            let mut entries = tokio::fs::read_dir(&self.path)
+                .await
+                .map_err(|e| ActionErrorKind::ReadDir(self.path.clone(), e))
+                .map_err(Self::error)?;
+            let mut found = vec![];
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| ActionErrorKind::ReadDir(self.path.clone(), e))
+                .map_err(Self::error)?
+            {
+                found.push(entry.path());
+            }
+            if !found.is_empty() {
+                found.sort();
+                return Err(Self::error(RemoveDirectoryError::NotEmpty(
+                    self.path.clone(),
+                    found,
                 )));
             }
-            remove_dir_all(&self.path)
+            remove_dir(&self.path)
                 .await
                 .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.clone(), e)))?;
         } else {
-            tracing::debug!("Directory `{}` not present, skipping", self.path.display(),);
-        };
+            remove_dir_all(&self.path)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.clone(), e)))?;
+        }
 
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        if self.require_empty {
+            Some(vec![format!("rmdir {}", shell_quote(&self.path))])
+        } else {
+            Some(vec![format!("rm -rf {}", shell_quote(&self.path))])
+        }
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![]
     }
@@ -76,3 +206,86 @@ impl Action for RemoveDirectory {
         Ok(())
     }
 }
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum RemoveDirectoryError {
+    #[error("Refusing to remove `{}`, only absolute paths below `/` without `..` can be removed", .0.display())]
+    UnsafePath(PathBuf),
+    #[error("Refusing to remove `{}`, it is not inside any of {}", .0.display(), .1.iter().map(|v| format!("`{}`", v.display())).collect::<Vec<_>>().join(", "))]
+    OutsideAllowedPrefixes(PathBuf, Vec<PathBuf>),
+    #[error("Refusing to remove `{}`, it unexpectedly contains {}", .0.display(), .1.iter().map(|v| format!("`{}`", v.display())).collect::<Vec<_>>().join(", "))]
+    NotEmpty(PathBuf, Vec<PathBuf>),
+}
+
+impl From<RemoveDirectoryError> for ActionErrorKind {
+    fn from(v: RemoveDirectoryError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_to_remove_outside_allowed_prefixes() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let allowed = temp_dir.path().join("allowed");
+        let scratch = allowed.join("scratch");
+        tokio::fs::create_dir_all(scratch.join("contents")).await?;
+
+        assert!(RemoveDirectory::plan("/").await.is_err());
+        assert!(RemoveDirectory::plan("nix/temp-install-dir").await.is_err());
+        assert!(RemoveDirectory::plan_guarded(
+            temp_dir.path().join("elsewhere"),
+            false,
+            vec![allowed.clone()]
+        )
+        .await
+        .is_err());
+        assert!(
+            RemoveDirectory::plan_guarded(&allowed, false, vec![allowed.clone()])
+                .await
+                .is_err()
+        );
+
+        // A symlink inside the allowed prefix may not lead out of it
+        let outside = temp_dir.path().join("outside");
+        tokio::fs::create_dir_all(&outside).await?;
+        tokio::fs::symlink(&outside, allowed.join("link")).await?;
+        let mut link =
+            RemoveDirectory::plan_guarded(allowed.join("link"), false, vec![allowed.clone()])
+                .await?;
+        assert!(link.try_execute().await.is_err());
+        assert!(outside.exists());
+
+        let mut require_empty =
+            RemoveDirectory::plan_guarded(&scratch, true, vec![allowed.clone()]).await?;
+        let err = require_empty.try_execute().await.unwrap_err();
+        assert!(err.kind().to_string().contains("unexpectedly contains"));
+        assert!(scratch.exists());
+
+        let mut remove = RemoveDirectory::plan_guarded(&scratch, false, vec![allowed]).await?;
+        remove.try_execute().await?;
+        assert!(!scratch.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn symlink_to_sibling_removes_only_the_link() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let allowed = temp_dir.path().join("allowed");
+        let sibling = allowed.join("sibling");
+        tokio::fs::create_dir_all(&sibling).await?;
+        tokio::fs::write(sibling.join("keep"), "").await?;
+        let link = allowed.join("link");
+        tokio::fs::symlink(&sibling, &link).await?;
+
+        let mut remove = RemoveDirectory::plan_guarded(&link, false, vec![allowed]).await?;
+        remove.try_execute().await?;
+        assert!(tokio::fs::symlink_metadata(&link).await.is_err());
+        assert!(sibling.join("keep").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::settings::in_target_root;
+
+const TEMPROOTS_DIR: &str = "/nix/var/nix/temproots";
+const STORE_DIR: &str = "/nix/store";
+
+/** Remove temporary GC roots and store path locks left behind by dead processes, does nothing on revert
+
+Only temporary roots whose owning PID provably no longer exists, and lock files which nothing holds,
+are removed.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct RemoveStaleTempRoots {
+    temproots_dir: PathBuf,
+    store_dir: PathBuf,
+}
+
+impl RemoveStaleTempRoots {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(target_root: &Path) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            temproots_dir: in_target_root(target_root, TEMPROOTS_DIR),
+            store_dir: in_target_root(target_root, STORE_DIR),
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "remove_stale_temp_roots")]
+impl Action for RemoveStaleTempRoots {
+    fn action_tag() -> ActionTag {
+        ActionTag("remove_stale_temp_roots")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Remove stale temporary roots in `{}` and orphaned locks in `{}`",
+            self.temproots_dir.display(),
+            self.store_dir.display(),
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "remove_stale_temp_roots",
+            temproots_dir = tracing::field::display(self.temproots_dir.display()),
+            store_dir = tracing::field::display(self.store_dir.display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                "A previously interrupted install or build can leave these behind, which can cause the Nix daemon to hang on startup".to_string(),
+                "Only files owned by processes which no longer exist are removed".to_string(),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        for temproot in stale_temproots(&self.temproots_dir)
+            .await
+            .map_err(Self::error)?
+        {
+            tracing::info!("Removing stale temporary root `{}`", temproot.display());
+            tokio::fs::remove_file(&temproot)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(temproot.clone(), e)))?;
+        }
+
+        // Each lock is held until it is removed so Nix cannot pick it up in the meantime
+        for (lock, _held) in orphaned_locks(&self.store_dir).await.map_err(Self::error)? {
+            tracing::info!("Removing orphaned lock `{}`", lock.display());
+            tokio::fs::remove_file(&lock)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(lock.clone(), e)))?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+/// Temporary roots are named after the PID of the process which registered them
+async fn stale_temproots(temproots_dir: &Path) -> Result<Vec<PathBuf>, ActionErrorKind> {
+    let mut stale = Vec::new();
+    if !temproots_dir.exists() {
+        return Ok(stale);
+    }
+
+    let mut entries = tokio::fs::read_dir(temproots_dir)
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(temproots_dir.to_path_buf(), e))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(temproots_dir.to_path_buf(), e))?
+    {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|v| v.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => {
+                tracing::debug!(
+                    "Skipping `{}` as it is not named after a PID",
+                    entry.path().display()
+                );
+                continue;
+            },
+        };
+
+        // `ESRCH` is the only answer which proves the process is gone, `EPERM` means it exists
+        if kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH) {
+            stale.push(entry.path());
+        } else {
+            tracing::debug!(
+                "Skipping `{}` as process {pid} may still be running",
+                entry.path().display()
+            );
+        }
+    }
+
+    Ok(stale)
+}
+
+/// Store path locks are `flock`ed by Nix while in use, so any lock we can acquire is unused
+async fn orphaned_locks(
+    store_dir: &Path,
+) -> Result<Vec<(PathBuf, tokio::fs::File)>, ActionErrorKind> {
+    let mut orphaned = Vec::new();
+    if !store_dir.exists() {
+        return Ok(orphaned);
+    }
+
+    let mut entries = tokio::fs::read_dir(store_dir)
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(store_dir.to_path_buf(), e))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(store_dir.to_path_buf(), e))?
+    {
+        let path = entry.path();
+        if path.extension().map(|v| v != "lock").unwrap_or(true) {
+            continue;
+        }
+
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| ActionErrorKind::Open(path.clone(), e))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => orphaned.push((path, file)),
+            Err(_) => tracing::debug!("Skipping `{}` as it is held", path.display()),
+        }
+    }
+
+    Ok(orphaned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn removes_only_stale_temproots_and_orphaned_locks() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let temproots_dir = temp_dir.path().join("nix/var/nix/temproots");
+        let store_dir = temp_dir.path().join("nix/store");
+        tokio::fs::create_dir_all(&temproots_dir).await?;
+        tokio::fs::create_dir_all(&store_dir).await?;
+
+        let mut child = tokio::process::Command::new("true").spawn()?;
+        let dead_pid = child.id().expect("a PID before it is waited on");
+        child.wait().await?;
+        let stale_temproot = temproots_dir.join(dead_pid.to_string());
+        let live_temproot = temproots_dir.join(std::process::id().to_string());
+        let unnamed_temproot = temproots_dir.join("not-a-pid");
+        for temproot in [&stale_temproot, &live_temproot, &unnamed_temproot] {
+            tokio::fs::write(temproot, "").await?;
+        }
+
+        let orphaned_lock = store_dir.join("abc-orphaned.lock");
+        let held_lock = store_dir.join("abc-held.lock");
+        let not_a_lock = store_dir.join("abc-hello");
+        for path in [&orphaned_lock, &held_lock, &not_a_lock] {
+            tokio::fs::write(path, "").await?;
+        }
+        let holder = std::fs::File::open(&held_lock)?;
+        flock(holder.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+
+        let mut action = RemoveStaleTempRoots::plan(temp_dir.path()).await?;
+        action.try_execute().await?;
+
+        assert!(!stale_temproot.exists());
+        assert!(live_temproot.exists());
+        assert!(unnamed_temproot.exists());
+        assert!(!orphaned_lock.exists());
+        assert!(held_lock.exists());
+        assert!(not_a_lock.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_directories_are_ignored() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let mut action = RemoveStaleTempRoots::plan(temp_dir.path()).await?;
+        action.try_execute().await?;
+        action.try_revert().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use std::num::NonZeroUsize;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinSet;
+
+use crate::action::{context::ActionContext, ActionErrorKind};
+use crate::plan::InstallEvent;
+
+/// How often [`remove_tree`] reports its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a [`remove_tree`] removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RemovalStats {
+    pub(crate) files: u64,
+    pub(crate) bytes: u64,
+}
+
+impl std::ops::AddAssign for RemovalStats {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/**
+Remove each of `paths` and everything in them, the way [`std::fs::remove_dir_all`] would, but
+spread over a thread per CPU
+
+`root` names the removal in progress reports, it is not removed itself.
+
+A tree like `/nix/store` holds a great many small files, so removing them one at a time is slow.
+Each directory is split into its entries (for `/nix/store`, the store paths), which are removed
+in parallel. Between entries, the removal stops if the uninstall is cancelled, leaving what is
+left for a later uninstall.
+*/
+#[tracing::instrument(level = "debug", skip_all)]
+pub(crate) async fn remove_tree(
+    root: &Path,
+    paths: Vec<PathBuf>,
+) -> Result<RemovalStats, ActionErrorKind> {
+    let context = ActionContext::current();
+    let parallelism = std::thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1);
+
+    // Directories are emptied in parallel, then removed themselves once their entries are gone
+    let mut entries = vec![];
+    let mut directories = vec![];
+    for path in paths {
+        let metadata = match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(ActionErrorKind::GettingMetadata(path, e)),
+        };
+        if metadata.is_dir() {
+            let mut read_dir = tokio::fs::read_dir(&path)
+                .await
+                .map_err(|e| ActionErrorKind::Read(path.clone(), e))?;
+            while let Some(entry) = read_dir
+                .next_entry()
+                .await
+                .map_err(|e| ActionErrorKind::Read(path.clone(), e))?
+            {
+                entries.push(entry.path());
+            }
+            directories.push(path);
+        } else {
+            entries.push(path);
+        }
+    }
+
+    let mut removed = RemovalStats::default();
+    let mut last_progress = Instant::now();
+    let mut set = JoinSet::new();
+    let mut entries = entries.into_iter();
+    let mut first_error = None;
+    loop {
+        while set.len() < parallelism && first_error.is_none() {
+            if context.is_cancelled() {
+                break;
+            }
+            let Some(entry) = entries.next() else { break };
+            set.spawn_blocking(move || remove_entry(&entry));
+        }
+
+        let Some(result) = set.join_next().await else {
+            break;
+        };
+        match result {
+            Ok(Ok(stats)) => removed += stats,
+            Ok(Err(err)) => {
+                first_error.get_or_insert(err);
+            },
+            Err(e) => {
+                first_error.get_or_insert(ActionErrorKind::Join(e));
+            },
+        }
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            report_progress(&context, root, removed);
+        }
+    }
+
+    if let Some(err) = first_error {
+        return Err(err);
+    }
+    if context.is_cancelled() {
+        return Err(RemoveTreeError::Cancelled {
+            path: root.to_path_buf(),
+            files: removed.files,
+            bytes: removed.bytes,
+        }
+        .into());
+    }
+
+    for directory in directories {
+        tokio::fs::remove_dir(&directory)
+            .await
+            .map_err(|e| ActionErrorKind::Remove(directory.clone(), e))?;
+    }
+
+    report_progress(&context, root, removed);
+    tracing::info!(
+        "Removed {} files ({} bytes) from `{}`",
+        removed.files,
+        removed.bytes,
+        root.display()
+    );
+    Ok(removed)
+}
+
+fn report_progress(context: &ActionContext, root: &Path, removed: RemovalStats) {
+    tracing::debug!(
+        "Removed {} files ({} bytes) from `{}` so far",
+        removed.files,
+        removed.bytes,
+        root.display()
+    );
+    context.send(InstallEvent::RemovalProgress {
+        path: root.to_path_buf(),
+        files: removed.files,
+        bytes: removed.bytes,
+    });
+}
+
+/// Remove `path` and its contents, deepest first, counting the files and bytes removed
+fn remove_entry(path: &Path) -> Result<RemovalStats, ActionErrorKind> {
+    let mut removed = RemovalStats::default();
+    // Never walk into what a symlink points at
+    let metadata = std::fs::symlink_metadata(path)
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+    if !metadata.is_dir() {
+        std::fs::remove_file(path).map_err(|e| ActionErrorKind::Remove(path.to_path_buf(), e))?;
+        removed.files += 1;
+        removed.bytes += metadata.size();
+        return Ok(removed);
+    }
+    for entry in walkdir::WalkDir::new(path).contents_first(true) {
+        let entry = entry.map_err(|e| {
+            let path = e.path().unwrap_or(path).to_path_buf();
+            ActionErrorKind::Read(path, e.into())
+        })?;
+        let entry_path = entry.path();
+        if entry.file_type().is_dir() {
+            std::fs::remove_dir(entry_path)
+                .map_err(|e| ActionErrorKind::Remove(entry_path.to_path_buf(), e))?;
+        } else {
+            let size = entry
+                .metadata()
+                .map(|metadata| metadata.size())
+                .unwrap_or(0);
+            std::fs::remove_file(entry_path)
+                .map_err(|e| ActionErrorKind::Remove(entry_path.to_path_buf(), e))?;
+            removed.files += 1;
+            removed.bytes += size;
+        }
+    }
+    Ok(removed)
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum RemoveTreeError {
+    #[error("Cancelled removing `{}` after {files} files ({bytes} bytes), uninstall again to remove the rest", .path.display())]
+    Cancelled {
+        path: PathBuf,
+        files: u64,
+        bytes: u64,
+    },
+}
+
+impl From<RemoveTreeError> for ActionErrorKind {
+    fn from(v: RemoveTreeError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn populate(dir: &Path) -> std::io::Result<()> {
+        for package in 0..8 {
+            let package = dir.join(format!("package-{package}"));
+            std::fs::create_dir_all(package.join("bin"))?;
+            std::fs::write(package.join("bin").join("tool"), "12345")?;
+            std::os::unix::fs::symlink("bin/tool", package.join("tool"))?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_tree_and_counts_files() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let store = temp_dir.path().join("store");
+        populate(&store)?;
+
+        let removed = remove_tree(temp_dir.path(), vec![store.clone()]).await?;
+        assert_eq!(removed.files, 16);
+        assert_eq!(removed.bytes, 8 * 5 + 8 * "bin/tool".len() as u64);
+        assert!(!store.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_files_and_skips_missing_paths() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("file");
+        std::fs::write(&file, "12345")?;
+
+        let removed = remove_tree(
+            temp_dir.path(),
+            vec![file.clone(), temp_dir.path().join("missing")],
+        )
+        .await?;
+        assert_eq!(removed, RemovalStats { files: 1, bytes: 5 });
+        assert!(!file.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let store = temp_dir.path().join("store");
+        populate(&store)?;
+
+        let context = ActionContext::default();
+        context
+            .cancelled
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let result = context
+            .scope(remove_tree(temp_dir.path(), vec![store.clone()]))
+            .await;
+
+        match result {
+            Err(ActionErrorKind::Custom(err)) => assert!(err.is::<RemoveTreeError>()),
+            _ => return Err(eyre::eyre!("Expected a RemoveTreeError::Cancelled")),
+        }
+        assert!(store.exists());
+
+        Ok(())
+    }
+}
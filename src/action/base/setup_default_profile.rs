@@ -1,8 +1,13 @@
-use std::path::PathBuf;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::{
-    action::{ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{shell_quote, ActionError, ActionErrorKind, ActionTag, StatefulAction},
     execute_command, set_env,
+    settings::{default_target_root, in_target_root, HOST_ROOT},
 };
 
 use glob::glob;
@@ -12,18 +17,53 @@ use tracing::{span, Span};
 
 use crate::action::{Action, ActionDescription};
 
+const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+/// Directories of garbage collector roots which only hold links created by Nix
+const GCROOTS_DIRS: &[&str] = &["/nix/var/nix/gcroots/auto", "/nix/var/nix/gcroots/profiles"];
+
 /**
 Setup the default Nix profile with `nss-cacert` and `nix` itself.
+
+On revert, the generations of the default profile and the garbage collector roots pinning them
+are removed, so the `/nix` tree can be removed after.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct SetupDefaultProfile {
     unpacked_path: PathBuf,
+    #[serde(default = "default_target_root")]
+    target_root: PathBuf,
 }
 
 impl SetupDefaultProfile {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
-        Ok(Self { unpacked_path }.into())
+    pub async fn plan(
+        unpacked_path: PathBuf,
+        target_root: PathBuf,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        if target_root != Path::new(HOST_ROOT) && which::which("chroot").is_err() {
+            return Err(Self::error(SetupDefaultProfileError::NoChroot));
+        }
+        Ok(Self {
+            unpacked_path,
+            target_root,
+        }
+        .into())
+    }
+
+    /// Inside an alternate target root, Nix is run through `chroot` so it finds its store at `/nix`
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        if self.target_root == Path::new(HOST_ROOT) {
+            Command::new(program)
+        } else {
+            let mut command = Command::new("chroot");
+            command.arg(&self.target_root).arg(program);
+            command
+        }
+    }
+
+    /// Map a path found on the host to where it appears from inside the target root
+    fn in_chroot(&self, path: &Path) -> PathBuf {
+        Path::new(HOST_ROOT).join(path.strip_prefix(&self.target_root).unwrap_or(path))
     }
 }
 
@@ -71,10 +111,11 @@ impl Action for SetupDefaultProfile {
             };
         }
         let nix_pkg = if let Some(nix_pkg) = found_nix_pkg {
-            tokio::fs::read_link(&nix_pkg)
+            let nix_pkg = tokio::fs::read_link(&nix_pkg)
                 .await
                 .map_err(|e| ActionErrorKind::ReadSymlink(nix_pkg, e))
-                .map_err(Self::error)?
+                .map_err(Self::error)?;
+            self.in_chroot(&nix_pkg)
         } else {
             return Err(Self::error(SetupDefaultProfileError::NoNix));
         };
@@ -104,10 +145,11 @@ impl Action for SetupDefaultProfile {
             };
         }
         let nss_ca_cert_pkg = if let Some(nss_ca_cert_pkg) = found_nss_ca_cert_pkg {
-            tokio::fs::read_link(&nss_ca_cert_pkg)
+            let nss_ca_cert_pkg = tokio::fs::read_link(&nss_ca_cert_pkg)
                 .await
                 .map_err(|e| ActionErrorKind::ReadSymlink(nss_ca_cert_pkg, e))
-                .map_err(Self::error)?
+                .map_err(Self::error)?;
+            self.in_chroot(&nss_ca_cert_pkg)
         } else {
             return Err(Self::error(SetupDefaultProfileError::NoNssCacert));
         };
@@ -125,7 +167,7 @@ impl Action for SetupDefaultProfile {
             .await
             .map_err(|e| ActionErrorKind::Read(reginfo_path.to_path_buf(), e))
             .map_err(Self::error)?;
-        let mut load_db_command = Command::new(nix_pkg.join("bin/nix-store"));
+        let mut load_db_command = self.command(nix_pkg.join("bin/nix-store"));
         load_db_command.process_group(0);
         load_db_command.arg("--load-db");
         load_db_command.stdin(std::process::Stdio::piped());
@@ -176,7 +218,7 @@ impl Action for SetupDefaultProfile {
 
         // Install `nix` itself into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
+            self.command(nix_pkg.join("bin/nix-env"))
                 .process_group(0)
                 .arg("-i")
                 .arg(&nix_pkg)
@@ -196,7 +238,7 @@ impl Action for SetupDefaultProfile {
 
         // Install `nix` itself into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
+            self.command(nix_pkg.join("bin/nix-env"))
                 .process_group(0)
                 .arg("-i")
                 .arg(&nss_ca_cert_pkg)
@@ -214,18 +256,51 @@ impl Action for SetupDefaultProfile {
         .await
         .map_err(Self::error)?;
 
-        set_env(
-            "NIX_SSL_CERT_FILE",
-            "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
-        );
+        if self.target_root == Path::new(HOST_ROOT) {
+            set_env(
+                "NIX_SSL_CERT_FILE",
+                "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
+            );
+        }
 
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        // Running the unpacked Nix inside another root needs paths mapped into it
+        if self.target_root != Path::new(HOST_ROOT) {
+            return None;
+        }
+        let unpacked_path = shell_quote(&self.unpacked_path);
+        let nix_env = "NIX_SSL_CERT_FILE=\"$nss_cacert_pkg/etc/ssl/certs/ca-bundle.crt\" \"$nix_pkg/bin/nix-env\"";
+        Some(vec![
+            format!("nix_pkg=\"$(readlink {unpacked_path}/nix-*/store/*-nix-*.*.*)\""),
+            format!("nss_cacert_pkg=\"$(readlink {unpacked_path}/nix-*/store/*-nss-cacert-*.*)\""),
+            format!("\"$nix_pkg/bin/nix-store\" --load-db < {unpacked_path}/nix-*/.reginfo"),
+            format!("{nix_env} -i \"$nix_pkg\""),
+            format!("{nix_env} -i \"$nss_cacert_pkg\""),
+        ])
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        // Nix is imported into the store and installed into the default profile
+        Duration::from_secs(5)
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Unset the default Nix profile".to_string(),
-            vec![],
+            vec![
+                format!("Remove the generations of `{PROFILES_DIR}/default`"),
+                format!(
+                    "Remove the garbage collector roots in {}",
+                    GCROOTS_DIRS
+                        .iter()
+                        .map(|dir| format!("`{dir}`"))
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                ),
+            ],
         )]
     }
 
@@ -233,8 +308,58 @@ impl Action for SetupDefaultProfile {
     async fn revert(&mut self) -> Result<(), ActionError> {
         std::env::remove_var("NIX_SSL_CERT_FILE");
 
-        Ok(())
+        let mut errors = remove_profile_links(&self.target_root).await;
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(Self::error(errors.remove(0)))
+        } else {
+            Err(Self::error(ActionErrorKind::Multiple(errors)))
+        }
+    }
+}
+
+/// Remove the default profile generation links and the GC root links below `target_root`
+///
+/// Only symlinks are removed, and every link which could not be removed is reported.
+async fn remove_profile_links(target_root: &Path) -> Vec<ActionErrorKind> {
+    let profiles_dir = in_target_root(target_root, PROFILES_DIR);
+    let mut links = vec![profiles_dir.join("default")];
+    links.extend(symlinks_in(&profiles_dir, |name| {
+        name.starts_with("default-") && name.ends_with("-link")
+    }));
+    for gcroots_dir in GCROOTS_DIRS {
+        links.extend(symlinks_in(
+            &in_target_root(target_root, gcroots_dir),
+            |_| true,
+        ));
+    }
+
+    let mut errors = vec![];
+    for link in links {
+        if !link.is_symlink() {
+            continue;
+        }
+        tracing::trace!("Removing `{}`", link.display());
+        if let Err(e) = tokio::fs::remove_file(&link).await {
+            errors.push(ActionErrorKind::Remove(link, e));
+        }
     }
+    errors
+}
+
+/// The symlinks directly in `dir` whose file name matches, or nothing if `dir` can't be read
+fn symlinks_in(dir: &Path, matches: impl Fn(&str) -> bool) -> Vec<PathBuf> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str().map(&matches).unwrap_or(false))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_symlink())
+        .collect()
 }
 
 #[non_exhaustive]
@@ -258,6 +383,8 @@ pub enum SetupDefaultProfileError {
     NoNix,
     #[error("No root home found to place channel configuration in")]
     NoRootHome,
+    #[error("`chroot` is required to set up the default profile in an alternate target root")]
+    NoChroot,
     #[error("Unarchived Nix store appears to contain multiple `nss-ca-cert` packages, cannot select one")]
     MultipleNssCaCertPackages,
     #[error("Unarchived Nix store appears to contain multiple `nix` packages, cannot select one")]
@@ -269,3 +396,39 @@ impl Into<ActionErrorKind> for SetupDefaultProfileError {
         ActionErrorKind::Custom(Box::new(self))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn removes_profile_generations_and_gcroots() -> eyre::Result<()> {
+        let target_root = tempfile::tempdir()?;
+        let profiles_dir = in_target_root(target_root.path(), PROFILES_DIR);
+        let auto_dir = in_target_root(target_root.path(), GCROOTS_DIRS[0]);
+        tokio::fs::create_dir_all(&profiles_dir).await?;
+        tokio::fs::create_dir_all(&auto_dir).await?;
+
+        tokio::fs::symlink(
+            "/nix/store/aaaa-profile",
+            profiles_dir.join("default-1-link"),
+        )
+        .await?;
+        tokio::fs::symlink("default-1-link", profiles_dir.join("default")).await?;
+        tokio::fs::symlink("/home/user/result", auto_dir.join("aaaa")).await?;
+        // Other profiles, and anything which isn't a link, are left alone
+        tokio::fs::symlink("/nix/store/bbbb-profile", profiles_dir.join("other-1-link")).await?;
+        tokio::fs::write(auto_dir.join("not-a-link"), "").await?;
+
+        let errors = remove_profile_links(target_root.path()).await;
+        assert!(errors.is_empty(), "{errors:?}");
+
+        assert!(!profiles_dir.join("default").is_symlink());
+        assert!(!profiles_dir.join("default-1-link").is_symlink());
+        assert!(!auto_dir.join("aaaa").is_symlink());
+        assert!(profiles_dir.join("other-1-link").is_symlink());
+        assert!(auto_dir.join("not-a-link").exists());
+
+        Ok(())
+    }
+}
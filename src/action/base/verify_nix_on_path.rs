@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+
+use crate::action::{
+    base::ChangeOwnership,
+    common::{
+        configure_shell_profile::{login_shell, nix_on_login_path, VERIFY_SHELL_TIMEOUT},
+        ConfigureNix,
+    },
+    shell_command, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction,
+};
+use crate::settings::Shell;
+
+/** Check `nix` resolves in a fresh login shell of each configured shell, does nothing on revert
+
+This checks what a user opening a new terminal sees, rather than only that the profiles were written.
+A shell which does not find `nix` fails the install, planners leave this action out when the check is skipped.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct VerifyNixOnPath {
+    shells: Vec<PathBuf>,
+}
+
+impl VerifyNixOnPath {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(shells: &[Shell]) -> Result<StatefulAction<Self>, ActionError> {
+        // Like `ConfigureShellProfile`, an empty list means every shell. Nushell is not probed, it
+        // is configured through `nix.nu` rather than a login profile.
+        let shells = [Shell::Bash, Shell::Zsh, Shell::Fish]
+            .into_iter()
+            .filter(|shell| shells.is_empty() || shells.contains(shell))
+            .filter_map(|shell| which::which(shell.to_string()).ok())
+            .collect();
+
+        Ok(Self { shells }.into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "verify_nix_on_path")]
+impl Action for VerifyNixOnPath {
+    fn action_tag() -> ActionTag {
+        ActionTag("verify_nix_on_path")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Verify `nix` is on the `PATH` of a new login shell".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "verify_nix_on_path",
+            shells = tracing::field::debug(&self.shells),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.shells
+                .iter()
+                .map(|shell| format!("Run `command -v nix` in `{} --login`", shell.display()))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for shell in &self.shells {
+            match nix_on_login_path(shell, VERIFY_SHELL_TIMEOUT).await {
+                Ok(Some(true)) => {
+                    tracing::debug!("`{}` found `nix`", shell.display())
+                },
+                Ok(Some(false)) => errors.push(ActionErrorKind::from(
+                    VerifyNixOnPathError::NotOnPath(shell.clone(), login_path(shell).await),
+                )),
+                Ok(None) => errors.push(ActionErrorKind::from(VerifyNixOnPathError::TimedOut(
+                    shell.clone(),
+                    VERIFY_SHELL_TIMEOUT.as_secs(),
+                ))),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(Self::error(errors.into_iter().next().unwrap()))
+        } else {
+            Err(Self::error(ActionErrorKind::Multiple(errors)))
+        }
+    }
+
+    fn depends_on(&self) -> Option<Vec<ActionTag>> {
+        // Only the shell profiles and the default profile need to be in place
+        Some(vec![
+            ConfigureNix::action_tag(),
+            ChangeOwnership::action_tag(),
+        ])
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        Some(
+            self.shells
+                .iter()
+                .map(|shell| {
+                    let command = login_shell(shell, "command -v nix");
+                    format!(
+                        "env -i HOME=\"$HOME\" USER=\"$USER\" LOGNAME=\"$LOGNAME\" TERM=\"${{TERM:-}}\" {}",
+                        shell_command(
+                            std::iter::once(command.as_std().get_program())
+                                .chain(command.as_std().get_args()),
+                        )
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![/* Deliberately empty -- this is a noop */]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+/// The `PATH` of a new login shell, for reporting why `nix` was not found
+async fn login_path(shell: &Path) -> String {
+    let output = login_shell(shell, "echo $PATH").output();
+    match tokio::time::timeout(VERIFY_SHELL_TIMEOUT, output).await {
+        Ok(Ok(output)) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        _ => String::from("<unknown>"),
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyNixOnPathError {
+    #[error("`nix` was not found in a new `{}` login shell, its `PATH` was `{1}`", .0.display())]
+    NotOnPath(PathBuf, String),
+    #[error("A new `{}` login shell did not exit within {1} seconds", .0.display())]
+    TimedOut(PathBuf, u64),
+}
+
+impl From<VerifyNixOnPathError> for ActionErrorKind {
+    fn from(v: VerifyNixOnPathError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_nix_fails() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        let shell = temp_dir.path().join("missing");
+        std::fs::write(&shell, "#!/bin/sh\nexit 1\n")?;
+        std::fs::set_permissions(&shell, std::fs::Permissions::from_mode(0o755))?;
+
+        let mut action = VerifyNixOnPath {
+            shells: vec![shell],
+        };
+        assert!(action.execute().await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tracing::{span, Span};
+
+use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
+use crate::action::common::configure_shell_profile::locations_in_target_root;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::planner::ShellProfileLocations;
+use crate::settings::{Shell, HOST_ROOT};
+
+/// The `conf.d` file of fish the hook is placed in, beside `conf.d/nix.fish`
+const FISH_CONFD_SUFFIX: &str = "conf.d/nix-installer-direnv.fish";
+
+/**
+Hook direnv into the shell profiles, so `.envrc` files load in new interactive shells
+
+The hook only runs once `direnv` is on the `PATH`, so it can be installed later, for example
+with `nix profile install nixpkgs#direnv`. Profiles already hooking direnv are left alone.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureDirenv {
+    shells: Vec<Shell>,
+    create_or_insert_into_files: Vec<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl ConfigureDirenv {
+    /// Hook each of `shells` (all of them, if empty) whose profiles exist
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        locations: ShellProfileLocations,
+        shells: Vec<Shell>,
+        target_root: &Path,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let shells = if shells.is_empty() {
+            vec![Shell::Bash, Shell::Zsh, Shell::Fish]
+        } else {
+            shells
+        };
+        let locations = if target_root == Path::new(HOST_ROOT) {
+            locations
+        } else {
+            locations_in_target_root(locations, target_root)
+        };
+
+        let mut targets = vec![];
+        for shell in &shells {
+            match shell {
+                // The hook belongs in the rc files of interactive shells, not login drop-ins like `profile.d/nix.sh`
+                Shell::Bash | Shell::Zsh => {
+                    let profiles = if *shell == Shell::Bash {
+                        &locations.bash
+                    } else {
+                        &locations.zsh
+                    };
+                    for profile in profiles {
+                        let is_drop_in = profile
+                            .parent()
+                            .is_some_and(|parent| parent.ends_with("profile.d"));
+                        if profile.is_file() && !is_drop_in {
+                            targets.push((*shell, profile.clone()));
+                        }
+                    }
+                },
+                Shell::Fish => {
+                    for prefix in &locations.fish.confd_prefixes {
+                        if prefix.join("conf.d").is_dir() {
+                            targets.push((*shell, prefix.join(FISH_CONFD_SUFFIX)));
+                        }
+                    }
+                },
+                Shell::Nushell => {
+                    tracing::debug!(
+                        "Not hooking direnv into nushell, `direnv hook` does not support it"
+                    );
+                },
+            }
+        }
+
+        let mut create_or_insert_into_files = vec![];
+        for (shell, target) in targets {
+            if already_hooked(&target).await.map_err(Self::error)? {
+                tracing::debug!(
+                    "Not hooking direnv into `{}`, it already does",
+                    target.display()
+                );
+                continue;
+            }
+            create_or_insert_into_files.push(
+                CreateOrInsertIntoFile::plan(
+                    &target,
+                    None,
+                    None,
+                    0o644,
+                    hook(shell),
+                    create_or_insert_into_file::Position::End,
+                    false,
+                )
+                .await
+                .map_err(Self::error)?,
+            );
+        }
+
+        Ok(Self {
+            shells,
+            create_or_insert_into_files,
+        }
+        .into())
+    }
+}
+
+/// The snippet loading direnv in an interactive `shell`, if it is installed
+fn hook(shell: Shell) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!(
+            "\n\
+            # Nix direnv\n\
+            if command -v direnv >/dev/null 2>&1; then\n\
+            {inde}eval \"$(direnv hook {shell})\"\n\
+            fi\n\
+            # End Nix direnv\n",
+            inde = "    ", // indent
+        ),
+        Shell::Fish => format!(
+            "\n\
+            # Nix direnv\n\
+            if status is-interactive; and type -q direnv\n\
+            {inde}direnv hook fish | source\n\
+            end\n\
+            # End Nix direnv\n",
+            inde = "    ", // indent
+        ),
+        Shell::Nushell => unreachable!("Nushell is never planned a direnv hook"),
+    }
+}
+
+/// If `path` already hooks direnv, like a user's own `eval "$(direnv hook bash)"`
+async fn already_hooked(path: &Path) -> Result<bool, ActionErrorKind> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(buf) => Ok(buf.contains("direnv hook")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_direnv")]
+impl Action for ConfigureDirenv {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_direnv")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Hook direnv into the shell profiles".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_direnv",
+            shells = tracing::field::debug(&self.shells),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .create_or_insert_into_files
+            .iter()
+            .flat_map(|create_or_insert_into_file| {
+                create_or_insert_into_file.action.touched_paths()
+            })
+            .map(|path| format!("Load direnv from `{}`", path.display()))
+            .collect::<Vec<_>>();
+        explanation.push(
+            "For cached `use nix` and `use flake` environments, install nix-direnv with `nix profile install nixpkgs#nix-direnv` and add `source $HOME/.nix-profile/share/nix-direnv/direnvrc` to `~/.config/direnv/direnvrc`".to_string(),
+        );
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            if let Err(err) = create_or_insert_into_file.try_execute().await {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+
+    fn check_drift(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .flat_map(|create_or_insert_into_file| create_or_insert_into_file.action.check_drift())
+            .collect()
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        for create_or_insert_into_file in &self.create_or_insert_into_files {
+            commands.extend(create_or_insert_into_file.to_shell()?);
+        }
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .flat_map(|create_or_insert_into_file| {
+                create_or_insert_into_file.action.touched_paths()
+            })
+            .collect()
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        self.create_or_insert_into_files
+            .iter()
+            .map(|create_or_insert_into_file| create_or_insert_into_file.estimated_duration())
+            .sum()
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Unhook direnv from the shell profiles".to_string(),
+            vec!["Remove the direnv hook from the shell profiles".to_string()],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            if let Err(err) = create_or_insert_into_file.try_revert().await {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn hooks_profiles_once() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bashrc = temp_dir.path().join("bashrc");
+        let zshrc = temp_dir.path().join("zshrc");
+        tokio::fs::write(&bashrc, "# bashrc\n").await?;
+        tokio::fs::write(&zshrc, "eval \"$(direnv hook zsh)\"\n").await?;
+        let mut locations = ShellProfileLocations::default();
+        locations.bash = vec![bashrc.clone()];
+        locations.zsh = vec![zshrc.clone()];
+        locations.fish.confd_prefixes = vec![];
+
+        let mut action = ConfigureDirenv::plan(locations, vec![], Path::new(HOST_ROOT)).await?;
+        assert_eq!(action.action.touched_paths(), vec![bashrc.clone()]);
+
+        action.try_execute().await?;
+        assert!(tokio::fs::read_to_string(&bashrc)
+            .await?
+            .contains("eval \"$(direnv hook bash)\""));
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read_to_string(&bashrc).await?, "# bashrc\n");
+        assert_eq!(
+            tokio::fs::read_to_string(&zshrc).await?,
+            "eval \"$(direnv hook zsh)\"\n"
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,28 @@
 #[cfg(target_os = "linux")]
+use std::ffi::OsStr;
+#[cfg(target_os = "linux")]
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
+#[cfg(target_os = "linux")]
+use crate::action::base::shell_write;
+#[cfg(target_os = "linux")]
+use crate::action::linux::{
+    start_systemd_unit::{daemon_ready_description, wait_for_daemon, wait_for_daemon_shell},
+    ConfigureOpenRcService, StartOpenRcService,
+};
+#[cfg(target_os = "linux")]
+use crate::action::shell_quote;
+use crate::action::{shell_command, ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
-use crate::settings::InitSystem;
+use crate::settings::{default_target_root, InitSystem};
+#[cfg(target_os = "linux")]
+use crate::settings::{in_target_root, HOST_ROOT};
 
 #[cfg(target_os = "linux")]
 const SERVICE_SRC: &str = "/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service";
@@ -35,17 +49,29 @@ pub struct ConfigureInitService {
     init: InitSystem,
     start_daemon: bool,
     ssl_cert_file: Option<PathBuf>,
+    #[serde(default = "default_target_root")]
+    target_root: PathBuf,
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    configure_openrc_service: Option<StatefulAction<ConfigureOpenRcService>>,
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    start_openrc_service: Option<StatefulAction<StartOpenRcService>>,
+    /// How long to wait for the Nix daemon to serve once systemd started its socket
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    daemon_ready_timeout: Option<Duration>,
 }
 
 impl ConfigureInitService {
     #[cfg(target_os = "linux")]
-    async fn check_if_systemd_unit_exists(src: &str, dest: &str) -> Result<(), ActionErrorKind> {
+    async fn check_if_systemd_unit_exists(src: &str, dest: &Path) -> Result<(), ActionErrorKind> {
         // TODO: once we have a way to communicate interaction between the library and the cli,
         // interactively ask for permission to remove the file
 
         let unit_src = PathBuf::from(src);
         // NOTE: Check if the unit file already exists...
-        let unit_dest = PathBuf::from(dest);
+        let unit_dest = dest.to_path_buf();
         if unit_dest.exists() {
             if unit_dest.is_symlink() {
                 let link_dest = tokio::fs::read_link(&unit_dest)
@@ -59,10 +85,9 @@ impl ConfigureInitService {
             }
         }
         // NOTE: ...and if there are any overrides in the most well-known places for systemd
-        if Path::new(&format!("{dest}.d")).exists() {
-            return Err(ActionErrorKind::DirExists(PathBuf::from(format!(
-                "{dest}.d"
-            ))));
+        let unit_overrides = PathBuf::from(format!("{}.d", dest.display()));
+        if unit_overrides.exists() {
+            return Err(ActionErrorKind::DirExists(unit_overrides));
         }
 
         Ok(())
@@ -73,6 +98,7 @@ impl ConfigureInitService {
         init: InitSystem,
         start_daemon: bool,
         ssl_cert_file: Option<PathBuf>,
+        target_root: PathBuf,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let ssl_cert_file_path = if let Some(ssl_cert_file) = ssl_cert_file {
             Some(
@@ -84,6 +110,8 @@ impl ConfigureInitService {
             None
         };
 
+        #[cfg(target_os = "linux")]
+        let (mut configure_openrc_service, mut start_openrc_service) = (None, None);
         match init {
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => {
@@ -93,7 +121,9 @@ impl ConfigureInitService {
             InitSystem::Systemd => {
                 // If /run/systemd/system exists, we can be reasonably sure the machine is booted
                 // with systemd: https://www.freedesktop.org/software/systemd/man/sd_booted.html
-                if !Path::new("/run/systemd/system").exists() {
+                // An alternate target root is never booted, its units are only enabled offline.
+                if target_root == Path::new(HOST_ROOT) && !Path::new("/run/systemd/system").exists()
+                {
                     return Err(Self::error(ActionErrorKind::SystemdMissing));
                 }
 
@@ -101,12 +131,34 @@ impl ConfigureInitService {
                     return Err(Self::error(ActionErrorKind::SystemdMissing));
                 }
 
-                Self::check_if_systemd_unit_exists(SERVICE_SRC, SERVICE_DEST)
-                    .await
-                    .map_err(Self::error)?;
-                Self::check_if_systemd_unit_exists(SOCKET_SRC, SOCKET_DEST)
-                    .await
-                    .map_err(Self::error)?;
+                Self::check_if_systemd_unit_exists(
+                    SERVICE_SRC,
+                    &in_target_root(&target_root, SERVICE_DEST),
+                )
+                .await
+                .map_err(Self::error)?;
+                Self::check_if_systemd_unit_exists(
+                    SOCKET_SRC,
+                    &in_target_root(&target_root, SOCKET_DEST),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                configure_openrc_service = Some(
+                    ConfigureOpenRcService::plan(ssl_cert_file_path.clone(), &target_root)
+                        .await
+                        .map_err(Self::error)?,
+                );
+                // Nothing runs in an alternate target root, its runlevel starts the daemon once booted
+                if start_daemon && target_root == Path::new(HOST_ROOT) {
+                    start_openrc_service = Some(
+                        StartOpenRcService::plan("nix-daemon")
+                            .await
+                            .map_err(Self::error)?,
+                    );
+                }
             },
             #[cfg(target_os = "linux")]
             InitSystem::None => {
@@ -118,11 +170,27 @@ impl ConfigureInitService {
             init,
             start_daemon,
             ssl_cert_file: ssl_cert_file_path,
+            target_root,
+            #[cfg(target_os = "linux")]
+            configure_openrc_service,
+            #[cfg(target_os = "linux")]
+            start_openrc_service,
+            #[cfg(target_os = "linux")]
+            daemon_ready_timeout: None,
         }
         .into())
     }
 }
 
+#[cfg(target_os = "linux")]
+impl StatefulAction<ConfigureInitService> {
+    /// With systemd, wait up to `timeout` after starting the daemon socket for the Nix daemon to answer `nix store ping`
+    pub fn with_daemon_ready_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.action.daemon_ready_timeout = timeout.into();
+        self
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "configure_init_service")]
 impl Action for ConfigureInitService {
@@ -133,6 +201,8 @@ impl Action for ConfigureInitService {
         match self.init {
             #[cfg(target_os = "linux")]
             InitSystem::Systemd => "Configure Nix daemon related settings with systemd".to_string(),
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => "Configure Nix daemon related settings with OpenRC".to_string(),
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => {
                 "Configure Nix daemon related settings with launchctl".to_string()
@@ -153,15 +223,48 @@ impl Action for ConfigureInitService {
             InitSystem::Systemd => {
                 let mut explanation = vec![
                     "Run `systemd-tempfiles --create --prefix=/nix/var/nix`".to_string(),
-                    format!("Symlink `{SERVICE_SRC}` to `{SERVICE_DEST}`"),
-                    format!("Symlink `{SOCKET_SRC}` to `{SOCKET_DEST}`"),
-                    "Run `systemctl daemon-reload`".to_string(),
+                    format!(
+                        "Symlink `{SERVICE_SRC}` to `{}`",
+                        in_target_root(&self.target_root, SERVICE_DEST).display()
+                    ),
+                    format!(
+                        "Symlink `{SOCKET_SRC}` to `{}`",
+                        in_target_root(&self.target_root, SOCKET_DEST).display()
+                    ),
                 ];
-                if self.start_daemon {
+                if self.target_root != Path::new(HOST_ROOT) {
+                    explanation.push(format!(
+                        "Run `systemctl --root={} enable nix-daemon.socket`",
+                        self.target_root.display()
+                    ));
+                    explanation.push(
+                        "Starting the Nix daemon is skipped, it will start when the target root is booted"
+                            .to_string(),
+                    );
+                } else if self.start_daemon {
+                    explanation.push("Run `systemctl daemon-reload`".to_string());
                     explanation.push(format!("Run `systemctl enable --now {SOCKET_SRC}`"));
+                    if let Some(timeout) = self.daemon_ready_timeout {
+                        explanation.push(daemon_ready_description(timeout));
+                    }
                 }
                 vec.push(ActionDescription::new(self.tracing_synopsis(), explanation))
             },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                let explanation = self
+                    .configure_openrc_service
+                    .iter()
+                    .flat_map(|action| action.describe_execute())
+                    .chain(
+                        self.start_openrc_service
+                            .iter()
+                            .flat_map(|action| action.describe_execute()),
+                    )
+                    .flat_map(|desc| std::iter::once(desc.description).chain(desc.explanation))
+                    .collect::<Vec<_>>();
+                vec.push(ActionDescription::new(self.tracing_synopsis(), explanation))
+            },
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => {
                 let mut explanation = vec![format!(
@@ -184,6 +287,7 @@ impl Action for ConfigureInitService {
             init,
             start_daemon,
             ssl_cert_file,
+            ..
         } = self;
 
         match init {
@@ -238,7 +342,14 @@ impl Action for ConfigureInitService {
             },
             #[cfg(target_os = "linux")]
             InitSystem::Systemd => {
-                if *start_daemon {
+                let target_root = &self.target_root;
+                let is_host_root = *target_root == Path::new(HOST_ROOT);
+                let service_dest = in_target_root(target_root, SERVICE_DEST);
+                let socket_dest = in_target_root(target_root, SOCKET_DEST);
+                let tmpfiles_dest = in_target_root(target_root, TMPFILES_DEST);
+
+                // The systemd of an alternate target root is not running, so there is nothing to reload
+                if *start_daemon && is_host_root {
                     execute_command(
                         Command::new("systemctl")
                             .process_group(0)
@@ -249,38 +360,44 @@ impl Action for ConfigureInitService {
                     .map_err(Self::error)?;
                 }
                 // The goal state is the `socket` enabled and active, the service not enabled and stopped (it activates via socket activation)
-                if is_enabled("nix-daemon.socket").await.map_err(Self::error)? {
-                    disable("nix-daemon.socket", false)
+                // Nothing in an alternate target root is running, so there is nothing to stop there
+                let socket_was_active = if is_host_root {
+                    if is_enabled("nix-daemon.socket").await.map_err(Self::error)? {
+                        disable("nix-daemon.socket", false)
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    let socket_was_active =
+                        if is_active("nix-daemon.socket").await.map_err(Self::error)? {
+                            stop("nix-daemon.socket").await.map_err(Self::error)?;
+                            true
+                        } else {
+                            false
+                        };
+                    if is_enabled("nix-daemon.service")
                         .await
-                        .map_err(Self::error)?;
-                }
-                let socket_was_active =
-                    if is_active("nix-daemon.socket").await.map_err(Self::error)? {
-                        stop("nix-daemon.socket").await.map_err(Self::error)?;
-                        true
-                    } else {
-                        false
+                        .map_err(Self::error)?
+                    {
+                        let now = is_active("nix-daemon.service").await.map_err(Self::error)?;
+                        disable("nix-daemon.service", now)
+                            .await
+                            .map_err(Self::error)?;
+                    } else if is_active("nix-daemon.service").await.map_err(Self::error)? {
+                        stop("nix-daemon.service").await.map_err(Self::error)?;
                     };
-                if is_enabled("nix-daemon.service")
-                    .await
-                    .map_err(Self::error)?
-                {
-                    let now = is_active("nix-daemon.service").await.map_err(Self::error)?;
-                    disable("nix-daemon.service", now)
-                        .await
-                        .map_err(Self::error)?;
-                } else if is_active("nix-daemon.service").await.map_err(Self::error)? {
-                    stop("nix-daemon.service").await.map_err(Self::error)?;
+                    socket_was_active
+                } else {
+                    false
                 };
 
-                tracing::trace!(src = TMPFILES_SRC, dest = TMPFILES_DEST, "Symlinking");
-                if !Path::new(TMPFILES_DEST).exists() {
-                    tokio::fs::symlink(TMPFILES_SRC, TMPFILES_DEST)
+                tracing::trace!(src = TMPFILES_SRC, dest = %tmpfiles_dest.display(), "Symlinking");
+                if !tmpfiles_dest.exists() {
+                    tokio::fs::symlink(TMPFILES_SRC, &tmpfiles_dest)
                         .await
                         .map_err(|e| {
                             ActionErrorKind::Symlink(
                                 PathBuf::from(TMPFILES_SRC),
-                                PathBuf::from(TMPFILES_DEST),
+                                tmpfiles_dest.clone(),
                                 e,
                             )
                         })
@@ -288,7 +405,7 @@ impl Action for ConfigureInitService {
                 }
 
                 execute_command(
-                    Command::new("systemd-tmpfiles")
+                    systemd_tmpfiles(target_root)
                         .process_group(0)
                         .arg("--create")
                         .arg("--prefix=/nix/var/nix")
@@ -300,46 +417,42 @@ impl Action for ConfigureInitService {
                 // TODO: once we have a way to communicate interaction between the library and the
                 // cli, interactively ask for permission to remove the file
 
-                Self::check_if_systemd_unit_exists(SERVICE_SRC, SERVICE_DEST)
+                Self::check_if_systemd_unit_exists(SERVICE_SRC, &service_dest)
                     .await
                     .map_err(Self::error)?;
-                if Path::new(SERVICE_DEST).exists() {
-                    tokio::fs::remove_file(SERVICE_DEST)
+                if service_dest.exists() {
+                    tokio::fs::remove_file(&service_dest)
                         .await
-                        .map_err(|e| ActionErrorKind::Remove(SERVICE_DEST.into(), e))
+                        .map_err(|e| ActionErrorKind::Remove(service_dest.clone(), e))
                         .map_err(Self::error)?;
                 }
-                tokio::fs::symlink(SERVICE_SRC, SERVICE_DEST)
+                tokio::fs::symlink(SERVICE_SRC, &service_dest)
                     .await
                     .map_err(|e| {
                         ActionErrorKind::Symlink(
                             PathBuf::from(SERVICE_SRC),
-                            PathBuf::from(SERVICE_DEST),
+                            service_dest.clone(),
                             e,
                         )
                     })
                     .map_err(Self::error)?;
-                Self::check_if_systemd_unit_exists(SOCKET_SRC, SOCKET_DEST)
+                Self::check_if_systemd_unit_exists(SOCKET_SRC, &socket_dest)
                     .await
                     .map_err(Self::error)?;
-                if Path::new(SOCKET_DEST).exists() {
-                    tokio::fs::remove_file(SOCKET_DEST)
+                if socket_dest.exists() {
+                    tokio::fs::remove_file(&socket_dest)
                         .await
-                        .map_err(|e| ActionErrorKind::Remove(SOCKET_DEST.into(), e))
+                        .map_err(|e| ActionErrorKind::Remove(socket_dest.clone(), e))
                         .map_err(Self::error)?;
                 }
-                tokio::fs::symlink(SOCKET_SRC, SOCKET_DEST)
+                tokio::fs::symlink(SOCKET_SRC, &socket_dest)
                     .await
                     .map_err(|e| {
-                        ActionErrorKind::Symlink(
-                            PathBuf::from(SOCKET_SRC),
-                            PathBuf::from(SOCKET_DEST),
-                            e,
-                        )
+                        ActionErrorKind::Symlink(PathBuf::from(SOCKET_SRC), socket_dest.clone(), e)
                     })
                     .map_err(Self::error)?;
 
-                if *start_daemon {
+                if *start_daemon && is_host_root {
                     execute_command(
                         Command::new("systemctl")
                             .process_group(0)
@@ -351,7 +464,8 @@ impl Action for ConfigureInitService {
                 }
 
                 if let Some(ssl_cert_file) = ssl_cert_file {
-                    let service_conf_dir_path = PathBuf::from(format!("{SERVICE_DEST}.d"));
+                    let service_conf_dir_path =
+                        PathBuf::from(format!("{}.d", service_dest.display()));
                     tokio::fs::create_dir(&service_conf_dir_path)
                         .await
                         .map_err(|e| {
@@ -360,26 +474,60 @@ impl Action for ConfigureInitService {
                         .map_err(Self::error)?;
                     let service_conf_file_path =
                         service_conf_dir_path.join("nix-ssl-cert-file.conf");
-                    tokio::fs::write(
-                        service_conf_file_path,
-                        format!(
-                            "\
+                    let service_conf_buf = format!(
+                        "\
                         [Service]\n\
                         Environment=\"NIX_SSL_CERT_FILE={ssl_cert_file:?}\"\n\
                     "
-                        ),
+                    );
+                    tokio::fs::write(&service_conf_file_path, &service_conf_buf)
+                        .await
+                        .map_err(|e| ActionErrorKind::Write(ssl_cert_file.clone(), e))
+                        .map_err(Self::error)?;
+                    crate::action::base::verify_written(
+                        &service_conf_file_path,
+                        &service_conf_buf,
+                        None,
                     )
                     .await
-                    .map_err(|e| ActionErrorKind::Write(ssl_cert_file.clone(), e))
                     .map_err(Self::error)?;
                 }
 
-                if *start_daemon || socket_was_active {
+                if !is_host_root {
+                    // The unit is already linked into the target root, so enable it by name
+                    execute_command(
+                        systemctl(target_root)
+                            .process_group(0)
+                            .args(["enable", "nix-daemon.socket"])
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    .map_err(Self::error)?;
+                } else if *start_daemon || socket_was_active {
                     enable(SOCKET_SRC, true).await.map_err(Self::error)?;
+                    // Starting the socket does not wait for the daemon to serve
+                    if let Some(timeout) = self.daemon_ready_timeout {
+                        wait_for_daemon(timeout).await.map_err(Self::error)?;
+                    }
                 } else {
                     enable(SOCKET_SRC, false).await.map_err(Self::error)?;
                 }
             },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                if let Some(configure_openrc_service) = &mut self.configure_openrc_service {
+                    configure_openrc_service
+                        .try_execute()
+                        .await
+                        .map_err(Self::error)?;
+                }
+                if let Some(start_openrc_service) = &mut self.start_openrc_service {
+                    start_openrc_service
+                        .try_execute()
+                        .await
+                        .map_err(Self::error)?;
+                }
+            },
             #[cfg(not(target_os = "macos"))]
             InitSystem::None => {
                 // Nothing here, no init system
@@ -389,6 +537,140 @@ impl Action for ConfigureInitService {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        match self.init {
+            #[cfg(target_os = "macos")]
+            InitSystem::Launchd => {
+                commands.push(shell_command([
+                    "cp",
+                    DARWIN_NIX_DAEMON_SOURCE,
+                    DARWIN_NIX_DAEMON_DEST,
+                ]));
+                commands.push(shell_command([
+                    "launchctl",
+                    "load",
+                    "-w",
+                    DARWIN_NIX_DAEMON_DEST,
+                ]));
+                if let Some(ssl_cert_file) = &self.ssl_cert_file {
+                    commands.push(shell_command([
+                        "launchctl",
+                        "setenv",
+                        "NIX_SSL_CERT_FILE",
+                        &format!("{ssl_cert_file:?}"),
+                    ]));
+                }
+                if self.start_daemon {
+                    commands.push("launchctl kickstart -k system/org.nixos.nix-daemon".to_string());
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::Systemd => {
+                // Enabling units offline inside another root is left to `execute`
+                if self.target_root != Path::new(HOST_ROOT) {
+                    return None;
+                }
+                let tmpfiles_dest = shell_quote(TMPFILES_DEST);
+                commands.push(format!(
+                    "[ -e {tmpfiles_dest} ] || ln -s {} {tmpfiles_dest}",
+                    shell_quote(TMPFILES_SRC)
+                ));
+                commands.push("systemd-tmpfiles --create --prefix=/nix/var/nix".to_string());
+                commands.push(shell_command(["ln", "-sfn", SERVICE_SRC, SERVICE_DEST]));
+                commands.push(shell_command(["ln", "-sfn", SOCKET_SRC, SOCKET_DEST]));
+                // Like `execute`, systemd only reloads its units for a daemon started right away
+                if self.start_daemon {
+                    commands.push("systemctl daemon-reload".to_string());
+                }
+                if let Some(ssl_cert_file) = &self.ssl_cert_file {
+                    let service_conf_dir_path = PathBuf::from(format!("{SERVICE_DEST}.d"));
+                    commands.push(shell_command([
+                        OsStr::new("mkdir"),
+                        OsStr::new("-p"),
+                        service_conf_dir_path.as_os_str(),
+                    ]));
+                    commands.push(shell_write(
+                        &service_conf_dir_path.join("nix-ssl-cert-file.conf"),
+                        &format!(
+                            "[Service]\nEnvironment=\"NIX_SSL_CERT_FILE={ssl_cert_file:?}\"\n"
+                        ),
+                    ));
+                }
+                commands.push(match self.start_daemon {
+                    true => shell_command(["systemctl", "enable", "--now", SOCKET_SRC]),
+                    false => shell_command(["systemctl", "enable", SOCKET_SRC]),
+                });
+                if let Some(timeout) = self.daemon_ready_timeout.filter(|_| self.start_daemon) {
+                    commands.push(wait_for_daemon_shell(timeout));
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                if let Some(configure_openrc_service) = &self.configure_openrc_service {
+                    commands.extend(configure_openrc_service.to_shell()?);
+                }
+                if let Some(start_openrc_service) = &self.start_openrc_service {
+                    commands.extend(start_openrc_service.to_shell()?);
+                }
+            },
+            #[cfg(not(target_os = "macos"))]
+            InitSystem::None => (),
+        }
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        match self.init {
+            #[cfg(target_os = "macos")]
+            InitSystem::Launchd => vec![PathBuf::from(DARWIN_NIX_DAEMON_DEST)],
+            #[cfg(target_os = "linux")]
+            InitSystem::Systemd => {
+                let service_dest = in_target_root(&self.target_root, SERVICE_DEST);
+                let mut paths = vec![
+                    in_target_root(&self.target_root, TMPFILES_DEST),
+                    in_target_root(&self.target_root, SOCKET_DEST),
+                ];
+                if self.ssl_cert_file.is_some() {
+                    paths.push(
+                        PathBuf::from(format!("{}.d", service_dest.display()))
+                            .join("nix-ssl-cert-file.conf"),
+                    );
+                }
+                paths.push(service_dest);
+                paths
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => self
+                .configure_openrc_service
+                .iter()
+                .flat_map(|configure_openrc_service| {
+                    configure_openrc_service.action.touched_paths()
+                })
+                .collect(),
+            #[cfg(not(target_os = "macos"))]
+            InitSystem::None => vec![],
+        }
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        // A few calls to the init system, then waiting for the daemon if it is started
+        let duration = Duration::from_secs(if self.start_daemon { 3 } else { 1 });
+        #[cfg(target_os = "linux")]
+        let duration = duration
+            + self
+                .configure_openrc_service
+                .as_ref()
+                .map(|configure_openrc_service| configure_openrc_service.estimated_duration())
+                .unwrap_or_default()
+            + self
+                .start_openrc_service
+                .as_ref()
+                .map(|start_openrc_service| start_openrc_service.estimated_duration())
+                .unwrap_or_default();
+        duration
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         match self.init {
             #[cfg(target_os = "linux")]
@@ -403,6 +685,24 @@ impl Action for ConfigureInitService {
                     ],
                 )]
             },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                let explanation = self
+                    .start_openrc_service
+                    .iter()
+                    .flat_map(|action| action.describe_revert())
+                    .chain(
+                        self.configure_openrc_service
+                            .iter()
+                            .flat_map(|action| action.describe_revert()),
+                    )
+                    .flat_map(|desc| std::iter::once(desc.description).chain(desc.explanation))
+                    .collect::<Vec<_>>();
+                vec![ActionDescription::new(
+                    "Unconfigure Nix daemon related settings with OpenRC".to_string(),
+                    explanation,
+                )]
+            },
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => {
                 vec![ActionDescription::new(
@@ -436,19 +736,30 @@ impl Action for ConfigureInitService {
             InitSystem::Systemd => {
                 // We separate stop and disable (instead of using `--now`) to avoid cases where the service isn't started, but is enabled.
 
+                let target_root = &self.target_root;
+                let is_host_root = *target_root == Path::new(HOST_ROOT);
+
                 // These have to fail fast.
-                let socket_is_active = is_active("nix-daemon.socket")
-                    .await
-                    .map_err(|e| Self::error(e))?;
-                let socket_is_enabled = is_enabled("nix-daemon.socket")
-                    .await
-                    .map_err(|e| Self::error(e))?;
-                let service_is_active = is_active("nix-daemon.service")
-                    .await
-                    .map_err(|e| Self::error(e))?;
-                let service_is_enabled = is_enabled("nix-daemon.service")
-                    .await
-                    .map_err(|e| Self::error(e))?;
+                let (socket_is_active, socket_is_enabled, service_is_active, service_is_enabled) =
+                    if is_host_root {
+                        (
+                            is_active("nix-daemon.socket")
+                                .await
+                                .map_err(|e| Self::error(e))?,
+                            is_enabled("nix-daemon.socket")
+                                .await
+                                .map_err(|e| Self::error(e))?,
+                            is_active("nix-daemon.service")
+                                .await
+                                .map_err(|e| Self::error(e))?,
+                            is_enabled("nix-daemon.service")
+                                .await
+                                .map_err(|e| Self::error(e))?,
+                        )
+                    } else {
+                        // Only the socket is ever enabled in an alternate target root, and nothing runs there
+                        (false, true, false, false)
+                    };
 
                 if socket_is_active {
                     if let Err(err) = execute_command(
@@ -465,7 +776,7 @@ impl Action for ConfigureInitService {
 
                 if socket_is_enabled {
                     if let Err(err) = execute_command(
-                        Command::new("systemctl")
+                        systemctl(target_root)
                             .process_group(0)
                             .args(["disable", "nix-daemon.socket"])
                             .stdin(std::process::Stdio::null()),
@@ -503,7 +814,7 @@ impl Action for ConfigureInitService {
                 }
 
                 if let Err(err) = execute_command(
-                    Command::new("systemd-tmpfiles")
+                    systemd_tmpfiles(target_root)
                         .process_group(0)
                         .arg("--remove")
                         .arg("--prefix=/nix/var/nix")
@@ -515,7 +826,10 @@ impl Action for ConfigureInitService {
                 }
 
                 if self.ssl_cert_file.is_some() {
-                    let service_conf_dir_path = PathBuf::from(format!("{SERVICE_DEST}.d"));
+                    let service_conf_dir_path = PathBuf::from(format!(
+                        "{}.d",
+                        in_target_root(target_root, SERVICE_DEST).display()
+                    ));
                     if let Err(err) = tokio::fs::remove_dir_all(&service_conf_dir_path)
                         .await
                         .map_err(|e| ActionErrorKind::Remove(service_conf_dir_path.clone(), e))
@@ -524,22 +838,38 @@ impl Action for ConfigureInitService {
                     }
                 }
 
-                if let Err(err) = tokio::fs::remove_file(TMPFILES_DEST)
+                let tmpfiles_dest = in_target_root(target_root, TMPFILES_DEST);
+                if let Err(err) = tokio::fs::remove_file(&tmpfiles_dest)
                     .await
-                    .map_err(|e| ActionErrorKind::Remove(PathBuf::from(TMPFILES_DEST), e))
+                    .map_err(|e| ActionErrorKind::Remove(tmpfiles_dest.clone(), e))
                 {
                     errors.push(err);
                 }
 
-                if let Err(err) = execute_command(
-                    Command::new("systemctl")
-                        .process_group(0)
-                        .arg("daemon-reload")
-                        .stdin(std::process::Stdio::null()),
-                )
-                .await
-                {
-                    errors.push(err);
+                if is_host_root {
+                    if let Err(err) = execute_command(
+                        Command::new("systemctl")
+                            .process_group(0)
+                            .arg("daemon-reload")
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    {
+                        errors.push(err);
+                    }
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => {
+                if let Some(start_openrc_service) = &mut self.start_openrc_service {
+                    if let Err(err) = start_openrc_service.try_revert().await {
+                        errors.push(ActionErrorKind::Child(Box::new(err)));
+                    }
+                }
+                if let Some(configure_openrc_service) = &mut self.configure_openrc_service {
+                    if let Err(err) = configure_openrc_service.try_revert().await {
+                        errors.push(ActionErrorKind::Child(Box::new(err)));
+                    }
                 }
             },
             #[cfg(not(target_os = "macos"))]
@@ -588,6 +918,25 @@ async fn stop(unit: &str) -> Result<(), ActionErrorKind> {
     }
 }
 
+/// `systemctl`, operating offline on the unit files of `target_root` if it is not the running system
+#[cfg(target_os = "linux")]
+fn systemctl(target_root: &Path) -> Command {
+    let mut command = Command::new("systemctl");
+    if target_root != Path::new(HOST_ROOT) {
+        command.arg(format!("--root={}", target_root.display()));
+    }
+    command
+}
+
+#[cfg(target_os = "linux")]
+fn systemd_tmpfiles(target_root: &Path) -> Command {
+    let mut command = Command::new("systemd-tmpfiles");
+    if target_root != Path::new(HOST_ROOT) {
+        command.arg(format!("--root={}", target_root.display()));
+    }
+    command
+}
+
 #[cfg(target_os = "linux")]
 async fn enable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
     let mut command = Command::new("systemctl");
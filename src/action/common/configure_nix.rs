@@ -1,9 +1,9 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use crate::{
     action::{
-        base::SetupDefaultProfile,
-        common::{ConfigureShellProfile, PlaceNixConfiguration},
+        base::{MoveUnpackedNix, RemoveStaleTempRoots, SetupDefaultProfile},
+        common::{ConfigureDirenv, ConfigureShellProfile, PlaceNixConfiguration, ProvisionNix},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
     },
     planner::ShellProfileLocations,
@@ -19,7 +19,11 @@ Configure Nix and start it
 pub struct ConfigureNix {
     setup_default_profile: StatefulAction<SetupDefaultProfile>,
     configure_shell_profile: Option<StatefulAction<ConfigureShellProfile>>,
+    #[serde(default)]
+    configure_direnv: Option<StatefulAction<ConfigureDirenv>>,
     place_nix_configuration: StatefulAction<PlaceNixConfiguration>,
+    #[serde(default)]
+    remove_stale_temp_roots: Option<StatefulAction<RemoveStaleTempRoots>>,
 }
 
 impl ConfigureNix {
@@ -28,15 +32,35 @@ impl ConfigureNix {
         shell_profile_locations: ShellProfileLocations,
         settings: &CommonSettings,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let setup_default_profile = SetupDefaultProfile::plan(PathBuf::from(SCRATCH_DIR))
-            .await
-            .map_err(Self::error)?;
+        let setup_default_profile = SetupDefaultProfile::plan(
+            settings.in_store_prefix(SCRATCH_DIR),
+            settings.target_root.clone(),
+        )
+        .await
+        .map_err(Self::error)?;
 
+        let configure_direnv = if settings.modify_profile && settings.direnv {
+            Some(
+                ConfigureDirenv::plan(
+                    shell_profile_locations.clone(),
+                    settings.direnv_shells.clone(),
+                    &settings.target_root,
+                )
+                .await
+                .map_err(Self::error)?,
+            )
+        } else {
+            None
+        };
         let configure_shell_profile = if settings.modify_profile {
             Some(
                 ConfigureShellProfile::plan(
                     shell_profile_locations,
+                    settings.profile_shells.clone(),
                     settings.ssl_cert_file.clone(),
+                    settings.enable_flakes && settings.experimental_features_in_profile,
+                    settings.use_xdg_base_directories,
+                    settings.target_root.clone(),
                 )
                 .await
                 .map_err(Self::error)?,
@@ -44,18 +68,25 @@ impl ConfigureNix {
         } else {
             None
         };
-        let place_nix_configuration = PlaceNixConfiguration::plan(
-            settings.nix_build_group_name.clone(),
-            settings.extra_conf.clone(),
-            settings.force,
-        )
-        .await
-        .map_err(Self::error)?;
+        let place_nix_configuration = PlaceNixConfiguration::plan(settings)
+            .await
+            .map_err(Self::error)?;
+        let remove_stale_temp_roots = if settings.cleanup_stale_temp_roots {
+            Some(
+                RemoveStaleTempRoots::plan(&settings.target_root)
+                    .await
+                    .map_err(Self::error)?,
+            )
+        } else {
+            None
+        };
 
         Ok(Self {
             place_nix_configuration,
             setup_default_profile,
             configure_shell_profile,
+            configure_direnv,
+            remove_stale_temp_roots,
         }
         .into())
     }
@@ -80,6 +111,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_direnv,
+            remove_stale_temp_roots,
         } = &self;
 
         let mut buf = setup_default_profile.describe_execute();
@@ -87,6 +120,12 @@ impl Action for ConfigureNix {
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_execute());
         }
+        if let Some(configure_direnv) = configure_direnv {
+            buf.append(&mut configure_direnv.describe_execute());
+        }
+        if let Some(remove_stale_temp_roots) = remove_stale_temp_roots {
+            buf.append(&mut remove_stale_temp_roots.describe_execute());
+        }
         buf
     }
 
@@ -96,6 +135,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_direnv,
+            remove_stale_temp_roots,
         } = self;
 
         if let Some(configure_shell_profile) = configure_shell_profile {
@@ -148,17 +189,103 @@ impl Action for ConfigureNix {
             )?;
         };
 
+        if let Some(configure_shell_profile) = configure_shell_profile {
+            configure_shell_profile.action.verify_sourced().await;
+        }
+
+        // Appended after the profiles import Nix, so `direnv` installed with Nix is found
+        if let Some(configure_direnv) = configure_direnv {
+            configure_direnv.try_execute().await.map_err(Self::error)?;
+        }
+
+        // This must happen before the daemon is started by `ConfigureInitService`
+        if let Some(remove_stale_temp_roots) = remove_stale_temp_roots {
+            remove_stale_temp_roots
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+
         Ok(())
     }
 
+    fn check_drift(&self) -> Vec<PathBuf> {
+        let mut paths = self
+            .configure_shell_profile
+            .as_ref()
+            .map(|configure_shell_profile| configure_shell_profile.action.check_drift())
+            .unwrap_or_default();
+        if let Some(configure_direnv) = &self.configure_direnv {
+            paths.extend(configure_direnv.action.check_drift());
+        }
+        paths
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = self.setup_default_profile.to_shell()?;
+        commands.extend(self.place_nix_configuration.to_shell()?);
+        if let Some(configure_shell_profile) = &self.configure_shell_profile {
+            commands.extend(configure_shell_profile.to_shell()?);
+        }
+        if let Some(configure_direnv) = &self.configure_direnv {
+            commands.extend(configure_direnv.to_shell()?);
+        }
+        if let Some(remove_stale_temp_roots) = &self.remove_stale_temp_roots {
+            commands.extend(remove_stale_temp_roots.to_shell()?);
+        }
+        Some(commands)
+    }
+
+    fn depends_on(&self) -> Option<Vec<ActionTag>> {
+        // Only the Nix in the store is needed, however it was placed there
+        Some(vec![
+            ProvisionNix::action_tag(),
+            MoveUnpackedNix::action_tag(),
+        ])
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.place_nix_configuration.action.touched_paths();
+        if let Some(configure_shell_profile) = &self.configure_shell_profile {
+            paths.extend(configure_shell_profile.action.touched_paths());
+        }
+        if let Some(configure_direnv) = &self.configure_direnv {
+            paths.extend(configure_direnv.action.touched_paths());
+        }
+        paths
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        let mut duration = self.setup_default_profile.estimated_duration()
+            + self.place_nix_configuration.estimated_duration();
+        if let Some(configure_shell_profile) = &self.configure_shell_profile {
+            duration += configure_shell_profile.estimated_duration();
+        }
+        if let Some(configure_direnv) = &self.configure_direnv {
+            duration += configure_direnv.estimated_duration();
+        }
+        if let Some(remove_stale_temp_roots) = &self.remove_stale_temp_roots {
+            duration += remove_stale_temp_roots.estimated_duration();
+        }
+        duration
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_direnv,
+            remove_stale_temp_roots,
         } = &self;
 
         let mut buf = Vec::default();
+        if let Some(remove_stale_temp_roots) = remove_stale_temp_roots {
+            buf.append(&mut remove_stale_temp_roots.describe_revert());
+        }
+        if let Some(configure_direnv) = configure_direnv {
+            buf.append(&mut configure_direnv.describe_revert());
+        }
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_revert());
         }
@@ -171,6 +298,16 @@ impl Action for ConfigureNix {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
+        if let Some(remove_stale_temp_roots) = &mut self.remove_stale_temp_roots {
+            if let Err(err) = remove_stale_temp_roots.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(configure_direnv) = &mut self.configure_direnv {
+            if let Err(err) = configure_direnv.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Some(configure_shell_profile) = &mut self.configure_shell_profile {
             if let Err(err) = configure_shell_profile.try_revert().await {
                 errors.push(err);
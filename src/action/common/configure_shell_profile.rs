@@ -1,50 +1,98 @@
 use crate::action::base::{create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile};
+use crate::action::common::place_nix_configuration::USER_EXPERIMENTAL_FEATURES;
+use crate::action::context::ActionContext;
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
 use crate::planner::ShellProfileLocations;
+use crate::settings::{default_target_root, in_target_root, Shell, HOST_ROOT};
 
 use nix::unistd::User;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
 use tokio::task::JoinSet;
 use tracing::{span, Instrument, Span};
 
 const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
 const PROFILE_NIX_FILE_FISH: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish";
+const PROFILE_NIX_DEFAULT: &str = "/nix/var/nix/profiles/default";
+/// How long a spawned shell has to report if `nix` is on its `PATH`
+pub(crate) const VERIFY_SHELL_TIMEOUT: Duration = Duration::from_secs(10);
 
 /**
 Configure any detected shell profiles to include Nix support
+
+Nix ships no profile script for nushell, so its `nix.nu` sets the variables `nix-daemon.sh` would.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct ConfigureShellProfile {
     locations: ShellProfileLocations,
+    /// The shells to configure, all of them if empty
+    #[serde(default)]
+    shells: Vec<Shell>,
     create_directories: Vec<StatefulAction<CreateDirectory>>,
     create_or_insert_into_files: Vec<StatefulAction<CreateOrInsertIntoFile>>,
+    #[serde(default = "default_target_root")]
+    target_root: PathBuf,
 }
 
 impl ConfigureShellProfile {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         locations: ShellProfileLocations,
+        shells: Vec<Shell>,
         ssl_cert_file: Option<PathBuf>,
+        experimental_features_in_profile: bool,
+        use_xdg_base_directories: bool,
+        target_root: PathBuf,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let is_host_root = target_root == Path::new(HOST_ROOT);
+        let locations = if is_host_root {
+            locations
+        } else {
+            locations_in_target_root(locations, &target_root)
+        };
         let mut create_or_insert_files = Vec::default();
         let mut create_directories = Vec::default();
 
-        let maybe_ssl_cert_file_setting = if let Some(ssl_cert_file) = ssl_cert_file {
-            format!(
-                "export NIX_SSL_CERT_FILE={:?}\n",
+        let ssl_cert_file = ssl_cert_file
+            .map(|ssl_cert_file| {
                 ssl_cert_file.canonicalize().map_err(|e| {
-                    Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e))
-                })?
-            )
+                    Self::error(ActionErrorKind::Canonicalize(ssl_cert_file.clone(), e))
+                })
+            })
+            .transpose()?;
+        let maybe_ssl_cert_file_setting = if let Some(ssl_cert_file) = &ssl_cert_file {
+            format!("export NIX_SSL_CERT_FILE={:?}\n", ssl_cert_file)
         } else {
             "".to_string()
         };
+        // Appended to any existing `NIX_CONFIG` so users can still set their own options
+        let experimental_features_setting = format!(
+            "extra-experimental-features = {}",
+            USER_EXPERIMENTAL_FEATURES.join(" ")
+        );
+        let (maybe_experimental_features_setting, maybe_experimental_features_setting_fish) =
+            if experimental_features_in_profile {
+                (
+                    format!(
+                        "NIX_CONFIG=\"${{NIX_CONFIG:+$NIX_CONFIG\n}}{experimental_features_setting}\"\n\
+                        export NIX_CONFIG\n"
+                    ),
+                    format!(
+                        "set -gx NIX_CONFIG (string join \\n $NIX_CONFIG '{experimental_features_setting}')\n"
+                    ),
+                )
+            } else {
+                ("".to_string(), "".to_string())
+            };
         let shell_buf = format!(
             "\n\
             # Nix\n\
             {maybe_ssl_cert_file_setting}\
+            {maybe_experimental_features_setting}\
             if [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n\
             {inde}. '{PROFILE_NIX_FILE_SHELL}'\n\
             fi\n\
@@ -53,7 +101,31 @@ impl ConfigureShellProfile {
             inde = "    ", // indent
         );
 
-        for profile_target in locations.bash.iter().chain(locations.zsh.iter()) {
+        let touches = |shell| shells.is_empty() || shells.contains(&shell);
+        let posix_targets = [
+            (
+                Shell::Bash,
+                &locations.bash,
+                create_or_insert_into_file::Position::Beginning,
+            ),
+            (
+                Shell::Zsh,
+                &locations.zsh,
+                create_or_insert_into_file::Position::Beginning,
+            ),
+            // After macOS' `path_helper` has set the `PATH`, or it would move Nix behind it
+            (
+                Shell::Zsh,
+                &locations.zsh_login,
+                create_or_insert_into_file::Position::End,
+            ),
+        ]
+        .into_iter()
+        .filter(|(shell, _, _)| touches(*shell))
+        .flat_map(|(_, targets, position)| {
+            targets.iter().map(move |target| (target, position.clone()))
+        });
+        for (profile_target, position) in posix_targets {
             let profile_target_path = Path::new(profile_target);
             if let Some(parent) = profile_target_path.parent() {
                 if !parent.exists() {
@@ -70,7 +142,8 @@ impl ConfigureShellProfile {
                         None,
                         0o644,
                         shell_buf.to_string(),
-                        create_or_insert_into_file::Position::Beginning,
+                        position,
+                        true,
                     )
                     .await?,
                 );
@@ -81,6 +154,7 @@ impl ConfigureShellProfile {
             "\n\
             # Nix\n\
             {maybe_ssl_cert_file_setting}\
+            {maybe_experimental_features_setting_fish}\
             if test -e '{PROFILE_NIX_FILE_FISH}'\n\
             {inde}. '{PROFILE_NIX_FILE_FISH}'\n\
             end\n\
@@ -89,7 +163,17 @@ impl ConfigureShellProfile {
             inde = "    ", // indent
         );
 
-        for fish_prefix in &locations.fish.confd_prefixes {
+        let fish_confd_prefixes = if touches(Shell::Fish) {
+            locations.fish.confd_prefixes.as_slice()
+        } else {
+            &[]
+        };
+        let fish_vendor_confd_prefixes = if touches(Shell::Fish) {
+            locations.fish.vendor_confd_prefixes.as_slice()
+        } else {
+            &[]
+        };
+        for fish_prefix in fish_confd_prefixes {
             let fish_prefix_path = PathBuf::from(fish_prefix);
 
             if !fish_prefix_path.exists() {
@@ -114,11 +198,12 @@ impl ConfigureShellProfile {
                     0o644,
                     fish_buf.to_string(),
                     create_or_insert_into_file::Position::Beginning,
+                    true,
                 )
                 .await?,
             );
         }
-        for fish_prefix in &locations.fish.vendor_confd_prefixes {
+        for fish_prefix in fish_vendor_confd_prefixes {
             let fish_prefix_path = PathBuf::from(fish_prefix);
 
             if !fish_prefix_path.exists() {
@@ -143,6 +228,84 @@ impl ConfigureShellProfile {
                     0o644,
                     fish_buf.to_string(),
                     create_or_insert_into_file::Position::Beginning,
+                    true,
+                )
+                .await?,
+            );
+        }
+
+        let user_profile = if use_xdg_base_directories {
+            ".local/state/nix/profile"
+        } else {
+            ".nix-profile"
+        };
+        let maybe_ssl_cert_file_setting_nushell = match &ssl_cert_file {
+            Some(ssl_cert_file) => format!(
+                "{inde}$env.NIX_SSL_CERT_FILE = '{}'\n",
+                ssl_cert_file.display(),
+                inde = "    ", // indent
+            ),
+            None => "".to_string(),
+        };
+        let maybe_experimental_features_setting_nushell = if experimental_features_in_profile {
+            format!(
+                "{inde}$env.NIX_CONFIG = ([($env.NIX_CONFIG? | default '') '{experimental_features_setting}'] | str join (char newline) | str trim)\n",
+                inde = "    ", // indent
+            )
+        } else {
+            "".to_string()
+        };
+        let nushell_buf = format!(
+            "\n\
+            # Nix\n\
+            if ('{PROFILE_NIX_DEFAULT}' | path exists) {{\n\
+            {inde}$env.NIX_PROFILES = $\"{PROFILE_NIX_DEFAULT} ($env.HOME)/{user_profile}\"\n\
+            {inde}$env.PATH = ($env.PATH | split row (char esep) | prepend [$\"($env.HOME)/{user_profile}/bin\" '{PROFILE_NIX_DEFAULT}/bin'] | uniq)\n\
+            {maybe_ssl_cert_file_setting_nushell}\
+            {maybe_experimental_features_setting_nushell}\
+            }}\n\
+            # End Nix\n\
+        \n",
+            inde = "    ", // indent
+        );
+
+        // Nushell packages rarely create their vendor autoload directories, so fall back to the
+        // first location when `nu` is installed
+        let nushell_targets = if touches(Shell::Nushell) {
+            locations.nushell.as_slice()
+        } else {
+            &[]
+        };
+        let nushell_target = nushell_targets
+            .iter()
+            .find(|target| target.parent().is_some_and(Path::exists))
+            .or_else(|| {
+                nushell_targets
+                    .first()
+                    .filter(|_| is_host_root && which::which("nu").is_ok())
+            });
+        if let Some(profile_target) = nushell_target {
+            // `.../nushell/vendor/autoload/nix.nu`, create each directory which is missing
+            for directory in profile_target
+                .ancestors()
+                .skip(1)
+                .take(3)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                create_directories
+                    .push(CreateDirectory::plan(directory, None, None, 0o755, false).await?);
+            }
+            create_or_insert_files.push(
+                CreateOrInsertIntoFile::plan(
+                    profile_target,
+                    None,
+                    None,
+                    0o644,
+                    nushell_buf.to_string(),
+                    create_or_insert_into_file::Position::Beginning,
+                    true,
                 )
                 .await?,
             );
@@ -150,14 +313,16 @@ impl ConfigureShellProfile {
 
         // If the `$GITHUB_PATH` environment exists, we're almost certainly running on Github
         // Actions, and almost certainly wants the relevant `$PATH` additions added.
-        if let Ok(github_path) = std::env::var("GITHUB_PATH") {
+        // That only applies to the running system, not an alternate target root.
+        if let (true, Ok(github_path)) = (is_host_root, std::env::var("GITHUB_PATH")) {
             let mut buf = "/nix/var/nix/profiles/default/bin\n".to_string();
             // Actions runners operate as `runner` user by default
             if let Ok(Some(runner)) = User::from_name("runner") {
+                // With `use-xdg-base-directories` Nix links the user profile from `$XDG_STATE_HOME`
                 #[cfg(target_os = "linux")]
-                let path = format!("/home/{}/.nix-profile/bin\n", runner.name);
+                let path = format!("/home/{}/{user_profile}/bin\n", runner.name);
                 #[cfg(target_os = "macos")]
-                let path = format!("/Users/{}/.nix-profile/bin\n", runner.name);
+                let path = format!("/Users/{}/{user_profile}/bin\n", runner.name);
                 buf += &path;
             }
             create_or_insert_files.push(
@@ -168,6 +333,7 @@ impl ConfigureShellProfile {
                     None,
                     buf,
                     create_or_insert_into_file::Position::End,
+                    false,
                 )
                 .await?,
             )
@@ -175,11 +341,138 @@ impl ConfigureShellProfile {
 
         Ok(Self {
             locations,
+            shells,
             create_directories,
             create_or_insert_into_files: create_or_insert_files,
+            target_root,
         }
         .into())
     }
+
+    /// Spawn a login, interactive shell for each configured shell and warn if `nix` is not on its `PATH`
+    ///
+    /// This never fails, some distributions do not source the files we edit for every kind of shell,
+    /// so this only turns a future "`nix: command not found`" into an actionable warning.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) async fn verify_sourced(&self) {
+        // The shells of an alternate target root can't be spawned from the host
+        if self.target_root != Path::new(HOST_ROOT) {
+            return;
+        }
+
+        let touches = |shell| self.shells.is_empty() || self.shells.contains(&shell);
+        let fish_targets = self
+            .locations
+            .fish
+            .confd_prefixes
+            .iter()
+            .map(|prefix| prefix.join(&self.locations.fish.confd_suffix))
+            .chain(
+                self.locations
+                    .fish
+                    .vendor_confd_prefixes
+                    .iter()
+                    .map(|prefix| prefix.join(&self.locations.fish.vendor_confd_suffix)),
+            )
+            .collect::<Vec<_>>();
+
+        let zsh_targets = self
+            .locations
+            .zsh
+            .iter()
+            .chain(&self.locations.zsh_login)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for (shell, targets) in [
+            ("bash", &self.locations.bash),
+            ("zsh", &zsh_targets),
+            ("fish", &fish_targets),
+        ] {
+            let touched = match shell {
+                "bash" => touches(Shell::Bash),
+                "zsh" => touches(Shell::Zsh),
+                _ => touches(Shell::Fish),
+            };
+            if !touched {
+                continue;
+            }
+            let targets = targets
+                .iter()
+                .filter(|target| target.exists())
+                .map(|target| format!("`{}`", target.display()))
+                .collect::<Vec<_>>();
+            if targets.is_empty() {
+                continue;
+            }
+            let shell_path = match which::which(shell) {
+                Ok(shell_path) => shell_path,
+                Err(_) => continue,
+            };
+
+            let found = match nix_on_login_path(&shell_path, VERIFY_SHELL_TIMEOUT).await {
+                Ok(Some(found)) => found,
+                Ok(None) => {
+                    tracing::debug!("Timed out waiting on `{shell}` to verify its profile");
+                    continue;
+                },
+                Err(e) => {
+                    tracing::debug!("Could not spawn `{shell}` to verify its profile: {e}");
+                    continue;
+                },
+            };
+
+            if found {
+                tracing::debug!("Verified `nix` is on the `PATH` of new `{shell}` login shells");
+            } else {
+                tracing::warn!(
+                    "`nix` was not on the `PATH` of a new `{shell}` login shell, {targets} {was_were} updated but {is_are} likely not sourced by `{shell}` on this system, you may need to source `{profile}` from your `{shell}` configuration",
+                    targets = targets.join(", "),
+                    was_were = if targets.len() == 1 { "was" } else { "were" },
+                    is_are = if targets.len() == 1 { "is" } else { "are" },
+                    profile = if shell == "fish" {
+                        PROFILE_NIX_FILE_FISH
+                    } else {
+                        PROFILE_NIX_FILE_SHELL
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Run `script` in a login shell started with a clean environment, the way a new terminal would
+pub(crate) fn login_shell(shell: &Path, script: &str) -> Command {
+    let mut command = Command::new(shell);
+    command.env_clear();
+    for var in ["HOME", "USER", "LOGNAME", "TERM"] {
+        if let Some(value) = std::env::var_os(var) {
+            command.env(var, value);
+        }
+    }
+    // bash and zsh only read the files the profiles are added to when interactive
+    if !shell.ends_with("fish") {
+        command.arg("-i");
+    }
+    command
+        .args(["-l", "-c", script])
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    command
+}
+
+/// If `nix` resolves in a new login shell, `None` if the shell did not exit within `timeout`
+pub(crate) async fn nix_on_login_path(
+    shell: &Path,
+    timeout: Duration,
+) -> Result<Option<bool>, ActionErrorKind> {
+    let mut command = login_shell(shell, "command -v nix");
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+    match tokio::time::timeout(timeout, command.status()).await {
+        Ok(Ok(status)) => Ok(Some(status.success())),
+        Ok(Err(e)) => Err(ActionErrorKind::command(&command, e)),
+        Err(_) => Ok(None),
+    }
 }
 
 #[async_trait::async_trait]
@@ -250,6 +543,45 @@ impl Action for ConfigureShellProfile {
         Ok(())
     }
 
+    fn check_drift(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .flat_map(|create_or_insert_into_file| create_or_insert_into_file.action.check_drift())
+            .collect()
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        for create_directory in &self.create_directories {
+            commands.extend(create_directory.to_shell()?);
+        }
+        for create_or_insert_into_file in &self.create_or_insert_into_files {
+            commands.extend(create_or_insert_into_file.to_shell()?);
+        }
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .flat_map(|create_or_insert_into_file| {
+                create_or_insert_into_file.action.touched_paths()
+            })
+            .collect()
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        let create_directories = self
+            .create_directories
+            .iter()
+            .map(|create_directory| create_directory.estimated_duration());
+        let create_or_insert_into_files = self
+            .create_or_insert_into_files
+            .iter()
+            .map(|create_or_insert_into_file| create_or_insert_into_file.estimated_duration());
+        create_directories.chain(create_or_insert_into_files).sum()
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Unconfigure the shell profiles".to_string(),
@@ -261,15 +593,16 @@ impl Action for ConfigureShellProfile {
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut set = JoinSet::new();
         let mut errors = vec![];
+        let context = ActionContext::current();
 
         for (idx, create_or_insert_into_file) in
             self.create_or_insert_into_files.iter_mut().enumerate()
         {
             let mut create_or_insert_file_clone = create_or_insert_into_file.clone();
-            let _abort_handle = set.spawn(async move {
+            let _abort_handle = set.spawn(context.clone().scope(async move {
                 create_or_insert_file_clone.try_revert().await?;
                 Result::<_, _>::Ok((idx, create_or_insert_file_clone))
-            });
+            }));
         }
 
         while let Some(result) = set.join_next().await {
@@ -283,7 +616,8 @@ impl Action for ConfigureShellProfile {
             };
         }
 
-        for create_directory in self.create_directories.iter_mut() {
+        // Deepest first, so nested directories like nushell's `vendor/autoload` are empty when reached
+        for create_directory in self.create_directories.iter_mut().rev() {
             if let Err(err) = create_directory.try_revert().await {
                 errors.push(err);
             }
@@ -301,3 +635,187 @@ impl Action for ConfigureShellProfile {
         }
     }
 }
+
+pub(crate) fn locations_in_target_root(
+    locations: ShellProfileLocations,
+    target_root: &Path,
+) -> ShellProfileLocations {
+    let ShellProfileLocations {
+        mut fish,
+        bash,
+        zsh,
+        zsh_login,
+        nushell,
+    } = locations;
+    let in_root = |paths: Vec<PathBuf>| {
+        paths
+            .into_iter()
+            .map(|path| in_target_root(target_root, path))
+            .collect::<Vec<_>>()
+    };
+    fish.confd_prefixes = in_root(fish.confd_prefixes);
+    fish.vendor_confd_prefixes = in_root(fish.vendor_confd_prefixes);
+    ShellProfileLocations {
+        fish,
+        bash: in_root(bash),
+        zsh: in_root(zsh),
+        zsh_login: in_root(zsh_login),
+        nushell: in_root(nushell),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn nix_on_login_path_reports_shell_outcome() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        let fake_shell = |name: &str, body: &str| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{body}\n"))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+            std::io::Result::Ok(path)
+        };
+
+        let found = fake_shell("found", "exit 0")?;
+        let missing = fake_shell("missing", "exit 1")?;
+        let hung = fake_shell("hung", "exec sleep 30")?;
+        let timeout = Duration::from_millis(500);
+
+        assert_eq!(nix_on_login_path(&found, timeout).await?, Some(true));
+        assert_eq!(nix_on_login_path(&missing, timeout).await?, Some(false));
+        assert_eq!(nix_on_login_path(&hung, timeout).await?, None);
+        assert!(nix_on_login_path(&temp_dir.path().join("absent"), timeout)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_configures_requested_shells() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bashrc = temp_dir.path().join("bashrc");
+        let autoload = temp_dir.path().join("nushell/vendor/autoload");
+        tokio::fs::write(&bashrc, "# bashrc\n").await?;
+        tokio::fs::create_dir_all(&autoload).await?;
+        let mut locations = ShellProfileLocations::default();
+        locations.bash = vec![bashrc.clone()];
+        locations.zsh = vec![];
+        locations.zsh_login = vec![];
+        locations.fish.confd_prefixes = vec![];
+        locations.fish.vendor_confd_prefixes = vec![];
+        locations.nushell = vec![autoload.join("nix.nu")];
+
+        let action = ConfigureShellProfile::plan(
+            locations,
+            vec![Shell::Nushell],
+            None,
+            false,
+            false,
+            HOST_ROOT.into(),
+        )
+        .await?;
+        let touched = action.action.touched_paths();
+        assert!(touched.contains(&autoload.join("nix.nu")));
+        assert!(!touched.contains(&bashrc));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn appends_to_zsh_login_profiles() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let zshrc = temp_dir.path().join("zshrc");
+        let zprofile = temp_dir.path().join("zprofile");
+        let zprofile_contents = "eval `/usr/libexec/path_helper -s`\n";
+        tokio::fs::write(&zshrc, "# zshrc\n").await?;
+        tokio::fs::write(&zprofile, zprofile_contents).await?;
+        let mut locations = ShellProfileLocations::default();
+        locations.bash = vec![];
+        locations.zsh = vec![zshrc.clone()];
+        locations.zsh_login = vec![zprofile.clone()];
+        locations.fish.confd_prefixes = vec![];
+        locations.fish.vendor_confd_prefixes = vec![];
+        locations.nushell = vec![];
+
+        let mut action = ConfigureShellProfile::plan(
+            locations,
+            vec![Shell::Zsh],
+            None,
+            false,
+            false,
+            HOST_ROOT.into(),
+        )
+        .await?;
+        action.try_execute().await?;
+
+        let executed_zshrc = tokio::fs::read_to_string(&zshrc).await?;
+        assert!(executed_zshrc.ends_with("# zshrc\n"));
+        assert!(executed_zshrc.contains(PROFILE_NIX_FILE_SHELL));
+        let executed_zprofile = tokio::fs::read_to_string(&zprofile).await?;
+        assert!(executed_zprofile.starts_with(zprofile_contents));
+        assert!(executed_zprofile.contains(PROFILE_NIX_FILE_SHELL));
+
+        action.try_revert().await?;
+        assert_eq!(tokio::fs::read_to_string(&zshrc).await?, "# zshrc\n");
+        assert_eq!(
+            tokio::fs::read_to_string(&zprofile).await?,
+            zprofile_contents
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn experimental_features_can_be_set_in_profile() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bashrc = temp_dir.path().join("bashrc");
+        let fish_prefix = temp_dir.path().join("fish");
+        tokio::fs::write(&bashrc, "# bashrc\n").await?;
+        tokio::fs::create_dir_all(&fish_prefix).await?;
+        let mut locations = ShellProfileLocations::default();
+        locations.bash = vec![bashrc.clone()];
+        locations.zsh = vec![];
+        locations.zsh_login = vec![];
+        locations.fish.confd_prefixes = vec![fish_prefix.clone()];
+        locations.fish.vendor_confd_prefixes = vec![];
+        locations.nushell = vec![];
+        let fish_conf = fish_prefix.join(&locations.fish.confd_suffix);
+
+        for experimental_features_in_profile in [true, false] {
+            let mut action = ConfigureShellProfile::plan(
+                locations.clone(),
+                vec![],
+                None,
+                experimental_features_in_profile,
+                false,
+                HOST_ROOT.into(),
+            )
+            .await?;
+            action.try_execute().await?;
+
+            let executed_bashrc = tokio::fs::read_to_string(&bashrc).await?;
+            let executed_fish_conf = tokio::fs::read_to_string(&fish_conf).await?;
+            for executed in [&executed_bashrc, &executed_fish_conf] {
+                assert_eq!(
+                    executed.contains("extra-experimental-features = nix-command flakes"),
+                    experimental_features_in_profile,
+                    "{executed}"
+                );
+            }
+            if experimental_features_in_profile {
+                assert!(executed_bashrc.contains("${NIX_CONFIG:+$NIX_CONFIG\n}"));
+                assert!(executed_bashrc.contains("export NIX_CONFIG"));
+                assert!(executed_fish_conf.contains("set -gx NIX_CONFIG"));
+            }
+
+            action.try_revert().await?;
+            assert_eq!(tokio::fs::read_to_string(&bashrc).await?, "# bashrc\n");
+        }
+
+        Ok(())
+    }
+}
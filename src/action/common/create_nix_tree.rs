@@ -1,9 +1,15 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use tracing::{span, Span};
 
 use crate::action::base::CreateDirectory;
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
+use crate::settings::{default_store_prefix, in_store_prefix, in_target_root, NixTreeMode};
 
 const PATHS: &[&str] = &[
     "/nix/var",
@@ -21,28 +27,92 @@ const PATHS: &[&str] = &[
     "/nix/var/nix/daemon-socket",
 ];
 
+/// The mode of each directory in [`PATHS`] unless it is overridden
+const DEFAULT_MODE: u32 = 0o0755;
+
+/// Permissions directories must give users other than `root` for them to use the Nix daemon, and what they are needed for
+const DAEMON_CRITICAL_PATHS: &[(&str, u32, &str)] = &[
+    ("/nix/var", 0o001, "reach the Nix daemon socket"),
+    ("/nix/var/nix", 0o001, "reach the Nix daemon socket"),
+    (
+        "/nix/var/nix/daemon-socket",
+        0o001,
+        "reach the Nix daemon socket",
+    ),
+    ("/nix/var/nix/profiles", 0o005, "use the default profile"),
+    (
+        "/nix/var/nix/profiles/per-user",
+        0o005,
+        "use their profiles",
+    ),
+];
+
 /**
-Create the `/nix` tree
+Create the `/nix` tree, in `store_prefix` if the store is kept elsewhere
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateNixTree {
+    #[serde(default = "default_store_prefix")]
+    store_prefix: PathBuf,
     create_directories: Vec<StatefulAction<CreateDirectory>>,
 }
 
 impl CreateNixTree {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(
+        target_root: &Path,
+        store_prefix: &Path,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_with_modes(target_root, store_prefix, &[]).await
+    }
+
+    /// Create the directories in `modes` with their given mode rather than `0755`
+    ///
+    /// Modes which would keep users other than `root` from using the Nix daemon are warned about,
+    /// but still used.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_with_modes(
+        target_root: &Path,
+        store_prefix: &Path,
+        modes: &[NixTreeMode],
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        for NixTreeMode { path, mode } in modes {
+            if !PATHS.iter().any(|known| Path::new(known) == path) {
+                return Err(Self::error(CreateNixTreeError::UnknownDirectory(
+                    path.clone(),
+                )));
+            }
+            warn_if_mode_breaks_daemon(path, *mode);
+        }
+
         let mut create_directories = Vec::default();
         for path in PATHS {
+            // The last mode given for a path wins, as with other repeated settings
+            let mode = modes
+                .iter()
+                .rev()
+                .find(|mode| mode.path == Path::new(path))
+                .map(|mode| mode.mode)
+                .unwrap_or(DEFAULT_MODE);
             // We use `create_dir` over `create_dir_all` to ensure we always set permissions right
             create_directories.push(
-                CreateDirectory::plan(path, String::from("root"), None, 0o0755, false)
-                    .await
-                    .map_err(Self::error)?,
+                CreateDirectory::plan(
+                    in_target_root(target_root, in_store_prefix(store_prefix, path)),
+                    String::from("root"),
+                    None,
+                    mode,
+                    false,
+                )
+                .await
+                .map_err(Self::error)?,
             )
         }
 
-        Ok(Self { create_directories }.into())
+        Ok(Self {
+            store_prefix: store_prefix.to_path_buf(),
+            create_directories,
+        }
+        .into())
     }
 }
 
@@ -53,7 +123,10 @@ impl Action for CreateNixTree {
         ActionTag("create_nix_tree")
     }
     fn tracing_synopsis(&self) -> String {
-        "Create a directory tree in `/nix`".to_string()
+        format!(
+            "Create a directory tree in `{}`",
+            self.store_prefix.display()
+        )
     }
 
     fn tracing_span(&self) -> Span {
@@ -61,7 +134,9 @@ impl Action for CreateNixTree {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { create_directories } = &self;
+        let Self {
+            create_directories, ..
+        } = &self;
 
         let mut create_directory_descriptions = Vec::new();
         for create_directory in create_directories {
@@ -85,19 +160,38 @@ impl Action for CreateNixTree {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        for create_directory in &self.create_directories {
+            commands.extend(create_directory.to_shell()?);
+        }
+        Some(commands)
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        self.create_directories
+            .iter()
+            .map(|create_directory| create_directory.estimated_duration())
+            .sum()
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
-            format!("Remove the directory tree in `/nix`"),
+            format!(
+                "Remove the directory tree in `{}`",
+                self.store_prefix.display()
+            ),
             vec![
                 format!(
-                    "Nix and the Nix daemon require a Nix Store, which will be stored at `/nix`"
+                    "Nix and the Nix daemon require a Nix Store, which will be stored at `{}`",
+                    self.store_prefix.display()
                 ),
                 format!(
                     "Removes: {}",
                     PATHS
                         .iter()
                         .rev()
-                        .map(|v| format!("`{v}`"))
+                        .map(|v| format!("`{}`", in_store_prefix(&self.store_prefix, v).display()))
                         .collect::<Vec<_>>()
                         .join(", ")
                 ),
@@ -127,3 +221,85 @@ impl Action for CreateNixTree {
         }
     }
 }
+
+/// Warn if `mode` on `path` keeps users other than `root` from using the Nix daemon, or lets them tamper with it
+fn warn_if_mode_breaks_daemon(path: &Path, mode: u32) {
+    if let Some((_, required, needed_for)) = DAEMON_CRITICAL_PATHS
+        .iter()
+        .find(|(critical, _, _)| Path::new(critical) == path)
+    {
+        if mode & required != *required {
+            tracing::warn!(
+                "Mode `{mode:04o}` on `{}` keeps users other than `root` from being able to {needed_for}",
+                path.display()
+            );
+        }
+    }
+    if mode & 0o002 != 0 && mode & 0o1000 == 0 {
+        tracing::warn!(
+            "Mode `{mode:04o}` makes `{}` writable by everyone without the sticky bit, so any user could remove what the Nix daemon keeps there",
+            path.display()
+        );
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateNixTreeError {
+    #[error("Cannot set the mode of `{}`, it is not one of the directories created in the `/nix` tree: {}", .0.display(), PATHS.iter().map(|v| format!("`{v}`")).collect::<Vec<_>>().join(", "))]
+    UnknownDirectory(PathBuf),
+}
+
+impl From<CreateNixTreeError> for ActionErrorKind {
+    fn from(v: CreateNixTreeError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn modes_are_overridden_per_directory() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let modes = vec![
+            "/nix/var/nix/profiles/per-user=0750".parse::<NixTreeMode>()?,
+            "/nix/var/log/nix/drvs=0700".parse()?,
+        ];
+        let action =
+            CreateNixTree::plan_with_modes(temp_dir.path(), Path::new("/nix"), &modes).await?;
+        let shell = action.to_shell().unwrap_or_default().join("\n");
+        assert!(shell.contains("chmod 750"), "{shell}");
+        assert!(shell.contains("chmod 700"), "{shell}");
+
+        let unknown = vec!["/nix/store=0755".parse::<NixTreeMode>()?];
+        assert!(
+            CreateNixTree::plan_with_modes(temp_dir.path(), Path::new("/nix"), &unknown)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn directories_are_planned_in_target_root() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let action = CreateNixTree::plan(temp_dir.path(), Path::new("/opt/nix")).await?;
+        let shell = action.to_shell().unwrap_or_default();
+        let created = shell
+            .iter()
+            .filter_map(|command| command.strip_prefix("mkdir "))
+            .map(|path| PathBuf::from(path.trim_matches('\'')))
+            .collect::<Vec<_>>();
+        assert_eq!(created.len(), PATHS.len());
+        for path in PATHS {
+            let expected = temp_dir
+                .path()
+                .join("opt/nix")
+                .join(Path::new(path).strip_prefix("/nix")?);
+            assert!(created.contains(&expected), "{created:?}");
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use crate::action::{
     base::DeleteUser, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
     StatefulAction,
 };
+use std::time::Duration;
 use tracing::{span, Span};
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
@@ -79,6 +80,13 @@ impl Action for DeleteUsersInGroup {
         Ok(())
     }
 
+    fn estimated_duration(&self) -> Duration {
+        self.delete_users
+            .iter()
+            .map(|delete_user| delete_user.estimated_duration())
+            .sum()
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let mut delete_users_descriptions = Vec::new();
         for delete_user in self.delete_users.iter() {
@@ -1,14 +1,32 @@
+use base64::Engine;
+use nix::unistd::{Group, User};
+use semver::Version;
 use tracing::{span, Span};
+use url::Url;
 
 use crate::action::base::create_or_merge_nix_config::CreateOrMergeNixConfigError;
 use crate::action::base::{CreateDirectory, CreateOrMergeNixConfig};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
-use std::collections::hash_map::Entry;
+use crate::execute_command;
+use crate::settings::{in_target_root, CommonSettings, HOST_ROOT};
+use std::collections::{hash_map::Entry, HashMap};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
 
 const NIX_CONF_FOLDER: &str = "/etc/nix";
-const NIX_CONF: &str = "/etc/nix/nix.conf";
+pub(crate) const NIX_CONF: &str = "/etc/nix/nix.conf";
+/// Experimental features only used by the `nix` command itself, rather than the daemon
+pub(crate) const USER_EXPERIMENTAL_FEATURES: &[&str] = &["nix-command", "flakes"];
+/// The first Nix release which understands `use-xdg-base-directories`
+const XDG_BASE_DIRECTORIES_MIN_VERSION: Version = Version::new(2, 14, 0);
+/// The default `start-id` of Nix, the first UID `auto-allocate-uids` hands to builds
+const DEFAULT_BUILD_USER_ID_BASE: u32 = 872_415_232;
+/// The default `id-count` of Nix
+const DEFAULT_BUILD_USER_COUNT: u32 = 128 * 65_536;
 
 /**
 Place the `/etc/nix.conf` file
@@ -21,50 +39,212 @@ pub struct PlaceNixConfiguration {
 
 impl PlaceNixConfiguration {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(
-        nix_build_group_name: String,
-        extra_conf: Vec<String>,
-        force: bool,
+    pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_inner(settings, false).await
+    }
+
+    /// Like [`plan`](Self::plan), but updating the settings of an existing `nix.conf` in place, for [`Planner::reconfigure`](crate::planner::Planner::reconfigure)
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) async fn plan_replacing(
+        settings: &CommonSettings,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_inner(settings, true).await
+    }
+
+    async fn plan_inner(
+        settings: &CommonSettings,
+        replace_existing: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let extra_conf = extra_conf.join("\n");
+        let extra_conf = merge_extra_conf(&settings.extra_conf).map_err(Self::error)?;
         let mut nix_config = nix_config_parser::NixConfig::parse_string(extra_conf, None)
             .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
             .map_err(Self::error)?;
-        let settings = nix_config.settings_mut();
+        let nix_settings = nix_config.settings_mut();
 
-        settings.insert("build-users-group".to_string(), nix_build_group_name);
-        let experimental_features = ["nix-command", "flakes", "auto-allocate-uids"];
-        match settings.entry("experimental-features".to_string()) {
+        nix_settings.insert(
+            "build-users-group".to_string(),
+            settings.nix_build_group_name.clone(),
+        );
+        let mut experimental_features = vec!["auto-allocate-uids"];
+        if settings.enable_flakes && !settings.experimental_features_in_profile {
+            experimental_features.extend(USER_EXPERIMENTAL_FEATURES);
+        }
+        // Features `extra-conf` already enables through `extra-experimental-features` are not repeated
+        let extra_experimental_features = nix_settings
+            .get("extra-experimental-features")
+            .cloned()
+            .unwrap_or_default();
+        experimental_features.retain(|experimental_feature| {
+            !extra_experimental_features
+                .split_whitespace()
+                .any(|v| v == *experimental_feature)
+        });
+        match nix_settings.entry("experimental-features".to_string()) {
             Entry::Occupied(mut slot) => {
                 let slot_mut = slot.get_mut();
-                for experimental_feature in experimental_features {
-                    if !slot_mut.contains(experimental_feature) {
+                for experimental_feature in &experimental_features {
+                    if !slot_mut
+                        .split_whitespace()
+                        .any(|v| v == *experimental_feature)
+                    {
                         *slot_mut += " ";
                         *slot_mut += experimental_feature;
                     }
                 }
             },
             Entry::Vacant(slot) => {
-                let _ = slot.insert(experimental_features.join(" ").to_string());
+                if !experimental_features.is_empty() {
+                    let _ = slot.insert(experimental_features.join(" ").to_string());
+                }
             },
         };
-        settings.insert("auto-optimise-store".to_string(), "true".to_string());
-        settings.insert(
+        nix_settings.insert("auto-optimise-store".to_string(), "true".to_string());
+        nix_settings.insert(
             "bash-prompt-prefix".to_string(),
             "(nix:$name)\\040".to_string(),
         );
-        settings.insert(
+        nix_settings.insert(
             "extra-nix-path".to_string(),
             "nixpkgs=flake:nixpkgs".to_string(),
         );
-        settings.insert("auto-allocate-uids".to_string(), "true".to_string());
+        nix_settings.insert("auto-allocate-uids".to_string(), "true".to_string());
+        if let Some(download_attempts) = settings.download_attempts {
+            nix_settings.insert(
+                "download-attempts".to_string(),
+                download_attempts.to_string(),
+            );
+        }
+        if let Some(http_connections) = settings.http_connections {
+            nix_settings.insert("http-connections".to_string(), http_connections.to_string());
+        }
+        if let Some(max_jobs) = settings.max_jobs {
+            nix_settings.insert("max-jobs".to_string(), max_jobs.to_string());
+        }
+        if let Some(cores) = settings.cores {
+            nix_settings.insert("cores".to_string(), cores.to_string());
+        }
+        if let Some(sandbox) = settings.sandbox {
+            nix_settings.insert("sandbox".to_string(), sandbox.to_string());
+        }
+        if settings.nix_build_user_count.is_some() || settings.nix_build_user_id_base.is_some() {
+            let count = settings
+                .nix_build_user_count
+                .map(NonZeroU32::get)
+                .unwrap_or(DEFAULT_BUILD_USER_COUNT);
+            let start = settings
+                .nix_build_user_id_base
+                .unwrap_or(DEFAULT_BUILD_USER_ID_BASE);
+            let end = start.checked_add(count).ok_or_else(|| {
+                Self::error(PlaceNixConfigurationError::BuildUserIdRangeTooLarge(
+                    start, count,
+                ))
+            })?;
+            let conflicts = existing_users(&settings.target_root)
+                .await
+                .map_err(Self::error)?
+                .into_iter()
+                .filter(|(_, uid)| (start..end).contains(uid))
+                .collect::<Vec<_>>();
+            if !conflicts.is_empty() {
+                return Err(Self::error(PlaceNixConfigurationError::BuildUserIdsInUse {
+                    start,
+                    end,
+                    conflicts,
+                }));
+            }
+            nix_settings.insert("start-id".to_string(), start.to_string());
+            nix_settings.insert("id-count".to_string(), count.to_string());
+        }
+        if settings.use_xdg_base_directories {
+            let nix_package_url = settings.resolved_nix_package_url().map_err(Self::error)?;
+            match nix_version_from_url(&nix_package_url) {
+                Some(version) if version < XDG_BASE_DIRECTORIES_MIN_VERSION => {
+                    return Err(Self::error(
+                        PlaceNixConfigurationError::XdgBaseDirectoriesUnsupported(version),
+                    ));
+                },
+                Some(_) => (),
+                None => tracing::warn!(
+                    "Could not determine the Nix version of `{}`, `use-xdg-base-directories` requires Nix {XDG_BASE_DIRECTORIES_MIN_VERSION} or later",
+                    nix_package_url
+                ),
+            }
+            nix_settings.insert("use-xdg-base-directories".to_string(), "true".to_string());
+        }
 
-        let create_directory = CreateDirectory::plan(NIX_CONF_FOLDER, None, None, 0o0755, force)
-            .await
-            .map_err(Self::error)?;
-        let create_or_merge_nix_config = CreateOrMergeNixConfig::plan(NIX_CONF, nix_config)
-            .await
-            .map_err(Self::error)?;
+        // A binary cache's key is written together with its substituter, so Nix never uses one it cannot trust
+        let substituters = settings
+            .extra_substituters
+            .iter()
+            .chain(settings.binary_caches.iter().map(|cache| &cache.url))
+            .cloned()
+            .collect::<Vec<_>>();
+        let public_keys = settings
+            .trusted_public_keys
+            .iter()
+            .chain(settings.binary_caches.iter().map(|cache| &cache.public_key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for substituter in &substituters {
+            if Url::parse(substituter).is_err() {
+                return Err(Self::error(PlaceNixConfigurationError::InvalidSubstituter(
+                    substituter.clone(),
+                )));
+            }
+        }
+        append_unique(nix_settings, "extra-substituters", &substituters);
+        for key in &public_keys {
+            if !is_public_key(key) {
+                return Err(Self::error(
+                    PlaceNixConfigurationError::InvalidTrustedPublicKey(key.clone()),
+                ));
+            }
+        }
+        append_unique(nix_settings, "extra-trusted-public-keys", &public_keys);
+
+        let mut trusted_users = settings.trusted_users.clone();
+        // The users and groups of an alternate target root are not visible through the host's NSS
+        if !settings.is_target_root_alternate() {
+            for trusted_user in &trusted_users {
+                warn_if_missing_trusted_user(trusted_user);
+            }
+        }
+        if let Some(admin_group) = &settings.admin_group {
+            let admin_group = admin_group.trim_start_matches('@');
+            if !settings.is_target_root_alternate()
+                && Group::from_name(admin_group)
+                    .map_err(|e| ActionErrorKind::GettingGroupId(admin_group.to_string(), e))
+                    .map_err(Self::error)?
+                    .is_none()
+            {
+                return Err(Self::error(PlaceNixConfigurationError::AdminGroupNotFound(
+                    admin_group.to_string(),
+                )));
+            }
+            if !nix_settings.contains_key("trusted-users") && trusted_users.is_empty() {
+                // Nix otherwise defaults to trusting `root`, which should be preserved
+                trusted_users.push("root".to_string());
+            }
+            trusted_users.push(format!("@{admin_group}"));
+        }
+        append_unique(nix_settings, "trusted-users", &trusted_users);
+
+        let create_directory = CreateDirectory::plan(
+            in_target_root(&settings.target_root, NIX_CONF_FOLDER),
+            None,
+            None,
+            0o0755,
+            settings.force,
+        )
+        .await
+        .map_err(Self::error)?;
+        let nix_conf = in_target_root(&settings.target_root, NIX_CONF);
+        let create_or_merge_nix_config = if replace_existing {
+            CreateOrMergeNixConfig::plan_replacing(nix_conf, nix_config).await
+        } else {
+            CreateOrMergeNixConfig::plan(nix_conf, nix_config).await
+        }
+        .map_err(Self::error)?;
         Ok(Self {
             create_directory,
             create_or_merge_nix_config,
@@ -122,6 +302,21 @@ impl Action for PlaceNixConfiguration {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = self.create_directory.to_shell()?;
+        commands.extend(self.create_or_merge_nix_config.to_shell()?);
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        self.create_or_merge_nix_config.action.touched_paths()
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        self.create_directory.estimated_duration()
+            + self.create_or_merge_nix_config.estimated_duration()
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             format!("Remove the Nix configuration in `{NIX_CONF}`"),
@@ -154,3 +349,550 @@ impl Action for PlaceNixConfiguration {
         }
     }
 }
+
+/// Release tarballs live under a `nix-<version>` folder, eg `https://releases.nixos.org/nix/nix-2.15.0/...`
+fn nix_version_from_url(url: &Url) -> Option<Version> {
+    url.path_segments()?.find_map(|segment| {
+        segment
+            .strip_prefix("nix-")
+            .and_then(|version| Version::parse(version).ok())
+    })
+}
+
+/// The name and UID of each user of `target_root`, including those from directory services like LDAP on the host
+async fn existing_users(target_root: &Path) -> Result<Vec<(String, u32)>, ActionErrorKind> {
+    if target_root != Path::new(HOST_ROOT) {
+        return read_passwd(&in_target_root(target_root, "/etc/passwd")).await;
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let output = execute_command(
+            Command::new("/usr/bin/dscl")
+                .args([".", "-list", "/Users", "UniqueID"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let name = fields.next()?;
+                let uid = fields.next()?.parse().ok()?;
+                Some((name.to_string(), uid))
+            })
+            .collect())
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        // `getent` also lists the users of NSS modules such as LDAP, where supported
+        if which::which("getent").is_ok() {
+            let output = execute_command(
+                Command::new("getent")
+                    .arg("passwd")
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await?;
+            Ok(parse_passwd(&String::from_utf8_lossy(&output.stdout)))
+        } else {
+            read_passwd(Path::new("/etc/passwd")).await
+        }
+    }
+}
+
+/// Check each of `extra_conf` is a `key = value` line, and merge keys given more than once, as Nix refuses duplicate keys
+///
+/// Blank lines and comments are dropped, `include` and `!include` lines are kept as they are.
+/// The values of a repeated list setting, see [`is_list_setting`], are merged, only words not
+/// already in the value are appended. For any other repeated setting the last value wins.
+fn merge_extra_conf(extra_conf: &[String]) -> Result<String, PlaceNixConfigurationError> {
+    let mut merged: Vec<(String, Option<String>)> = Vec::new();
+    for line in extra_conf.iter().flat_map(|v| v.lines()) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.starts_with("include ") || trimmed.starts_with("!include ") {
+            merged.push((trimmed.to_string(), None));
+            continue;
+        }
+        let (key, value) = match trimmed.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !key.trim().contains(' ') => {
+                (key.trim(), value.trim())
+            },
+            _ => {
+                return Err(PlaceNixConfigurationError::InvalidExtraConf(
+                    line.to_string(),
+                ))
+            },
+        };
+        match merged
+            .iter_mut()
+            .find(|(existing, existing_value)| existing == key && existing_value.is_some())
+        {
+            Some((_, Some(existing))) if is_list_setting(key) => {
+                for word in value.split_whitespace() {
+                    if !existing.split_whitespace().any(|v| v == word) {
+                        if !existing.is_empty() {
+                            existing.push(' ');
+                        }
+                        existing.push_str(word);
+                    }
+                }
+            },
+            Some((_, existing)) => {
+                tracing::debug!("`{key}` is given more than once in `extra-conf`, using `{value}`");
+                *existing = Some(value.to_string());
+            },
+            None => merged.push((key.to_string(), Some(value.to_string()))),
+        }
+    }
+    Ok(merged
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => format!("{key} = {value}"),
+            None => key.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// If the Nix setting `key` is a space separated list, which repeated values can be merged into
+fn is_list_setting(key: &str) -> bool {
+    key.starts_with("extra-")
+        || key.starts_with("trusted-")
+        || matches!(
+            key,
+            "experimental-features"
+                | "substituters"
+                | "allowed-users"
+                | "allowed-uris"
+                | "system-features"
+                | "nix-path"
+                | "secret-key-files"
+                | "sandbox-paths"
+                | "plugin-files"
+        )
+}
+
+/// If `key` looks like `<name>:<base64 ed25519 key>`, as made by `nix key generate-secret`
+fn is_public_key(key: &str) -> bool {
+    match key.split_once(':') {
+        Some((name, key)) => {
+            !name.is_empty()
+                && base64::engine::general_purpose::STANDARD
+                    .decode(key)
+                    .is_ok_and(|key| key.len() == 32)
+        },
+        None => false,
+    }
+}
+
+/// Add each of `values` to the space separated list `key`, unless it is already there
+fn append_unique(nix_settings: &mut HashMap<String, String>, key: &str, values: &[String]) {
+    if values.is_empty() {
+        return;
+    }
+    let slot = nix_settings.entry(key.to_string()).or_default();
+    for value in values {
+        if !slot.split_whitespace().any(|existing| existing == value) {
+            if !slot.is_empty() {
+                *slot += " ";
+            }
+            *slot += value;
+        }
+    }
+}
+
+/// Warn, rather than fail, if the user or `@group` in `trusted_user` does not exist, as it may be created after Nix is installed
+fn warn_if_missing_trusted_user(trusted_user: &str) {
+    let exists = match trusted_user.strip_prefix('@') {
+        Some(group) => Group::from_name(group).map(|group| group.is_some()),
+        None => User::from_name(trusted_user).map(|user| user.is_some()),
+    };
+    match exists {
+        Ok(true) => (),
+        Ok(false) => tracing::warn!(
+            "`{trusted_user}` is listed in `trusted-users`, but no such {} exists",
+            if trusted_user.starts_with('@') {
+                "group"
+            } else {
+                "user"
+            }
+        ),
+        Err(err) => tracing::warn!("Could not check that `{trusted_user}` exists: {err}"),
+    }
+}
+
+async fn read_passwd(path: &Path) -> Result<Vec<(String, u32)>, ActionErrorKind> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(buf) => Ok(parse_passwd(&buf)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+fn parse_passwd(buf: &str) -> Vec<(String, u32)> {
+    buf.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), uid))
+        })
+        .collect()
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum PlaceNixConfigurationError {
+    #[error(
+        "The admin group `{0}` does not exist, consider creating it or choosing a different group"
+    )]
+    AdminGroupNotFound(String),
+    #[error(
+        "Nix {0} does not support `use-xdg-base-directories`, it requires Nix {XDG_BASE_DIRECTORIES_MIN_VERSION} or later"
+    )]
+    XdgBaseDirectoriesUnsupported(Version),
+    #[error(
+        "Extra configuration `{0}` is not a `key = value` line, such as `keep-outputs = true`"
+    )]
+    InvalidExtraConf(String),
+    #[error("Substituter `{0}` is not a URL, such as `https://cache.example.com`")]
+    InvalidSubstituter(String),
+    #[error("Trusted public key `{0}` is not of the form `<name>:<base64 key>`, such as `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`")]
+    InvalidTrustedPublicKey(String),
+    #[error("The build user ID range starting at {0} with {1} IDs extends past the largest UID")]
+    BuildUserIdRangeTooLarge(u32, u32),
+    #[error(
+        "The build user ID range {start}..{end} overlaps existing users: {}\n\nChoose a different range with `--nix-build-user-id-base` and `--nix-build-user-count`",
+        conflicts.iter().map(|(name, uid)| format!("`{name}` (UID {uid})")).collect::<Vec<_>>().join(", ")
+    )]
+    BuildUserIdsInUse {
+        start: u32,
+        end: u32,
+        conflicts: Vec<(String, u32)>,
+    },
+}
+
+impl From<PlaceNixConfigurationError> for ActionErrorKind {
+    fn from(v: PlaceNixConfigurationError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::{MaxJobs, SandboxMode};
+    use nix_config_parser::NixConfig;
+
+    /// Point `settings` at a new target root with an `etc`, which lives as long as the returned `TempDir`
+    async fn scratch_target_root(settings: &mut CommonSettings) -> eyre::Result<tempfile::TempDir> {
+        let temp_dir = tempfile::TempDir::new()?;
+        tokio::fs::create_dir_all(temp_dir.path().join("etc")).await?;
+        settings.target_root = temp_dir.path().to_path_buf();
+        Ok(temp_dir)
+    }
+
+    /// Place `nix.conf` for `settings` in a [`scratch_target_root`] and parse it
+    async fn place_and_parse(
+        settings: &mut CommonSettings,
+    ) -> eyre::Result<(
+        tempfile::TempDir,
+        StatefulAction<PlaceNixConfiguration>,
+        NixConfig,
+    )> {
+        let temp_dir = scratch_target_root(settings).await?;
+        let mut action = PlaceNixConfiguration::plan(settings).await?;
+        action.try_execute().await?;
+        let nix_config = NixConfig::parse_file(&temp_dir.path().join("etc/nix/nix.conf"))?;
+        Ok((temp_dir, action, nix_config))
+    }
+
+    #[tokio::test]
+    async fn build_user_ids_in_use_are_listed() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        let temp_dir = scratch_target_root(&mut settings).await?;
+        tokio::fs::write(
+            temp_dir.path().join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\nldapuser:x:30001:100::/home/ldapuser:/bin/sh\n",
+        )
+        .await?;
+
+        settings.nix_build_user_id_base = Some(30_000);
+        settings.nix_build_user_count = NonZeroU32::new(10);
+
+        let err = PlaceNixConfiguration::plan(&settings)
+            .await
+            .expect_err("the range overlaps `ldapuser`");
+        let err = std::error::Error::source(&err)
+            .expect("a source")
+            .to_string();
+        assert!(err.contains("`ldapuser` (UID 30001)"), "{err}");
+
+        settings.nix_build_user_id_base = Some(40_000);
+        PlaceNixConfiguration::plan(&settings).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn substituters_are_appended_once() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.extra_conf = vec!["extra-substituters = https://cache.example.com".into()];
+        settings.extra_substituters = vec![
+            "https://cache.example.com".into(),
+            "https://mirror.example.com".into(),
+        ];
+        settings.trusted_public_keys =
+            vec!["cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".into()];
+
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("extra-substituters")
+                .map(String::as_str),
+            Some("https://cache.example.com https://mirror.example.com")
+        );
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("extra-trusted-public-keys")
+                .map(String::as_str),
+            Some("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=")
+        );
+
+        settings.trusted_public_keys = vec!["cache.example.com".into()];
+        assert!(PlaceNixConfiguration::plan(&settings).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binary_caches_are_written_with_their_keys() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.extra_substituters = vec!["https://mirror.example.com".into()];
+        settings.binary_caches = vec![
+            "https://cache.example.com cache.example.com-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="
+                .parse()?,
+        ];
+
+        let (temp_dir, mut action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("extra-substituters")
+                .map(String::as_str),
+            Some("https://mirror.example.com https://cache.example.com")
+        );
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("extra-trusted-public-keys")
+                .map(String::as_str),
+            Some("cache.example.com-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=")
+        );
+
+        action.try_revert().await?;
+        assert!(!temp_dir.path().join("etc/nix/nix.conf").exists());
+
+        settings.binary_caches = vec!["https://cache.example.com not-a-key".parse()?];
+        assert!(PlaceNixConfiguration::plan(&settings).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_tuning_is_written_and_reverted() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.max_jobs = Some("auto".parse()?);
+        settings.cores = Some(4);
+        settings.sandbox = Some(SandboxMode::Relaxed);
+        let (temp_dir, mut action, nix_config) = place_and_parse(&mut settings).await?;
+        for (key, value) in [("max-jobs", "auto"), ("cores", "4"), ("sandbox", "relaxed")] {
+            assert_eq!(
+                nix_config.settings().get(key).map(String::as_str),
+                Some(value)
+            );
+        }
+
+        action.try_revert().await?;
+        assert!(!temp_dir.path().join("etc/nix/nix.conf").exists());
+
+        assert!("0".parse::<MaxJobs>().is_err());
+        assert!("many".parse::<MaxJobs>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_settings_are_written() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.download_attempts = NonZeroU32::new(7);
+        settings.http_connections = NonZeroU32::new(5);
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        for (key, value) in [("download-attempts", "7"), ("http-connections", "5")] {
+            assert_eq!(
+                nix_config.settings().get(key).map(String::as_str),
+                Some(value)
+            );
+        }
+
+        settings.download_attempts = None;
+        settings.http_connections = None;
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(nix_config.settings().get("download-attempts"), None);
+        assert_eq!(nix_config.settings().get("http-connections"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn extra_conf_is_validated_and_merged() {
+        let merged = merge_extra_conf(&[
+            "keep-outputs = true".into(),
+            "# A comment\n\nextra-substituters = https://a.example.com".into(),
+            "extra-substituters = https://b.example.com https://a.example.com".into(),
+        ])
+        .unwrap();
+        assert_eq!(
+            merged,
+            "keep-outputs = true\nextra-substituters = https://a.example.com https://b.example.com"
+        );
+
+        assert!(matches!(
+            merge_extra_conf(&["keep-outputs".into()]),
+            Err(PlaceNixConfigurationError::InvalidExtraConf(line)) if line == "keep-outputs"
+        ));
+        assert!(merge_extra_conf(&["= true".into()]).is_err());
+    }
+
+    #[test]
+    fn extra_conf_keeps_includes_and_the_last_scalar() {
+        let merged = merge_extra_conf(&[
+            "include /etc/nix/other.conf".into(),
+            "max-jobs = 4\n!include /etc/nix/optional.conf".into(),
+            "max-jobs = 8".into(),
+            "trusted-users = alice\ntrusted-users = bob".into(),
+        ])
+        .unwrap();
+        assert_eq!(
+            merged,
+            "include /etc/nix/other.conf\nmax-jobs = 8\n!include /etc/nix/optional.conf\ntrusted-users = alice bob"
+        );
+    }
+
+    #[tokio::test]
+    async fn flakes_merge_with_extra_conf() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.extra_conf = vec!["experimental-features = flakes ca-derivations".into()];
+        let (temp_dir, mut action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("experimental-features")
+                .map(String::as_str),
+            Some("flakes ca-derivations auto-allocate-uids nix-command")
+        );
+
+        action.try_revert().await?;
+        assert!(!temp_dir.path().join("etc/nix/nix.conf").exists());
+
+        settings.enable_flakes = false;
+        settings.extra_conf = vec![];
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("experimental-features")
+                .map(String::as_str),
+            Some("auto-allocate-uids")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trusted_users_are_rendered() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        assert_eq!(
+            settings.trusted_users.first().map(String::as_str),
+            Some("root")
+        );
+        settings.trusted_users = vec!["root".into(), "@wheel".into(), "alice".into()];
+        settings.extra_conf = vec!["trusted-users = alice bob".into()];
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("trusted-users")
+                .map(String::as_str),
+            Some("alice bob root @wheel")
+        );
+
+        settings.trusted_users = vec![];
+        settings.extra_conf = vec![];
+        let (_temp_dir, _action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(nix_config.settings().get("trusted-users"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_group_is_trusted() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.trusted_users = vec![];
+        settings.admin_group = Some("@wheel".into());
+        let (_temp_dir, mut action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("trusted-users")
+                .map(String::as_str),
+            Some("root @wheel")
+        );
+        action.try_revert().await?;
+
+        let mut settings = CommonSettings::default().await?;
+        settings.admin_group = Some("nix-installer-no-such-group".into());
+        let err = PlaceNixConfiguration::plan(&settings)
+            .await
+            .expect_err("the admin group does not exist");
+        let err = std::error::Error::source(&err)
+            .expect("a source")
+            .to_string();
+        assert!(
+            err.contains("`nix-installer-no-such-group` does not exist"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn xdg_base_directories_require_nix_2_14() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_version = None;
+        settings.use_xdg_base_directories = true;
+        settings.nix_package_url =
+            "https://releases.nixos.org/nix/nix-2.15.0/nix-2.15.0-x86_64-linux.tar.xz".parse()?;
+        let (_temp_dir, mut action, nix_config) = place_and_parse(&mut settings).await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("use-xdg-base-directories")
+                .map(String::as_str),
+            Some("true")
+        );
+        action.try_revert().await?;
+
+        settings.nix_package_url =
+            "https://releases.nixos.org/nix/nix-2.13.3/nix-2.13.3-x86_64-linux.tar.xz".parse()?;
+        let err = PlaceNixConfiguration::plan(&settings)
+            .await
+            .expect_err("Nix 2.13.3 does not support `use-xdg-base-directories`");
+        let err = std::error::Error::source(&err)
+            .expect("a source")
+            .to_string();
+        assert!(err.contains("2.13.3"), "{err}");
+
+        // Unversioned URLs are only warned about
+        settings.nix_package_url = "https://example.com/nix.tar.xz".parse()?;
+        PlaceNixConfiguration::plan(&settings).await?;
+        Ok(())
+    }
+}
@@ -7,9 +7,22 @@ use crate::{
         base::{CreateGroup, FetchAndUnpackNix, MoveUnpackedNix},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
     },
-    settings::{CommonSettings, SCRATCH_DIR},
+    settings::{CommonSettings, NIX_ROOT, SCRATCH_DIR},
 };
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The location of the Nix database schema version, present if a Nix store already exists
+const NIX_DB_SCHEMA: &str = "/nix/var/nix/db/schema";
+/// The Nix database schema version expected by the Nix this installer provisions
+///
+/// This tracks `nixSchemaVersion` in Nix's `src/libstore/local-store.hh`, which has been `10` since
+/// Nix 2.0 and is unchanged in the Nix 2.15.0 release provisioned by default. The schema version is
+/// compiled into Nix rather than shipped in the release tarball, so it cannot be read from the
+/// unpacked Nix during planning; bump this alongside the default Nix release if it ever changes.
+const NIX_DB_SCHEMA_VERSION: u32 = 10;
 
 /**
 Place Nix and it's requirements onto the target
@@ -18,7 +31,8 @@ Place Nix and it's requirements onto the target
 pub struct ProvisionNix {
     fetch_nix: StatefulAction<FetchAndUnpackNix>,
     delete_users_in_group: Option<StatefulAction<DeleteUsersInGroup>>,
-    create_group: StatefulAction<CreateGroup>,
+    /// The build users group, absent in a single-user install
+    create_group: Option<StatefulAction<CreateGroup>>,
     create_nix_tree: StatefulAction<CreateNixTree>,
     move_unpacked_nix: StatefulAction<MoveUnpackedNix>,
 }
@@ -26,20 +40,42 @@ pub struct ProvisionNix {
 impl ProvisionNix {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_inner(settings, true).await
+    }
+
+    /// Like [`plan`](Self::plan), but without the build users group, for a single-user install where the owner of the store builds as themselves
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_single_user(
+        settings: &CommonSettings,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_inner(settings, false).await
+    }
+
+    async fn plan_inner(
+        settings: &CommonSettings,
+        build_users: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        check_existing_db_schema(&settings.in_store_prefix(NIX_DB_SCHEMA))
+            .await
+            .map_err(Self::error)?;
+
+        let scratch_dir = settings.in_store_prefix(SCRATCH_DIR);
+        let nix_package_url = settings.resolved_nix_package_url().map_err(Self::error)?;
         let fetch_nix = FetchAndUnpackNix::plan(
-            settings.nix_package_url.clone(),
-            PathBuf::from(SCRATCH_DIR),
-            settings.proxy.clone(),
-            settings.ssl_cert_file.clone(),
+            nix_package_url.clone(),
+            scratch_dir.clone(),
+            settings
+                .fetch_options(&nix_package_url)
+                .map_err(Self::error)?,
         )
         .await?;
 
-        let delete_users_in_group = if let Some(group) =
-            Group::from_name(settings.nix_build_group_name.as_str())
-                .map_err(|e| {
-                    ActionErrorKind::GettingGroupId(settings.nix_build_group_name.clone(), e)
-                })
-                .map_err(Self::error)?
+        // Users of an alternate target root are not visible through the host's NSS
+        let delete_users_in_group = if !build_users || settings.is_target_root_alternate() {
+            None
+        } else if let Some(group) = Group::from_name(settings.nix_build_group_name.as_str())
+            .map_err(|e| ActionErrorKind::GettingGroupId(settings.nix_build_group_name.clone(), e))
+            .map_err(Self::error)?
         {
             if group.gid.as_raw() != settings.nix_build_group_id {
                 return Err(Self::error(ActionErrorKind::GroupGidMismatch(
@@ -64,15 +100,30 @@ impl ProvisionNix {
             None
         };
 
-        let create_group = CreateGroup::plan(
-            settings.nix_build_group_name.clone(),
-            settings.nix_build_group_id,
+        let create_group = if build_users {
+            Some(
+                CreateGroup::plan(
+                    settings.nix_build_group_name.clone(),
+                    settings.nix_build_group_id,
+                    settings.target_root.clone(),
+                )
+                .map_err(Self::error)?,
+            )
+        } else {
+            None
+        };
+        let create_nix_tree = CreateNixTree::plan_with_modes(
+            &settings.target_root,
+            &settings.store_prefix,
+            &settings.nix_tree_modes,
         )
+        .await
         .map_err(Self::error)?;
-        let create_nix_tree = CreateNixTree::plan().await.map_err(Self::error)?;
-        let move_unpacked_nix = MoveUnpackedNix::plan(PathBuf::from(SCRATCH_DIR))
-            .await
-            .map_err(Self::error)?;
+        let move_unpacked_nix =
+            MoveUnpackedNix::plan(scratch_dir, settings.in_store_prefix(NIX_ROOT))
+                .await
+                .map_err(Self::error)?
+                .with_copy_strategy(settings.copy_strategy);
         Ok(Self {
             fetch_nix,
             delete_users_in_group,
@@ -114,13 +165,38 @@ impl Action for ProvisionNix {
             buf.append(&mut delete_users_in_group.describe_execute());
         }
 
-        buf.append(&mut create_group.describe_execute());
+        if let Some(create_group) = create_group {
+            buf.append(&mut create_group.describe_execute());
+        }
         buf.append(&mut create_nix_tree.describe_execute());
         buf.append(&mut move_unpacked_nix.describe_execute());
 
         buf
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn preflight(&self) -> Result<(), ActionError> {
+        self.fetch_nix.try_preflight().await.map_err(Self::error)?;
+        if let Some(delete_users_in_group) = &self.delete_users_in_group {
+            delete_users_in_group
+                .try_preflight()
+                .await
+                .map_err(Self::error)?;
+        }
+        if let Some(create_group) = &self.create_group {
+            create_group.try_preflight().await.map_err(Self::error)?;
+        }
+        self.create_nix_tree
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.move_unpacked_nix
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        Ok(())
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         // We fetch nix while doing the rest, then move it over.
@@ -137,7 +213,9 @@ impl Action for ProvisionNix {
                 .map_err(Self::error)?;
         }
 
-        self.create_group.try_execute().await.map_err(Self::error)?;
+        if let Some(create_group) = &mut self.create_group {
+            create_group.try_execute().await.map_err(Self::error)?;
+        }
         self.create_nix_tree
             .try_execute()
             .await
@@ -155,6 +233,39 @@ impl Action for ProvisionNix {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![];
+        if let Some(delete_users_in_group) = &self.delete_users_in_group {
+            commands.extend(delete_users_in_group.to_shell()?);
+        }
+        if let Some(create_group) = &self.create_group {
+            commands.extend(create_group.to_shell()?);
+        }
+        commands.extend(self.create_nix_tree.to_shell()?);
+        commands.extend(self.fetch_nix.to_shell()?);
+        commands.extend(self.move_unpacked_nix.to_shell()?);
+        Some(commands)
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        let mut duration = self.fetch_nix.estimated_duration()
+            + self.create_nix_tree.estimated_duration()
+            + self.move_unpacked_nix.estimated_duration();
+        if let Some(delete_users_in_group) = &self.delete_users_in_group {
+            duration += delete_users_in_group.estimated_duration();
+        }
+        if let Some(create_group) = &self.create_group {
+            duration += create_group.estimated_duration();
+        }
+        duration
+    }
+
+    fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+        let mut required = self.fetch_nix.required_disk_space();
+        required.extend(self.move_unpacked_nix.required_disk_space());
+        required
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             fetch_nix,
@@ -167,7 +278,9 @@ impl Action for ProvisionNix {
         let mut buf = Vec::default();
         buf.append(&mut move_unpacked_nix.describe_revert());
         buf.append(&mut create_nix_tree.describe_revert());
-        buf.append(&mut create_group.describe_revert());
+        if let Some(create_group) = create_group {
+            buf.append(&mut create_group.describe_revert());
+        }
 
         if let Some(delete_users_in_group) = delete_users_in_group {
             buf.append(&mut delete_users_in_group.describe_execute());
@@ -192,8 +305,10 @@ impl Action for ProvisionNix {
                 .map_err(Self::error)?;
         }
 
-        if let Err(err) = self.create_group.try_revert().await {
-            errors.push(err)
+        if let Some(create_group) = &mut self.create_group {
+            if let Err(err) = create_group.try_revert().await {
+                errors.push(err)
+            }
         }
         if let Err(err) = self.create_nix_tree.try_revert().await {
             errors.push(err)
@@ -215,3 +330,79 @@ impl Action for ProvisionNix {
         }
     }
 }
+
+/// If a Nix database already exists, ensure the Nix we provision is able to use it
+async fn check_existing_db_schema(schema_path: &Path) -> Result<(), ActionErrorKind> {
+    if !schema_path.exists() {
+        return Ok(());
+    }
+
+    let buf = tokio::fs::read_to_string(&schema_path)
+        .await
+        .map_err(|e| ActionErrorKind::Read(schema_path.to_path_buf(), e))?;
+    let found = buf
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| ProvisionNixError::ParseDbSchema(buf.trim().to_string(), e))?;
+
+    if found > NIX_DB_SCHEMA_VERSION {
+        return Err(ProvisionNixError::IncompatibleDbSchema(found))?;
+    } else if found < NIX_DB_SCHEMA_VERSION {
+        tracing::warn!(
+            "Existing Nix database at `{}` uses schema version {found}, it will be upgraded to schema version {NIX_DB_SCHEMA_VERSION} when the Nix daemon first starts",
+            schema_path.display(),
+        );
+    }
+
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ProvisionNixError {
+    #[error("Parsing existing Nix database schema version `{0}` in `{NIX_DB_SCHEMA}`")]
+    ParseDbSchema(String, #[source] std::num::ParseIntError),
+    #[error("The existing Nix database in `/nix/var/nix/db` uses schema version {0}, which is newer than the schema version {NIX_DB_SCHEMA_VERSION} the Nix being installed supports, so the Nix daemon would refuse to start. Consider removing the existing store with `rm -rf /nix` or installing a newer Nix with `--nix-package-url`")]
+    IncompatibleDbSchema(u32),
+}
+
+impl From<ProvisionNixError> for ActionErrorKind {
+    fn from(v: ProvisionNixError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn db_schema_is_checked() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let schema = temp_dir.path().join("schema");
+
+        check_existing_db_schema(&schema).await?;
+
+        tokio::fs::write(&schema, format!("{}\n", NIX_DB_SCHEMA_VERSION)).await?;
+        check_existing_db_schema(&schema).await?;
+
+        tokio::fs::write(&schema, format!("{}\n", NIX_DB_SCHEMA_VERSION - 1)).await?;
+        check_existing_db_schema(&schema).await?;
+
+        tokio::fs::write(&schema, format!("{}\n", NIX_DB_SCHEMA_VERSION + 1)).await?;
+        let err = check_existing_db_schema(&schema)
+            .await
+            .expect_err("a newer schema is incompatible");
+        assert!(
+            err.to_string().contains("newer than the schema version"),
+            "{err}"
+        );
+
+        tokio::fs::write(&schema, "ten\n").await?;
+        let err = check_existing_db_schema(&schema)
+            .await
+            .expect_err("the schema is not a number");
+        assert!(err.to_string().contains("`ten`"), "{err}");
+        Ok(())
+    }
+}
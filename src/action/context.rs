@@ -0,0 +1,49 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::broadcast::Sender;
+
+use crate::plan::InstallEvent;
+
+tokio::task_local! {
+    static ACTION_CONTEXT: ActionContext;
+}
+
+/**
+Lets [`InstallPlan`](crate::InstallPlan) cancel long running actions and hear of their progress,
+without threading either through [`Action::execute`](crate::action::Action::execute) or
+[`Action::revert`](crate::action::Action::revert)
+
+Outside of a [`scope`](Self::scope), such as when an action is executed on its own, the default
+context is used: nothing is listening and nothing is cancelled.
+*/
+#[derive(Clone, Default)]
+pub(crate) struct ActionContext {
+    pub(crate) cancelled: Arc<AtomicBool>,
+    pub(crate) event_channel: Option<Sender<InstallEvent>>,
+    /// Set by [`StatefulAction::try_revert_force`](crate::action::StatefulAction::try_revert_force), so the actions nested within are forced too
+    pub(crate) revert_forced: bool,
+}
+
+impl ActionContext {
+    /// Run `f` with this context visible to any action it executes or reverts
+    pub(crate) async fn scope<F: Future>(self, f: F) -> F::Output {
+        ACTION_CONTEXT.scope(self, f).await
+    }
+
+    /// The context of the running task, to [`scope`](Self::scope) a task it spawns with
+    pub(crate) fn current() -> Self {
+        ACTION_CONTEXT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn send(&self, event: InstallEvent) {
+        if let Some(event_channel) = &self.event_channel {
+            let _ = event_channel.send(event);
+        }
+    }
+}
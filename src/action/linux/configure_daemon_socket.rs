@@ -0,0 +1,284 @@
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::unistd::Group;
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::{CreateDirectory, CreateFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+use crate::settings::{in_target_root, HOST_ROOT};
+
+const SOCKET_DROP_IN_DIR: &str = "/etc/systemd/system/nix-daemon.socket.d";
+const SOCKET_DROP_IN: &str =
+    "/etc/systemd/system/nix-daemon.socket.d/nix-installer-permissions.conf";
+const DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+/**
+Set the group and mode of the Nix daemon socket through a systemd drop-in, then check the socket has them
+
+Reverting removes the drop-in, so the socket returns to the defaults of the unit Nix ships.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureDaemonSocket {
+    group: Option<String>,
+    mode: Option<u32>,
+    start_daemon: bool,
+    target_root: PathBuf,
+    create_directory: StatefulAction<CreateDirectory>,
+    create_drop_in: StatefulAction<CreateFile>,
+}
+
+impl ConfigureDaemonSocket {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        group: Option<String>,
+        mode: Option<u32>,
+        start_daemon: bool,
+        target_root: &Path,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut buf = "[Socket]\n".to_string();
+        if let Some(group) = &group {
+            buf += &format!("SocketGroup={group}\n");
+        }
+        if let Some(mode) = mode {
+            buf += &format!("SocketMode={mode:04o}\n");
+        }
+
+        let create_directory = CreateDirectory::plan(
+            in_target_root(target_root, SOCKET_DROP_IN_DIR),
+            None,
+            None,
+            0o0755,
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+        let create_drop_in = CreateFile::plan(
+            in_target_root(target_root, SOCKET_DROP_IN),
+            None,
+            None,
+            0o0644,
+            buf,
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            group,
+            mode,
+            start_daemon,
+            target_root: target_root.to_path_buf(),
+            create_directory,
+            create_drop_in,
+        }
+        .into())
+    }
+
+    fn is_host_root(&self) -> bool {
+        self.target_root == Path::new(HOST_ROOT)
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_daemon_socket")]
+impl Action for ConfigureDaemonSocket {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_daemon_socket")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Configure the permissions of the Nix daemon socket".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_daemon_socket",
+            group = self.group,
+            mode = self
+                .mode
+                .map(|v| tracing::field::display(format!("{:#o}", v))),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!("Create `{SOCKET_DROP_IN}`")];
+        if let Some(group) = &self.group {
+            explanation.push(format!("Only members of `{group}` may use the daemon"));
+        }
+        if let Some(mode) = self.mode {
+            explanation.push(format!("Set the mode of `{DAEMON_SOCKET}` to `{mode:04o}`"));
+        }
+        if self.is_host_root() && self.start_daemon {
+            explanation.push(format!(
+                "Restart `nix-daemon.socket` and check the permissions of `{DAEMON_SOCKET}`"
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_directory
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+        self.create_drop_in
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+
+        // Nothing runs in an alternate target root, the drop-in applies once it boots
+        if !self.is_host_root() {
+            return Ok(());
+        }
+
+        execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .arg("daemon-reload")
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        if self.start_daemon {
+            execute_command(
+                Command::new("systemctl")
+                    .process_group(0)
+                    .args(["restart", "nix-daemon.socket"])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+
+            verify_socket(self.group.as_deref(), self.mode)
+                .await
+                .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = self.create_directory.to_shell()?;
+        commands.extend(self.create_drop_in.to_shell()?);
+        if self.is_host_root() {
+            commands.push("systemctl daemon-reload".to_string());
+            if self.start_daemon {
+                commands.push("systemctl restart nix-daemon.socket".to_string());
+            }
+        }
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        self.create_drop_in.action.touched_paths()
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Restore the default permissions of the Nix daemon socket".to_string(),
+            vec![format!("Remove `{SOCKET_DROP_IN}`")],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let Err(err) = self.create_drop_in.try_revert().await {
+            errors.push(err);
+        }
+        if let Err(err) = self.create_directory.try_revert().await {
+            errors.push(err);
+        }
+
+        if self.is_host_root() {
+            for args in [
+                &["daemon-reload"][..],
+                &["try-restart", "nix-daemon.socket"],
+            ] {
+                if let Err(err) = execute_command(
+                    Command::new("systemctl")
+                        .process_group(0)
+                        .args(args)
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                {
+                    errors.push(Self::error(err));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+/// Check the daemon socket systemd created has the configured group and mode
+async fn verify_socket(group: Option<&str>, mode: Option<u32>) -> Result<(), ActionErrorKind> {
+    let metadata = tokio::fs::metadata(DAEMON_SOCKET)
+        .await
+        .map_err(|e| ActionErrorKind::GettingMetadata(PathBuf::from(DAEMON_SOCKET), e))?;
+
+    if let Some(group) = group {
+        let expected_gid = Group::from_name(group)
+            .map_err(|e| ActionErrorKind::GettingGroupId(group.to_string(), e))?
+            .ok_or_else(|| ActionErrorKind::NoGroup(group.to_string()))?
+            .gid
+            .as_raw();
+        if metadata.gid() != expected_gid {
+            return Err(ConfigureDaemonSocketError::GroupMismatch {
+                group: group.to_string(),
+                expected_gid,
+                found_gid: metadata.gid(),
+            }
+            .into());
+        }
+    }
+    if let Some(mode) = mode {
+        let found_mode = metadata.permissions().mode() & 0o7777;
+        if found_mode != mode {
+            return Err(ConfigureDaemonSocketError::ModeMismatch {
+                expected_mode: mode,
+                found_mode,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureDaemonSocketError {
+    #[error("The Nix daemon socket `{DAEMON_SOCKET}` belongs to GID {found_gid} rather than `{group}` (GID {expected_gid}), check `systemctl cat nix-daemon.socket` for overrides of `SocketGroup`")]
+    GroupMismatch {
+        group: String,
+        expected_gid: u32,
+        found_gid: u32,
+    },
+    #[error("The Nix daemon socket `{DAEMON_SOCKET}` has mode {found_mode:04o} rather than {expected_mode:04o}, check `systemctl cat nix-daemon.socket` for overrides of `SocketMode`")]
+    ModeMismatch { expected_mode: u32, found_mode: u32 },
+}
+
+impl From<ConfigureDaemonSocketError> for ActionErrorKind {
+    fn from(v: ConfigureDaemonSocketError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
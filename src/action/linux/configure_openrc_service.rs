@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::{
+    shell_quote, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::settings::in_target_root;
+
+const OPENRC_RUN: &str = "/sbin/openrc-run";
+const INIT_SCRIPT_DEST: &str = "/etc/init.d/nix-daemon";
+const RUNLEVEL_DEST: &str = "/etc/runlevels/default/nix-daemon";
+const NIX_DAEMON_BIN: &str = "/nix/var/nix/profiles/default/bin/nix-daemon";
+
+/**
+Place an OpenRC init script for the Nix daemon and add it to the `default` runlevel
+
+Nix does not ship an OpenRC service, so the init script is written by the installer.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureOpenRcService {
+    create_init_script: StatefulAction<CreateFile>,
+    runlevel_dest: PathBuf,
+}
+
+impl ConfigureOpenRcService {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        ssl_cert_file: Option<PathBuf>,
+        target_root: &Path,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let openrc_run = in_target_root(target_root, OPENRC_RUN);
+        if !openrc_run.exists() {
+            return Err(Self::error(ConfigureOpenRcServiceError::OpenRcMissing(
+                openrc_run,
+            )));
+        }
+
+        // `rc-update add` only creates this symlink, which also works for an alternate target root
+        let runlevel_dest = in_target_root(target_root, RUNLEVEL_DEST);
+        if runlevel_dest.is_symlink() {
+            let link_dest = tokio::fs::read_link(&runlevel_dest)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::ReadSymlink(runlevel_dest.clone(), e)))?;
+            if link_dest != Path::new(INIT_SCRIPT_DEST) {
+                return Err(Self::error(ActionErrorKind::SymlinkExists(runlevel_dest)));
+            }
+        } else if runlevel_dest.exists() {
+            return Err(Self::error(ActionErrorKind::FileExists(runlevel_dest)));
+        }
+
+        let maybe_ssl_cert_file_setting = if let Some(ssl_cert_file) = ssl_cert_file {
+            format!(
+                "export NIX_SSL_CERT_FILE={:?}\n",
+                ssl_cert_file.canonicalize().map_err(|e| {
+                    Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e))
+                })?
+            )
+        } else {
+            "".to_string()
+        };
+        let init_script_buf = format!(
+            "\
+            #!{OPENRC_RUN}\n\
+            \n\
+            description=\"Nix package manager daemon\"\n\
+            command=\"{NIX_DAEMON_BIN}\"\n\
+            command_background=\"yes\"\n\
+            pidfile=\"/run/${{RC_SVCNAME}}.pid\"\n\
+            {maybe_ssl_cert_file_setting}\
+            \n\
+            depend() {{\n\
+            {inde}need localmount\n\
+            {inde}after net\n\
+            }}\n\
+        ",
+            inde = "    ", // indent
+        );
+        let create_init_script = CreateFile::plan(
+            in_target_root(target_root, INIT_SCRIPT_DEST),
+            None,
+            None,
+            0o755,
+            init_script_buf,
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            create_init_script,
+            runlevel_dest,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_openrc_service")]
+impl Action for ConfigureOpenRcService {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_openrc_service")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Configure the Nix daemon OpenRC service".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_openrc_service",
+            runlevel_dest = tracing::field::display(self.runlevel_dest.display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .create_init_script
+            .describe_execute()
+            .into_iter()
+            .map(|desc| desc.description)
+            .collect::<Vec<_>>();
+        explanation.push(format!(
+            "Symlink `{INIT_SCRIPT_DEST}` to `{}`",
+            self.runlevel_dest.display()
+        ));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_init_script
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+
+        if !self.runlevel_dest.is_symlink() {
+            tokio::fs::symlink(INIT_SCRIPT_DEST, &self.runlevel_dest)
+                .await
+                .map_err(|e| {
+                    ActionErrorKind::Symlink(
+                        PathBuf::from(INIT_SCRIPT_DEST),
+                        self.runlevel_dest.clone(),
+                        e,
+                    )
+                })
+                .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = self.create_init_script.to_shell()?;
+        let runlevel_dest = shell_quote(&self.runlevel_dest);
+        commands.push(format!(
+            "[ -L {runlevel_dest} ] || ln -s {} {runlevel_dest}",
+            shell_quote(INIT_SCRIPT_DEST)
+        ));
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.create_init_script.action.touched_paths();
+        paths.push(self.runlevel_dest.clone());
+        paths
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Unconfigure the Nix daemon OpenRC service".to_string(),
+            vec![
+                format!("Remove `{}`", self.runlevel_dest.display()),
+                format!("Remove `{INIT_SCRIPT_DEST}`"),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if self.runlevel_dest.is_symlink() {
+            if let Err(err) = tokio::fs::remove_file(&self.runlevel_dest)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.runlevel_dest.clone(), e)))
+            {
+                errors.push(err);
+            }
+        }
+        if let Err(err) = self.create_init_script.try_revert().await {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureOpenRcServiceError {
+    #[error("Could not find `{0}`, is OpenRC installed? Consider choosing a different init system with `--init`")]
+    OpenRcMissing(PathBuf),
+}
+
+impl From<ConfigureOpenRcServiceError> for ActionErrorKind {
+    fn from(v: ConfigureOpenRcServiceError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
@@ -0,0 +1,415 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::{
+    shell_quote, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+
+const WSL_CONF: &str = "/etc/wsl.conf";
+const WRAPPER_DEST: &str = "/nix/var/nix/wsl-start-nix-daemon";
+const NIX_DAEMON_BIN: &str = "/nix/var/nix/profiles/default/bin/nix-daemon";
+const NIX_DAEMON_LOG: &str = "/var/log/nix-daemon.log";
+
+/**
+Start the Nix daemon from the `[boot]` command of `/etc/wsl.conf`, for WSL without systemd
+
+WSL runs the boot command as `root` whenever the distribution starts. It runs a wrapper script,
+which starts the Nix daemon in the background unless it is already running.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureWslDaemon {
+    create_wrapper: StatefulAction<CreateFile>,
+    wsl_conf: PathBuf,
+    start_daemon: bool,
+}
+
+impl ConfigureWslDaemon {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        ssl_cert_file: Option<PathBuf>,
+        start_daemon: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let wsl_conf = PathBuf::from(WSL_CONF);
+        // WSL only runs one boot command, so one set by the user can't be kept alongside it
+        if let Some(existing) = boot_command(&read_wsl_conf(&wsl_conf).await.map_err(Self::error)?)
+        {
+            if existing != WRAPPER_DEST {
+                return Err(Self::error(ConfigureWslDaemonError::BootCommandExists(
+                    existing,
+                )));
+            }
+        }
+
+        let maybe_ssl_cert_file_setting = if let Some(ssl_cert_file) = ssl_cert_file {
+            format!(
+                "export NIX_SSL_CERT_FILE={}\n",
+                shell_quote(ssl_cert_file.canonicalize().map_err(|e| {
+                    Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e))
+                })?)
+            )
+        } else {
+            "".to_string()
+        };
+        let wrapper_buf = format!(
+            "\
+            #!/bin/sh\n\
+            # Run as the `[boot]` command of `{WSL_CONF}`, WSL has no init system to start the Nix daemon\n\
+            {maybe_ssl_cert_file_setting}\
+            if ! pgrep -x nix-daemon >/dev/null 2>&1; then\n\
+            {inde}setsid {NIX_DAEMON_BIN} </dev/null >>{NIX_DAEMON_LOG} 2>&1 &\n\
+            fi\n\
+        ",
+            inde = "    ", // indent
+        );
+        let create_wrapper = CreateFile::plan(WRAPPER_DEST, None, None, 0o755, wrapper_buf, false)
+            .await
+            .map_err(Self::error)?;
+
+        Ok(Self {
+            create_wrapper,
+            wsl_conf,
+            start_daemon,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_wsl_daemon")]
+impl Action for ConfigureWslDaemon {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_wsl_daemon")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Start the Nix daemon when WSL starts".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_wsl_daemon",
+            wsl_conf = tracing::field::display(self.wsl_conf.display()),
+            start_daemon = self.start_daemon,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .create_wrapper
+            .describe_execute()
+            .into_iter()
+            .map(|desc| desc.description)
+            .collect::<Vec<_>>();
+        explanation.push(format!(
+            "Set `command = \"{WRAPPER_DEST}\"` in the `[boot]` section of `{}`",
+            self.wsl_conf.display()
+        ));
+        if self.start_daemon {
+            explanation.push(format!("Run `{WRAPPER_DEST}` to start the Nix daemon now"));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_wrapper
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+
+        let buf = read_wsl_conf(&self.wsl_conf).await.map_err(Self::error)?;
+        if boot_command(&buf).is_none() {
+            tokio::fs::write(&self.wsl_conf, insert_boot_command(&buf))
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Write(self.wsl_conf.clone(), e)))?;
+        }
+
+        if self.start_daemon {
+            execute_command(
+                Command::new(WRAPPER_DEST)
+                    .process_group(0)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = self.create_wrapper.to_shell()?;
+        let wsl_conf = shell_quote(&self.wsl_conf);
+        commands.push(format!(
+            "grep -q '^\\[boot\\]' {wsl_conf} 2>/dev/null || printf '\\n[boot]\\n' >> {wsl_conf}"
+        ));
+        // Like `execute`, only add the boot command if it is not already there
+        commands.push(format!(
+            "grep -qF {} {wsl_conf} || sed -i {} {wsl_conf}",
+            shell_quote(WRAPPER_DEST),
+            shell_quote(format!("/^\\[boot\\]/a {}", boot_command_line()))
+        ));
+        if self.start_daemon {
+            commands.push(shell_quote(WRAPPER_DEST));
+        }
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.create_wrapper.action.touched_paths();
+        paths.push(self.wsl_conf.clone());
+        paths
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            format!(
+                "Remove the `[boot]` command from `{}`",
+                self.wsl_conf.display()
+            ),
+            format!("Remove `{WRAPPER_DEST}`"),
+        ];
+        if self.start_daemon {
+            explanation.insert(0, "Stop the Nix daemon".to_string());
+        }
+        vec![ActionDescription::new(
+            "Stop starting the Nix daemon when WSL starts".to_string(),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if self.start_daemon {
+            // `pkill` fails if the daemon already stopped, which is fine
+            match Command::new("pkill")
+                .process_group(0)
+                .args(["-x", "nix-daemon"])
+                .stdin(std::process::Stdio::null())
+                .status()
+                .await
+            {
+                Ok(status) if !status.success() => {
+                    tracing::debug!("No running Nix daemon to stop");
+                },
+                Ok(_) => (),
+                Err(e) => errors.push(Self::error(ActionErrorKind::command(
+                    Command::new("pkill").args(["-x", "nix-daemon"]),
+                    e,
+                ))),
+            }
+        }
+
+        match read_wsl_conf(&self.wsl_conf).await {
+            Ok(buf) if boot_command(&buf).as_deref() == Some(WRAPPER_DEST) => {
+                let removed = remove_boot_command(&buf);
+                let res = if removed.trim().is_empty() {
+                    tokio::fs::remove_file(&self.wsl_conf)
+                        .await
+                        .map_err(|e| ActionErrorKind::Remove(self.wsl_conf.clone(), e))
+                } else {
+                    tokio::fs::write(&self.wsl_conf, removed)
+                        .await
+                        .map_err(|e| ActionErrorKind::Write(self.wsl_conf.clone(), e))
+                };
+                if let Err(err) = res {
+                    errors.push(Self::error(err));
+                }
+            },
+            Ok(_) => (),
+            Err(err) => errors.push(Self::error(err)),
+        }
+
+        if let Err(err) = self.create_wrapper.try_revert().await {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+async fn read_wsl_conf(path: &Path) -> Result<String, ActionErrorKind> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(buf) => Ok(buf),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+fn boot_command_line() -> String {
+    format!("command = \"{WRAPPER_DEST}\"")
+}
+
+fn is_section(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('[') && line.ends_with(']')
+}
+
+fn is_boot_section(line: &str) -> bool {
+    line.trim().eq_ignore_ascii_case("[boot]")
+}
+
+/// The value of `command` in the `[boot]` section of `wsl_conf`
+fn boot_command(wsl_conf: &str) -> Option<String> {
+    let mut in_boot = false;
+    for line in wsl_conf.lines() {
+        if is_section(line) {
+            in_boot = is_boot_section(line);
+        } else if in_boot {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "command" {
+                    return Some(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// `wsl_conf` with the boot command added to its `[boot]` section, which is added if missing
+fn insert_boot_command(wsl_conf: &str) -> String {
+    let mut lines = wsl_conf.lines().map(String::from).collect::<Vec<_>>();
+    match lines.iter().position(|line| is_boot_section(line)) {
+        Some(idx) => lines.insert(idx + 1, boot_command_line()),
+        None => {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push("[boot]".to_string());
+            lines.push(boot_command_line());
+        },
+    }
+    lines.join("\n") + "\n"
+}
+
+/// `wsl_conf` without the boot command, and without the `[boot]` section if nothing else is in it
+fn remove_boot_command(wsl_conf: &str) -> String {
+    let mut lines = vec![];
+    let mut in_boot = false;
+    for line in wsl_conf.lines() {
+        if is_section(line) {
+            in_boot = is_boot_section(line);
+        } else if in_boot && boot_command(&format!("[boot]\n{line}")).is_some() {
+            continue;
+        }
+        lines.push(line);
+    }
+
+    // Drop the `[boot]` header if only blank lines follow it before the next section
+    if let Some(idx) = lines.iter().position(|line| is_boot_section(line)) {
+        let empty = lines[idx + 1..]
+            .iter()
+            .take_while(|line| !is_section(line))
+            .all(|line| line.trim().is_empty());
+        if empty {
+            let end = idx
+                + 1
+                + lines[idx + 1..]
+                    .iter()
+                    .take_while(|line| !is_section(line))
+                    .count();
+            let mut start = idx;
+            while start > 0 && lines[start - 1].trim().is_empty() {
+                start -= 1;
+            }
+            // Keep a blank line between the sections around it
+            if start < idx && end < lines.len() {
+                start += 1;
+            }
+            lines.drain(start..end);
+        }
+    }
+
+    if lines.iter().all(|line| line.trim().is_empty()) {
+        String::new()
+    } else {
+        lines.join("\n") + "\n"
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureWslDaemonError {
+    #[error("`{WSL_CONF}` already runs `{0}` as its `[boot]` command, and WSL only runs one. Remove it, or enable systemd in WSL with `[boot]` `systemd=true` and use the `linux` planner")]
+    BootCommandExists(String),
+}
+
+impl From<ConfigureWslDaemonError> for ActionErrorKind {
+    fn from(v: ConfigureWslDaemonError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boot_command_round_trips() {
+        let wsl_conf = "[network]\ngenerateHosts = false\n";
+        let inserted = insert_boot_command(wsl_conf);
+        assert_eq!(
+            inserted,
+            format!("[network]\ngenerateHosts = false\n\n[boot]\ncommand = \"{WRAPPER_DEST}\"\n")
+        );
+        assert_eq!(boot_command(&inserted).as_deref(), Some(WRAPPER_DEST));
+        assert_eq!(remove_boot_command(&inserted), wsl_conf);
+
+        let wsl_conf = "[boot]\nsystemd = false\n";
+        let inserted = insert_boot_command(wsl_conf);
+        assert_eq!(
+            inserted,
+            format!("[boot]\ncommand = \"{WRAPPER_DEST}\"\nsystemd = false\n")
+        );
+        assert_eq!(remove_boot_command(&inserted), wsl_conf);
+
+        assert_eq!(remove_boot_command(&insert_boot_command("")), "");
+    }
+
+    #[tokio::test]
+    async fn to_shell_adds_the_boot_command_once() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let wsl_conf = temp_dir.path().join("wsl.conf");
+        tokio::fs::write(&wsl_conf, "[network]\ngenerateHosts = false\n").await?;
+        let action = ConfigureWslDaemon {
+            create_wrapper: CreateFile::plan(
+                temp_dir.path().join("wrapper"),
+                None,
+                None,
+                0o755,
+                String::new(),
+                false,
+            )
+            .await?,
+            wsl_conf: wsl_conf.clone(),
+            start_daemon: false,
+        };
+
+        let wrapper_commands = action.create_wrapper.to_shell().unwrap().len();
+        let script = action.to_shell().unwrap()[wrapper_commands..].join("\n");
+        for _ in 0..2 {
+            let status = Command::new("sh").args(["-ec", &script]).status().await?;
+            assert!(status.success());
+        }
+        assert_eq!(
+            tokio::fs::read_to_string(&wsl_conf).await?,
+            format!("[network]\ngenerateHosts = false\n\n[boot]\ncommand = \"{WRAPPER_DEST}\"\n")
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,19 @@
+pub(crate) mod configure_daemon_socket;
+pub(crate) mod configure_openrc_service;
+pub(crate) mod configure_wsl_daemon;
 pub(crate) mod provision_selinux;
+pub(crate) mod restart_systemd_unit;
+pub(crate) mod start_openrc_service;
 pub(crate) mod start_systemd_unit;
+pub(crate) mod stop_systemd_unit;
 
+pub use configure_daemon_socket::{ConfigureDaemonSocket, ConfigureDaemonSocketError};
+pub use configure_openrc_service::{ConfigureOpenRcService, ConfigureOpenRcServiceError};
+pub use configure_wsl_daemon::{ConfigureWslDaemon, ConfigureWslDaemonError};
 pub use provision_selinux::ProvisionSelinux;
-pub use start_systemd_unit::{StartSystemdUnit, StartSystemdUnitError};
+pub use restart_systemd_unit::RestartSystemdUnit;
+pub use start_openrc_service::StartOpenRcService;
+pub use start_systemd_unit::{
+    StartSystemdUnit, StartSystemdUnitError, DEFAULT_DAEMON_READY_TIMEOUT,
+};
+pub use stop_systemd_unit::StopSystemdUnit;
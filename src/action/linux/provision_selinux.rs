@@ -1,10 +1,13 @@
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
+use base64::Engine;
+
 use tokio::fs::{create_dir_all, remove_file};
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::action::{shell_command, shell_quote, ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
@@ -93,6 +96,29 @@ impl Action for ProvisionSelinux {
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let policy_path = shell_quote(&self.policy_path);
+        let mut commands = vec![];
+        if let Some(parent) = self.policy_path.parent() {
+            commands.push(shell_command([
+                OsStr::new("mkdir"),
+                OsStr::new("-p"),
+                parent.as_os_str(),
+            ]));
+        }
+        commands.push(format!(
+            "echo {} | base64 -d > {policy_path}",
+            base64::engine::general_purpose::STANDARD.encode(SE_LINUX_POLICY_PP_CONTENT)
+        ));
+        commands.push(format!("semodule --install {policy_path}"));
+        commands.push("restorecon -FR /nix".to_string());
+        Some(commands)
+    }
+
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        vec![self.policy_path.clone()]
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Remove the SELinux policy for Nix".into(),
@@ -0,0 +1,81 @@
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{shell_command, ActionError, ActionTag, StatefulAction};
+use crate::execute_command;
+
+use crate::action::{Action, ActionDescription};
+
+/**
+Restart a given systemd unit, such as to apply a changed configuration, does nothing on revert
+
+Unlike [`StartSystemdUnit`](crate::action::linux::StartSystemdUnit), the unit is restarted even if
+it is already running, and started if it is not.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct RestartSystemdUnit {
+    unit: String,
+}
+
+impl RestartSystemdUnit {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(unit: impl AsRef<str>) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(StatefulAction::uncompleted(Self {
+            unit: unit.as_ref().to_string(),
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "restart_systemd_unit")]
+impl Action for RestartSystemdUnit {
+    fn action_tag() -> ActionTag {
+        ActionTag("restart_systemd_unit")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Restart the systemd unit {}", self.unit)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "restart_systemd_unit",
+            unit = %self.unit,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![format!("Run `systemctl restart {}`", self.unit)],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .arg("restart")
+                .arg(&self.unit)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        Some(vec![shell_command(["systemctl", "restart", &self.unit])])
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![/* Deliberately empty -- this is a noop */]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
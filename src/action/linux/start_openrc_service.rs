@@ -0,0 +1,114 @@
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{
+    shell_command, ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+
+use crate::action::{Action, ActionDescription};
+
+/**
+Start a given OpenRC service
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct StartOpenRcService {
+    service: String,
+}
+
+impl StartOpenRcService {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(service: impl AsRef<str>) -> Result<StatefulAction<Self>, ActionError> {
+        let service = service.as_ref();
+        let mut command = Command::new("rc-service");
+        command.arg(service);
+        command.arg("status");
+        command.stdin(std::process::Stdio::null());
+        let output = command
+            .output()
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
+
+        let state = if output.status.success() {
+            tracing::debug!("Starting OpenRC service `{}` already complete", service);
+            ActionState::Skipped
+        } else {
+            ActionState::Uncompleted
+        };
+
+        Ok(StatefulAction {
+            action: Self {
+                service: service.to_string(),
+            },
+            state,
+            timeout: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "start_openrc_service")]
+impl Action for StartOpenRcService {
+    fn action_tag() -> ActionTag {
+        ActionTag("start_openrc_service")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Start the OpenRC service {}", self.service)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "start_openrc_service",
+            service = %self.service,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![format!("Run `rc-service {} start`", self.service)],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        execute_command(
+            Command::new("rc-service")
+                .process_group(0)
+                .arg(&self.service)
+                .arg("start")
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn to_shell(&self) -> Option<Vec<String>> {
+        Some(vec![shell_command(["rc-service", &self.service, "start"])])
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Stop the OpenRC service {}", self.service),
+            vec![format!("Run `rc-service {} stop`", self.service)],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        execute_command(
+            Command::new("rc-service")
+                .process_group(0)
+                .arg(&self.service)
+                .arg("stop")
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+}
@@ -1,18 +1,35 @@
+use std::time::{Duration, Instant};
+
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction};
+use crate::action::{
+    shell_command, ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction,
+};
 use crate::execute_command;
+use crate::verify::{NIX_BIN, NIX_DAEMON_SOCKET};
 
 use crate::action::{Action, ActionDescription};
 
+/// How long to wait for the Nix daemon to serve once the unit starting it has started
+pub const DEFAULT_DAEMON_READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait between checks of whether the Nix daemon serves
+const DAEMON_READY_INTERVAL: Duration = Duration::from_millis(500);
+
 /**
 Start a given systemd unit
+
+`systemctl start` returns once the unit is started, which for a socket unit like
+`nix-daemon.socket` may be before the daemon answers. With
+[`with_daemon_ready_timeout`](StatefulAction::with_daemon_ready_timeout), the action only completes
+once the Nix daemon answers `nix store ping`.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct StartSystemdUnit {
     unit: String,
     enable: bool,
+    #[serde(default)]
+    daemon_ready_timeout: Option<Duration>,
 }
 
 impl StartSystemdUnit {
@@ -41,12 +58,22 @@ impl StartSystemdUnit {
             action: Self {
                 unit: unit.to_string(),
                 enable,
+                daemon_ready_timeout: None,
             },
             state,
+            timeout: None,
         })
     }
 }
 
+impl StatefulAction<StartSystemdUnit> {
+    /// Wait up to `timeout` after starting the unit for the Nix daemon to answer `nix store ping`
+    pub fn with_daemon_ready_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.action.daemon_ready_timeout = timeout.into();
+        self
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "start_systemd_unit")]
 impl Action for StartSystemdUnit {
@@ -66,12 +93,20 @@ impl Action for StartSystemdUnit {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if let Some(timeout) = self.daemon_ready_timeout {
+            explanation.push(daemon_ready_description(timeout));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { unit, enable } = self;
+        let Self {
+            unit,
+            enable,
+            daemon_ready_timeout,
+        } = self;
 
         match enable {
             true => {
@@ -101,9 +136,32 @@ impl Action for StartSystemdUnit {
             },
         }
 
+        if let Some(timeout) = daemon_ready_timeout {
+            wait_for_daemon(*timeout).await.map_err(Self::error)?;
+        }
+
         Ok(())
     }
 
+    fn to_shell(&self) -> Option<Vec<String>> {
+        let mut commands = vec![match self.enable {
+            true => shell_command(["systemctl", "enable", "--now", &self.unit]),
+            false => shell_command(["systemctl", "start", &self.unit]),
+        }];
+        if let Some(timeout) = self.daemon_ready_timeout {
+            commands.push(wait_for_daemon_shell(timeout));
+        }
+        Some(commands)
+    }
+
+    fn estimated_duration(&self) -> Duration {
+        match self.daemon_ready_timeout {
+            // The daemon usually serves within a second or two
+            Some(_) => Duration::from_secs(2),
+            None => Duration::from_millis(100),
+        }
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             format!("Disable (and stop) the systemd unit {}", self.unit),
@@ -157,9 +215,85 @@ impl Action for StartSystemdUnit {
     }
 }
 
+/// Describes waiting for the Nix daemon, for the [`ActionDescription`] of an action starting it
+pub(crate) fn daemon_ready_description(timeout: Duration) -> String {
+    format!(
+        "Wait up to {}s for the Nix daemon to answer `nix store ping`",
+        timeout.as_secs()
+    )
+}
+
+/// Wait until the Nix daemon accepts connections on its socket and answers `nix store ping`, at most `timeout`
+pub(crate) async fn wait_for_daemon(timeout: Duration) -> Result<(), StartSystemdUnitError> {
+    let start = Instant::now();
+    loop {
+        // A hung attempt is dropped once the time is up, which kills its `nix store ping`
+        let attempt = async {
+            tokio::net::UnixStream::connect(NIX_DAEMON_SOCKET)
+                .await
+                .map_err(|e| format!("Connecting to `{NIX_DAEMON_SOCKET}`: {e}"))?;
+            execute_command(
+                Command::new(NIX_BIN)
+                    .process_group(0)
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["store", "ping", "--store", "daemon"])
+                    .stdin(std::process::Stdio::null())
+                    .kill_on_drop(true),
+            )
+            .await
+            .map_err(|e| e.to_string())
+        };
+        let error =
+            match tokio::time::timeout(timeout.saturating_sub(start.elapsed()), attempt).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(error)) => error,
+                Err(_) => "The attempt did not finish in time".to_string(),
+            };
+        if start.elapsed() >= timeout {
+            return Err(StartSystemdUnitError::DaemonNotReady(timeout, error));
+        }
+        tracing::debug!("Nix daemon is not ready yet: {error}");
+        tokio::time::sleep(DAEMON_READY_INTERVAL).await;
+    }
+}
+
+/// The shell equivalent of [`wait_for_daemon`]
+pub(crate) fn wait_for_daemon_shell(timeout: Duration) -> String {
+    format!(
+        "timeout {} sh -c 'until {NIX_BIN} --extra-experimental-features nix-command store ping --store daemon >/dev/null 2>&1; do sleep 1; done'",
+        timeout.as_secs()
+    )
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum StartSystemdUnitError {
     #[error("Failed to execute command")]
     Command(#[source] std::io::Error),
+    #[error("The Nix daemon did not answer within {}s of starting, the last attempt failed with: {1}", .0.as_secs())]
+    DaemonNotReady(Duration, String),
+}
+
+impl From<StartSystemdUnitError> for ActionErrorKind {
+    fn from(v: StartSystemdUnitError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, time::Duration};
+
+    use super::{wait_for_daemon, StartSystemdUnitError};
+    use crate::verify::NIX_DAEMON_SOCKET;
+
+    #[tokio::test]
+    async fn wait_for_daemon_times_out() {
+        // Only without a Nix daemon on this machine
+        if Path::new(NIX_DAEMON_SOCKET).exists() {
+            return;
+        }
+        let err = wait_for_daemon(Duration::from_millis(1)).await.unwrap_err();
+        assert!(matches!(err, StartSystemdUnitError::DaemonNotReady(_, _)));
+    }
 }
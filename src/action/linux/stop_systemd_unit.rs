@@ -0,0 +1,96 @@
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction};
+use crate::execute_command;
+
+use crate::action::{Action, ActionDescription};
+
+/**
+Stop a given systemd unit, does nothing on revert
+
+Unlike reverting [`StartSystemdUnit`](crate::action::linux::StartSystemdUnit), this does not disable the unit.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct StopSystemdUnit {
+    unit: String,
+}
+
+impl StopSystemdUnit {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(unit: impl AsRef<str>) -> Result<StatefulAction<Self>, ActionError> {
+        let unit = unit.as_ref();
+        let mut command = Command::new("systemctl");
+        command.arg("is-active");
+        command.arg(unit);
+        let output = command
+            .output()
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
+
+        let state = if output.status.success() {
+            ActionState::Uncompleted
+        } else {
+            tracing::debug!("Stopping systemd unit `{}` already complete", unit);
+            ActionState::Skipped
+        };
+
+        Ok(StatefulAction {
+            action: Self {
+                unit: unit.to_string(),
+            },
+            state,
+            timeout: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "stop_systemd_unit")]
+impl Action for StopSystemdUnit {
+    fn action_tag() -> ActionTag {
+        ActionTag("stop_systemd_unit")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Stop the systemd unit {}", self.unit)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "stop_systemd_unit",
+            unit = %self.unit,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![format!("Run `systemctl stop {}`", self.unit)],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .arg("stop")
+                .arg(&self.unit)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![/* Deliberately empty -- this is a noop */]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionTag, StatefulAction};
+use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
@@ -23,23 +24,12 @@ impl CreateApfsVolume {
         name: String,
         case_sensitive: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let output =
-            execute_command(Command::new("/usr/sbin/diskutil").args(["apfs", "list", "-plist"]))
-                .await
-                .map_err(Self::error)?;
-
-        let parsed: DiskUtilApfsListOutput =
-            plist::from_bytes(&output.stdout).map_err(Self::error)?;
-        for container in parsed.containers {
-            for volume in container.volumes {
-                if volume.name == name {
-                    return Ok(StatefulAction::completed(Self {
-                        disk: disk.as_ref().to_path_buf(),
-                        name,
-                        case_sensitive,
-                    }));
-                }
-            }
+        if volume_exists(&name).await.map_err(Self::error)? {
+            return Ok(StatefulAction::completed(Self {
+                disk: disk.as_ref().to_path_buf(),
+                name,
+                case_sensitive,
+            }));
         }
 
         Ok(StatefulAction::uncompleted(Self {
@@ -78,6 +68,17 @@ impl Action for CreateApfsVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn preflight(&self) -> Result<(), ActionError> {
+        // The volume did not exist during planning, something else may have created it since
+        if volume_exists(&self.name).await.map_err(Self::error)? {
+            return Err(Self::error(ActionErrorKind::VolumeExists(
+                self.name.clone(),
+            )));
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
@@ -109,6 +110,10 @@ impl Action for CreateApfsVolume {
         Ok(())
     }
 
+    fn estimated_duration(&self) -> Duration {
+        Duration::from_secs(3)
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             format!(
@@ -134,3 +139,17 @@ impl Action for CreateApfsVolume {
         Ok(())
     }
 }
+
+/// If `diskutil` lists an APFS volume named `name` in any container
+pub(crate) async fn volume_exists(name: &str) -> Result<bool, ActionErrorKind> {
+    let output =
+        execute_command(Command::new("/usr/sbin/diskutil").args(["apfs", "list", "-plist"]))
+            .await?;
+
+    let parsed: DiskUtilApfsListOutput = plist::from_bytes(&output.stdout)?;
+    Ok(parsed
+        .containers
+        .into_iter()
+        .flat_map(|container| container.volumes)
+        .any(|volume| volume.name == name))
+}
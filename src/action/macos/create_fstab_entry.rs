@@ -4,7 +4,11 @@ use super::{get_uuid_for_label, CreateApfsVolume};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction,
 };
-use std::{io::SeekFrom, path::Path};
+use crate::settings::NIX_ROOT;
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::OpenOptions,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -22,7 +26,7 @@ enum ExistingFstabEntry {
     None,
 }
 
-/** Create an `/etc/fstab` entry for the given volume
+/** Create an `/etc/fstab` entry mounting the given volume on `mount_point`
 
 This action queries `diskutil info` on the volume to fetch it's UUID and
 add the relevant information to `/etc/fstab`.
@@ -32,6 +36,8 @@ add the relevant information to `/etc/fstab`.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateFstabEntry {
     apfs_volume_label: String,
+    #[serde(default = "default_mount_point")]
+    mount_point: PathBuf,
     existing_entry: ExistingFstabEntry,
 }
 
@@ -39,9 +45,11 @@ impl CreateFstabEntry {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         apfs_volume_label: String,
+        mount_point: impl AsRef<Path>,
         planned_create_apfs_volume: &StatefulAction<CreateApfsVolume>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let fstab_path = Path::new(FSTAB_PATH);
+        let mount_point = mount_point.as_ref().to_path_buf();
 
         if fstab_path.exists() {
             let fstab_buf = tokio::fs::read_to_string(&fstab_path)
@@ -54,21 +62,24 @@ impl CreateFstabEntry {
                 if planned_create_apfs_volume.state != ActionState::Completed {
                     return Ok(StatefulAction::completed(Self {
                         apfs_volume_label,
+                        mount_point,
                         existing_entry: ExistingFstabEntry::NixInstallerEntry,
                     }));
                 }
 
                 return Ok(StatefulAction::uncompleted(Self {
                     apfs_volume_label,
+                    mount_point,
                     existing_entry: ExistingFstabEntry::NixInstallerEntry,
                 }));
             } else if fstab_buf
                 .lines()
-                .any(|line| line.split(&[' ', '\t']).nth(2) == Some("/nix"))
+                .any(|line| is_mount_point_entry(line, &mount_point, 2))
             {
-                // See if the user already has a `/nix` related entry, if so, invite them to remove it.
+                // See if the user already has an entry for the mount point, if so, invite them to remove it.
                 return Ok(StatefulAction::uncompleted(Self {
                     apfs_volume_label,
+                    mount_point,
                     existing_entry: ExistingFstabEntry::Foreign,
                 }));
             }
@@ -76,6 +87,7 @@ impl CreateFstabEntry {
 
         Ok(StatefulAction::uncompleted(Self {
             apfs_volume_label,
+            mount_point,
             existing_entry: ExistingFstabEntry::None,
         }))
     }
@@ -119,6 +131,7 @@ impl Action for CreateFstabEntry {
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
             apfs_volume_label,
+            mount_point,
             existing_entry,
         } = self;
         let fstab_path = Path::new(FSTAB_PATH);
@@ -164,8 +177,8 @@ impl Action for CreateFstabEntry {
                         saw_prelude = true;
                         continue;
                     }
-                    if saw_prelude && line.split(&[' ', '\t']).nth(1) == Some("/nix") {
-                        *line = fstab_entry(&uuid);
+                    if saw_prelude && is_mount_point_entry(line, mount_point, 1) {
+                        *line = fstab_entry(&uuid, mount_point);
                         updated_line = true;
                         break;
                     }
@@ -185,8 +198,8 @@ impl Action for CreateFstabEntry {
                     .collect::<Vec<String>>();
                 let mut updated_line = false;
                 for line in current_fstab_lines.iter_mut() {
-                    if line.split(&[' ', '\t']).nth(2) == Some("/nix") {
-                        *line = fstab_lines(&uuid, apfs_volume_label);
+                    if is_mount_point_entry(line, mount_point, 2) {
+                        *line = fstab_lines(&uuid, apfs_volume_label, mount_point);
                         updated_line = true;
                         break;
                     }
@@ -198,7 +211,9 @@ impl Action for CreateFstabEntry {
                 }
                 current_fstab_lines.join("\n")
             },
-            ExistingFstabEntry::None => fstab_buf + "\n" + &fstab_lines(&uuid, apfs_volume_label),
+            ExistingFstabEntry::None => {
+                fstab_buf + "\n" + &fstab_lines(&uuid, apfs_volume_label, mount_point)
+            },
         };
 
         fstab
@@ -220,6 +235,7 @@ impl Action for CreateFstabEntry {
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             apfs_volume_label,
+            mount_point: _,
             existing_entry: _,
         } = &self;
         vec![ActionDescription::new(
@@ -239,7 +255,7 @@ impl Action for CreateFstabEntry {
             .await
             .map_err(Self::error)?
         {
-            let fstab_entry = fstab_lines(&uuid, &self.apfs_volume_label);
+            let fstab_entry = fstab_lines(&uuid, &self.apfs_volume_label, &self.mount_point);
 
             let mut file = OpenOptions::new()
                 .create(false)
@@ -279,9 +295,18 @@ impl Action for CreateFstabEntry {
     }
 }
 
-fn fstab_lines(uuid: &Uuid, apfs_volume_label: &str) -> String {
+fn default_mount_point() -> PathBuf {
+    PathBuf::from(NIX_ROOT)
+}
+
+/// If the whitespace separated field at `index` of an `/etc/fstab` `line` is `mount_point`
+fn is_mount_point_entry(line: &str, mount_point: &Path, index: usize) -> bool {
+    line.split(&[' ', '\t']).nth(index).map(Path::new) == Some(mount_point)
+}
+
+fn fstab_lines(uuid: &Uuid, apfs_volume_label: &str, mount_point: &Path) -> String {
     let prelude_comment = fstab_prelude_comment(apfs_volume_label);
-    let fstab_entry = fstab_entry(uuid);
+    let fstab_entry = fstab_entry(uuid, mount_point);
     prelude_comment + "\n" + &fstab_entry
 }
 
@@ -289,8 +314,11 @@ fn fstab_prelude_comment(apfs_volume_label: &str) -> String {
     format!("# nix-installer created volume labelled `{apfs_volume_label}`")
 }
 
-fn fstab_entry(uuid: &Uuid) -> String {
-    format!("UUID={uuid} /nix apfs rw,noauto,nobrowse,suid,owners")
+fn fstab_entry(uuid: &Uuid, mount_point: &Path) -> String {
+    format!(
+        "UUID={uuid} {} apfs rw,noauto,nobrowse,suid,owners",
+        mount_point.display()
+    )
 }
 
 #[non_exhaustive]
@@ -302,7 +330,7 @@ pub enum CreateFstabEntryError {
     ExistingForeignEntryDisappeared,
     #[error("Unable to determine how to add APFS volume `{0}` the `/etc/fstab` line, likely the volume is not yet created or there is some synchronization issue, please report this")]
     CannotDetermineUuid(String),
-    #[error("Unable to reliably determine which `/etc/fstab` line to remove, the volume is likely already deleted, the line involving `/nix` in `/etc/fstab` should be removed manually")]
+    #[error("Unable to reliably determine which `/etc/fstab` line to remove, the volume is likely already deleted, the line involving the mount point in `/etc/fstab` should be removed manually")]
     CannotDetermineFstabLine,
 }
 
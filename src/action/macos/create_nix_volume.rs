@@ -2,10 +2,12 @@ use crate::action::{
     base::{create_or_insert_into_file, CreateOrInsertIntoFile},
     macos::{
         BootstrapLaunchctlService, CreateApfsVolume, CreateSyntheticObjects, EnableOwnership,
-        EncryptApfsVolume, UnmountApfsVolume,
+        EncryptApfsVolume, PasswordSource, UnmountApfsVolume,
     },
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
+use crate::os::darwin::MacosVersion;
+use crate::settings::NIX_ROOT;
 use std::{
     path::{Path, PathBuf},
     time::Duration,
@@ -22,6 +24,8 @@ pub const NIX_VOLUME_MOUNTD_DEST: &str = "/Library/LaunchDaemons/org.nixos.darwi
 pub struct CreateNixVolume {
     disk: PathBuf,
     name: String,
+    #[serde(default = "default_mount_point")]
+    mount_point: PathBuf,
     case_sensitive: bool,
     encrypt: bool,
     create_or_append_synthetic_conf: StatefulAction<CreateOrInsertIntoFile>,
@@ -41,22 +45,34 @@ impl CreateNixVolume {
     pub async fn plan(
         disk: impl AsRef<Path>,
         name: String,
+        mount_point: impl AsRef<Path>,
         case_sensitive: bool,
         encrypt: bool,
+        password_source: PasswordSource,
+        macos_version: MacosVersion,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let disk = disk.as_ref();
+        let mount_point = mount_point.as_ref();
+        // `/etc/synthetic.conf` names directories directly under `/` without the leading slash
+        let synthetic_name = mount_point
+            .strip_prefix("/")
+            .unwrap_or(mount_point)
+            .display();
         let create_or_append_synthetic_conf = CreateOrInsertIntoFile::plan(
             "/etc/synthetic.conf",
             None,
             None,
             None,
-            "nix\n".into(), /* The newline is required otherwise it segfaults */
+            format!("{synthetic_name}\n"), /* The newline is required otherwise it segfaults */
             create_or_insert_into_file::Position::End,
+            false,
         )
         .await
         .map_err(Self::error)?;
 
-        let create_synthetic_objects = CreateSyntheticObjects::plan().await.map_err(Self::error)?;
+        let create_synthetic_objects = CreateSyntheticObjects::plan(macos_version)
+            .await
+            .map_err(Self::error)?;
 
         let unmount_volume = UnmountApfsVolume::plan(disk, name.clone())
             .await
@@ -66,12 +82,12 @@ impl CreateNixVolume {
             .await
             .map_err(Self::error)?;
 
-        let create_fstab_entry = CreateFstabEntry::plan(name.clone(), &create_volume)
+        let create_fstab_entry = CreateFstabEntry::plan(name.clone(), mount_point, &create_volume)
             .await
             .map_err(Self::error)?;
 
         let encrypt_volume = if encrypt {
-            Some(EncryptApfsVolume::plan(disk, &name, &create_volume).await?)
+            Some(EncryptApfsVolume::plan(disk, &name, password_source, &create_volume).await?)
         } else {
             None
         };
@@ -80,7 +96,7 @@ impl CreateNixVolume {
             NIX_VOLUME_MOUNTD_DEST,
             "org.nixos.darwin-store",
             name.clone(),
-            "/nix",
+            mount_point,
             encrypt,
         )
         .await
@@ -97,11 +113,14 @@ impl CreateNixVolume {
             KickstartLaunchctlService::plan("system", "org.nixos.darwin-store")
                 .await
                 .map_err(Self::error)?;
-        let enable_ownership = EnableOwnership::plan("/nix").await.map_err(Self::error)?;
+        let enable_ownership = EnableOwnership::plan(mount_point)
+            .await
+            .map_err(Self::error)?;
 
         Ok(Self {
             disk: disk.to_path_buf(),
             name,
+            mount_point: mount_point.to_path_buf(),
             case_sensitive,
             encrypt,
             create_or_append_synthetic_conf,
@@ -119,6 +138,10 @@ impl CreateNixVolume {
     }
 }
 
+fn default_mount_point() -> PathBuf {
+    PathBuf::from(NIX_ROOT)
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "create_apfs_volume")]
 impl Action for CreateNixVolume {
@@ -127,10 +150,11 @@ impl Action for CreateNixVolume {
     }
     fn tracing_synopsis(&self) -> String {
         format!(
-            "Create an{maybe_encrypted} APFS volume `{name}` for Nix on `{disk}` and add it to `/etc/fstab` mounting on `/nix`",
-            maybe_encrypted = if self.encrypt { " encrypted" } else { "" }, 
+            "Create an{maybe_encrypted} APFS volume `{name}` for Nix on `{disk}` and add it to `/etc/fstab` mounting on `{mount_point}`",
+            maybe_encrypted = if self.encrypt { " encrypted" } else { "" },
             name = self.name,
             disk = self.disk.display(),
+            mount_point = self.mount_point.display(),
         )
     }
 
@@ -163,6 +187,46 @@ impl Action for CreateNixVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn preflight(&self) -> Result<(), ActionError> {
+        self.create_or_append_synthetic_conf
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.create_synthetic_objects
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.create_volume
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.create_fstab_entry
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        if let Some(encrypt_volume) = &self.encrypt_volume {
+            encrypt_volume.try_preflight().await.map_err(Self::error)?;
+        }
+        self.setup_volume_daemon
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.bootstrap_volume
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.kickstart_launchctl_service
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        self.enable_ownership
+            .try_preflight()
+            .await
+            .map_err(Self::error)?;
+        Ok(())
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         self.create_or_append_synthetic_conf
@@ -228,7 +292,8 @@ impl Action for CreateNixVolume {
         let mut retry_tokens: usize = 50;
         loop {
             let mut command = Command::new("/usr/sbin/diskutil");
-            command.args(["info", "/nix"]);
+            command.arg("info");
+            command.arg(&self.mount_point);
             command.stderr(std::process::Stdio::null());
             command.stdout(std::process::Stdio::null());
             tracing::trace!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store mount path existence");
@@ -257,6 +322,22 @@ impl Action for CreateNixVolume {
         Ok(())
     }
 
+    fn estimated_duration(&self) -> Duration {
+        let mut duration = self.create_or_append_synthetic_conf.estimated_duration()
+            + self.create_synthetic_objects.estimated_duration()
+            + self.unmount_volume.estimated_duration()
+            + self.create_volume.estimated_duration()
+            + self.create_fstab_entry.estimated_duration()
+            + self.setup_volume_daemon.estimated_duration()
+            + self.bootstrap_volume.estimated_duration()
+            + self.kickstart_launchctl_service.estimated_duration()
+            + self.enable_ownership.estimated_duration();
+        if let Some(encrypt_volume) = &self.encrypt_volume {
+            duration += encrypt_volume.estimated_duration();
+        }
+        duration
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
         let mut explanation = vec![
             self.create_or_append_synthetic_conf.tracing_synopsis(),
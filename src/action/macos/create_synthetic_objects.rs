@@ -2,17 +2,72 @@ use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::execute_command;
+use crate::os::darwin::MacosVersion;
 
 use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
 
+const APFS_UTIL: &str = "/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util";
+
 /// Create the synthetic objects defined in `/etc/synthetic.conf`
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
-pub struct CreateSyntheticObjects;
+#[serde(from = "CreateSyntheticObjectsReceipt")]
+pub struct CreateSyntheticObjects {
+    /// Receipts from before this was recorded do not know the version, so both strategies are tried
+    macos_version: Option<MacosVersion>,
+}
+
+/// This action used to be a unit struct, which older receipts record as `null`
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum CreateSyntheticObjectsReceipt {
+    Unit(()),
+    Versioned {
+        #[serde(default)]
+        macos_version: Option<MacosVersion>,
+    },
+}
+
+impl From<CreateSyntheticObjectsReceipt> for CreateSyntheticObjects {
+    fn from(receipt: CreateSyntheticObjectsReceipt) -> Self {
+        match receipt {
+            CreateSyntheticObjectsReceipt::Unit(()) => Self {
+                macos_version: None,
+            },
+            CreateSyntheticObjectsReceipt::Versioned { macos_version } => Self { macos_version },
+        }
+    }
+}
 
 impl CreateSyntheticObjects {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
-        Ok(Self.into())
+    pub async fn plan(macos_version: MacosVersion) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            macos_version: Some(macos_version),
+        }
+        .into())
+    }
+
+    /// `apfs.util -t` stitches synthetic objects on Big Sur and later, Catalina only understands `-B`
+    fn apfs_util_args(&self) -> &'static [&'static str] {
+        match self.macos_version {
+            Some(version) if version >= MacosVersion::BIG_SUR => &["-t"],
+            Some(_) => &["-B"],
+            None => &["-t", "-B"],
+        }
+    }
+
+    async fn stitch(&self) {
+        // Yup we literally ignore the error! Reasoning: https://github.com/NixOS/nix/blob/95331cb9c99151cbd790ceb6ddaf49fc1c0da4b3/scripts/create-darwin-volume.sh#L261
+        for arg in self.apfs_util_args() {
+            execute_command(
+                Command::new(APFS_UTIL)
+                    .process_group(0)
+                    .arg(arg)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .ok(); // Deliberate
+        }
     }
 }
 
@@ -27,7 +82,11 @@ impl Action for CreateSyntheticObjects {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "create_synthetic_objects",)
+        span!(
+            tracing::Level::DEBUG,
+            "create_synthetic_objects",
+            macos_version = self.macos_version.map(tracing::field::display),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -39,23 +98,7 @@ impl Action for CreateSyntheticObjects {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        // Yup we literally call both and ignore the error! Reasoning: https://github.com/NixOS/nix/blob/95331cb9c99151cbd790ceb6ddaf49fc1c0da4b3/scripts/create-darwin-volume.sh#L261
-        execute_command(
-            Command::new("/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util")
-                .process_group(0)
-                .arg("-t")
-                .stdin(std::process::Stdio::null()),
-        )
-        .await
-        .ok(); // Deliberate
-        execute_command(
-            Command::new("/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util")
-                .process_group(0)
-                .arg("-B")
-                .stdin(std::process::Stdio::null()),
-        )
-        .await
-        .ok(); // Deliberate
+        self.stitch().await;
 
         Ok(())
     }
@@ -69,23 +112,7 @@ impl Action for CreateSyntheticObjects {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        // Yup we literally call both and ignore the error! Reasoning: https://github.com/NixOS/nix/blob/95331cb9c99151cbd790ceb6ddaf49fc1c0da4b3/scripts/create-darwin-volume.sh#L261
-        execute_command(
-            Command::new("/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util")
-                .process_group(0)
-                .arg("-t")
-                .stdin(std::process::Stdio::null()),
-        )
-        .await
-        .ok(); // Deliberate
-        execute_command(
-            Command::new("/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util")
-                .process_group(0)
-                .arg("-B")
-                .stdin(std::process::Stdio::null()),
-        )
-        .await
-        .ok(); // Deliberate
+        self.stitch().await;
 
         Ok(())
     }
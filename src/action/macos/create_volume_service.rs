@@ -109,8 +109,8 @@ impl CreateVolumeService {
                         this.path.display()
                     );
 
-                    // If there is already a line in `/etc/fstab` with `/nix` in it, the user will likely experience an error during execute,
-                    // so check if there exists a line, which is not a comment, that contains `/nix`
+                    // If there is already a line in `/etc/fstab` with the mount point in it, the user will likely experience an error during execute,
+                    // so check if there exists a line, which is not a comment, that contains the mount point
                     let fstab = PathBuf::from("/etc/fstab");
                     if fstab.exists() {
                         let contents = tokio::fs::read_to_string(&fstab)
@@ -122,8 +122,8 @@ impl CreateVolumeService {
                             }
                             let split = line.split_whitespace();
                             for item in split {
-                                if item == "/nix" {
-                                    return Err(Self::error(CreateVolumeServiceError::VolumeDoesNotExistButVolumeServiceAndFstabEntryDoes(this.path.clone(), this.apfs_volume_label)));
+                                if Path::new(item) == this.mount_point {
+                                    return Err(Self::error(CreateVolumeServiceError::VolumeDoesNotExistButVolumeServiceAndFstabEntryDoes(this.path.clone(), this.apfs_volume_label, this.mount_point)));
                                 }
                             }
                         }
@@ -311,8 +311,8 @@ pub enum CreateVolumeServiceError {
     },
     #[error("UUID for APFS volume labelled `{0}` was not found")]
     CannotDetermineUuid(String),
-    #[error("An APFS volume labelled `{1}` does not exist, but there exists an fstab entry for that volume, as well as a service file at `{0}`. Consider removing the line containing `/nix` from the `/etc/fstab` and running `rm {0}`")]
-    VolumeDoesNotExistButVolumeServiceAndFstabEntryDoes(PathBuf, String),
+    #[error("An APFS volume labelled `{1}` does not exist, but there exists an fstab entry for that volume, as well as a service file at `{0}`. Consider removing the line containing `{}` from the `/etc/fstab` and running `rm {0}`", .2.display())]
+    VolumeDoesNotExistButVolumeServiceAndFstabEntryDoes(PathBuf, String, PathBuf),
 }
 
 impl Into<ActionErrorKind> for CreateVolumeServiceError {
@@ -10,12 +10,37 @@ use rand::Rng;
 use std::{
     path::{Path, PathBuf},
     process::Stdio,
+    time::Duration,
 };
 use tokio::process::Command;
 use tracing::{span, Span};
 
 use super::CreateApfsVolume;
 
+const SYSTEM_KEYCHAIN: &str = "/Library/Keychains/System.keychain";
+
+/// Where the password used to encrypt an APFS volume comes from
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordSource {
+    /// Generate a random password
+    #[default]
+    Generate,
+    /// Run a command (the program followed by its arguments) and use its standard output as the password
+    Command(Vec<String>),
+    /// Use the password already stored in the System keychain under the volume label, such as one provisioned by an MDM
+    Keychain,
+    /// Ask for the password on the terminal
+    Prompt,
+}
+
+impl PasswordSource {
+    /// If the password is stored in the keychain by the installer, rather than being there already
+    fn adds_keychain_entry(&self) -> bool {
+        !matches!(self, PasswordSource::Keychain)
+    }
+}
+
 /**
 Encrypt an APFS volume
  */
@@ -23,6 +48,8 @@ Encrypt an APFS volume
 pub struct EncryptApfsVolume {
     disk: PathBuf,
     name: String,
+    #[serde(default)]
+    password_source: PasswordSource,
 }
 
 impl EncryptApfsVolume {
@@ -30,16 +57,50 @@ impl EncryptApfsVolume {
     pub async fn plan(
         disk: impl AsRef<Path>,
         name: impl AsRef<str>,
+        password_source: PasswordSource,
         planned_create_apfs_volume: &StatefulAction<CreateApfsVolume>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let name = name.as_ref().to_owned();
         let disk = disk.as_ref().to_path_buf();
 
+        if let PasswordSource::Command(password_command) = &password_source {
+            let program = password_command
+                .first()
+                .ok_or_else(|| Self::error(EncryptApfsVolumeError::EmptyPasswordCommand))?;
+            if which::which(program).is_err() {
+                return Err(Self::error(
+                    EncryptApfsVolumeError::PasswordCommandNotFound(program.clone()),
+                ));
+            }
+        }
+
+        if password_source == PasswordSource::Keychain {
+            if !keychain_password_exists(&name).await.map_err(Self::error)? {
+                return Err(Self::error(
+                    EncryptApfsVolumeError::KeychainPasswordNotFound(name),
+                ));
+            }
+            if planned_create_apfs_volume.state == ActionState::Completed {
+                // The volume was already created and the keychain can unlock it
+                return Ok(StatefulAction::completed(Self {
+                    name,
+                    disk,
+                    password_source,
+                }));
+            }
+            return Ok(StatefulAction::uncompleted(Self {
+                name,
+                disk,
+                password_source,
+            }));
+        }
+
         let mut command = Command::new("/usr/bin/security");
         command.args(["find-generic-password", "-a"]);
         command.arg(&name);
+        // The mount service looks the password up by the volume label, see `CreateVolumeService`
         command.arg("-s");
-        command.arg("Nix Store");
+        command.arg(&name);
         command.arg("-l");
         command.arg(&format!("{} encryption password", disk.display()));
         command.arg("-D");
@@ -57,7 +118,11 @@ impl EncryptApfsVolume {
             // The user has a password matching what we would create.
             if planned_create_apfs_volume.state == ActionState::Completed {
                 // We detected a created volume already, and a password exists, so we can keep using that and skip doing anything
-                return Ok(StatefulAction::completed(Self { name, disk }));
+                return Ok(StatefulAction::completed(Self {
+                    name,
+                    disk,
+                    password_source,
+                }));
             }
 
             // Ask the user to remove it
@@ -86,7 +151,11 @@ impl EncryptApfsVolume {
                 if volume.name == name {
                     match volume.encryption == false {
                         true => {
-                            return Ok(StatefulAction::completed(Self { disk, name }));
+                            return Ok(StatefulAction::completed(Self {
+                                disk,
+                                name,
+                                password_source,
+                            }));
                         },
                         false => {
                             return Err(Self::error(
@@ -98,7 +167,11 @@ impl EncryptApfsVolume {
             }
         }
 
-        Ok(StatefulAction::uncompleted(Self { name, disk }))
+        Ok(StatefulAction::uncompleted(Self {
+            name,
+            disk,
+            password_source,
+        }))
     }
 }
 
@@ -132,22 +205,19 @@ impl Action for EncryptApfsVolume {
         disk = %self.disk.display(),
     ))]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { disk, name } = self;
-
-        // Generate a random password.
-        let password: String = {
-            const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                abcdefghijklmnopqrstuvwxyz\
-                                    0123456789)(*&^%$#@!~";
-            const PASSWORD_LEN: usize = 32;
-            let mut rng = rand::thread_rng();
-
-            (0..PASSWORD_LEN)
-                .map(|_| {
-                    let idx = rng.gen_range(0..CHARSET.len());
-                    CHARSET[idx] as char
-                })
-                .collect()
+        let Self {
+            disk,
+            name,
+            password_source,
+        } = self;
+
+        let password = match password_source {
+            PasswordSource::Generate => generate_password(),
+            PasswordSource::Command(password_command) => password_from_command(password_command)
+                .await
+                .map_err(Self::error)?,
+            PasswordSource::Keychain => password_from_keychain(name).await.map_err(Self::error)?,
+            PasswordSource::Prompt => password_from_prompt(name).map_err(Self::error)?,
         };
 
         let disk_str = disk.to_str().expect("Could not turn disk into string"); /* Should not reasonably ever fail */
@@ -157,35 +227,37 @@ impl Action for EncryptApfsVolume {
             .map_err(Self::error)?;
 
         // Add the password to the user keychain so they can unlock it later.
-        execute_command(
-            Command::new("/usr/bin/security").process_group(0).args([
-                "add-generic-password",
-                "-a",
-                name.as_str(),
-                "-s",
-                "Nix Store",
-                "-l",
-                format!("{} encryption password", disk_str).as_str(),
-                "-D",
-                "Encrypted volume password",
-                "-j",
-                format!(
-                    "Added automatically by the Nix installer for use by {NIX_VOLUME_MOUNTD_DEST}"
-                )
-                .as_str(),
-                "-w",
-                password.as_str(),
-                "-T",
-                "/System/Library/CoreServices/APFSUserAgent",
-                "-T",
-                "/System/Library/CoreServices/CSUserAgent",
-                "-T",
-                "/usr/bin/security",
-                "/Library/Keychains/System.keychain",
-            ]),
-        )
-        .await
-        .map_err(Self::error)?;
+        if password_source.adds_keychain_entry() {
+            execute_command(
+                Command::new("/usr/bin/security").process_group(0).args([
+                    "add-generic-password",
+                    "-a",
+                    name.as_str(),
+                    "-s",
+                    name.as_str(),
+                    "-l",
+                    format!("{} encryption password", disk_str).as_str(),
+                    "-D",
+                    "Encrypted volume password",
+                    "-j",
+                    format!(
+                        "Added automatically by the Nix installer for use by {NIX_VOLUME_MOUNTD_DEST}"
+                    )
+                    .as_str(),
+                    "-w",
+                    password.as_str(),
+                    "-T",
+                    "/System/Library/CoreServices/APFSUserAgent",
+                    "-T",
+                    "/System/Library/CoreServices/CSUserAgent",
+                    "-T",
+                    "/usr/bin/security",
+                    "/Library/Keychains/System.keychain",
+                ]),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
 
         // Encrypt the mounted volume
         execute_command(Command::new("/usr/sbin/diskutil").process_group(0).args([
@@ -213,7 +285,15 @@ impl Action for EncryptApfsVolume {
         Ok(())
     }
 
+    fn estimated_duration(&self) -> Duration {
+        // The volume is mounted, encrypted and unmounted again
+        Duration::from_secs(5)
+    }
+
     fn revert_description(&self) -> Vec<ActionDescription> {
+        if !self.password_source.adds_keychain_entry() {
+            return vec![];
+        }
         vec![ActionDescription::new(
             format!(
                 "Remove encryption keys for volume `{}`",
@@ -227,6 +307,10 @@ impl Action for EncryptApfsVolume {
         disk = %self.disk.display(),
     ))]
     async fn revert(&mut self) -> Result<(), ActionError> {
+        if !self.password_source.adds_keychain_entry() {
+            // The keychain entry was there before the install, so it is left in place
+            return Ok(());
+        }
         let disk_str = self.disk.to_str().expect("Could not turn disk into string"); /* Should not reasonably ever fail */
 
         // TODO: This seems very rough and unsafe
@@ -255,18 +339,188 @@ impl Action for EncryptApfsVolume {
     }
 }
 
+fn generate_password() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                            abcdefghijklmnopqrstuvwxyz\
+                                0123456789)(*&^%$#@!~";
+    const PASSWORD_LEN: usize = 32;
+    let mut rng = rand::thread_rng();
+
+    (0..PASSWORD_LEN)
+        .map(|_| {
+            let idx = rng.gen_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// Run the password command, its output is deliberately never logged or included in errors
+async fn password_from_command(password_command: &[String]) -> Result<String, ActionErrorKind> {
+    let (program, args) = password_command
+        .split_first()
+        .ok_or(EncryptApfsVolumeError::EmptyPasswordCommand)?;
+
+    let mut command = Command::new(program);
+    command.args(args);
+    command.process_group(0);
+    command.stdin(Stdio::null());
+    command.stderr(Stdio::null());
+    let output = command
+        .output()
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))?;
+    if !output.status.success() {
+        return Err(EncryptApfsVolumeError::PasswordCommandFailed(
+            program.clone(),
+            output.status,
+        ))?;
+    }
+
+    let password = String::from_utf8(output.stdout)
+        .map_err(|_| EncryptApfsVolumeError::PasswordCommandNotUtf8(program.clone()))?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(EncryptApfsVolumeError::PasswordCommandEmptyOutput(
+            program.clone(),
+        ))?;
+    }
+
+    Ok(password.to_string())
+}
+
+/// If the System keychain has a password for the `name` volume, as looked up by the mount service
+async fn keychain_password_exists(name: &str) -> Result<bool, ActionErrorKind> {
+    let mut command = Command::new("/usr/bin/security");
+    command.args(["find-generic-password", "-s"]);
+    command.arg(name);
+    command.arg(SYSTEM_KEYCHAIN);
+    command.process_group(0);
+    command.stdin(Stdio::null());
+    command.stdout(Stdio::null());
+    command.stderr(Stdio::null());
+    Ok(command
+        .status()
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))?
+        .success())
+}
+
+/// Read the password for the `name` volume from the System keychain, it is deliberately never logged or included in errors
+async fn password_from_keychain(name: &str) -> Result<String, ActionErrorKind> {
+    let mut command = Command::new("/usr/bin/security");
+    command.args(["find-generic-password", "-s"]);
+    command.arg(name);
+    command.arg("-w");
+    command.arg(SYSTEM_KEYCHAIN);
+    command.process_group(0);
+    command.stdin(Stdio::null());
+    command.stderr(Stdio::null());
+    let output = command
+        .output()
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))?;
+    if !output.status.success() {
+        return Err(EncryptApfsVolumeError::KeychainPasswordNotFound(
+            name.to_string(),
+        ))?;
+    }
+
+    let password = String::from_utf8(output.stdout)
+        .map_err(|_| EncryptApfsVolumeError::KeychainPasswordNotFound(name.to_string()))?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Ask for the password for the `name` volume on the terminal without echoing it
+fn password_from_prompt(name: &str) -> Result<String, ActionErrorKind> {
+    use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+    use std::os::unix::io::AsRawFd;
+
+    let tty_path = Path::new("/dev/tty");
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(tty_path)
+        .map_err(|e| ActionErrorKind::Open(tty_path.to_path_buf(), e))?;
+    let fd = tty.as_raw_fd();
+
+    let original = tcgetattr(fd).map_err(EncryptApfsVolumeError::Terminal)?;
+    let mut hidden = original.clone();
+    hidden.local_flags.remove(LocalFlags::ECHO);
+    hidden.local_flags.insert(LocalFlags::ECHONL);
+    tcsetattr(fd, SetArg::TCSANOW, &hidden).map_err(EncryptApfsVolumeError::Terminal)?;
+
+    // Restore echoing before looking at the result, even if reading failed
+    let read = read_password_line(&mut tty, name);
+    tcsetattr(fd, SetArg::TCSANOW, &original).map_err(EncryptApfsVolumeError::Terminal)?;
+    let password = read.map_err(|e| ActionErrorKind::Read(tty_path.to_path_buf(), e))?;
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(EncryptApfsVolumeError::EmptyPromptedPassword)?;
+    }
+
+    Ok(password.to_string())
+}
+
+fn read_password_line(tty: &mut std::fs::File, name: &str) -> std::io::Result<String> {
+    use std::io::{BufRead, BufReader, Write};
+
+    write!(tty, "Password to encrypt the `{name}` APFS volume with: ")?;
+    tty.flush()?;
+    let mut line = String::new();
+    BufReader::new(tty).read_line(&mut line)?;
+    Ok(line)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum EncryptApfsVolumeError {
-    #[error("The keychain has an existing password for a non-existing \"{0}\" volume on disk `{1}`, consider removing the password with `security delete-generic-password  -a \"{0}\" -s \"Nix Store\" -l \"{1} encryption password\" -D \"Encrypted volume password\"`")]
+    #[error("The keychain has an existing password for a non-existing \"{0}\" volume on disk `{1}`, consider removing the password with `security delete-generic-password  -a \"{0}\" -s \"{0}\" -l \"{1} encryption password\" -D \"Encrypted volume password\"`")]
     ExistingPasswordFound(String, PathBuf),
     #[error("The keychain lacks a password for the already existing \"{0}\" volume on disk `{1}`, consider removing the volume with `diskutil apfs deleteVolume \"{0}\"` (if you receive error -69888, you may need to run `launchctl bootout system/org.nixos.darwin-store` and `launchctl bootout system/org.nixos.nix-daemon` first)")]
     MissingPasswordForExistingVolume(String, PathBuf),
     #[error("The existing APFS volume \"{0}\" on disk `{1}` is not encrypted but it should be, consider removing the volume with `diskutil apfs deleteVolume \"{0}\"` (if you receive error -69888, you may need to run `launchctl bootout system/org.nixos.darwin-store` and `launchctl bootout system/org.nixos.nix-daemon` first)")]
     ExistingVolumeNotEncrypted(String, PathBuf),
+    #[error("The password command for the APFS volume was empty, it must contain at least the program to run")]
+    EmptyPasswordCommand,
+    #[error("The password command `{0}` for the APFS volume could not be found")]
+    PasswordCommandNotFound(String),
+    #[error("The password command `{0}` for the APFS volume failed with {1}")]
+    PasswordCommandFailed(String, std::process::ExitStatus),
+    #[error("The password command `{0}` for the APFS volume did not output valid UTF-8")]
+    PasswordCommandNotUtf8(String),
+    #[error("The password command `{0}` for the APFS volume did not output a password")]
+    PasswordCommandEmptyOutput(String),
+    #[error("The System keychain has no password for the \"{0}\" volume, add one with `security add-generic-password -a \"{0}\" -s \"{0}\" -w /Library/Keychains/System.keychain` or use another password source")]
+    KeychainPasswordNotFound(String),
+    #[error("Could not hide the password typed on the terminal")]
+    Terminal(#[source] nix::errno::Errno),
+    #[error("No password for the APFS volume was entered")]
+    EmptyPromptedPassword,
+}
+
+impl From<EncryptApfsVolumeError> for ActionErrorKind {
+    fn from(v: EncryptApfsVolumeError) -> ActionErrorKind {
+        ActionErrorKind::Custom(Box::new(v))
+    }
 }
 
-impl Into<ActionErrorKind> for EncryptApfsVolumeError {
-    fn into(self) -> ActionErrorKind {
-        ActionErrorKind::Custom(Box::new(self))
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn password_command_output_is_used() -> eyre::Result<()> {
+        let password = password_from_command(&[
+            "sh".into(),
+            "-c".into(),
+            "printf 'correct horse battery staple\\n'".into(),
+        ])
+        .await?;
+        assert_eq!(password, "correct horse battery staple");
+
+        assert!(password_from_command(&[]).await.is_err());
+        assert!(password_from_command(&["false".into()]).await.is_err());
+        assert!(password_from_command(&["true".into()]).await.is_err());
+        Ok(())
     }
 }
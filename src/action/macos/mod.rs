@@ -18,7 +18,7 @@ pub use create_nix_volume::{CreateNixVolume, NIX_VOLUME_MOUNTD_DEST};
 pub use create_synthetic_objects::CreateSyntheticObjects;
 pub use create_volume_service::CreateVolumeService;
 pub use enable_ownership::{EnableOwnership, EnableOwnershipError};
-pub use encrypt_apfs_volume::EncryptApfsVolume;
+pub use encrypt_apfs_volume::{EncryptApfsVolume, PasswordSource};
 pub use kickstart_launchctl_service::KickstartLaunchctlService;
 use serde::Deserialize;
 use tokio::process::Command;
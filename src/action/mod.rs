@@ -163,6 +163,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.user_agent.clone(),
         )?)
     }
 }
@@ -170,7 +171,7 @@ impl Planner for MyPlanner {
 # async fn custom_planner_install() -> color_eyre::Result<()> {
 let planner = MyPlanner::default().await?;
 let mut plan = InstallPlan::plan(planner).await?;
-match plan.install(None).await {
+match plan.install(None, None).await {
     Ok(()) => tracing::info!("Done"),
     Err(e) => {
         match e.source() {
@@ -185,16 +186,20 @@ match plan.install(None).await {
 # }
 ```
 
+A custom [`Action`] can also be appended to the plan of a built-in planner with
+[`InstallPlanBuilder::extra_action`](crate::InstallPlanBuilder::extra_action).
+
 */
 
 pub mod base;
 pub mod common;
+pub(crate) mod context;
 pub mod linux;
 pub mod macos;
 mod stateful;
 
 pub use stateful::{ActionState, StatefulAction};
-use std::{error::Error, process::Output};
+use std::{error::Error, ffi::OsStr, process::Output};
 use tokio::task::JoinError;
 use tracing::Span;
 
@@ -245,6 +250,73 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
     ///
     /// /// This is called by [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) through [`StatefulAction::try_revert`] which handles tracing as well as if the action needs to revert based on its `action_state`.
     async fn revert(&mut self) -> Result<(), ActionError>;
+    /// The [`ActionTag`]s of earlier actions this action must run after
+    ///
+    /// The default of `None` means this action depends on every action before it. Returning a list
+    /// lets [`InstallPlan::install_with_concurrency`](crate::InstallPlan::install_with_concurrency) run this action alongside the others.
+    fn depends_on(&self) -> Option<Vec<ActionTag>> {
+        None
+    }
+    /// Any files written during execution which have since been modified by something else
+    ///
+    /// If this action calls sub-[`Action`]s, it should include their drift.
+    ///
+    /// This is called by [`InstallPlan::check_drift`](crate::InstallPlan::check_drift).
+    fn check_drift(&self) -> Vec<std::path::PathBuf> {
+        Vec::new()
+    }
+    /// Any files which execution may create, replace or modify
+    ///
+    /// If this action calls sub-[`Action`]s, it should include their paths.
+    ///
+    /// This is called by [`InstallPlan::install`](crate::InstallPlan::install) to capture a [`SystemSnapshot`](crate::SystemSnapshot) when one was requested.
+    fn touched_paths(&self) -> Vec<std::path::PathBuf> {
+        Vec::new()
+    }
+    /// A rough guess of how long [`execute`][Action::execute] takes, only meant to help plan an install
+    ///
+    /// The default suits actions which write a file or run a short command. If this action calls sub-[`Action`]s, it should sum their [`StatefulAction::estimated_duration`] so completed ones are left out.
+    ///
+    /// This is summed by [`InstallPlan::estimated_duration`](crate::InstallPlan::estimated_duration).
+    fn estimated_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(100)
+    }
+    /// How many bytes [`execute`][Action::execute] writes, each with a path on the filesystem it writes them to
+    ///
+    /// The path need not exist yet. If this action calls sub-[`Action`]s, it should include their [`StatefulAction::required_disk_space`] so completed ones are left out.
+    ///
+    /// This is checked against the free space of each filesystem by [`InstallPlan::install`](crate::InstallPlan::install) before any action executes.
+    fn required_disk_space(&self) -> Vec<(std::path::PathBuf, u64)> {
+        Vec::new()
+    }
+    /// How much of an install executing this action is, relative to other actions, for rendering progress
+    ///
+    /// The default is one per 100ms of [`estimated_duration`][Action::estimated_duration], and at
+    /// least one, so actions which call sub-[`Action`]s and sum their durations also sum their
+    /// weights. Override it only if the duration is a poor measure of the share of the work.
+    ///
+    /// This is summed into the weights of [`InstallEvent::ActionStarted`](crate::InstallEvent::ActionStarted).
+    fn weight(&self) -> u32 {
+        u32::try_from(self.estimated_duration().as_millis() / 100)
+            .unwrap_or(u32::MAX)
+            .max(1)
+    }
+    /// Check the preconditions of [`execute`][Action::execute] still hold, without changing the system
+    ///
+    /// If this action calls sub-[`Action`]s, care should be taken to call [`try_preflight`][StatefulAction::try_preflight] on those actions, not [`preflight`][Action::preflight].
+    ///
+    /// This is called by [`InstallPlan::dry_run`](crate::InstallPlan::dry_run) through [`StatefulAction::try_preflight`] which will skip the check if the action is completed.
+    async fn preflight(&self) -> Result<(), ActionError> {
+        Ok(())
+    }
+    /// The shell commands [`execute`][Action::execute] would run, or `None` if they cannot be expressed in a script
+    ///
+    /// If this action calls sub-[`Action`]s, care should be taken to call [`to_shell`][StatefulAction::to_shell] on those actions, so completed ones are left out.
+    ///
+    /// This is called by [`InstallPlan::to_shell_script`](crate::InstallPlan::to_shell_script) through [`StatefulAction::to_shell`].
+    fn to_shell(&self) -> Option<Vec<String>> {
+        None
+    }
 
     fn stateful(self) -> StatefulAction<Self>
     where
@@ -253,6 +325,7 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
         StatefulAction {
             action: self,
             state: ActionState::Uncompleted,
+            timeout: None,
         }
     }
 
@@ -268,6 +341,28 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
 
 dyn_clone::clone_trait_object!(Action);
 
+/// Quote `arg` for a POSIX shell, leaving it bare if it has no special characters
+pub(crate) fn shell_quote(arg: impl AsRef<OsStr>) -> String {
+    let arg = arg.as_ref().to_string_lossy();
+    let is_plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c));
+    if is_plain {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// A shell command line running `args`, each quoted with [`shell_quote`]
+pub(crate) fn shell_command<S: AsRef<OsStr>>(args: impl IntoIterator<Item = S>) -> String {
+    args.into_iter()
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 /**
 A description of an [`Action`](crate::action::Action), intended for humans to review
 */
@@ -287,8 +382,18 @@ impl ActionDescription {
 }
 
 /// A 'tag' name an action has that corresponds to the one we serialize in [`typetag]`
+///
+/// Tags are cheap to copy and compare, so they can key metrics or pick out the actions of a plan
+/// with [`InstallPlan::actions_tagged`](crate::InstallPlan::actions_tagged).
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ActionTag(&'static str);
 
+impl ActionTag {
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
 impl std::fmt::Display for ActionTag {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.0)
@@ -329,6 +434,16 @@ impl ActionError {
         &self.action_tag
     }
 
+    /// A stable code for the failure, as `<action>.<cause>`, like `fetch-and-unpack-nix.network` or `create-file.eexist`
+    ///
+    /// The action is the innermost one which failed, rather than an action grouping it.
+    pub fn code(&self) -> String {
+        match &self.kind {
+            ActionErrorKind::Child(child) => child.code(),
+            kind => format!("{}.{}", self.action_tag.0.replace('_', "-"), kind.code()),
+        }
+    }
+
     #[cfg(feature = "diagnostics")]
     pub fn diagnostic(&self) -> String {
         use crate::diagnostics::ErrorDiagnostic;
@@ -409,8 +524,12 @@ pub enum ActionErrorKind {
     PathModeMismatch(std::path::PathBuf, u32, u32),
     #[error("Path `{0}` exists, but is not a file, consider removing it with `rm {0}`")]
     PathWasNotFile(std::path::PathBuf),
+    #[error("`{0}` on disk differs from what was written, it may have been modified by another program or the filesystem may have dropped the write")]
+    WriteVerificationFailed(std::path::PathBuf),
     #[error("Path `{0}` exists, but is not a directory, consider removing it with `rm {0}`")]
     PathWasNotDirectory(std::path::PathBuf),
+    #[error("Path `{0}` has no parent directory to write it in")]
+    NoParentDirectory(std::path::PathBuf),
     #[error("Getting metadata for {0}`")]
     GettingMetadata(std::path::PathBuf, #[source] std::io::Error),
     #[error("Creating directory `{0}`")]
@@ -469,6 +588,8 @@ pub enum ActionErrorKind {
     GettingGroupId(String, #[source] nix::errno::Errno),
     #[error("Group `{0}` existed but had a different gid ({1}) than planned ({2})")]
     GroupGidMismatch(String, u32, u32),
+    #[error("GID {0} is already used by group `{1}`, consider choosing a different one with `--nix-build-group-id`")]
+    GidInUse(u32, String),
     #[error("Getting group `{0}`")]
     NoGroup(String),
     #[error("Chowning path `{0}`")]
@@ -541,6 +662,8 @@ pub enum ActionErrorKind {
     SystemdMissing,
     #[error("`{command}` failed, message: {message}")]
     DiskUtilInfoError { command: String, message: String },
+    #[error("An APFS volume named `{0}` already exists, consider removing it with `diskutil apfs deleteVolume {0}`")]
+    VolumeExists(String),
 }
 
 impl ActionErrorKind {
@@ -562,6 +685,123 @@ impl ActionErrorKind {
     }
 }
 
+impl ActionErrorKind {
+    /// A stable code for the cause of the error, like `network`, `eacces`, or `path-mode-mismatch`
+    ///
+    /// Errors caused by a failing system call or request are coded by that cause, whichever variant carries it.
+    pub fn code(&self) -> &'static str {
+        if let Some(code) = cause_code(self) {
+            return code;
+        }
+        match self {
+            // A transparent error skips the custom error itself when walking the chain
+            Self::Custom(err) => cause_code(err.as_ref()).unwrap_or("custom"),
+            Self::Certificate(_) => "certificate",
+            Self::Child(child) => child.kind.code(),
+            Self::MultipleChildren(_) | Self::Multiple(_) => "multiple",
+            Self::DifferentContent(_) => "different-content",
+            Self::FileExists(_) | Self::DirExists(_) | Self::SymlinkExists(_) => "eexist",
+            Self::PathUserMismatch(_, _, _) => "path-user-mismatch",
+            Self::PathGroupMismatch(_, _, _) => "path-group-mismatch",
+            Self::PathModeMismatch(_, _, _) => "path-mode-mismatch",
+            Self::PathWasNotFile(_) => "not-a-file",
+            Self::WriteVerificationFailed(_) => "write-verification-failed",
+            Self::PathWasNotDirectory(_) => "not-a-directory",
+            Self::NoParentDirectory(_) => "no-parent-directory",
+            Self::GettingMetadata(_, _)
+            | Self::CreateDirectory(_, _)
+            | Self::Symlink(_, _, _)
+            | Self::SetPermissions(_, _, _)
+            | Self::Remove(_, _)
+            | Self::Copy(_, _, _)
+            | Self::Rename(_, _, _)
+            | Self::Canonicalize(_, _)
+            | Self::Read(_, _)
+            | Self::ReadDir(_, _)
+            | Self::ReadSymlink(_, _)
+            | Self::Open(_, _)
+            | Self::Write(_, _)
+            | Self::Sync(_, _)
+            | Self::Seek(_, _)
+            | Self::Flush(_, _)
+            | Self::Truncate(_, _)
+            | Self::GettingUserId(_, _)
+            | Self::GettingGroupId(_, _)
+            | Self::Chown(_, _) => "io",
+            Self::UserUidMismatch(_, _, _) => "user-uid-mismatch",
+            Self::UserGidMismatch(_, _, _) => "user-gid-mismatch",
+            Self::NoUser(_) => "no-user",
+            Self::GroupGidMismatch(_, _, _) => "group-gid-mismatch",
+            Self::GidInUse(_, _) => "gid-in-use",
+            Self::NoGroup(_) => "no-group",
+            Self::Command { .. } => "command",
+            Self::CommandOutput { .. } => "command-failed",
+            Self::Join(_) => "join",
+            Self::FromUtf8(_) => "utf8",
+            Self::Plist(_) => "plist",
+            Self::MalformedBinaryTarball => "malformed-tarball",
+            Self::MissingUserCreationCommand
+            | Self::MissingGroupCreationCommand
+            | Self::MissingAddUserToGroupCommand
+            | Self::MissingUserDeletionCommand
+            | Self::MissingGroupDeletionCommand
+            | Self::MissingRemoveUserFromGroupCommand => "missing-command",
+            Self::SystemdMissing => "systemd-missing",
+            Self::DiskUtilInfoError { .. } => "diskutil",
+            Self::VolumeExists(_) => "volume-exists",
+        }
+    }
+}
+
+/// The code of the first I/O, system call, or HTTP error in the chain of `err`, if it has one
+fn cause_code(err: &(dyn Error + 'static)) -> Option<&'static str> {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            return Some(if err.is_timeout() {
+                "timeout"
+            } else {
+                "network"
+            });
+        }
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if let Some(code) = io_code(err) {
+                return Some(code);
+            }
+        }
+        if let Some(errno) = err.downcast_ref::<nix::errno::Errno>() {
+            if let Some(code) = io_code(&std::io::Error::from(*errno)) {
+                return Some(code);
+            }
+        }
+        current = err.source();
+    }
+    None
+}
+
+fn io_code(err: &std::io::Error) -> Option<&'static str> {
+    use nix::errno::Errno;
+    let code = match err.raw_os_error().map(Errno::from_i32) {
+        Some(Errno::EACCES) => "eacces",
+        Some(Errno::EPERM) => "eperm",
+        Some(Errno::ENOENT) => "enoent",
+        Some(Errno::EEXIST) => "eexist",
+        Some(Errno::ENOSPC) => "enospc",
+        Some(Errno::EROFS) => "erofs",
+        Some(Errno::EXDEV) => "exdev",
+        Some(Errno::EBUSY) => "ebusy",
+        Some(Errno::ETIMEDOUT) => "timeout",
+        _ => match err.kind() {
+            std::io::ErrorKind::NotFound => "enoent",
+            std::io::ErrorKind::PermissionDenied => "eacces",
+            std::io::ErrorKind::AlreadyExists => "eexist",
+            std::io::ErrorKind::TimedOut => "timeout",
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
 impl HasExpectedErrors for ActionErrorKind {
     fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
         match self {
@@ -591,6 +831,8 @@ impl crate::diagnostics::ErrorDiagnostic for ActionErrorKind {
             | Self::GettingMetadata(path, _)
             | Self::CreateDirectory(path, _)
             | Self::PathWasNotFile(path)
+            | Self::NoParentDirectory(path)
+            | Self::WriteVerificationFailed(path)
             | Self::Remove(path, _) => {
                 vec![path.to_string_lossy().to_string()]
             },
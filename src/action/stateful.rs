@@ -1,7 +1,9 @@
+use std::{path::PathBuf, time::Duration};
+
 use serde::{Deserialize, Serialize};
 use tracing::{Instrument, Span};
 
-use super::{Action, ActionDescription, ActionError, ActionTag};
+use super::{context::ActionContext, Action, ActionDescription, ActionError, ActionTag};
 
 /// A wrapper around an [`Action`](crate::action::Action) which tracks the [`ActionState`] and
 /// handles some tracing output
@@ -9,6 +11,9 @@ use super::{Action, ActionDescription, ActionError, ActionTag};
 pub struct StatefulAction<A> {
     pub(crate) action: A,
     pub(crate) state: ActionState,
+    /// How long [`InstallPlan::install`](crate::InstallPlan::install) waits for the action to execute, forever if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout: Option<Duration>,
 }
 
 impl<A> From<A> for StatefulAction<A>
@@ -19,14 +24,23 @@ where
         Self {
             action,
             state: ActionState::Uncompleted,
+            timeout: None,
         }
     }
 }
 
 impl StatefulAction<Box<dyn Action>> {
+    /// How long executing the action may take, set with [`with_timeout`](StatefulAction::with_timeout)
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
     pub fn inner_typetag_name(&self) -> &'static str {
         self.action.typetag_name()
     }
+    /// The [`ActionTag`] of the boxed action, as [`Action::action_tag`] can't be called on it
+    pub fn action_tag(&self) -> ActionTag {
+        self.action.typetag_name().into()
+    }
     pub fn tracing_synopsis(&self) -> String {
         self.action.tracing_synopsis()
     }
@@ -43,6 +57,33 @@ impl StatefulAction<Box<dyn Action>> {
             _ => self.action.execute_description(),
         }
     }
+    /// How long executing the action may take, nothing if it has already completed
+    ///
+    /// You should prefer this ([`estimated_duration`][StatefulAction::estimated_duration]) over [`Action::estimated_duration`] as it skips actions which will not execute
+    pub fn estimated_duration(&self) -> Duration {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Duration::ZERO,
+            _ => self.action.estimated_duration(),
+        }
+    }
+    /// How much of an install executing the action is, nothing if it has already completed
+    ///
+    /// You should prefer this ([`weight`][StatefulAction::weight]) over [`Action::weight`] as it skips actions which will not execute
+    pub fn weight(&self) -> u32 {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => 0,
+            _ => self.action.weight(),
+        }
+    }
+    /// How many bytes executing the action writes, and where, nothing if it has already completed
+    ///
+    /// You should prefer this ([`required_disk_space`][StatefulAction::required_disk_space]) over [`Action::required_disk_space`] as it skips actions which will not execute
+    pub fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => vec![],
+            _ => self.action.required_disk_space(),
+        }
+    }
     /// A description of what this action would do during revert
     pub fn describe_revert(&self) -> Vec<ActionDescription> {
         match self.state {
@@ -52,6 +93,28 @@ impl StatefulAction<Box<dyn Action>> {
             _ => self.action.revert_description(),
         }
     }
+    /// The shell commands to execute the action, nothing if it has already completed
+    ///
+    /// You should prefer this ([`to_shell`][StatefulAction::to_shell]) over [`Action::to_shell`] as it skips actions which will not execute
+    pub fn to_shell(&self) -> Option<Vec<String>> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Some(vec![]),
+            _ => self.action.to_shell(),
+        }
+    }
+    /// Check the preconditions of any execution steps
+    ///
+    /// You should prefer this ([`try_preflight`][StatefulAction::try_preflight]) over [`preflight`][Action::preflight] as it skips actions which will not execute
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn try_preflight(&self) -> Result<(), ActionError> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Ok(()),
+            _ => {
+                tracing::debug!("Preflight: {}", self.action.tracing_synopsis());
+                self.action.preflight().await
+            },
+        }
+    }
     /// Perform any execution steps
     ///
     /// You should prefer this ([`try_execute`][StatefulAction::try_execute]) over [`execute`][Action::execute] as it handles [`ActionState`] and does tracing
@@ -85,7 +148,7 @@ impl StatefulAction<Box<dyn Action>> {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn try_revert(&mut self) -> Result<(), ActionError> {
         match self.state {
-            ActionState::Uncompleted => {
+            ActionState::Uncompleted if !ActionContext::current().revert_forced => {
                 tracing::trace!(
                     "Reverted: (Already done) {}",
                     self.action.tracing_synopsis()
@@ -106,6 +169,27 @@ impl StatefulAction<Box<dyn Action>> {
             },
         }
     }
+
+    /// Perform any revert steps, even if the action never completed
+    ///
+    /// Used by a forced [`uninstall`](crate::InstallPlan::uninstall) to remove as much as possible.
+    /// The actions nested within are forced too. Actions which were
+    /// [`Skipped`](ActionState::Skipped) are still not reverted, as they found nothing to do.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn try_revert_force(&mut self) -> Result<(), ActionError> {
+        if self.state == ActionState::Uncompleted {
+            tracing::debug!(
+                "Forcing revert of uncompleted: {}",
+                self.action.tracing_synopsis()
+            );
+        }
+        ActionContext {
+            revert_forced: true,
+            ..ActionContext::current()
+        }
+        .scope(self.try_revert())
+        .await
+    }
 }
 
 impl<A> StatefulAction<A>
@@ -131,6 +215,12 @@ where
         &self.action
     }
 
+    /// Fail the install if executing the action takes longer than `timeout`
+    pub fn with_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
     pub fn boxed(self) -> StatefulAction<Box<dyn Action>>
     where
         Self: 'static,
@@ -138,6 +228,7 @@ where
         StatefulAction {
             action: Box::new(self.action),
             state: self.state,
+            timeout: self.timeout,
         }
     }
     /// A description of what this action would do during execution
@@ -147,6 +238,33 @@ where
         }
         return self.action.execute_description();
     }
+    /// How long executing the action may take, nothing if it has already completed
+    ///
+    /// You should prefer this ([`estimated_duration`][StatefulAction::estimated_duration]) over [`Action::estimated_duration`] as it skips actions which will not execute
+    pub fn estimated_duration(&self) -> Duration {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Duration::ZERO,
+            _ => self.action.estimated_duration(),
+        }
+    }
+    /// How much of an install executing the action is, nothing if it has already completed
+    ///
+    /// You should prefer this ([`weight`][StatefulAction::weight]) over [`Action::weight`] as it skips actions which will not execute
+    pub fn weight(&self) -> u32 {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => 0,
+            _ => self.action.weight(),
+        }
+    }
+    /// How many bytes executing the action writes, and where, nothing if it has already completed
+    ///
+    /// You should prefer this ([`required_disk_space`][StatefulAction::required_disk_space]) over [`Action::required_disk_space`] as it skips actions which will not execute
+    pub fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => vec![],
+            _ => self.action.required_disk_space(),
+        }
+    }
     /// A description of what this action would do during revert
     pub fn describe_revert(&self) -> Vec<ActionDescription> {
         if self.state == ActionState::Uncompleted {
@@ -154,6 +272,32 @@ where
         }
         return self.action.revert_description();
     }
+    /// The shell commands to execute the action, nothing if it has already completed
+    ///
+    /// You should prefer this ([`to_shell`][StatefulAction::to_shell]) over [`Action::to_shell`] as it skips actions which will not execute
+    pub fn to_shell(&self) -> Option<Vec<String>> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Some(vec![]),
+            _ => self.action.to_shell(),
+        }
+    }
+    /// Check the preconditions of any execution steps
+    ///
+    /// You should prefer this ([`try_preflight`][StatefulAction::try_preflight]) over [`preflight`][Action::preflight] as it skips actions which will not execute
+    pub async fn try_preflight(&self) -> Result<(), ActionError> {
+        let span = self.action.tracing_span();
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => Ok(()),
+            _ => {
+                tracing::debug!(
+                    parent: &span,
+                    "Preflight: {}",
+                    self.action.tracing_synopsis()
+                );
+                self.action.preflight().instrument(span.clone()).await
+            },
+        }
+    }
     /// Perform any execution steps
     ///
     /// You should prefer this ([`try_execute`][StatefulAction::try_execute]) over [`execute`][Action::execute] as it handles [`ActionState`] and does tracing
@@ -196,7 +340,7 @@ where
     pub async fn try_revert(&mut self) -> Result<(), ActionError> {
         let span = self.action.tracing_span();
         match self.state {
-            ActionState::Uncompleted => {
+            ActionState::Uncompleted if !ActionContext::current().revert_forced => {
                 tracing::trace!(
                     parent: &span,
                     "Reverted: (Already done) {}",
@@ -231,6 +375,7 @@ where
         Self {
             state: ActionState::Completed,
             action,
+            timeout: None,
         }
     }
 
@@ -238,6 +383,7 @@ where
         Self {
             state: ActionState::Skipped,
             action,
+            timeout: None,
         }
     }
 
@@ -245,6 +391,7 @@ where
         Self {
             state: ActionState::Uncompleted,
             action,
+            timeout: None,
         }
     }
 }
@@ -21,5 +21,10 @@ async fn main() -> eyre::Result<ExitCode> {
 
     cli.instrumentation.setup()?;
 
-    cli.execute().await
+    let result = cli.execute().await;
+
+    #[cfg(feature = "opentelemetry")]
+    nix_installer::otel::shutdown_otel();
+
+    result
 }
@@ -0,0 +1,148 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    action::{Action, ActionDescription, StatefulAction},
+    plan::DescribeOverride,
+    planner::Planner,
+    InstallEvent, InstallPlan, NixInstallerError,
+};
+
+/**
+Options for an [`InstallPlan`] which are not settings of its [`Planner`]
+
+```rust,no_run
+use std::time::Duration;
+use nix_installer::{InstallPlan, planner::Planner};
+
+# async fn builder_example() -> color_eyre::Result<()> {
+#[cfg(target_os = "linux")]
+let planner = nix_installer::planner::linux::Linux::default().await?;
+#[cfg(target_os = "macos")]
+let planner = nix_installer::planner::macos::Macos::default().await?;
+let (event_channel, _events) = tokio::sync::broadcast::channel(64);
+
+let mut plan = InstallPlan::builder()
+    .action_timeout(Duration::from_secs(600))
+    .event_channel(event_channel)
+    .plan(planner)
+    .await?;
+plan.install(None, None).await?;
+#
+# Ok(())
+# }
+```
+*/
+#[derive(Debug, Clone, Default)]
+pub struct InstallPlanBuilder {
+    receipt_location: Option<PathBuf>,
+    action_timeout: Option<Duration>,
+    event_channel: Option<Sender<InstallEvent>>,
+    describe_override: Option<DescribeOverride>,
+    capture_snapshot: bool,
+    extra_actions: Vec<StatefulAction<Box<dyn Action>>>,
+    #[cfg(feature = "diagnostics")]
+    diagnostics: Option<bool>,
+}
+
+impl InstallPlanBuilder {
+    /// Write the receipt to `receipt_location` instead of [`RECEIPT_LOCATION`](crate::plan::RECEIPT_LOCATION)
+    pub fn receipt_location(mut self, receipt_location: impl Into<PathBuf>) -> Self {
+        self.receipt_location = Some(receipt_location.into());
+        self
+    }
+
+    /// Fail the install if any action takes longer than `action_timeout` to execute
+    pub fn action_timeout(mut self, action_timeout: Duration) -> Self {
+        self.action_timeout = Some(action_timeout);
+        self
+    }
+
+    /// Send [`InstallEvent`]s along `event_channel` whenever [`install`](InstallPlan::install) is not passed one
+    pub fn event_channel(mut self, event_channel: Sender<InstallEvent>) -> Self {
+        self.event_channel = Some(event_channel);
+        self
+    }
+
+    /// See [`InstallPlan::describe_override`]
+    pub fn describe_override(
+        mut self,
+        describe_override: impl Fn(&dyn Action) -> Option<ActionDescription> + Send + Sync + 'static,
+    ) -> Self {
+        self.describe_override = Some(DescribeOverride(Arc::new(describe_override)));
+        self
+    }
+
+    /// See [`InstallPlan::capture_snapshot`]
+    pub fn capture_snapshot(mut self, capture_snapshot: bool) -> Self {
+        self.capture_snapshot = capture_snapshot;
+        self
+    }
+
+    /// Execute `action` after the actions of the planner, such as a custom [`Action`] of another crate
+    ///
+    /// Like any other action, it is recorded in the receipt, so its [`typetag::serde`] name must
+    /// stay registered for the install to be resumed or uninstalled.
+    pub fn extra_action(mut self, action: StatefulAction<Box<dyn Action>>) -> Self {
+        self.extra_actions.push(action);
+        self
+    }
+
+    /// Whether to send diagnostics to the planner's `diagnostic_endpoint`, on by default
+    #[cfg(feature = "diagnostics")]
+    pub fn diagnostics(mut self, diagnostics: bool) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    /// Plan with `planner`, then apply the options
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan<P>(self, planner: P) -> Result<InstallPlan, NixInstallerError>
+    where
+        P: Planner + 'static,
+    {
+        let Self {
+            receipt_location,
+            action_timeout,
+            event_channel,
+            describe_override,
+            capture_snapshot,
+            extra_actions,
+            #[cfg(feature = "diagnostics")]
+            diagnostics,
+        } = self;
+
+        if let Some(receipt_location) = &receipt_location {
+            if !receipt_location.is_absolute() {
+                return Err(NixInstallerError::InvalidPlanOptions(format!(
+                    "the receipt location `{}` must be absolute",
+                    receipt_location.display()
+                )));
+            }
+        }
+        if action_timeout == Some(Duration::ZERO) {
+            return Err(NixInstallerError::InvalidPlanOptions(
+                "the action timeout must be longer than zero".to_string(),
+            ));
+        }
+
+        let mut plan = InstallPlan::plan(planner).await?;
+        plan.receipt_location = receipt_location;
+        plan.event_channel = event_channel;
+        plan.describe_override = describe_override;
+        plan.capture_snapshot = capture_snapshot;
+        plan.actions.extend(extra_actions);
+        if let Some(action_timeout) = action_timeout {
+            for action in plan.actions.iter_mut() {
+                action.timeout.get_or_insert(action_timeout);
+            }
+        }
+        #[cfg(feature = "diagnostics")]
+        if diagnostics == Some(false) {
+            plan.diagnostic_data = None;
+        }
+
+        Ok(plan)
+    }
+}
@@ -2,6 +2,8 @@ use atty::Stream;
 use eyre::WrapErr;
 use std::error::Error;
 use tracing_error::ErrorLayer;
+
+use crate::ACTION_LOG_TARGET;
 use tracing_subscriber::{
     filter::Directive, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
@@ -40,6 +42,12 @@ pub struct Instrumentation {
     /// See https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
     #[clap(long = "log-directive", global = true, env = "NIX_INSTALLER_LOG_DIRECTIVES", value_delimiter = ',', num_args = 0..)]
     pub log_directives: Vec<Directive>,
+    /// Export each step as an OpenTelemetry span to this OTLP/HTTP collector endpoint, like `http://localhost:4318/v1/traces`
+    ///
+    /// The steps within each step are only exported with `-v`, as they are traced at the debug level
+    #[cfg(feature = "opentelemetry")]
+    #[clap(long, env = "NIX_INSTALLER_OTEL_ENDPOINT", global = true)]
+    pub otel_endpoint: Option<url::Url>,
 }
 
 impl<'a> Instrumentation {
@@ -58,6 +66,13 @@ impl<'a> Instrumentation {
         let registry = tracing_subscriber::registry()
             .with(filter_layer)
             .with(ErrorLayer::default());
+        #[cfg(feature = "opentelemetry")]
+        let registry = registry.with(
+            self.otel_endpoint
+                .as_ref()
+                .map(crate::otel::otel_layer)
+                .transpose()?,
+        );
 
         match self.logger {
             Logger::Compact => {
@@ -145,6 +160,12 @@ impl<'a> Instrumentation {
             },
         };
 
+        // The JSON logger also logs each step of the plan as an object
+        if let Logger::Json = self.logger {
+            filter_layer =
+                filter_layer.add_directive(format!("{ACTION_LOG_TARGET}=debug").parse()?);
+        }
+
         for directive in &self.log_directives {
             let directive_clone = directive.clone();
             filter_layer = filter_layer.add_directive(directive_clone);
@@ -5,7 +5,7 @@ use std::{
 };
 
 use crate::{
-    action::ActionState,
+    action::{ActionDescription, ActionState},
     cli::{
         ensure_root,
         interaction::{self, PromptChoice},
@@ -51,7 +51,56 @@ pub struct Install {
     )]
     pub explain: bool,
 
-    #[clap(env = "NIX_INSTALLER_PLAN")]
+    /// Run the preflight checks of the plan and print what would be done, without changing the system
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_DRY_RUN",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub dry_run: bool,
+
+    /// Record the files the install touches beforehand, so uninstalling restores them exactly (users, groups and services are not recorded)
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_SNAPSHOT",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub snapshot: bool,
+
+    /// Apply changed settings to an existing, completed install, such as rewriting `nix.conf` and restarting the Nix daemon, instead of reinstalling
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RECONFIGURE",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub reconfigure: bool,
+
+    /// Write the progress of the install to stdout as newline-delimited JSON, one record per action starting, completing, or failing, and a final summary (implies `--no-confirm`)
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_JSON_PROGRESS",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub json_progress: bool,
+
+    /// Read the planner and its settings from a TOML (`.toml`) or JSON file, settings passed as flags take precedence
+    #[clap(long, env = "NIX_INSTALLER_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Install a plan written by `nix-installer plan`, instead of planning one
+    #[clap(long, conflicts_with_all = ["plan", "config"])]
+    pub from_plan: Option<PathBuf>,
+
+    // The conflicts are declared here rather than on the global `--config`, as the planner subcommands it is propagated to have no `plan`
+    #[clap(env = "NIX_INSTALLER_PLAN", conflicts_with_all = ["config", "from_plan"])]
     pub plan: Option<PathBuf>,
 
     #[clap(subcommand)]
@@ -64,21 +113,30 @@ impl CommandExecute for Install {
     async fn execute(self) -> eyre::Result<ExitCode> {
         let Self {
             no_confirm,
+            config,
+            from_plan,
             plan,
             planner,
             settings,
             explain,
+            dry_run,
+            snapshot,
+            reconfigure,
+            json_progress,
         } = self;
+        // Prompts would be interleaved with the records on stdout
+        let no_confirm = no_confirm || json_progress;
 
         ensure_root()?;
 
         let existing_receipt: Option<InstallPlan> = match Path::new(RECEIPT_LOCATION).exists() {
             true => {
                 tracing::trace!("Reading existing receipt");
-                let install_plan_string = tokio::fs::read_to_string(&RECEIPT_LOCATION)
-                    .await
-                    .wrap_err("Reading plan")?;
-                Some(serde_json::from_str(&install_plan_string)?)
+                Some(
+                    InstallPlan::resume_from_receipt(RECEIPT_LOCATION)
+                        .await
+                        .map_err(|e| eyre!(e))?,
+                )
             },
             false => None,
         };
@@ -88,7 +146,27 @@ impl CommandExecute for Install {
             false => format!("curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v{} | sh -s -- uninstall", env!("CARGO_PKG_VERSION")),
         };
 
-        let mut install_plan = match (planner, plan) {
+        let planner = match config {
+            Some(config) => {
+                let from_config = BuiltinPlanner::from_config_file(&config)
+                    .await
+                    .map_err(|e| eyre!(e))?;
+                // Without a planner subcommand, the flags only set the common settings
+                let overrides = match planner {
+                    Some(planner) => planner,
+                    None => from_config.clone().with_common_settings(settings.clone()),
+                };
+                Some(
+                    from_config
+                        .with_overrides(&overrides)
+                        .await
+                        .map_err(|e| eyre!(e))?,
+                )
+            },
+            None => planner,
+        };
+
+        let mut install_plan = match (planner, plan.or(from_plan)) {
             (Some(planner), None) => {
                 let chosen_planner: Box<dyn Planner> = planner.clone().boxed();
 
@@ -99,11 +177,17 @@ impl CommandExecute for Install {
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != chosen_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
+                            if reconfigure {
+                                return reconfigure_install(existing_receipt, chosen_planner).await;
+                            }
+                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try applying them with `--reconfigure`, or uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
-                        eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed, try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
-                        return Ok(ExitCode::FAILURE)
+                        if existing_receipt.actions.iter().all(|v| v.state == ActionState::Completed) {
+                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed, try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
+                            return Ok(ExitCode::FAILURE)
+                        }
+                        existing_receipt
                     } ,
                     None => {
                         let res = planner.plan().await;
@@ -121,10 +205,16 @@ impl CommandExecute for Install {
                 }
             },
             (None, Some(plan_path)) => {
-                let install_plan_string = tokio::fs::read_to_string(&plan_path)
-                .await
-                .wrap_err("Reading plan")?;
-                serde_json::from_str(&install_plan_string)?
+                match InstallPlan::from_file(&plan_path).await {
+                    Ok(plan) => plan,
+                    Err(err) => {
+                        if let Some(expected) = err.expected() {
+                            eprintln!("{}", expected.red());
+                            return Ok(ExitCode::FAILURE);
+                        }
+                        return Err(err)?;
+                    },
+                }
             },
             (None, None) => {
                 let builtin_planner = BuiltinPlanner::from_common_settings(settings.clone())
@@ -138,7 +228,10 @@ impl CommandExecute for Install {
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != builtin_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
+                            if reconfigure {
+                                return reconfigure_install(existing_receipt, builtin_planner.boxed()).await;
+                            }
+                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try applying them with `--reconfigure`, or uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.actions.iter().all(|v| v.state == ActionState::Completed) {
@@ -162,9 +255,34 @@ impl CommandExecute for Install {
                     },
                 }
             },
-            (Some(_), Some(_)) => return Err(eyre!("`--plan` conflicts with passing a planner, a planner creates plans, so passing an existing plan doesn't make sense")),
+            (Some(_), Some(_)) => return Err(eyre!("`--from-plan` conflicts with passing a planner, a planner creates plans, so passing an existing plan doesn't make sense")),
         };
 
+        if dry_run {
+            match install_plan.dry_run().await {
+                Ok(descriptions) => {
+                    for ActionDescription {
+                        description,
+                        explanation,
+                    } in descriptions
+                    {
+                        println!("* {description}");
+                        if explain {
+                            for line in explanation {
+                                println!("  {line}");
+                            }
+                        }
+                    }
+                    println!("{}", "Preflight checks passed, nothing was changed".green());
+                    return Ok(ExitCode::SUCCESS);
+                },
+                Err(err) => {
+                    eprintln!("{}", err.to_string().red());
+                    return Ok(ExitCode::FAILURE);
+                },
+            }
+        }
+
         if !no_confirm {
             let mut currently_explaining = explain;
             loop {
@@ -189,7 +307,15 @@ impl CommandExecute for Install {
 
         let (tx, rx1) = signal_channel().await?;
 
-        match install_plan.install(rx1).await {
+        install_plan.capture_snapshot(snapshot);
+        let install_result = if json_progress {
+            install_plan
+                .install_with_json_progress(rx1, tokio::io::stdout())
+                .await
+        } else {
+            install_plan.install(rx1, None).await
+        };
+        match install_result {
             Err(err) => {
                 if !no_confirm {
                     // Attempt to copy self to the store if possible, but since the install failed, this might not work, that's ok.
@@ -270,6 +396,10 @@ impl CommandExecute for Install {
                 copy_self_to_nix_store()
                     .await
                     .wrap_err("Copying `nix-installer` to `/nix/nix-installer`")?;
+                if json_progress {
+                    // The final record already reports success
+                    return Ok(ExitCode::SUCCESS);
+                }
                 println!(
                     "\
                     {success}\n\
@@ -307,3 +437,74 @@ async fn copy_self_to_nix_store() -> Result<(), std::io::Error> {
     tokio::fs::set_permissions("/nix/nix-installer", PermissionsExt::from_mode(0o0755)).await?;
     Ok(())
 }
+
+/// Apply the settings of `planner` to the completed install recorded in `existing_receipt`
+async fn reconfigure_install(
+    mut existing_receipt: InstallPlan,
+    planner: Box<dyn Planner>,
+) -> eyre::Result<ExitCode> {
+    if !existing_receipt
+        .actions
+        .iter()
+        .all(|v| v.state == ActionState::Completed)
+    {
+        eprintln!(
+            "{}",
+            format!("Found an incomplete plan in `{RECEIPT_LOCATION}`, only a completed install can be reconfigured").red()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    match existing_receipt.reconfigure(planner).await {
+        Ok(()) => {
+            println!("{}", "Nix was reconfigured successfully!".green().bold());
+            Ok(ExitCode::SUCCESS)
+        },
+        Err(err) => {
+            if let Some(expected) = err.expected() {
+                eprintln!("{}", expected.red());
+                return Ok(ExitCode::FAILURE);
+            }
+            Err(eyre!(err).wrap_err("Reconfigure failure"))
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{CommandFactory, Parser};
+
+    use crate::cli::NixInstallerCli;
+
+    #[test]
+    fn plan_conflicts_with_from_plan() {
+        NixInstallerCli::command().debug_assert();
+
+        for args in [
+            [
+                "nix-installer",
+                "install",
+                "plan.json",
+                "--from-plan",
+                "other.json",
+            ],
+            [
+                "nix-installer",
+                "install",
+                "--config",
+                "config.toml",
+                "--from-plan",
+                "plan.json",
+            ],
+        ] {
+            assert!(NixInstallerCli::try_parse_from(args).is_err());
+        }
+        assert!(NixInstallerCli::try_parse_from([
+            "nix-installer",
+            "install",
+            "--from-plan",
+            "plan.json"
+        ])
+        .is_ok());
+    }
+}
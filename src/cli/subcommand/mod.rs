@@ -4,10 +4,13 @@ mod install;
 use install::Install;
 mod uninstall;
 use uninstall::Uninstall;
+mod verify;
+use verify::Verify;
 
 #[derive(Debug, clap::Subcommand)]
 pub enum NixInstallerSubcommand {
     Plan(Plan),
     Install(Install),
     Uninstall(Uninstall),
+    Verify(Verify),
 }
@@ -38,6 +38,15 @@ pub struct Uninstall {
     )]
     pub explain: bool,
 
+    /// Remove as much as possible: revert even actions which never completed, and accept a receipt from an incompatible `nix-installer`
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_FORCE",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub force: bool,
+
     #[clap(default_value = RECEIPT_LOCATION)]
     pub receipt: PathBuf,
 }
@@ -50,6 +59,7 @@ impl CommandExecute for Uninstall {
             no_confirm,
             receipt,
             explain,
+            force,
         } = self;
 
         ensure_root()?;
@@ -96,10 +106,23 @@ impl CommandExecute for Uninstall {
             }
         }
 
-        let install_receipt_string = tokio::fs::read_to_string(receipt)
-            .await
-            .wrap_err("Reading receipt")?;
-        let mut plan: InstallPlan = serde_json::from_str(&install_receipt_string)?;
+        let mut plan = if force {
+            InstallPlan::force_from_receipt(&receipt).await?
+        } else {
+            match InstallPlan::resume_from_receipt(&receipt).await {
+                Ok(plan) => plan,
+                // The recorded actions may still load, even if the recorded planner does not
+                Err(err) => {
+                    tracing::warn!(
+                        "Loading the planner of `{}` failed, uninstalling only from its recorded actions: {err}",
+                        receipt.display()
+                    );
+                    InstallPlan::uninstall_plan_from_receipt(&receipt)
+                        .await
+                        .map_err(|_| err)?
+                },
+            }
+        };
 
         if !no_confirm {
             let mut currently_explaining = explain;
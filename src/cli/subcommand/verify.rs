@@ -0,0 +1,52 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{ArgAction, Parser};
+use owo_colors::OwoColorize;
+
+use crate::{plan::RECEIPT_LOCATION, InstallPlan};
+
+use crate::cli::CommandExecute;
+
+/// Check that a completed install of Nix works, such as that the Nix daemon answers
+#[derive(Debug, Parser)]
+pub struct Verify {
+    /// Print the report as JSON, rather than a line per check
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+
+    #[clap(default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Verify {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { json, receipt } = self;
+
+        let plan = InstallPlan::resume_from_receipt(&receipt).await?;
+        let report = plan.verify().await?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            for check in &report.checks {
+                match &check.error {
+                    None => println!("{} {}", "✓".green(), check.name),
+                    Some(error) => println!("{} {}\n  {}", "✗".red(), check.name, error.red()),
+                }
+            }
+        }
+
+        if report.passed() {
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
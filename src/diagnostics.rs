@@ -3,6 +3,8 @@
 When enabled with the `diagnostics` feature (default) this module provides automated install success/failure reporting to an endpoint.
 
 That endpoint can be a URL such as `https://our.project.org/nix-installer/diagnostics` or `file:///home/$USER/diagnostic.json` which receives a [`DiagnosticReport`] in JSON format.
+
+Setting `NIX_INSTALLER_DIAGNOSTICS_URL` points every report at another endpoint, such as an internal collector, or disables reporting if empty. Fields which may identify the machine can be left out with [`DiagnosticData::with_redacted`].
 */
 
 use std::{path::PathBuf, time::Duration};
@@ -11,7 +13,10 @@ use os_release::OsRelease;
 use reqwest::Url;
 
 use crate::{
-    action::ActionError, parse_ssl_cert, planner::PlannerError, settings::InstallSettingsError,
+    action::ActionError,
+    parse_ssl_cert,
+    planner::PlannerError,
+    settings::{InstallSettingsError, DEFAULT_USER_AGENT},
     CertificateError, NixInstallerError,
 };
 
@@ -24,6 +29,28 @@ pub enum DiagnosticStatus {
     Failure,
 }
 
+/// Overrides the endpoint of every [`DiagnosticData`], reporting nowhere if empty
+pub const DIAGNOSTICS_URL_ENV: &str = "NIX_INSTALLER_DIAGNOSTICS_URL";
+
+/// The value of a string field of a [`DiagnosticReport`] left out with [`DiagnosticData::with_redacted`]
+pub const REDACTED: &str = "redacted";
+
+/// A field of a [`DiagnosticReport`] which may identify the machine, see [`DiagnosticData::with_redacted`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DiagnosticField {
+    /// The names of the settings which differ from their defaults
+    ConfiguredSettings,
+    /// The name and version of the operating system
+    Os,
+    Triple,
+    IsCi,
+    UserAgent,
+    /// The kinds of error a failure was caused by
+    FailureChain,
+}
+
 /// The action attempted
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy)]
 pub enum DiagnosticAction {
@@ -41,6 +68,9 @@ pub struct DiagnosticReport {
     pub os_version: String,
     pub triple: String,
     pub is_ci: bool,
+    /// The `User-Agent` of HTTP requests, so reports can be correlated with server logs
+    #[serde(default)]
+    pub user_agent: String,
     pub action: DiagnosticAction,
     pub status: DiagnosticStatus,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
@@ -57,10 +87,15 @@ pub struct DiagnosticData {
     os_version: String,
     triple: String,
     is_ci: bool,
+    #[serde(default)]
+    user_agent: String,
     endpoint: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
     failure_chain: Option<Vec<String>>,
+    /// Fields left out of the [`report`](Self::report)
+    #[serde(default)]
+    redacted: Vec<DiagnosticField>,
 }
 
 impl DiagnosticData {
@@ -69,7 +104,13 @@ impl DiagnosticData {
         planner: String,
         configured_settings: Vec<String>,
         ssl_cert_file: Option<PathBuf>,
+        user_agent: Option<String>,
     ) -> Result<Self, DiagnosticError> {
+        let endpoint = match std::env::var(DIAGNOSTICS_URL_ENV) {
+            Ok(url) if url.is_empty() => None,
+            Ok(url) => Some(url),
+            Err(_) => endpoint,
+        };
         let endpoint = match endpoint {
             Some(endpoint) => diagnostic_endpoint_parser(&endpoint)?,
             None => None,
@@ -89,11 +130,25 @@ impl DiagnosticData {
             os_version,
             triple: target_lexicon::HOST.to_string(),
             is_ci,
+            user_agent: user_agent.unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
             ssl_cert_file,
             failure_chain: None,
+            redacted: vec![],
         })
     }
 
+    /// Send the report to `endpoint` instead, or nowhere if `None`
+    pub fn with_endpoint(mut self, endpoint: impl Into<Option<Url>>) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Leave `fields` out of the report, keeping the version, planner, action and status
+    pub fn with_redacted(mut self, fields: impl IntoIterator<Item = DiagnosticField>) -> Self {
+        self.redacted.extend(fields);
+        self
+    }
+
     pub fn failure(mut self, err: &NixInstallerError) -> Self {
         let mut failure_chain = vec![];
         let diagnostic = err.diagnostic();
@@ -138,11 +193,13 @@ impl DiagnosticData {
             os_version,
             triple,
             is_ci,
+            user_agent,
             endpoint: _,
             ssl_cert_file: _,
             failure_chain,
+            redacted,
         } = self;
-        DiagnosticReport {
+        let mut report = DiagnosticReport {
             version: version.clone(),
             planner: planner.clone(),
             configured_settings: configured_settings.clone(),
@@ -150,10 +207,25 @@ impl DiagnosticData {
             os_version: os_version.clone(),
             triple: triple.clone(),
             is_ci: *is_ci,
+            user_agent: user_agent.clone(),
             action,
             status,
             failure_chain: failure_chain.clone(),
+        };
+        for field in redacted {
+            match field {
+                DiagnosticField::ConfiguredSettings => report.configured_settings = vec![],
+                DiagnosticField::Os => {
+                    report.os_name = REDACTED.to_string();
+                    report.os_version = REDACTED.to_string();
+                },
+                DiagnosticField::Triple => report.triple = REDACTED.to_string(),
+                DiagnosticField::IsCi => report.is_ci = false,
+                DiagnosticField::UserAgent => report.user_agent = REDACTED.to_string(),
+                DiagnosticField::FailureChain => report.failure_chain = None,
+            }
         }
+        report
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -162,17 +234,21 @@ impl DiagnosticData {
         action: DiagnosticAction,
         status: DiagnosticStatus,
     ) -> Result<(), DiagnosticError> {
-        let serialized = serde_json::to_string_pretty(&self.report(action, status))?;
-
-        let endpoint = match self.endpoint {
-            Some(endpoint) => endpoint,
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.clone(),
             None => return Ok(()),
         };
 
+        let serialized = serde_json::to_string_pretty(&self.report(action, status))?;
+
         match endpoint.scheme() {
             "https" | "http" => {
                 tracing::debug!("Sending diagnostic to `{endpoint}`");
                 let mut buildable_client = reqwest::Client::builder();
+                // An invalid user agent is reported by fetching Nix, not here
+                if let Ok(user_agent) = reqwest::header::HeaderValue::from_str(&self.user_agent) {
+                    buildable_client = buildable_client.user_agent(user_agent);
+                }
                 if let Some(ssl_cert_file) = &self.ssl_cert_file {
                     let ssl_cert = parse_ssl_cert(&ssl_cert_file).await?;
                     buildable_client = buildable_client.add_root_certificate(ssl_cert);
@@ -269,3 +345,31 @@ pub fn diagnostic_endpoint_validator(input: &str) -> Result<String, DiagnosticEr
     let _ = diagnostic_endpoint_parser(input)?;
     Ok(input.to_string())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DiagnosticAction, DiagnosticData, DiagnosticField, DiagnosticStatus, REDACTED};
+
+    #[test]
+    fn redacted_fields_are_left_out_of_report() -> Result<(), super::DiagnosticError> {
+        let data = DiagnosticData::new(
+            None,
+            "linux".into(),
+            vec!["modify_profile".into()],
+            None,
+            Some("acme-provisioner/1.0".into()),
+        )?
+        .with_redacted([
+            DiagnosticField::ConfiguredSettings,
+            DiagnosticField::UserAgent,
+        ]);
+
+        let report = data.report(DiagnosticAction::Install, DiagnosticStatus::Success);
+        assert!(report.configured_settings.is_empty());
+        assert_eq!(report.user_agent, REDACTED);
+        assert_eq!(report.planner, "linux");
+        assert!(matches!(report.action, DiagnosticAction::Install));
+        assert!(matches!(report.status, DiagnosticStatus::Success));
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
-use std::{error::Error, path::PathBuf};
+use std::{error::Error, path::PathBuf, time::Duration};
 
-use crate::{action::ActionError, planner::PlannerError, settings::InstallSettingsError};
+use crate::{
+    action::ActionError, planner::PlannerError, settings::InstallSettingsError, SystemSnapshotError,
+};
+
+const MIB: u64 = 1024 * 1024;
 
 /// An error occurring during a call defined in this crate
 #[non_exhaustive]
@@ -18,9 +22,57 @@ pub enum NixInstallerError {
         }
     }).collect::<Vec<_>>().join("\n"))]
     ActionRevert(Vec<ActionError>),
+    /// An [`Action`](crate::action::Action) did not finish within its [`StatefulAction::timeout`](crate::action::StatefulAction::timeout)
+    #[error("Timed out after {}s: {synopsis}", elapsed.as_secs_f64())]
+    ActionTimeout { synopsis: String, elapsed: Duration },
+    /// An action index given to [`InstallPlan::revert_through`](crate::InstallPlan::revert_through) which is not in the plan
+    #[error("There is no action {index} to revert through, the plan only has {actions} actions")]
+    ActionIndexOutOfRange { index: usize, actions: usize },
+    /// Errors from the [`Action::preflight`](crate::action::Action::preflight) checks of a [`dry_run`](crate::InstallPlan::dry_run)
+    #[error("Preflight checks failed\n{}", .0.iter().map(|err| {
+        if let Some(source) = err.source() {
+            format!("{err}\n{source}\n")
+        } else {
+            format!("{err}\n")
+        }
+    }).collect::<Vec<_>>().join("\n"))]
+    Preflight(Vec<ActionError>),
+    /// An error while capturing a [`SystemSnapshot`](crate::SystemSnapshot) before installing
+    #[error("Capturing the system state before installing")]
+    CapturingSnapshot(#[source] SystemSnapshotError),
+    /// Errors while restoring a [`SystemSnapshot`](crate::SystemSnapshot) after reverting
+    #[error("Error restoring the system state from before the install\n{}", .0.iter().map(|err| {
+        if let Some(source) = err.source() {
+            format!("{err}\n{source}\n")
+        } else {
+            format!("{err}\n")
+        }
+    }).collect::<Vec<_>>().join("\n"))]
+    RestoringSnapshot(Vec<SystemSnapshotError>),
     /// An error while writing the [`InstallPlan`](crate::InstallPlan)
     #[error("Recording install receipt")]
     RecordingReceipt(PathBuf, #[source] std::io::Error),
+    /// An error while reading the [`InstallPlan`](crate::InstallPlan) from a receipt
+    #[error("Reading install receipt `{0}`")]
+    ReadingReceipt(PathBuf, #[source] std::io::Error),
+    /// An error while deserializing the [`InstallPlan`](crate::InstallPlan) from a receipt
+    #[error("Deserializing install receipt `{0}`")]
+    DeserializingReceipt(PathBuf, #[source] serde_json::Error),
+    /// An error while upgrading a receipt of an older receipt schema version with [`migrate_receipt`](crate::migrate_receipt)
+    #[error("Migrating install receipt{}", .0.map(|version| format!(" from receipt schema version {version}")).unwrap_or_default())]
+    MigratingReceipt(Option<u32>, #[source] serde_json::Error),
+    /// An error while reading an [`InstallPlan`](crate::InstallPlan) with [`InstallPlan::from_file`](crate::InstallPlan::from_file)
+    #[error("Reading install plan `{}`", .0.display())]
+    ReadingPlan(PathBuf, #[source] std::io::Error),
+    /// An error while deserializing an [`InstallPlan`](crate::InstallPlan) with [`InstallPlan::from_reader`](crate::InstallPlan::from_reader)
+    #[error("Deserializing install plan")]
+    DeserializingPlan(#[source] serde_json::Error),
+    /// An [`InstallPlan`](crate::InstallPlan) loaded with [`InstallPlan::from_reader`](crate::InstallPlan::from_reader) was made by another version of `nix-installer`
+    #[error("The plan was made by `nix-installer` {plan}, which is not compatible with this `nix-installer` ({current}). Make the plan again with `nix-installer plan`, or install it with `nix-installer` {plan}")]
+    IncompatiblePlanVersion {
+        plan: semver::Version,
+        current: semver::Version,
+    },
     /// An error while writing copying the binary into the `/nix` folder
     #[error("Copying `nix-installer` binary into `/nix`")]
     CopyingSelf(
@@ -35,9 +87,40 @@ pub enum NixInstallerError {
         #[source]
         serde_json::Error,
     ),
+    /// An [`Action`](crate::action::Action) of a plan given to [`InstallPlan::to_shell_script`](crate::InstallPlan::to_shell_script) cannot be expressed as shell commands
+    #[error("The `{0}` action cannot be exported to a shell script")]
+    NotRepresentableAsShell(String),
+    /// Options given to an [`InstallPlanBuilder`](crate::InstallPlanBuilder) which cannot be used together or at all
+    #[error("Invalid install plan options: {0}")]
+    InvalidPlanOptions(String),
+    /// A Nix store, possibly from a distribution package of Nix, was found by [`BuiltinPlanner::plan`](crate::planner::BuiltinPlanner::plan)
+    #[error("Found an existing Nix store at `{}`, installing over it would mix its state with this install. Remove it first (if `nix-installer` installed it, run `/nix/nix-installer uninstall`, otherwise use the uninstall instructions of the package manager which did), or pass `--install-over-existing-store` to install over it anyway", .0.display())]
+    ExistingNixStore(PathBuf),
+    /// The filesystem holding `path` has too little free space for [`InstallPlan::install`](crate::InstallPlan::install) to finish
+    #[error("Only {} MiB of disk space is available for `{}`, at least {} MiB is required to install Nix", available / MIB, path.display(), required.div_ceil(MIB))]
+    InsufficientDiskSpace {
+        required: u64,
+        available: u64,
+        path: PathBuf,
+    },
+    /// An error while finding the free space of the filesystem holding a path
+    #[error("Checking the free disk space of `{}`", .0.display())]
+    CheckingDiskSpace(PathBuf, #[source] nix::errno::Errno),
+    /// Another `nix-installer` holds the [`LOCK_LOCATION`](crate::plan::LOCK_LOCATION) lock, so is installing or uninstalling
+    #[error("Another `nix-installer` is already installing or uninstalling Nix, as it holds the lock `{}`. Wait for it to finish, then try again", .0.display())]
+    AlreadyRunning(PathBuf),
+    /// An error while taking the [`LOCK_LOCATION`](crate::plan::LOCK_LOCATION) lock
+    #[error("Taking the lock `{}`", .0.display())]
+    Locking(PathBuf, #[source] std::io::Error),
+    /// The [`LOCK_LOCATION`](crate::plan::LOCK_LOCATION) lock file is owned by another user, who could hold it
+    #[error("The lock `{}` is owned by the user with UID {1} rather than `root`, remove it with `rm {}` then try again", .0.display(), .0.display())]
+    LockNotOwned(PathBuf, u32),
     /// An error occurring when a signal is issued along [`InstallPlan::install`](crate::InstallPlan::install)'s `cancel_channel` argument
     #[error("Cancelled by user")]
     Cancelled,
+    /// An error while listening for the signals which cancel [`InstallPlan::install_with_ctrlc`](crate::InstallPlan::install_with_ctrlc)
+    #[error("Listening for signals to cancel the install")]
+    Signal(#[source] std::io::Error),
     /// Semver error
     #[error("Semantic Versioning error")]
     SemVer(
@@ -70,6 +153,41 @@ pub enum NixInstallerError {
     ),
 }
 
+impl NixInstallerError {
+    /// A stable code for the failure, to branch on without matching error messages
+    ///
+    /// Action failures are coded as in [`ActionError::code`], like `fetch-and-unpack-nix.network`,
+    /// planner failures as in [`PlannerError::code`], and others as `installer.<variant>`, like
+    /// `installer.insufficient-disk-space`.
+    pub fn code(&self) -> String {
+        match self {
+            NixInstallerError::Action(action_error) => action_error.code(),
+            NixInstallerError::ActionRevert(action_errors)
+            | NixInstallerError::Preflight(action_errors) => match action_errors.as_slice() {
+                [action_error] => action_error.code(),
+                _ => "installer.multiple".to_string(),
+            },
+            NixInstallerError::Planner(planner_error) => planner_error.code(),
+            this => {
+                let static_str: &'static str = this.into();
+                format!("installer.{}", kebab_case(static_str))
+            },
+        }
+    }
+}
+
+/// `SomeVariantName` as `some-variant-name`
+pub(crate) fn kebab_case(name: &str) -> String {
+    let mut kebab = String::with_capacity(name.len() + 4);
+    for (index, character) in name.char_indices() {
+        if character.is_uppercase() && index != 0 {
+            kebab.push('-');
+        }
+        kebab.extend(character.to_lowercase());
+    }
+    kebab
+}
+
 pub(crate) trait HasExpectedErrors: std::error::Error + Sized + Send + Sync {
     fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>>;
 }
@@ -79,10 +197,30 @@ impl HasExpectedErrors for NixInstallerError {
         match self {
             NixInstallerError::Action(action_error) => action_error.kind().expected(),
             NixInstallerError::ActionRevert(_) => None,
+            NixInstallerError::ActionTimeout { .. } => None,
+            this @ NixInstallerError::ActionIndexOutOfRange { .. } => Some(Box::new(this)),
+            NixInstallerError::Preflight(_) => None,
+            NixInstallerError::CapturingSnapshot(_) => None,
+            NixInstallerError::RestoringSnapshot(_) => None,
             NixInstallerError::RecordingReceipt(_, _) => None,
+            NixInstallerError::ReadingReceipt(_, _) => None,
+            NixInstallerError::DeserializingReceipt(_, _) => None,
+            NixInstallerError::MigratingReceipt(_, _) => None,
+            NixInstallerError::ReadingPlan(_, _) => None,
+            NixInstallerError::DeserializingPlan(_) => None,
+            this @ NixInstallerError::IncompatiblePlanVersion { .. } => Some(Box::new(this)),
             NixInstallerError::CopyingSelf(_) => None,
             NixInstallerError::SerializingReceipt(_) => None,
+            this @ NixInstallerError::NotRepresentableAsShell(_) => Some(Box::new(this)),
+            this @ NixInstallerError::InvalidPlanOptions(_) => Some(Box::new(this)),
+            this @ NixInstallerError::ExistingNixStore(_) => Some(Box::new(this)),
+            this @ NixInstallerError::InsufficientDiskSpace { .. } => Some(Box::new(this)),
+            NixInstallerError::CheckingDiskSpace(_, _) => None,
+            this @ NixInstallerError::AlreadyRunning(_) => Some(Box::new(this)),
+            NixInstallerError::Locking(_, _) => None,
+            this @ NixInstallerError::LockNotOwned(_, _) => Some(Box::new(this)),
             this @ NixInstallerError::Cancelled => Some(Box::new(this)),
+            NixInstallerError::Signal(_) => None,
             NixInstallerError::SemVer(_) => None,
             NixInstallerError::Planner(planner_error) => planner_error.expected(),
             NixInstallerError::InstallSettings(_) => None,
@@ -98,7 +236,7 @@ impl crate::diagnostics::ErrorDiagnostic for NixInstallerError {
         let static_str: &'static str = (self).into();
         let context = match self {
             Self::Action(action_error) => vec![action_error.diagnostic().to_string()],
-            Self::ActionRevert(action_errors) => action_errors
+            Self::ActionRevert(action_errors) | Self::Preflight(action_errors) => action_errors
                 .iter()
                 .map(|action_error| action_error.diagnostic().to_string())
                 .collect(),
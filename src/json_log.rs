@@ -0,0 +1,100 @@
+/*! Structured logging of the steps of a plan, as lines of JSON
+
+When enabled with the `json-logging` feature (part of `cli`) this module provides a
+[`tracing_subscriber`] layer which writes each [`Action`](crate::action::Action) executed or
+reverted by an [`InstallPlan`](crate::InstallPlan) as one line of JSON, for ingestion into log
+pipelines:
+
+```json
+{"timestamp":"2023-06-01T12:00:00.000000Z","action":"create_directory","operation":"execute","state":"Completed","synopsis":"Create directory `/nix`","duration_ms":1}
+```
+
+[`init_json_logging`] installs it as the global subscriber. To combine it with other layers, add
+[`json_action_layer`] to a [`Registry`](tracing_subscriber::Registry) instead.
+*/
+
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::{SubscriberInitExt, TryInitError},
+    Layer,
+};
+
+use crate::ACTION_LOG_TARGET;
+
+/// A layer writing each event on [`ACTION_LOG_TARGET`] to `writer` as a line of JSON, ignoring all other events
+pub fn json_action_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    W: for<'writer> tracing_subscriber::fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::Layer::new()
+        .with_writer(writer)
+        .with_ansi(false)
+        .json()
+        .flatten_event(true)
+        .with_level(false)
+        .with_target(false)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_filter(Targets::new().with_target(ACTION_LOG_TARGET, LevelFilter::DEBUG))
+}
+
+/// Install a global subscriber which logs each step of a plan to stderr as a line of JSON, and nothing else
+pub fn init_json_logging() -> Result<(), TryInitError> {
+    tracing_subscriber::registry()
+        .with(json_action_layer(std::io::stderr))
+        .try_init()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::json_action_layer;
+    use crate::ACTION_LOG_TARGET;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_action_steps_as_json_lines() -> eyre::Result<()> {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(json_action_layer(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Not a step");
+            tracing::debug!(
+                target: ACTION_LOG_TARGET,
+                action = "create_directory",
+                operation = "execute",
+                state = "Completed",
+                synopsis = "Create directory `/nix`",
+                duration_ms = 1u64,
+            );
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let step: serde_json::Value = serde_json::from_str(lines[0])?;
+        assert_eq!(step["action"], "create_directory");
+        assert_eq!(step["state"], "Completed");
+        assert_eq!(step["duration_ms"], 1);
+        assert!(step["timestamp"].is_string());
+        Ok(())
+    }
+}
@@ -19,7 +19,7 @@ use nix_installer::InstallPlan;
 
 # async fn default_install() -> color_eyre::Result<()> {
 let mut plan = InstallPlan::default().await?;
-match plan.install(None).await {
+match plan.install(None, None).await {
     Ok(()) => tracing::info!("Done"),
     Err(e) => {
         match e.source() {
@@ -52,7 +52,7 @@ let planner = nix_installer::planner::macos::Macos::default().await?;
 // Customize any settings...
 
 let mut plan = InstallPlan::plan(planner).await?;
-match plan.install(None).await {
+match plan.install(None, None).await {
     Ok(()) => tracing::info!("Done"),
     Err(e) => {
         match e.source() {
@@ -70,21 +70,35 @@ match plan.install(None).await {
 */
 
 pub mod action;
+mod builder;
 #[cfg(feature = "cli")]
 pub mod cli;
 #[cfg(feature = "diagnostics")]
 pub mod diagnostics;
 mod error;
+#[cfg(feature = "json-logging")]
+pub mod json_log;
 mod os;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+mod outcome;
 mod plan;
 pub mod planner;
+mod progress;
 pub mod settings;
+mod snapshot;
+mod verify;
 
 use std::{ffi::OsStr, path::Path, process::Output};
 
+pub use builder::InstallPlanBuilder;
 pub use error::NixInstallerError;
-pub use plan::InstallPlan;
+pub use outcome::{InstallOutcome, OutcomeKind};
+pub use plan::{migrate_receipt, InstallEvent, InstallPlan, PlanDiffEntry, ACTION_LOG_TARGET};
 use planner::BuiltinPlanner;
+pub use progress::{ProgressRecord, ProgressState};
+pub use snapshot::{PriorState, SystemSnapshot, SystemSnapshotError};
+pub use verify::{VerificationCheck, VerificationReport};
 
 use reqwest::Certificate;
 use tokio::process::Command;
@@ -94,7 +108,9 @@ use crate::action::{Action, ActionErrorKind};
 #[tracing::instrument(level = "debug", skip_all, fields(command = %format!("{:?}", command.as_std())))]
 async fn execute_command(command: &mut Command) -> Result<Output, ActionErrorKind> {
     tracing::trace!("Executing");
+    // An action which times out is dropped mid-command, which should not leave the command running
     let output = command
+        .kill_on_drop(true)
         .output()
         .await
         .map_err(|e| ActionErrorKind::command(command, e))?;
@@ -26,3 +26,64 @@ pub struct DiskUtilApfsListVolume {
     pub name: String,
     pub encryption: bool,
 }
+
+/// A macOS release as reported by `sw_vers -productVersion`, eg `13.4.1`
+#[derive(
+    serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct MacosVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl MacosVersion {
+    /// Catalina, the first release with a read-only system volume and `/etc/synthetic.conf`
+    pub const CATALINA: MacosVersion = MacosVersion::new(10, 15, 0);
+    /// Big Sur, the first release with a sealed system volume
+    pub const BIG_SUR: MacosVersion = MacosVersion::new(11, 0, 0);
+
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse the output of `sw_vers -productVersion`, where the minor and patch versions may be omitted
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().map(str::parse).transpose().ok()?.unwrap_or(0);
+        let patch = parts.next().map(str::parse).transpose().ok()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl std::fmt::Display for MacosVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MacosVersion;
+
+    #[test]
+    fn parses_sw_vers_product_version() {
+        assert_eq!(
+            MacosVersion::parse("13.4.1\n"),
+            Some(MacosVersion::new(13, 4, 1))
+        );
+        assert_eq!(MacosVersion::parse("11.0"), Some(MacosVersion::BIG_SUR));
+        assert_eq!(MacosVersion::parse("14"), Some(MacosVersion::new(14, 0, 0)));
+        assert!(MacosVersion::parse("10.14.6") < Some(MacosVersion::CATALINA));
+        assert_eq!(MacosVersion::parse("13.x"), None);
+        assert_eq!(MacosVersion::parse(""), None);
+    }
+}
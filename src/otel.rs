@@ -0,0 +1,81 @@
+/*! Exporting the steps of a plan as OpenTelemetry traces
+
+When enabled with the `opentelemetry` feature, each [`Action`](crate::action::Action) executed or
+reverted by an [`InstallPlan`](crate::InstallPlan) is a span, with the actions it is made of, such
+as those of [`ConfigureNix`](crate::action::common::ConfigureNix), as its children. Spans carry
+these attributes:
+
+* `action.type`: the tag of the action, like `create_directory`
+* `action.operation`: `execute` or `revert`
+* `action.state.from` and `action.state.to`: the [`ActionState`](crate::action::ActionState) before and after
+* `otel.status_code`, and `otel.status_message` with the error if it failed
+
+[`init_otel`] installs a global subscriber exporting them to an OTLP/HTTP collector. To combine it
+with other layers, add [`otel_layer`] to a [`Registry`](tracing_subscriber::Registry) instead.
+Either way, call [`shutdown_otel`] before exiting so the last spans are sent.
+*/
+
+use opentelemetry::{sdk::Resource, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::{SubscriberInitExt, TryInitError},
+    Layer,
+};
+use url::Url;
+
+/// A layer exporting the spans of this crate to the OTLP/HTTP collector at `endpoint`, like `http://localhost:4318/v1/traces`
+///
+/// Must be called within a Tokio runtime, which sends the spans in batches.
+pub fn otel_layer<S>(endpoint: &Url) -> Result<impl Layer<S>, OtelError>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint.as_str()),
+        )
+        .with_trace_config(
+            opentelemetry::sdk::trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            ])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .map_err(OtelError::Pipeline)?;
+
+    // Sub-actions are traced at `DEBUG`, and should be children of their action whatever is logged
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(
+            Targets::new()
+                .with_target(env!("CARGO_PKG_NAME").replace('-', "_"), LevelFilter::DEBUG),
+        ))
+}
+
+/// Install a global subscriber which exports the steps of a plan to the OTLP/HTTP collector at `endpoint`, and nothing else
+pub fn init_otel(endpoint: &Url) -> Result<(), OtelError> {
+    tracing_subscriber::registry()
+        .with(otel_layer(endpoint)?)
+        .try_init()
+        .map_err(OtelError::Init)
+}
+
+/// Send any spans not yet exported, and stop exporting
+pub fn shutdown_otel() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// An error setting up the export of traces
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum OtelError {
+    #[error("Setting up the OpenTelemetry trace exporter")]
+    Pipeline(#[source] opentelemetry::trace::TraceError),
+    #[error("Installing the OpenTelemetry subscriber")]
+    Init(#[source] TryInitError),
+}
@@ -0,0 +1,165 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::broadcast::{Receiver, Sender};
+
+use crate::{InstallEvent, InstallPlan, NixInstallerError};
+
+/// Which operation an [`InstallOutcome`] summarizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeKind {
+    Install,
+    Uninstall,
+}
+
+/**
+A serializable summary of an [`InstallPlan::install`] or [`InstallPlan::uninstall`] run
+
+Tools driving installs across many hosts can collect these and aggregate them with [`InstallOutcome::batch_report`].
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InstallOutcome {
+    /// The hostname of the machine the plan ran on
+    pub host: String,
+    pub kind: OutcomeKind,
+    pub success: bool,
+    /// Seconds since the Unix epoch when the run started
+    pub started_at: u64,
+    pub duration: Duration,
+    pub warnings: Vec<String>,
+    /// Each error, followed by the errors which caused it
+    pub errors: Vec<String>,
+}
+
+impl InstallOutcome {
+    /// Serialize the outcomes of several hosts into a single JSON report
+    pub fn batch_report(outcomes: &[InstallOutcome]) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(outcomes)
+    }
+}
+
+impl InstallPlan {
+    /// Run [`install`](Self::install), summarizing the result as an [`InstallOutcome`]
+    pub async fn install_with_outcome(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        event_channel: impl Into<Option<Sender<InstallEvent>>>,
+    ) -> InstallOutcome {
+        let (started_at, start) = now();
+        let result = self.install(cancel_channel, event_channel).await;
+
+        let mut warnings = Vec::new();
+        if result.is_ok() && self.requires_reboot_before_use {
+            warnings.push("A reboot is required before Nix can be used".to_string());
+        }
+        outcome(OutcomeKind::Install, started_at, start, warnings, result)
+    }
+
+    /// Run [`uninstall`](Self::uninstall), summarizing the result as an [`InstallOutcome`]
+    pub async fn uninstall_with_outcome(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> InstallOutcome {
+        let (started_at, start) = now();
+        let result = self.uninstall(cancel_channel).await;
+        outcome(
+            OutcomeKind::Uninstall,
+            started_at,
+            start,
+            Vec::new(),
+            result,
+        )
+    }
+}
+
+fn now() -> (u64, Instant) {
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    (started_at, Instant::now())
+}
+
+fn outcome(
+    kind: OutcomeKind,
+    started_at: u64,
+    start: Instant,
+    warnings: Vec<String>,
+    result: Result<(), NixInstallerError>,
+) -> InstallOutcome {
+    let host = nix::unistd::gethostname()
+        .ok()
+        .and_then(|host| host.into_string().ok())
+        .unwrap_or_default();
+
+    let mut errors = Vec::new();
+    if let Err(err) = &result {
+        let mut current: Option<&dyn Error> = Some(err);
+        while let Some(err) = current {
+            errors.push(err.to_string());
+            current = err.source();
+        }
+    }
+
+    InstallOutcome {
+        host,
+        kind,
+        success: result.is_ok(),
+        started_at,
+        duration: start.elapsed(),
+        warnings,
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::action::{ActionError, ActionErrorKind, ActionTag};
+
+    #[test]
+    fn outcome_records_error_chain() -> eyre::Result<()> {
+        let err = NixInstallerError::Action(ActionError::new(
+            ActionTag::from("create_file"),
+            ActionErrorKind::Read(
+                "/nix/receipt.json".into(),
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Permission denied"),
+            ),
+        ));
+        let failed = outcome(OutcomeKind::Install, 0, Instant::now(), vec![], Err(err));
+        assert!(!failed.success);
+        assert_eq!(
+            failed.errors.first().map(String::as_str),
+            Some("Error executing action")
+        );
+        assert!(failed
+            .errors
+            .iter()
+            .any(|err| err.contains("/nix/receipt.json")));
+        assert_eq!(
+            failed.errors.last().map(String::as_str),
+            Some("Permission denied")
+        );
+
+        let succeeded = outcome(
+            OutcomeKind::Uninstall,
+            0,
+            Instant::now(),
+            vec!["A warning".into()],
+            Ok(()),
+        );
+        assert!(succeeded.success);
+        assert!(succeeded.errors.is_empty());
+
+        let report = InstallOutcome::batch_report(&[failed, succeeded])?;
+        let parsed: Vec<InstallOutcome> = serde_json::from_str(&report)?;
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].kind, OutcomeKind::Install);
+        assert_eq!(parsed[1].kind, OutcomeKind::Uninstall);
+        assert_eq!(parsed[1].warnings, vec!["A warning".to_string()]);
+        Ok(())
+    }
+}
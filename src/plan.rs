@@ -1,32 +1,216 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    future::Future,
+    io::{Read, Write},
+    num::NonZeroUsize,
+    ops::Range,
+    os::unix::{
+        fs::{MetadataExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    action::{Action, ActionDescription, StatefulAction},
-    planner::{BuiltinPlanner, Planner},
-    NixInstallerError,
+    action::{
+        context::ActionContext, Action, ActionDescription, ActionError, ActionErrorKind,
+        ActionState, ActionTag, StatefulAction,
+    },
+    planner::{receipt::Receipt, BuiltinPlanner, ExistingNixStore, Planner, PlannerError},
+    settings::{in_store_prefix, in_target_root, NIX_ROOT},
+    InstallPlanBuilder, NixInstallerError, SystemSnapshot,
+};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::statvfs::statvfs,
+    unistd::geteuid,
 };
 use owo_colors::OwoColorize;
-use semver::{Version, VersionReq};
+use rand::Rng;
+use semver::Version;
 use serde::{de::Error, Deserialize, Deserializer};
-use tokio::sync::broadcast::Receiver;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::{Receiver, Sender},
+};
+use tracing::{Instrument, Span};
+use url::Url;
 
 pub const RECEIPT_LOCATION: &str = "/nix/receipt.json";
 
+/// The lock held by [`InstallPlan::install`] and [`InstallPlan::uninstall`] while they run, so only one of them changes the system at a time
+///
+/// It is kept in a directory only `root` can write, so no other user can put a file or symlink
+/// there first, and outside of `/nix`, as an open file there would keep a Nix volume or bind
+/// mount busy while uninstalling unmounts it.
+pub const LOCK_LOCATION: &str = "/var/run/nix-installer.lock";
+
+/// The target of the `DEBUG` event logged each time an [`Action`] of a plan is executed or reverted
+///
+/// Each event has the fields `action`, `operation`, `state`, `synopsis`, `duration_ms`, and
+/// `error` if it failed. Enable this target to ingest the steps of a plan into a log pipeline,
+/// such as with the `json-logging` feature's `json_log::init_json_logging`.
+pub const ACTION_LOG_TARGET: &str = "nix_installer::action_log";
+
+/// The version of the receipt format, bumped only on breaking changes to the serialized [`InstallPlan`]
+///
+/// Receipts written before this field existed are treated as version `1`, unless they still name
+/// the `linux-multi` or `darwin-multi` planners or the `configure_nix_daemon_service` action of
+/// version `0`. [`migrate_receipt`] upgrades receipts of older versions.
+pub const RECEIPT_SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades a receipt of a receipt schema version to the next
+type ReceiptMigration = fn(&mut serde_json::Value) -> Result<(), serde_json::Error>;
+
+/// The [`ReceiptMigration`] of each older receipt schema version, indexed by the version it upgrades from
+const RECEIPT_MIGRATIONS: &[ReceiptMigration] = &[migrate_receipt_from_v0];
+
+/// Progress through an [`InstallPlan::install`], sent along its `event_channel` argument so frontends can render it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InstallEvent {
+    ActionStarted {
+        /// The position of the action in the plan
+        index: usize,
+        synopsis: String,
+        /// The [`StatefulAction::weight`] of the action
+        #[serde(default)]
+        weight: u32,
+        /// The weights of the actions before this one in the plan, so `completed_weight / total_weight` of the install is done
+        #[serde(default)]
+        completed_weight: u64,
+        /// The weights of all actions this install runs
+        #[serde(default)]
+        total_weight: u64,
+    },
+    ActionCompleted {
+        index: usize,
+        synopsis: String,
+    },
+    ActionFailed {
+        index: usize,
+        synopsis: String,
+        error: String,
+    },
+    PlanCompleted,
+    /// Sent while the Nix package is downloaded, `total` is unknown if the server does not tell it up front
+    DownloadProgress {
+        url: Url,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// Sent during [`InstallPlan::uninstall`] while a large directory, like `/nix`, is removed
+    RemovalProgress {
+        path: PathBuf,
+        files: u64,
+        bytes: u64,
+    },
+}
+
+/// A difference found by [`InstallPlan::diff`], from the plan `diff` is called on to the other
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanDiffEntry {
+    PlannerChanged {
+        from: String,
+        to: String,
+    },
+    SettingAdded {
+        name: String,
+        value: serde_json::Value,
+    },
+    SettingRemoved {
+        name: String,
+        value: serde_json::Value,
+    },
+    SettingChanged {
+        name: String,
+        from: serde_json::Value,
+        to: serde_json::Value,
+    },
+    ActionAdded {
+        /// The typetag name of the action, like `create_directory`
+        action: String,
+        synopsis: String,
+    },
+    ActionRemoved {
+        action: String,
+        synopsis: String,
+    },
+    /// An action with the same name and synopsis in both plans, but planned differently
+    ActionChanged {
+        action: String,
+        synopsis: String,
+    },
+}
+
 /**
 A set of [`Action`]s, along with some metadata, which can be carried out to drive an install or
 revert
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct InstallPlan {
-    #[serde(deserialize_with = "ensure_version")]
     pub(crate) version: Version,
 
+    #[serde(
+        default = "legacy_receipt_schema_version",
+        deserialize_with = "ensure_receipt_schema_version"
+    )]
+    pub(crate) receipt_schema_version: u32,
+
     pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
 
     pub(crate) planner: Box<dyn Planner>,
 
+    /// If a reboot is needed after installing before Nix can be used
+    #[serde(default)]
+    pub(crate) requires_reboot_before_use: bool,
+
     #[cfg(feature = "diagnostics")]
     pub(crate) diagnostic_data: Option<crate::diagnostics::DiagnosticData>,
+
+    #[serde(skip)]
+    pub(crate) describe_override: Option<DescribeOverride>,
+
+    /// Where to write the receipt instead of [`RECEIPT_LOCATION`] in the store prefix and target root
+    #[serde(skip)]
+    pub(crate) receipt_location: Option<PathBuf>,
+
+    /// Used by [`install`](Self::install) when it is not passed an `event_channel`, and for the
+    /// [`InstallEvent::RemovalProgress`] of [`uninstall`](Self::uninstall)
+    #[serde(skip)]
+    pub(crate) event_channel: Option<Sender<InstallEvent>>,
+
+    /// The state of the files the actions touch from before they executed, restored on uninstall
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) snapshot: Option<SystemSnapshot>,
+
+    /// If [`install`](Self::install) should capture a [`SystemSnapshot`] before executing
+    #[serde(skip)]
+    pub(crate) capture_snapshot: bool,
+
+    /// If [`uninstall`](Self::uninstall) should also revert actions which never completed
+    #[serde(skip)]
+    pub(crate) uninstall_force: bool,
+
+    /// The Nix store [`BuiltinPlanner::plan`] found and was forced to install over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) existing_nix_store: Option<ExistingNixStore>,
+}
+
+/// Replaces the description of an [`Action`] in [`InstallPlan::describe_install`], see [`InstallPlan::describe_override`]
+#[derive(Clone)]
+pub(crate) struct DescribeOverride(pub(crate) Arc<DescribeOverrideFn>);
+
+type DescribeOverrideFn = dyn Fn(&dyn Action) -> Option<ActionDescription> + Send + Sync;
+
+impl std::fmt::Debug for DescribeOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DescribeOverride").finish_non_exhaustive()
+    }
 }
 
 impl InstallPlan {
@@ -38,13 +222,23 @@ impl InstallPlan {
 
         let planner = planner.boxed();
         let actions = planner.plan().await?;
+        let requires_reboot_before_use = planner.requires_reboot_before_use();
 
         Ok(Self {
             planner,
             actions,
+            requires_reboot_before_use,
             version: current_version()?,
+            receipt_schema_version: RECEIPT_SCHEMA_VERSION,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
+            describe_override: None,
+            receipt_location: None,
+            event_channel: None,
+            snapshot: None,
+            capture_snapshot: false,
+            uninstall_force: false,
+            existing_nix_store: None,
         })
     }
 
@@ -56,20 +250,183 @@ impl InstallPlan {
         let diagnostic_data = Some(planner.diagnostic_data().await?);
 
         let actions = planner.plan().await?;
+        let requires_reboot_before_use = planner.requires_reboot_before_use();
         Ok(Self {
             planner: planner.boxed(),
             actions,
+            requires_reboot_before_use,
             version: current_version()?,
+            receipt_schema_version: RECEIPT_SCHEMA_VERSION,
+            #[cfg(feature = "diagnostics")]
+            diagnostic_data,
+            describe_override: None,
+            receipt_location: None,
+            event_channel: None,
+            snapshot: None,
+            capture_snapshot: false,
+            uninstall_force: false,
+            existing_nix_store: None,
+        })
+    }
+
+    /// Configure a plan with options beyond those of its [`Planner`], see [`InstallPlanBuilder`]
+    pub fn builder() -> InstallPlanBuilder {
+        InstallPlanBuilder::default()
+    }
+
+    /// Load a plan written by `nix-installer plan`, or serialized with [`serde_json`], to review before [`install`](Self::install)ing it
+    ///
+    /// The plan must have been made by this version of `nix-installer`, since the actions it
+    /// records are only planned the same way by the version which planned them. Unlike a receipt,
+    /// an outdated plan is not migrated, it should be planned again instead.
+    pub fn from_reader(reader: impl Read) -> Result<Self, NixInstallerError> {
+        let plan: Self =
+            serde_json::from_reader(reader).map_err(NixInstallerError::DeserializingPlan)?;
+        plan.ensure_version()?;
+        Ok(plan)
+    }
+
+    /// Load a plan from the file at `path`, see [`from_reader`](Self::from_reader)
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))]
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self, NixInstallerError> {
+        let path = path.as_ref();
+        let buf = tokio::fs::read(path)
+            .await
+            .map_err(|e| NixInstallerError::ReadingPlan(path.to_path_buf(), e))?;
+        Self::from_reader(buf.as_slice())
+    }
+
+    /// Refuse a plan made by another version of `nix-installer`
+    fn ensure_version(&self) -> Result<(), NixInstallerError> {
+        let current = current_version()?;
+        if self.version != current {
+            return Err(NixInstallerError::IncompatiblePlanVersion {
+                plan: self.version.clone(),
+                current,
+            });
+        }
+        Ok(())
+    }
+
+    /// Load the plan recorded in a receipt (usually [`RECEIPT_LOCATION`]) by a previous, possibly interrupted, install
+    ///
+    /// Calling [`install`](Self::install) on the loaded plan resumes it: actions which already
+    /// completed are skipped, and actions which were in progress descend into their sub-actions,
+    /// only running the ones which had not completed yet.
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))]
+    pub async fn resume_from_receipt(path: impl AsRef<Path>) -> Result<Self, NixInstallerError> {
+        migrate_receipt(read_receipt(path.as_ref()).await?)
+    }
+
+    /// Load the plan recorded in a receipt for a forced [`uninstall`](Self::uninstall), even if its receipt schema version is not compatible with this `nix-installer`
+    ///
+    /// This is a best-effort way to remove what a newer (or older) `nix-installer` installed. The
+    /// receipt must still describe actions this `nix-installer` knows. The loaded plan has
+    /// [`uninstall_force`](Self::uninstall_force) set.
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))]
+    pub async fn force_from_receipt(path: impl AsRef<Path>) -> Result<Self, NixInstallerError> {
+        let path = path.as_ref();
+        let mut receipt = read_receipt(path).await?;
+        // Older receipts are migrated, only newer ones are incompatible
+        if let Some(receipt_schema_version) = receipt.get_mut("receipt_schema_version") {
+            if receipt_schema_version
+                .as_u64()
+                .is_none_or(|version| version > RECEIPT_SCHEMA_VERSION.into())
+            {
+                tracing::warn!(
+                    "Ignoring incompatible receipt schema version {receipt_schema_version} of `{}`",
+                    path.display()
+                );
+                *receipt_schema_version = RECEIPT_SCHEMA_VERSION.into();
+            }
+        }
+        let mut plan = migrate_receipt(receipt)?;
+        plan.uninstall_force = true;
+        Ok(plan)
+    }
+
+    /// Load only the actions recorded in a receipt, and their states, for an [`uninstall`](Self::uninstall) which never re-evaluates the planner
+    ///
+    /// The planner recorded in the receipt is not deserialized, so the receipt loads even if its
+    /// planner or its settings have since changed or are unknown to this `nix-installer`. Only its
+    /// name and settings are kept, to describe the plan. Reverting runs purely from the recorded
+    /// actions, without the planner's [`pre_uninstall`](Planner::pre_uninstall) actions.
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))]
+    pub async fn uninstall_plan_from_receipt(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, NixInstallerError> {
+        let mut receipt = read_receipt(path.as_ref()).await?;
+        let migrated_from = migrate_receipt_value(&mut receipt)?;
+        let planner = Receipt::from_recorded(receipt.get("planner"));
+        let UninstallReceipt {
+            version,
+            receipt_schema_version,
+            actions,
+            requires_reboot_before_use,
+            #[cfg(feature = "diagnostics")]
+            diagnostic_data,
+            snapshot,
+            existing_nix_store,
+        } = serde_json::from_value(receipt)
+            .map_err(|e| NixInstallerError::MigratingReceipt(Some(migrated_from), e))?;
+
+        Ok(Self {
+            version,
+            receipt_schema_version,
+            actions,
+            planner: planner.boxed(),
+            requires_reboot_before_use,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
+            describe_override: None,
+            receipt_location: None,
+            event_channel: None,
+            snapshot,
+            capture_snapshot: false,
+            uninstall_force: false,
+            existing_nix_store,
         })
     }
+
+    /// Make [`uninstall`](Self::uninstall) remove as much as possible, also reverting the actions which never completed
+    ///
+    /// Reverting an action which never completed usually fails on what it never created. Those
+    /// errors are still returned, in the [`NixInstallerError::ActionRevert`] of the uninstall.
+    pub fn uninstall_force(&mut self, uninstall_force: bool) -> &mut Self {
+        self.uninstall_force = uninstall_force;
+        self
+    }
+
+    /// Replace the description [`describe_install`](Self::describe_install) shows for any action `describe_override` returns `Some` for, such as to brand or localize it
+    ///
+    /// Actions `describe_override` returns `None` for keep their built-in description. Actions
+    /// which have already completed are never described.
+    pub fn describe_override(
+        &mut self,
+        describe_override: impl Fn(&dyn Action) -> Option<ActionDescription> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.describe_override = Some(DescribeOverride(Arc::new(describe_override)));
+        self
+    }
+
+    /// Capture a [`SystemSnapshot`] of the files the actions touch before [`install`](Self::install) executes them
+    ///
+    /// The snapshot is stored in the receipt, and [`uninstall`](Self::uninstall) uses it to
+    /// remove the files which did not exist before the install and restore the prior content of
+    /// the ones which did. A resumed install keeps the snapshot taken by the first attempt. Only
+    /// files are captured, users, groups and services are left to the action reverts.
+    pub fn capture_snapshot(&mut self, capture_snapshot: bool) -> &mut Self {
+        self.capture_snapshot = capture_snapshot;
+        self
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn describe_install(&self, explain: bool) -> Result<String, NixInstallerError> {
         let Self {
             planner,
-            actions,
             version,
+            requires_reboot_before_use,
+            existing_nix_store,
             ..
         } = self;
 
@@ -91,12 +448,50 @@ impl InstallPlan {
             "\
             Nix install plan (v{version})\n\
             Planner: {planner}{maybe_default_setting_note}\n\
+            {maybe_store_prefix_note}\
             \n\
             {maybe_plan_settings}\
             Planned actions:\n\
             {actions}\n\
+            \n\
+            Estimated time: ~{estimated_duration}\n\
+            {disk_space}\
+            {maybe_reboot_note}\
+            {maybe_existing_nix_store_note}\
+            {maybe_profile_note}\
         ",
             planner = planner.typetag_name(),
+            maybe_profile_note = match profile_note(planner.as_ref()) {
+                Some(profile_note) => format!("\n{}\n", profile_note.bold().yellow()),
+                None => String::new(),
+            },
+            estimated_duration = format_estimated_duration(self.estimated_duration()),
+            disk_space = self
+                .disk_space()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|disk_space| format!("{disk_space}\n"))
+                .collect::<String>(),
+            maybe_store_prefix_note = store_prefix_note(planner.as_ref()),
+            maybe_existing_nix_store_note = match existing_nix_store {
+                Some(existing_nix_store) => format!(
+                    "\n{}\n",
+                    format!("{existing_nix_store}, which will be installed over")
+                        .bold()
+                        .yellow()
+                ),
+                None => String::new(),
+            },
+            maybe_reboot_note = if *requires_reboot_before_use {
+                format!(
+                    "\n{}\n",
+                    "A reboot will be required after installing before Nix can be used"
+                        .bold()
+                        .yellow()
+                )
+            } else {
+                String::new()
+            },
             maybe_default_setting_note = if plan_settings.is_empty() {
                 String::from(" (with default settings)")
             } else {
@@ -114,10 +509,9 @@ impl InstallPlan {
                     plan_settings = plan_settings.join("\n")
                 )
             },
-            actions = actions
-                .iter()
-                .map(|v| v.describe_execute())
-                .flatten()
+            actions = self
+                .install_descriptions()
+                .into_iter()
                 .map(|desc| {
                     let ActionDescription {
                         description,
@@ -139,47 +533,316 @@ impl InstallPlan {
         Ok(buf)
     }
 
+    /// The same plan as [`describe_install`](Self::describe_install), as a document for tools which wrap the installer
+    ///
+    /// Explanations of the actions are only included when `explain` is set, as are settings left at their default.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn describe_install_json(
+        &self,
+        explain: bool,
+    ) -> Result<serde_json::Value, NixInstallerError> {
+        let plan_settings = if explain {
+            self.planner.settings()?
+        } else {
+            self.planner.configured_settings().await?
+        };
+        // Stabilize output order
+        let plan_settings = plan_settings
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let actions = self
+            .install_descriptions()
+            .into_iter()
+            .map(
+                |ActionDescription {
+                     description,
+                     explanation,
+                 }| {
+                    serde_json::json!({
+                        "description": description,
+                        "explanation": if explain { explanation } else { vec![] },
+                    })
+                },
+            )
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::json!({
+            "version": self.version.to_string(),
+            "planner": self.planner.typetag_name(),
+            "settings": plan_settings,
+            "actions": actions,
+            "estimated_duration_secs": self.estimated_duration().as_secs_f64().ceil() as u64,
+            "disk_space": self.disk_space().unwrap_or_default().into_iter().map(|DiskSpace { path, required, available }| {
+                serde_json::json!({
+                    "path": path,
+                    "required_bytes": required,
+                    "available_bytes": available,
+                })
+            }).collect::<Vec<_>>(),
+            "requires_reboot_before_use": self.requires_reboot_before_use,
+            "existing_nix_store": self.existing_nix_store,
+        }))
+    }
+
+    /// The descriptions of what [`install`](Self::install) would do, with any [`describe_override`](Self::describe_override) applied
+    fn install_descriptions(&self) -> Vec<ActionDescription> {
+        self.actions
+            .iter()
+            .flat_map(|v| {
+                let descriptions = v.describe_execute();
+                match &self.describe_override {
+                    Some(DescribeOverride(describe_override)) if !descriptions.is_empty() => {
+                        describe_override(v.action.as_ref())
+                            .map(|description| vec![description])
+                            .unwrap_or(descriptions)
+                    },
+                    _ => descriptions,
+                }
+            })
+            .collect()
+    }
+
+    /// The top-level actions of the plan with the given [`ActionTag`]
+    ///
+    /// Actions nested in another, like those of [`ProvisionNix`](crate::action::common::ProvisionNix), are not included.
+    pub fn actions_tagged(
+        &self,
+        tag: ActionTag,
+    ) -> impl Iterator<Item = &StatefulAction<Box<dyn Action>>> {
+        self.actions
+            .iter()
+            .filter(move |action| action.action_tag() == tag)
+    }
+
+    /// A rough guess of how long [`install`](Self::install) takes, the sum of [`Action::estimated_duration`] over the actions which have yet to run
+    pub fn estimated_duration(&self) -> Duration {
+        self.actions
+            .iter()
+            .map(|action| action.estimated_duration())
+            .sum()
+    }
+
+    /// Check every filesystem the actions which have yet to run write to has the free space they need
+    ///
+    /// [`install`](Self::install) checks this before any action executes, so it does not run out of
+    /// space halfway, such as with a half-populated Nix store.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn check_disk_space(&self) -> Result<(), NixInstallerError> {
+        for DiskSpace {
+            path,
+            required,
+            available,
+        } in self.disk_space()?
+        {
+            tracing::debug!(
+                "`{}` needs {required} bytes, {available} are available",
+                path.display()
+            );
+            if required > available {
+                return Err(NixInstallerError::InsufficientDiskSpace {
+                    required,
+                    available,
+                    path,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The free space each filesystem needs for the actions which have yet to run, see [`Action::required_disk_space`]
+    // The `statvfs` fields are narrower on some platforms
+    #[allow(clippy::useless_conversion)]
+    fn disk_space(&self) -> Result<Vec<DiskSpace>, NixInstallerError> {
+        let mut filesystems: Vec<(u64, DiskSpace)> = Vec::new();
+        for (path, required) in self
+            .actions
+            .iter()
+            .flat_map(|action| action.required_disk_space())
+        {
+            // Paths like `/nix` are only created by the install, so their closest existing parent is
+            // checked. On macOS, the Nix volume is then created in the APFS container of `/`, and
+            // grows within it, so the free space of the container (which `/` reports) is what counts.
+            let existing = path
+                .ancestors()
+                .find(|ancestor| ancestor.exists())
+                .unwrap_or_else(|| Path::new("/"));
+            let stat = statvfs(existing)
+                .map_err(|e| NixInstallerError::CheckingDiskSpace(existing.to_path_buf(), e))?;
+            let filesystem_id = u64::from(stat.filesystem_id());
+            match filesystems.iter_mut().find(|(id, _)| *id == filesystem_id) {
+                Some((_, disk_space)) => {
+                    disk_space.required = disk_space.required.saturating_add(required)
+                },
+                None => filesystems.push((
+                    filesystem_id,
+                    DiskSpace {
+                        path,
+                        required,
+                        available: u64::from(stat.blocks_available())
+                            .saturating_mul(u64::from(stat.fragment_size())),
+                    },
+                )),
+            }
+        }
+        Ok(filesystems
+            .into_iter()
+            .map(|(_, disk_space)| disk_space)
+            .collect())
+    }
+
+    /// Check the preconditions of every action which has yet to run, without changing the system
+    ///
+    /// Returns what [`install`](Self::install) would do, or every failed [`Action::preflight`] check.
+    /// The free disk space is checked first, see [`check_disk_space`](Self::check_disk_space).
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn dry_run(&self) -> Result<Vec<ActionDescription>, NixInstallerError> {
+        self.check_disk_space()?;
+
+        let mut descriptions = Vec::new();
+        let mut errors = Vec::new();
+        for action in &self.actions {
+            if let Err(err) = action.try_preflight().await {
+                errors.push(err);
+            }
+            descriptions.extend(action.describe_execute());
+        }
+
+        if errors.is_empty() {
+            Ok(descriptions)
+        } else {
+            Err(NixInstallerError::Preflight(errors))
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn install(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+        event_channel: impl Into<Option<Sender<InstallEvent>>>,
     ) -> Result<(), NixInstallerError> {
-        let Self { actions, .. } = self;
+        self.install_with_concurrency(cancel_channel, event_channel, None)
+            .await
+    }
+
+    /// Like [`install`](Self::install), but cancelled by the first `SIGINT` (Ctrl-C) or `SIGTERM`
+    ///
+    /// The running actions finish and the receipt is written before [`NixInstallerError::Cancelled`]
+    /// is returned, so the install can be resumed or uninstalled. A second signal exits the process
+    /// immediately. Once this is called, these signals no longer terminate the process by default.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn install_with_ctrlc(&mut self) -> Result<(), NixInstallerError> {
+        let mut interrupt = signal(SignalKind::interrupt()).map_err(NixInstallerError::Signal)?;
+        let mut terminate = signal(SignalKind::terminate()).map_err(NixInstallerError::Signal)?;
+        let (sender, receiver) = tokio::sync::broadcast::channel(1);
+
+        let handler = tokio::spawn(async move {
+            let mut received = false;
+            loop {
+                let (name, code) = tokio::select! {
+                    Some(()) = interrupt.recv() => ("SIGINT", 130),
+                    Some(()) = terminate.recv() => ("SIGTERM", 143),
+                    else => return,
+                };
+                if received {
+                    tracing::warn!("Got {name} signal again, exiting immediately");
+                    std::process::exit(code);
+                }
+                tracing::warn!("Got {name} signal, cancelling once the running actions finish (send it again to exit immediately)");
+                received = true;
+                sender.send(()).ok();
+            }
+        });
+
+        let result = self.install(receiver, None).await;
+        handler.abort();
+        result
+    }
+
+    /// Like [`install`](Self::install), but run up to `concurrency` (default 1) actions at once
+    ///
+    /// Only consecutive actions which declare they do not depend on each other through
+    /// [`Action::depends_on`] are run together, all other actions still run in plan order.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn install_with_concurrency(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        event_channel: impl Into<Option<Sender<InstallEvent>>>,
+        concurrency: impl Into<Option<NonZeroUsize>>,
+    ) -> Result<(), NixInstallerError> {
+        let _lock = InstallLock::acquire(self.planner.as_ref())?;
+        self.check_disk_space()?;
         let mut cancel_channel = cancel_channel.into();
+        let event_channel = event_channel.into().or_else(|| self.event_channel.clone());
+        let concurrency = concurrency.into().map(NonZeroUsize::get).unwrap_or(1);
 
-        // This is **deliberately sequential**.
-        // Actions which are parallelizable are represented by "group actions" like CreateUsers
+        if self.capture_snapshot && self.snapshot.is_none() {
+            let touched_paths = self
+                .actions
+                .iter()
+                .flat_map(|action| action.action.touched_paths())
+                .collect::<Vec<_>>();
+            let snapshot = SystemSnapshot::capture(touched_paths)
+                .await
+                .map_err(NixInstallerError::CapturingSnapshot)?;
+            self.snapshot = Some(snapshot);
+            // Written before anything changes, so even an interrupted install restores precisely
+            write_receipt(self.clone()).await?;
+        }
+
+        // Weighed up front, as actions weigh nothing once completed
+        let weights = self
+            .actions
+            .iter()
+            .map(|action| action.weight())
+            .collect::<Vec<_>>();
+
+        // Batches are **deliberately sequential**.
+        // Actions which are parallelizable are typically represented by "group actions" like CreateUsers
         // The plan itself represents the concept of the sequence of stages.
-        for action in actions {
+        // A cancellation received while a batch runs is only acted on once its actions finish
+        let mut cancelled = false;
+        for batch in batches(&self.actions, concurrency) {
             if let Some(ref mut cancel_channel) = cancel_channel {
-                if cancel_channel.try_recv()
-                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
-                {
-                    if let Err(err) = write_receipt(self.clone()).await {
-                        tracing::error!("Error saving receipt: {:?}", err);
-                    }
-
-                    #[cfg(feature = "diagnostics")]
-                    if let Some(diagnostic_data) = &self.diagnostic_data {
-                        diagnostic_data
-                            .clone()
-                            .send(
-                                crate::diagnostics::DiagnosticAction::Install,
-                                crate::diagnostics::DiagnosticStatus::Cancelled,
-                            )
-                            .await?;
-                    }
+                cancelled = cancelled
+                    || cancel_channel.try_recv()
+                        != Err(tokio::sync::broadcast::error::TryRecvError::Empty);
+            }
+            if cancelled {
+                if let Err(err) = write_receipt(self.clone()).await {
+                    tracing::error!("Error saving receipt: {:?}", err);
+                }
 
-                    return Err(NixInstallerError::Cancelled);
+                #[cfg(feature = "diagnostics")]
+                if let Some(diagnostic_data) = &self.diagnostic_data {
+                    diagnostic_data
+                        .clone()
+                        .send(
+                            crate::diagnostics::DiagnosticAction::Install,
+                            crate::diagnostics::DiagnosticStatus::Cancelled,
+                        )
+                        .await?;
                 }
+
+                return Err(NixInstallerError::Cancelled);
             }
 
-            tracing::info!("Step: {}", action.tracing_synopsis());
-            if let Err(err) = action.try_execute().await {
+            let execute = self.execute_batch(batch, &weights, &event_channel);
+            tokio::pin!(execute);
+            let result = loop {
+                tokio::select! {
+                    result = &mut execute => break result,
+                    () = cancel_received(&mut cancel_channel), if !cancelled => {
+                        tracing::info!("Cancelling once the running actions finish");
+                        cancelled = true;
+                    },
+                }
+            };
+
+            if let Err(err) = result {
+                // The receipt records which actions of the batch completed
                 if let Err(err) = write_receipt(self.clone()).await {
                     tracing::error!("Error saving receipt: {:?}", err);
                 }
-                let err = NixInstallerError::Action(err);
                 #[cfg(feature = "diagnostics")]
                 if let Some(diagnostic_data) = &self.diagnostic_data {
                     diagnostic_data
@@ -197,6 +860,7 @@ impl InstallPlan {
         }
 
         write_receipt(self.clone()).await?;
+        send_event(&event_channel, InstallEvent::PlanCompleted);
         #[cfg(feature = "diagnostics")]
         if let Some(diagnostic_data) = &self.diagnostic_data {
             diagnostic_data
@@ -211,12 +875,122 @@ impl InstallPlan {
         Ok(())
     }
 
+    /// Apply the settings of `planner` to this completed install, instead of uninstalling and reinstalling
+    ///
+    /// Executes the [`reconfigure`](Planner::reconfigure) actions of `planner`, which must be the
+    /// same kind of planner as planned this install, then records `planner` in the receipt. The
+    /// reconfigure actions are not recorded themselves, instead the recorded actions of the same
+    /// kind and path are updated to them, since reverting the install already removes what they
+    /// change.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn reconfigure(
+        &mut self,
+        planner: Box<dyn Planner>,
+    ) -> Result<(), NixInstallerError> {
+        if planner.typetag_name() != self.planner.typetag_name() {
+            return Err(PlannerError::ReconfigurePlannerChanged {
+                recorded: self.planner.typetag_name(),
+                given: planner.typetag_name(),
+            }
+            .into());
+        }
+
+        let event_channel = self.event_channel.clone();
+        let mut reconfigured = Vec::new();
+        for mut action in planner.reconfigure().await? {
+            tracing::info!("Step: {}", action.tracing_synopsis());
+            execute_action(&mut action, &event_channel).await?;
+            actions_with_paths(&serde_json::to_value(&action)?, &mut reconfigured);
+        }
+
+        let mut recorded = serde_json::to_value(&self.actions)?;
+        update_recorded_actions(&mut recorded, &reconfigured);
+        self.actions = serde_json::from_value(recorded)?;
+        self.planner = planner;
+        write_receipt(self.clone()).await
+    }
+
+    /// Execute the actions in `batch` concurrently, waiting for all of them before returning the first error
+    ///
+    /// `weights` are the [`StatefulAction::weight`]s of all actions, from before the install started.
+    async fn execute_batch(
+        &mut self,
+        batch: Range<usize>,
+        weights: &[u32],
+        event_channel: &Option<Sender<InstallEvent>>,
+    ) -> Result<(), NixInstallerError> {
+        let total_weight = weights.iter().map(|&weight| u64::from(weight)).sum::<u64>();
+        let started = |index: usize, synopsis: String| InstallEvent::ActionStarted {
+            index,
+            synopsis,
+            weight: weights[index],
+            completed_weight: weights[..index]
+                .iter()
+                .map(|&weight| u64::from(weight))
+                .sum(),
+            total_weight,
+        };
+
+        if batch.len() == 1 {
+            let index = batch.start;
+            let action = &mut self.actions[index];
+            let synopsis = action.tracing_synopsis();
+            tracing::info!("Step: {synopsis}");
+            send_event(event_channel, started(index, synopsis.clone()));
+            let result = execute_action(action, event_channel).await;
+            send_event(event_channel, action_finished(index, synopsis, &result));
+            return result;
+        }
+
+        let mut handles = Vec::with_capacity(batch.len());
+        for idx in batch {
+            let mut action = self.actions[idx].clone();
+            let synopsis = action.tracing_synopsis();
+            tracing::info!("Step: {synopsis}");
+            send_event(event_channel, started(idx, synopsis.clone()));
+            let span = tracing::Span::current();
+            let event_channel = event_channel.clone();
+            let handle = tokio::spawn(
+                async move {
+                    let result = execute_action(&mut action, &event_channel).await;
+                    send_event(&event_channel, action_finished(idx, synopsis, &result));
+                    (action, result)
+                }
+                .instrument(span),
+            );
+            handles.push((idx, handle));
+        }
+
+        let mut first_error = None;
+        for (idx, handle) in handles {
+            let result = match handle.await {
+                Ok((action, result)) => {
+                    self.actions[idx] = action;
+                    result
+                },
+                Err(e) => Err(NixInstallerError::Action(ActionError::new(
+                    self.actions[idx].action_tag(),
+                    ActionErrorKind::Join(e),
+                ))),
+            };
+            if let Err(err) = result {
+                first_error.get_or_insert(err);
+            }
+        }
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn describe_uninstall(&self, explain: bool) -> Result<String, NixInstallerError> {
         let Self {
             version,
             planner,
             actions,
+            snapshot,
             ..
         } = self;
 
@@ -233,18 +1007,31 @@ impl InstallPlan {
             .collect::<Vec<_>>();
         // Stabilize output order
         plan_settings.sort();
+        let restore_snapshot = snapshot.iter().map(|snapshot| {
+            vec![ActionDescription::new(
+                "Restore the files touched by the install to their prior state".to_string(),
+                snapshot
+                    .files
+                    .keys()
+                    .map(|path| format!("`{}`", path.display()))
+                    .collect(),
+            )]
+        });
+        let pre_uninstall = planner.pre_uninstall().await?;
 
         let buf = format!(
             "\
             Nix uninstall plan (v{version})\n\
             \n\
             Planner: {planner}{maybe_default_setting_note}\n\
+            {maybe_store_prefix_note}\
             \n\
             {maybe_plan_settings}\
             Planned actions:\n\
             {actions}\n\
         ",
             planner = planner.typetag_name(),
+            maybe_store_prefix_note = store_prefix_note(planner.as_ref()),
             maybe_default_setting_note = if plan_settings.is_empty() {
                 String::from(" (with default settings)")
             } else {
@@ -262,10 +1049,11 @@ impl InstallPlan {
                     plan_settings = plan_settings.join("\n")
                 )
             },
-            actions = actions
+            actions = pre_uninstall
                 .iter()
-                .rev()
-                .map(|v| v.describe_revert())
+                .map(|v| v.describe_execute())
+                .chain(actions.iter().rev().map(|v| v.describe_revert()))
+                .chain(restore_snapshot)
                 .flatten()
                 .map(|desc| {
                     let ActionDescription {
@@ -293,43 +1081,104 @@ impl InstallPlan {
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
     ) -> Result<(), NixInstallerError> {
-        let Self { actions, .. } = self;
+        let _lock = InstallLock::acquire(self.planner.as_ref())?;
         let mut cancel_channel = cancel_channel.into();
         let mut errors = vec![];
 
+        // Stop anything still using the files about to be removed, like the Nix daemon
+        for mut action in self.planner.pre_uninstall().await? {
+            tracing::info!("Step: {}", action.tracing_synopsis());
+            let span = action_span("execute", &action);
+            let start = Instant::now();
+            let executed = traced(span.clone(), action.try_execute()).await;
+            log_action_step("execute", &action, start, executed.as_ref().err());
+            record_final_state(&span, &action);
+            if let Err(err) = executed {
+                errors.push(err);
+            }
+        }
+
+        // A revert removing a large directory checks this between entries, rather than waiting
+        // until the next action to notice the cancellation
+        let removal_context = ActionContext {
+            event_channel: self.event_channel.clone(),
+            ..Default::default()
+        };
+        let cancel_watcher = cancel_channel.as_ref().map(|cancel_channel| {
+            let mut cancel_channel = cancel_channel.resubscribe();
+            let cancelled = removal_context.cancelled.clone();
+            tokio::spawn(async move {
+                let _ = cancel_channel.recv().await;
+                cancelled.store(true, Ordering::Relaxed);
+            })
+        });
+
+        let Self { actions, .. } = self;
+
         // This is **deliberately sequential**.
         // Actions which are parallelizable are represented by "group actions" like CreateUsers
         // The plan itself represents the concept of the sequence of stages.
         for action in actions.iter_mut().rev() {
-            if let Some(ref mut cancel_channel) = cancel_channel {
-                if cancel_channel.try_recv()
-                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
-                {
-                    if let Err(err) = write_receipt(self.clone()).await {
-                        tracing::error!("Error saving receipt: {:?}", err);
-                    }
+            let cancelled = removal_context.is_cancelled()
+                || cancel_channel.as_mut().is_some_and(|cancel_channel| {
+                    cancel_channel.try_recv()
+                        != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+                });
+            if cancelled {
+                if let Some(cancel_watcher) = &cancel_watcher {
+                    cancel_watcher.abort();
+                }
+                if let Err(err) = write_receipt(self.clone()).await {
+                    tracing::error!("Error saving receipt: {:?}", err);
+                }
 
-                    #[cfg(feature = "diagnostics")]
-                    if let Some(diagnostic_data) = &self.diagnostic_data {
-                        diagnostic_data
-                            .clone()
-                            .send(
-                                crate::diagnostics::DiagnosticAction::Uninstall,
-                                crate::diagnostics::DiagnosticStatus::Cancelled,
-                            )
-                            .await?;
-                    }
-                    return Err(NixInstallerError::Cancelled);
+                #[cfg(feature = "diagnostics")]
+                if let Some(diagnostic_data) = &self.diagnostic_data {
+                    diagnostic_data
+                        .clone()
+                        .send(
+                            crate::diagnostics::DiagnosticAction::Uninstall,
+                            crate::diagnostics::DiagnosticStatus::Cancelled,
+                        )
+                        .await?;
                 }
+                return Err(NixInstallerError::Cancelled);
             }
 
             tracing::info!("Revert: {}", action.tracing_synopsis());
-            if let Err(errs) = action.try_revert().await {
+            let span = action_span("revert", action);
+            let start = Instant::now();
+            let reverted = if self.uninstall_force {
+                removal_context
+                    .clone()
+                    .scope(traced(span.clone(), action.try_revert_force()))
+                    .await
+            } else {
+                removal_context
+                    .clone()
+                    .scope(traced(span.clone(), action.try_revert()))
+                    .await
+            };
+            log_action_step("revert", action, start, reverted.as_ref().err());
+            record_final_state(&span, action);
+            if let Err(errs) = reverted {
                 errors.push(errs);
             }
         }
+        if let Some(cancel_watcher) = cancel_watcher {
+            cancel_watcher.abort();
+        }
 
-        if errors.is_empty() {
+        // The actions only know what they changed, the snapshot knows what was there before
+        let snapshot_errors = match &self.snapshot {
+            Some(snapshot) => {
+                tracing::info!("Restore: Files touched by the install");
+                snapshot.restore().await
+            },
+            None => vec![],
+        };
+
+        if errors.is_empty() && snapshot_errors.is_empty() {
             #[cfg(feature = "diagnostics")]
             if let Some(diagnostic_data) = &self.diagnostic_data {
                 diagnostic_data
@@ -343,7 +1192,14 @@ impl InstallPlan {
 
             Ok(())
         } else {
-            let error = NixInstallerError::ActionRevert(errors);
+            let error = if errors.is_empty() {
+                NixInstallerError::RestoringSnapshot(snapshot_errors)
+            } else {
+                for snapshot_error in snapshot_errors {
+                    tracing::error!("Error restoring the system state: {:?}", snapshot_error);
+                }
+                NixInstallerError::ActionRevert(errors)
+            };
             #[cfg(feature = "diagnostics")]
             if let Some(diagnostic_data) = &self.diagnostic_data {
                 diagnostic_data
@@ -359,74 +1215,1666 @@ impl InstallPlan {
             return Err(error);
         }
     }
-}
 
-async fn write_receipt(plan: InstallPlan) -> Result<(), NixInstallerError> {
-    tokio::fs::create_dir_all("/nix")
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(PathBuf::from("/nix"), e))?;
-    let install_receipt_path = PathBuf::from(RECEIPT_LOCATION);
-    let self_json =
-        serde_json::to_string_pretty(&plan).map_err(NixInstallerError::SerializingReceipt)?;
-    tokio::fs::write(&install_receipt_path, format!("{self_json}\n"))
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(install_receipt_path, e))?;
-    Result::<(), NixInstallerError>::Ok(())
-}
+    /// Revert the actions from the last one started back to, and including, the one at `index`
+    ///
+    /// Allows undoing only the last few steps of a failed install before retrying it with
+    /// [`install`](Self::install), instead of a full [`uninstall`](Self::uninstall). Actions are
+    /// reverted with [`try_revert`](StatefulAction::try_revert), so ones never started are left
+    /// alone, and an action left in [`Progress`](ActionState::Progress) which is made of other
+    /// actions only reverts those of them it started. Stops at the first action which fails to
+    /// revert, so nothing it depends on is removed from under it. The receipt is updated either way.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn revert_through(&mut self, index: usize) -> Result<(), NixInstallerError> {
+        if index >= self.actions.len() {
+            return Err(NixInstallerError::ActionIndexOutOfRange {
+                index,
+                actions: self.actions.len(),
+            });
+        }
 
-fn current_version() -> Result<Version, semver::Error> {
-    let nix_installer_version_str = env!("CARGO_PKG_VERSION");
-    Version::from_str(nix_installer_version_str)
-}
+        let mut reverted = Ok(());
+        for action in self.actions[index..].iter_mut().rev() {
+            if action.state == ActionState::Uncompleted {
+                continue;
+            }
+            tracing::info!("Revert: {}", action.tracing_synopsis());
+            let span = action_span("revert", action);
+            let start = Instant::now();
+            let result = traced(span.clone(), action.try_revert()).await;
+            log_action_step("revert", action, start, result.as_ref().err());
+            record_final_state(&span, action);
+            if let Err(err) = result {
+                reverted = Err(NixInstallerError::ActionRevert(vec![err]));
+                break;
+            }
+        }
 
-fn ensure_version<'de, D: Deserializer<'de>>(d: D) -> Result<Version, D::Error> {
-    let plan_version = Version::deserialize(d)?;
-    let req = VersionReq::parse(&plan_version.to_string()).map_err(|_e| {
-        D::Error::custom(&format!(
-            "Could not parse version `{plan_version}` as a version requirement, please report this",
-        ))
-    })?;
-    let nix_installer_version = current_version().map_err(|_e| {
-        D::Error::custom(&format!(
-            "Could not parse `nix-installer`'s version `{}` as a valid version according to Semantic Versioning, therefore the plan version ({plan_version}) compatibility cannot be checked", env!("CARGO_PKG_VERSION")
-        ))
-    })?;
-    if req.matches(&nix_installer_version) {
-        Ok(plan_version)
-    } else {
-        Err(D::Error::custom(&format!(
-            "This version of `nix-installer` ({nix_installer_version}) is not compatible with this plan's version ({plan_version}), you probably are trying to install with a new version of `nix-installer` which is not compatible with version {plan_version} plans. To upgrade Nix, try `sudo -i nix upgrade-nix`. To reinstall Nix, try `/nix/nix-installer uninstall` then installing again from the instructions on https://github.com/DeterminateSystems/nix-installer. To continue using this plan, download the matching release from https://github.com/DeterminateSystems/nix-installer/releases.",
-        )))
+        if let Err(err) = write_receipt(self.clone()).await {
+            tracing::error!("Error saving receipt: {:?}", err);
+        }
+        reverted
     }
-}
 
-#[cfg(test)]
-mod test {
-    use semver::Version;
+    /// Files written during the install which have since been modified, such as shell profiles
+    /// edited by users or other tools
+    pub fn check_drift(&self) -> Vec<PathBuf> {
+        self.actions
+            .iter()
+            .flat_map(|action| action.action.check_drift())
+            .collect()
+    }
 
-    use crate::{planner::BuiltinPlanner, InstallPlan, NixInstallerError};
+    /// What changed from this plan to `other`, such as between the receipts of two installs
+    ///
+    /// Actions are matched by their typetag name and synopsis, an action matching one in this plan
+    /// but planned differently is [`PlanDiffEntry::ActionChanged`]. Whether actions have
+    /// completed is not compared.
+    pub fn diff(&self, other: &InstallPlan) -> Vec<PlanDiffEntry> {
+        let mut entries = vec![];
 
-    #[tokio::test]
-    async fn ensure_version_allows_compatible() -> Result<(), NixInstallerError> {
-        let planner = BuiltinPlanner::default().await?;
-        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
-        let value = serde_json::json!({
-            "planner": planner.boxed(),
-            "version": good_version,
-            "actions": [],
-        });
-        let maybe_plan: Result<InstallPlan, serde_json::Error> = serde_json::from_value(value);
-        maybe_plan.unwrap();
+        let (from_planner, to_planner) =
+            (self.planner.typetag_name(), other.planner.typetag_name());
+        if from_planner != to_planner {
+            entries.push(PlanDiffEntry::PlannerChanged {
+                from: from_planner.to_string(),
+                to: to_planner.to_string(),
+            });
+        }
+
+        // Listing settings only fails if one can't be serialized, which would also fail writing the receipt
+        let from_settings = self.planner.settings().unwrap_or_default();
+        let to_settings = other.planner.settings().unwrap_or_default();
+        let mut names = from_settings
+            .keys()
+            .chain(to_settings.keys())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let name = name.clone();
+            match (from_settings.get(&name), to_settings.get(&name)) {
+                (None, Some(value)) => entries.push(PlanDiffEntry::SettingAdded {
+                    name,
+                    value: value.clone(),
+                }),
+                (Some(value), None) => entries.push(PlanDiffEntry::SettingRemoved {
+                    name,
+                    value: value.clone(),
+                }),
+                (Some(from), Some(to)) if from != to => {
+                    entries.push(PlanDiffEntry::SettingChanged {
+                        name,
+                        from: from.clone(),
+                        to: to.clone(),
+                    })
+                },
+                _ => (),
+            }
+        }
+
+        let key = |action: &StatefulAction<Box<dyn Action>>| {
+            (
+                action.inner_typetag_name().to_string(),
+                action.tracing_synopsis(),
+                serde_json::to_value(&action.action)
+                    .map(without_states)
+                    .unwrap_or_default(),
+            )
+        };
+        let mut unmatched = self.actions.iter().map(key).collect::<Vec<_>>();
+        for (action, synopsis, planned) in other.actions.iter().map(key) {
+            match unmatched
+                .iter()
+                .position(|(a, s, _)| *a == action && *s == synopsis)
+            {
+                Some(index) => {
+                    let (_, _, previously_planned) = unmatched.remove(index);
+                    if previously_planned != planned {
+                        entries.push(PlanDiffEntry::ActionChanged { action, synopsis });
+                    }
+                },
+                None => entries.push(PlanDiffEntry::ActionAdded { action, synopsis }),
+            }
+        }
+        entries.extend(
+            unmatched
+                .into_iter()
+                .map(|(action, synopsis, _)| PlanDiffEntry::ActionRemoved { action, synopsis }),
+        );
+
+        entries
+    }
+
+    /// A bash script running the same commands as [`install`](Self::install), for environments which only allow reviewed scripts
+    ///
+    /// Fails naming the first action which cannot be expressed as shell commands, such as one
+    /// prompting for input. Actions which have already completed are left out.
+    pub fn to_shell_script(&self) -> Result<String, NixInstallerError> {
+        let mut buf = format!(
+            "#!/usr/bin/env bash\n\
+            # Generated by nix-installer {version} from the `{planner}` planner\n\
+            set -euo pipefail\n",
+            version = self.version,
+            planner = self.planner.typetag_name(),
+        );
+        for action in &self.actions {
+            let commands = action.to_shell().ok_or_else(|| {
+                NixInstallerError::NotRepresentableAsShell(action.inner_typetag_name().to_string())
+            })?;
+            if commands.is_empty() {
+                continue;
+            }
+            buf.push_str(&format!("\n# {}\n", action.tracing_synopsis()));
+            for command in commands {
+                buf.push_str(&command);
+                buf.push('\n');
+            }
+        }
+        Ok(buf)
+    }
+}
+
+/// `value`, a serialized action, without the `state` of any of the actions nested within it
+fn without_states(mut value: serde_json::Value) -> serde_json::Value {
+    match &mut value {
+        serde_json::Value::Object(map) => {
+            // A nested `StatefulAction`, rather than an action tagged with its typetag name
+            if map.get("action").is_some_and(serde_json::Value::is_object) {
+                map.remove("state");
+            }
+            for (_, nested) in map.iter_mut() {
+                *nested = without_states(nested.take());
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for nested in values.iter_mut() {
+                *nested = without_states(nested.take());
+            }
+        },
+        _ => (),
+    }
+    value
+}
+
+/// Collect the actions nested in `value`, a serialized action, which act on a `path`
+fn actions_with_paths(value: &serde_json::Value, found: &mut Vec<serde_json::Value>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            // An action tagged with its typetag name, rather than a nested `StatefulAction`
+            if map.get("action").is_some_and(serde_json::Value::is_string)
+                && map.contains_key("path")
+            {
+                found.push(value.clone());
+            }
+            for nested in map.values() {
+                actions_with_paths(nested, found);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for nested in values {
+                actions_with_paths(nested, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Replace the actions nested in `recorded`, serialized actions, with those of `reconfigured` of the same kind and path
+///
+/// The recorded states are kept, so reverting the install still removes what it created.
+fn update_recorded_actions(recorded: &mut serde_json::Value, reconfigured: &[serde_json::Value]) {
+    if let serde_json::Value::Object(map) = recorded {
+        if let Some(action) = map.get_mut("action").filter(|action| action.is_object()) {
+            let same_action = |update: &&serde_json::Value| {
+                update.get("action") == action.get("action")
+                    && update.get("path") == action.get("path")
+            };
+            if let Some(update) = reconfigured.iter().find(same_action) {
+                *action = update.clone();
+                return;
+            }
+        }
+    }
+    match recorded {
+        serde_json::Value::Object(map) => {
+            for nested in map.values_mut() {
+                update_recorded_actions(nested, reconfigured);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for nested in values.iter_mut() {
+                update_recorded_actions(nested, reconfigured);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Send `event` if anyone is listening, a frontend going away should not interrupt the install
+fn send_event(event_channel: &Option<Sender<InstallEvent>>, event: InstallEvent) {
+    if let Some(event_channel) = event_channel {
+        let _ = event_channel.send(event);
+    }
+}
+
+fn action_finished(
+    index: usize,
+    synopsis: String,
+    result: &Result<(), NixInstallerError>,
+) -> InstallEvent {
+    match result {
+        Ok(()) => InstallEvent::ActionCompleted { index, synopsis },
+        Err(err) => InstallEvent::ActionFailed {
+            index,
+            synopsis,
+            error: err.to_string(),
+        },
+    }
+}
+
+/// Execute `action`, giving up once its [`StatefulAction::timeout`] has passed
+///
+/// A timed out action is left in progress, so it is reverted as if it had failed.
+async fn execute_action(
+    action: &mut StatefulAction<Box<dyn Action>>,
+    event_channel: &Option<Sender<InstallEvent>>,
+) -> Result<(), NixInstallerError> {
+    let span = action_span("execute", action);
+    let timeout = action.timeout;
+    let synopsis = action.tracing_synopsis();
+    let download_context = ActionContext {
+        event_channel: event_channel.clone(),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let execute = traced(span.clone(), async {
+        match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, action.try_execute()).await {
+                Ok(result) => result.map_err(NixInstallerError::Action),
+                Err(_) => Err(NixInstallerError::ActionTimeout {
+                    synopsis,
+                    elapsed: timeout,
+                }),
+            },
+            None => action
+                .try_execute()
+                .await
+                .map_err(NixInstallerError::Action),
+        }
+    });
+    let result = download_context.scope(execute).await;
+    log_action_step("execute", action, start, result.as_ref().err());
+    record_final_state(&span, action);
+    result
+}
+
+/// Log running `operation` on `action` since `start` as one event on [`ACTION_LOG_TARGET`]
+fn log_action_step<E: std::fmt::Display>(
+    operation: &'static str,
+    action: &StatefulAction<Box<dyn Action>>,
+    start: Instant,
+    error: Option<&E>,
+) {
+    tracing::debug!(
+        target: ACTION_LOG_TARGET,
+        action = action.action.typetag_name(),
+        operation,
+        state = ?action.state,
+        synopsis = action.tracing_synopsis(),
+        duration_ms = start.elapsed().as_millis() as u64,
+        error = error.map(tracing::field::display),
+    );
+}
+
+/// A span for running `operation` on `action`, carrying the attributes `tracing-opentelemetry` maps onto OpenTelemetry spans
+#[cfg(feature = "opentelemetry")]
+fn action_span(operation: &'static str, action: &StatefulAction<Box<dyn Action>>) -> Span {
+    let action_type = action.action.typetag_name();
+    tracing::info_span!(
+        "action",
+        otel.name = format!("{operation} {action_type}"),
+        otel.status_code = tracing::field::Empty,
+        otel.status_message = tracing::field::Empty,
+        "action.type" = action_type,
+        action.operation = operation,
+        action.state.from = ?action.state,
+        action.state.to = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+fn action_span(_operation: &'static str, _action: &StatefulAction<Box<dyn Action>>) -> Span {
+    Span::none()
+}
+
+/// Record the state `action` was left in on its [`action_span`]
+fn record_final_state(span: &Span, action: &StatefulAction<Box<dyn Action>>) {
+    span.record("action.state.to", tracing::field::debug(action.state));
+}
+
+/// Await `fut` within `span`, recording how long it took and whether it succeeded
+async fn traced<E: std::fmt::Display>(
+    span: Span,
+    fut: impl Future<Output = Result<(), E>>,
+) -> Result<(), E> {
+    let start = std::time::Instant::now();
+    let result = fut.instrument(span.clone()).await;
+    span.record("duration_ms", start.elapsed().as_millis() as u64);
+    match &result {
+        Ok(()) => {
+            span.record("otel.status_code", "OK");
+        },
+        Err(err) => {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", tracing::field::display(err));
+        },
+    }
+    result
+}
+
+/// Resolves once `cancel_channel` is sent to or closed, never if there is none
+async fn cancel_received(cancel_channel: &mut Option<Receiver<()>>) {
+    match cancel_channel {
+        Some(cancel_channel) => {
+            let _ = cancel_channel.recv().await;
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Group consecutive actions into batches which may run concurrently
+///
+/// An action joins the batch before it if it declares its dependencies, none of them are in that
+/// batch, and the batch is not yet `concurrency` actions long.
+fn batches(actions: &[StatefulAction<Box<dyn Action>>], concurrency: usize) -> Vec<Range<usize>> {
+    let mut batches: Vec<Range<usize>> = Vec::new();
+    for (idx, action) in actions.iter().enumerate() {
+        let joins_last = match (batches.last(), action.action.depends_on()) {
+            (Some(last), Some(depends_on)) => {
+                last.len() < concurrency
+                    && !actions[last.clone()]
+                        .iter()
+                        .any(|other| depends_on.contains(&other.action_tag()))
+            },
+            _ => false,
+        };
+        match batches.last_mut() {
+            Some(last) if joins_last => last.end = idx + 1,
+            _ => batches.push(idx..idx + 1),
+        }
+    }
+    batches
+}
+
+/// Format an estimate like `2m30s`, rounded up to whole seconds as it is only a rough guess
+fn format_estimated_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    match (seconds / 60, seconds % 60) {
+        (0, seconds) => format!("{seconds}s"),
+        (minutes, 0) => format!("{minutes}m"),
+        (minutes, seconds) => format!("{minutes}m{seconds}s"),
+    }
+}
+
+/// The free space a filesystem needs for an install, see [`InstallPlan::check_disk_space`]
+struct DiskSpace {
+    /// A path on the filesystem, as given by [`Action::required_disk_space`]
+    path: PathBuf,
+    /// In bytes
+    required: u64,
+    /// In bytes
+    available: u64,
+}
+
+impl std::fmt::Display for DiskSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: u64 = 1024 * 1024;
+        write!(
+            f,
+            "Disk space: ~{} MiB needed for `{}` ({} MiB available)",
+            self.required.div_ceil(MIB),
+            self.path.display(),
+            self.available / MIB
+        )
+    }
+}
+
+/// Where the planner keeps the Nix store, if it is not `/nix`
+fn store_prefix_note(planner: &dyn Planner) -> String {
+    let store_prefix = planner.store_prefix();
+    if store_prefix == Path::new(NIX_ROOT) {
+        String::new()
+    } else {
+        format!(
+            "Store prefix: `{}` (bind mounted on `{NIX_ROOT}`)\n",
+            store_prefix.display()
+        )
+    }
+}
+
+/// A reminder that `PATH` is left alone if `planner` does not modify shell profiles
+fn profile_note(planner: &dyn Planner) -> Option<String> {
+    let modify_profile = planner.settings().ok()?.get("modify_profile").cloned();
+    if modify_profile != Some(serde_json::Value::Bool(false)) {
+        return None;
+    }
+    // Without a daemon, Nix ships a differently named profile script
+    let profile_script = if planner.typetag_name() == "single-user" {
+        "nix.sh"
+    } else {
+        "nix-daemon.sh"
+    };
+    Some(format!(
+        "Shell profiles will not be modified, so Nix will not be on `PATH`. \
+        Source `{NIX_ROOT}/var/nix/profiles/default/etc/profile.d/{profile_script}` from your shell configuration to use it"
+    ))
+}
+
+async fn write_receipt(plan: InstallPlan) -> Result<(), NixInstallerError> {
+    let target_root = plan.planner.target_root();
+    let store_prefix = plan.planner.store_prefix();
+    let install_receipt_path = plan.receipt_location.clone().unwrap_or_else(|| {
+        in_target_root(
+            &target_root,
+            in_store_prefix(&store_prefix, RECEIPT_LOCATION),
+        )
+    });
+    let mut receipt = serde_json::to_value(&plan).map_err(NixInstallerError::SerializingReceipt)?;
+    Receipt::restore_recorded(&mut receipt);
+    let self_json =
+        serde_json::to_string_pretty(&receipt).map_err(NixInstallerError::SerializingReceipt)?;
+    // Like `CreateFile`, the receipt is written in full beside its path, then renamed over it,
+    // so a reader or a crash never sees it half written
+    let path = install_receipt_path.clone();
+    let write = move || -> std::io::Result<()> {
+        let receipt_dir = path.parent().unwrap_or(Path::new("/"));
+        std::fs::create_dir_all(receipt_dir)?;
+        // Held until the new receipt is in place, so concurrent writers take turns
+        let lock = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+        let temp_path = receipt_dir.join(format!(
+            "nix-installer-tmp.{}",
+            rand::thread_rng().gen::<u32>()
+        ));
+        let written = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(format!("{self_json}\n").as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&temp_path, &path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        written?;
+        if let Ok(receipt_dir) = std::fs::File::open(receipt_dir) {
+            // Also persist the rename itself, failing to is no worse than before the rename
+            let _ = receipt_dir.sync_all();
+        }
+        Ok(())
+    };
+    tokio::task::spawn_blocking(write)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+        .map_err(|e| NixInstallerError::RecordingReceipt(install_receipt_path, e))
+}
+
+/// Read and parse the receipt at `path`, waiting for any [`write_receipt`] in progress to finish
+///
+/// Every way of loading a receipt starts here, before migrating it with [`migrate_receipt`].
+async fn read_receipt(path: &Path) -> Result<serde_json::Value, NixInstallerError> {
+    let owned_path = path.to_path_buf();
+    let read = move || -> std::io::Result<String> {
+        let mut file = std::fs::File::open(&owned_path)?;
+        flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        let mut receipt = String::new();
+        file.read_to_string(&mut receipt)?;
+        Ok(receipt)
+    };
+    let receipt = tokio::task::spawn_blocking(read)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+        .map_err(|e| NixInstallerError::ReadingReceipt(path.to_path_buf(), e))?;
+    serde_json::from_str(&receipt)
+        .map_err(|e| NixInstallerError::DeserializingReceipt(path.to_path_buf(), e))
+}
+
+/// Held while a plan is installed or uninstalled, released when dropped
+struct InstallLock {
+    _file: std::fs::File,
+}
+
+impl InstallLock {
+    /// Take the [`LOCK_LOCATION`] lock in the target root of `planner`, failing if another installer holds it
+    fn acquire(planner: &dyn Planner) -> Result<Option<Self>, NixInstallerError> {
+        let lock_path = in_target_root(&planner.target_root(), LOCK_LOCATION);
+        // An alternate target root may have no `/var/run`, nothing else runs there
+        if !lock_path.parent().is_some_and(Path::is_dir) {
+            tracing::debug!("Not locking `{}`", lock_path.display());
+            return Ok(None);
+        }
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o0600)
+            .custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_CLOEXEC)
+            .open(&lock_path)
+            .map_err(|e| NixInstallerError::Locking(lock_path.clone(), e))?;
+        // A lock file of another user could be held by them to block installs
+        let owner = file
+            .metadata()
+            .map_err(|e| NixInstallerError::Locking(lock_path.clone(), e))?
+            .uid();
+        if owner != geteuid().as_raw() {
+            return Err(NixInstallerError::LockNotOwned(lock_path, owner));
+        }
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(Errno::EWOULDBLOCK) => Err(NixInstallerError::AlreadyRunning(lock_path)),
+            Err(e) => Err(NixInstallerError::Locking(lock_path, e.into())),
+        }
+    }
+}
+
+fn current_version() -> Result<Version, semver::Error> {
+    let nix_installer_version_str = env!("CARGO_PKG_VERSION");
+    Version::from_str(nix_installer_version_str)
+}
+
+fn legacy_receipt_schema_version() -> u32 {
+    1
+}
+
+/**
+Load an [`InstallPlan`] from a receipt written with any receipt schema version up to [`RECEIPT_SCHEMA_VERSION`]
+
+Receipts of older versions are upgraded to the current shape, one version at a time, so that
+what older `nix-installer`s installed can still be uninstalled. Receipts of newer versions are
+not compatible.
+*/
+pub fn migrate_receipt(mut receipt: serde_json::Value) -> Result<InstallPlan, NixInstallerError> {
+    let receipt_schema_version = migrate_receipt_value(&mut receipt)?;
+    serde_json::from_value(receipt)
+        .map_err(|e| NixInstallerError::MigratingReceipt(Some(receipt_schema_version), e))
+}
+
+/// Upgrade `receipt` in place to [`RECEIPT_SCHEMA_VERSION`], returning the receipt schema version it was written with
+fn migrate_receipt_value(receipt: &mut serde_json::Value) -> Result<u32, NixInstallerError> {
+    let receipt_schema_version = receipt_schema_version_of(receipt)
+        .map_err(|e| NixInstallerError::MigratingReceipt(None, e))?;
+    if let Some(migrations) = RECEIPT_MIGRATIONS.get(receipt_schema_version as usize..) {
+        for (version, migration) in (receipt_schema_version..).zip(migrations) {
+            tracing::debug!("Migrating receipt from receipt schema version {version}");
+            migration(receipt)
+                .map_err(|e| NixInstallerError::MigratingReceipt(Some(version), e))?;
+        }
+        if let Some(receipt) = receipt.as_object_mut() {
+            receipt.insert(
+                "receipt_schema_version".to_string(),
+                RECEIPT_SCHEMA_VERSION.into(),
+            );
+        }
+    }
+    Ok(receipt_schema_version)
+}
+
+/// The parts of a receipt [`InstallPlan::uninstall_plan_from_receipt`] loads, leaving out its planner
+#[derive(serde::Deserialize)]
+struct UninstallReceipt {
+    version: Version,
+    #[serde(
+        default = "legacy_receipt_schema_version",
+        deserialize_with = "ensure_receipt_schema_version"
+    )]
+    receipt_schema_version: u32,
+    actions: Vec<StatefulAction<Box<dyn Action>>>,
+    #[serde(default)]
+    requires_reboot_before_use: bool,
+    #[cfg(feature = "diagnostics")]
+    #[serde(default)]
+    diagnostic_data: Option<crate::diagnostics::DiagnosticData>,
+    #[serde(default)]
+    snapshot: Option<SystemSnapshot>,
+    #[serde(default)]
+    existing_nix_store: Option<ExistingNixStore>,
+}
+
+fn receipt_schema_version_of(receipt: &serde_json::Value) -> Result<u32, serde_json::Error> {
+    match receipt.get("receipt_schema_version") {
+        Some(receipt_schema_version) => u32::deserialize(receipt_schema_version),
+        None if is_receipt_v0(receipt) => Ok(0),
+        None => Ok(legacy_receipt_schema_version()),
+    }
+}
+
+fn is_receipt_v0(receipt: &serde_json::Value) -> bool {
+    let planner = receipt.pointer("/planner/planner");
+    planner == Some(&"linux-multi".into())
+        || planner == Some(&"darwin-multi".into())
+        || receipt
+            .get("actions")
+            .is_some_and(|actions| find_actions(actions, "configure_nix_daemon_service"))
+}
+
+/// If `value` holds any action tagged `action`, at any depth
+fn find_actions(value: &serde_json::Value, action: &str) -> bool {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.get("action").and_then(|tag| tag.as_str()) == Some(action)
+                || map.values().any(|value| find_actions(value, action))
+        },
+        serde_json::Value::Array(values) => values.iter().any(|value| find_actions(value, action)),
+        _ => false,
+    }
+}
+
+/// Version `0` named the planners `linux-multi` and `darwin-multi`, and configured the Nix daemon with a field-less `configure_nix_daemon_service` action
+fn migrate_receipt_from_v0(receipt: &mut serde_json::Value) -> Result<(), serde_json::Error> {
+    let planner = receipt
+        .get_mut("planner")
+        .ok_or_else(|| serde_json::Error::missing_field("planner"))?;
+    let (init, start_daemon) = match planner.get("planner").and_then(|tag| tag.as_str()) {
+        Some("linux-multi") => {
+            planner["planner"] = "linux".into();
+            (
+                planner
+                    .pointer("/init/init")
+                    .cloned()
+                    .unwrap_or_else(|| "Systemd".into()),
+                planner
+                    .pointer("/init/start_daemon")
+                    .cloned()
+                    .unwrap_or_else(|| true.into()),
+            )
+        },
+        Some("darwin-multi") => {
+            planner["planner"] = "macos".into();
+            ("Launchd".into(), true.into())
+        },
+        _ => ("Systemd".into(), true.into()),
+    };
+    let ssl_cert_file = planner
+        .pointer("/settings/ssl_cert_file")
+        .cloned()
+        .unwrap_or_default();
+
+    let actions = receipt
+        .get_mut("actions")
+        .ok_or_else(|| serde_json::Error::missing_field("actions"))?;
+    rename_actions(
+        actions,
+        "configure_nix_daemon_service",
+        "configure_init_service",
+        &|action| {
+            action.entry("init").or_insert_with(|| init.clone());
+            action
+                .entry("start_daemon")
+                .or_insert_with(|| start_daemon.clone());
+            action
+                .entry("ssl_cert_file")
+                .or_insert_with(|| ssl_cert_file.clone());
+        },
+    );
+    Ok(())
+}
+
+/// Retag every action tagged `from` as `to`, at any depth, calling `fill` on each to add the fields the renamed action needs
+fn rename_actions(
+    value: &mut serde_json::Value,
+    from: &str,
+    to: &str,
+    fill: &dyn Fn(&mut serde_json::Map<String, serde_json::Value>),
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if map.get("action").and_then(|tag| tag.as_str()) == Some(from) {
+                map.insert("action".to_string(), to.into());
+                fill(map);
+            }
+            for value in map.values_mut() {
+                rename_actions(value, from, to, fill);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                rename_actions(value, from, to, fill);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn ensure_receipt_schema_version<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let receipt_schema_version = u32::deserialize(d)?;
+    if receipt_schema_version == RECEIPT_SCHEMA_VERSION {
+        Ok(receipt_schema_version)
+    } else {
+        Err(D::Error::custom(&format!(
+            "This version of `nix-installer` ({nix_installer_version}) uses receipt schema version {RECEIPT_SCHEMA_VERSION}, which is not compatible with this plan's receipt schema version ({receipt_schema_version}). To upgrade Nix, try `sudo -i nix upgrade-nix`. To reinstall Nix, try `/nix/nix-installer uninstall` then installing again from the instructions on https://github.com/DeterminateSystems/nix-installer. To continue using this plan, download the matching release from https://github.com/DeterminateSystems/nix-installer/releases.",
+            nix_installer_version = env!("CARGO_PKG_VERSION"),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use semver::Version;
+    use tracing::{span, Span};
+
+    use crate::{
+        action::{
+            base::RemoveDirectory, Action, ActionDescription, ActionError, ActionErrorKind,
+            ActionState, ActionTag, StatefulAction,
+        },
+        planner::{receipt::Receipt, BuiltinPlanner, Planner, PlannerError},
+        InstallEvent, InstallPlan, NixInstallerError,
+    };
+
+    use super::{
+        batches, current_version, format_estimated_duration, migrate_receipt_from_v0,
+        receipt_schema_version_of, write_receipt, InstallLock, PlanDiffEntry, RECEIPT_MIGRATIONS,
+        RECEIPT_SCHEMA_VERSION,
+    };
+
+    #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+    struct TestAction {
+        #[serde(skip)]
+        depends_on: Option<Vec<&'static str>>,
+        #[serde(default)]
+        executions: usize,
+        #[serde(default)]
+        reverts: usize,
+        #[serde(default)]
+        fail_preflight: bool,
+        #[serde(default)]
+        execute_delay_ms: u64,
+        #[serde(default)]
+        required_disk_space: u64,
+    }
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "test_action")]
+    impl Action for TestAction {
+        fn action_tag() -> ActionTag {
+            "test_action".into()
+        }
+        fn tracing_synopsis(&self) -> String {
+            "Test action".to_string()
+        }
+        fn tracing_span(&self) -> Span {
+            span!(tracing::Level::DEBUG, "test_action")
+        }
+        fn execute_description(&self) -> Vec<ActionDescription> {
+            vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        }
+        fn revert_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn preflight(&self) -> Result<(), ActionError> {
+            if self.fail_preflight {
+                return Err(Self::error(ActionErrorKind::NoUser("test".to_string())));
+            }
+            Ok(())
+        }
+        async fn execute(&mut self) -> Result<(), ActionError> {
+            tokio::time::sleep(std::time::Duration::from_millis(self.execute_delay_ms)).await;
+            self.executions += 1;
+            Ok(())
+        }
+        async fn revert(&mut self) -> Result<(), ActionError> {
+            self.reverts += 1;
+            Ok(())
+        }
+        fn required_disk_space(&self) -> Vec<(PathBuf, u64)> {
+            if self.required_disk_space == 0 {
+                return vec![];
+            }
+            vec![(std::env::temp_dir(), self.required_disk_space)]
+        }
+        fn depends_on(&self) -> Option<Vec<ActionTag>> {
+            self.depends_on
+                .as_ref()
+                .map(|tags| tags.iter().map(|tag| ActionTag::from(*tag)).collect())
+        }
+    }
+
+    /// Like a composite action such as [`ProvisionNix`](crate::action::common::ProvisionNix)
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct TestGroupAction {
+        children: Vec<StatefulAction<TestAction>>,
+    }
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "test_group_action")]
+    impl Action for TestGroupAction {
+        fn action_tag() -> ActionTag {
+            "test_group_action".into()
+        }
+        fn tracing_synopsis(&self) -> String {
+            "Test group action".to_string()
+        }
+        fn tracing_span(&self) -> Span {
+            span!(tracing::Level::DEBUG, "test_group_action")
+        }
+        fn execute_description(&self) -> Vec<ActionDescription> {
+            vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        }
+        fn revert_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn execute(&mut self) -> Result<(), ActionError> {
+            for child in self.children.iter_mut() {
+                child.try_execute().await?;
+            }
+            Ok(())
+        }
+        fn estimated_duration(&self) -> std::time::Duration {
+            self.children
+                .iter()
+                .map(|child| child.estimated_duration())
+                .sum()
+        }
+        async fn revert(&mut self) -> Result<(), ActionError> {
+            for child in self.children.iter_mut().rev() {
+                child.try_revert().await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn test_action(depends_on: Option<&[&'static str]>) -> StatefulAction<Box<dyn Action>> {
+        TestAction {
+            depends_on: depends_on.map(|tags| tags.to_vec()),
+            ..Default::default()
+        }
+        .stateful()
+        .boxed()
+    }
+
+    fn test_plan(
+        planner: Box<dyn Planner>,
+        actions: Vec<StatefulAction<Box<dyn Action>>>,
+    ) -> InstallPlan {
+        InstallPlan {
+            version: current_version().unwrap(),
+            receipt_schema_version: RECEIPT_SCHEMA_VERSION,
+            requires_reboot_before_use: planner.requires_reboot_before_use(),
+            planner,
+            actions,
+            #[cfg(feature = "diagnostics")]
+            diagnostic_data: None,
+            describe_override: None,
+            receipt_location: None,
+            event_channel: None,
+            snapshot: None,
+            capture_snapshot: false,
+            uninstall_force: false,
+            existing_nix_store: None,
+        }
+    }
+
+    #[test]
+    fn batches_only_group_independent_actions() {
+        let actions = vec![
+            test_action(None),
+            test_action(Some(&[])),
+            test_action(Some(&["create_directory"])),
+            test_action(Some(&["test_action"])),
+            test_action(None),
+        ];
+        assert_eq!(batches(&actions, 1), vec![0..1, 1..2, 2..3, 3..4, 4..5]);
+        assert_eq!(batches(&actions, 4), vec![0..3, 3..4, 4..5]);
+        assert_eq!(batches(&actions, 2), vec![0..2, 2..3, 3..4, 4..5]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn linux_plan_batches_independent_actions() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, settings::InitSystem};
+
+        let mut planner = Linux::default().await?;
+        planner.init.init = InitSystem::None;
+        planner.init.start_daemon = false;
+        let actions = planner.plan().await?;
+        let tags = actions
+            .iter()
+            .map(|action| action.action_tag())
+            .collect::<Vec<_>>();
+        let batches = batches(&actions, 4);
+        let batch_of = |tag: &'static str| {
+            batches
+                .iter()
+                .position(|batch| tags[batch.clone()].contains(&ActionTag::from(tag)))
+                .unwrap_or_else(|| panic!("`{tag}` is planned, planned {tags:?}"))
+        };
+
+        assert!(batch_of("provision_nix") < batch_of("configure_nix"));
+        assert!(batch_of("configure_nix") < batch_of("verify_nix_on_path"));
+        // Checking the `PATH` runs alongside removing the scratch directory
+        assert_eq!(batch_of("remove_directory"), batch_of("verify_nix_on_path"));
+        assert_eq!(batches.len(), actions.len() - 1);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn install_cancels_after_running_batch() -> eyre::Result<()> {
+        use crate::planner::linux::Linux;
+
+        let delayed = |depends_on| {
+            StatefulAction::from(TestAction {
+                depends_on,
+                execute_delay_ms: 200,
+                ..Default::default()
+            })
+            .boxed()
+        };
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = Linux::default().await?;
+        planner.settings.target_root = temp_dir.path().to_path_buf();
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![delayed(Some(vec![])), delayed(Some(vec![])), delayed(None)],
+        );
+
+        let (sender, receiver) = tokio::sync::broadcast::channel(1);
+        let cancel = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            sender.send(()).ok();
+            // Keep the channel open until the install returns
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+        let result = plan
+            .install_with_concurrency(receiver, None, std::num::NonZeroUsize::new(2))
+            .await;
+        cancel.abort();
+
+        assert!(matches!(result, Err(NixInstallerError::Cancelled)));
+        assert_eq!(plan.actions[0].state, ActionState::Completed);
+        assert_eq!(plan.actions[1].state, ActionState::Completed);
+        assert_eq!(plan.actions[2].state, ActionState::Uncompleted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn actions_tagged_filters_by_tag() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(None),
+                TestGroupAction { children: vec![] }.stateful().boxed(),
+                test_action(None),
+            ],
+        );
+        assert_eq!(plan.actions_tagged(TestAction::action_tag()).count(), 2);
+        assert_eq!(
+            plan.actions[1].action_tag(),
+            StatefulAction::<TestGroupAction>::tag()
+        );
+        assert_eq!(plan.actions_tagged("create_directory".into()).count(), 0);
         Ok(())
     }
 
     #[tokio::test]
-    async fn ensure_version_denies_incompatible() -> Result<(), NixInstallerError> {
+    async fn execute_batch_completes_every_action() -> Result<(), NixInstallerError> {
         let planner = BuiltinPlanner::default().await?;
-        let bad_version = Version::parse("9999999999999.9999999999.99999999")?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(Some(&[])),
+                test_action(Some(&[])),
+                test_action(Some(&[])),
+            ],
+        );
+        let (event_channel, mut events) = tokio::sync::broadcast::channel(16);
+        let event_channel = Some(event_channel);
+        let weights = plan
+            .actions
+            .iter()
+            .map(|action| action.weight())
+            .collect::<Vec<_>>();
+        assert_eq!(weights, vec![1, 1, 1]);
+        let batches = batches(&plan.actions, 3);
+        assert_eq!(batches, vec![0..3]);
+        for batch in batches {
+            plan.execute_batch(batch, &weights, &event_channel).await?;
+        }
+        assert!(plan
+            .actions
+            .iter()
+            .all(|action| action.state == crate::action::ActionState::Completed));
+        assert!(plan.actions.iter().all(|action| action.weight() == 0));
+
+        let mut started = vec![];
+        let mut completed = vec![];
+        while let Ok(event) = events.try_recv() {
+            match event {
+                InstallEvent::ActionStarted {
+                    completed_weight,
+                    total_weight,
+                    ..
+                } => started.push((completed_weight, total_weight)),
+                InstallEvent::ActionCompleted { index, .. } => completed.push(index),
+                _ => (),
+            }
+        }
+        started.sort();
+        assert_eq!(started, vec![(0, 3), (1, 3), (2, 3)]);
+        completed.sort();
+        assert_eq!(completed, vec![0, 1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn execute_batch_times_out_hung_actions() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![StatefulAction::from(TestAction {
+                execute_delay_ms: 10_000,
+                ..Default::default()
+            })
+            .with_timeout(std::time::Duration::from_millis(10))
+            .boxed()],
+        );
+
+        let err = plan.execute_batch(0..1, &[1], &None).await.unwrap_err();
+        assert!(matches!(err, NixInstallerError::ActionTimeout { .. }));
+        assert_eq!(plan.actions[0].state, ActionState::Progress);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_reports_failed_preflights() -> Result<(), NixInstallerError> {
+        let test_action = |fail_preflight, state| {
+            StatefulAction {
+                action: TestAction {
+                    fail_preflight,
+                    ..Default::default()
+                },
+                state,
+                timeout: None,
+            }
+            .boxed()
+        };
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(false, ActionState::Uncompleted),
+                test_action(true, ActionState::Completed),
+                test_action(false, ActionState::Uncompleted),
+            ],
+        );
+        // Completed actions are neither checked nor described
+        assert_eq!(plan.dry_run().await?.len(), 2);
+
+        plan.actions.extend([
+            test_action(true, ActionState::Uncompleted),
+            test_action(true, ActionState::Progress),
+        ]);
+        match plan.dry_run().await {
+            Err(NixInstallerError::Preflight(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("Expected preflight errors, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_disk_space_refuses_too_little_space() -> Result<(), NixInstallerError> {
+        let test_action = |required_disk_space, state| {
+            StatefulAction {
+                action: TestAction {
+                    required_disk_space,
+                    ..Default::default()
+                },
+                state,
+                timeout: None,
+            }
+            .boxed()
+        };
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(1, ActionState::Uncompleted),
+                test_action(u64::MAX, ActionState::Completed),
+            ],
+        );
+        // Completed actions need no more space
+        plan.check_disk_space()?;
+        assert!(plan
+            .describe_install(false)
+            .await?
+            .contains("Disk space: ~1 MiB needed for"));
+
+        // What the actions need on the same filesystem adds up
+        plan.actions.extend([
+            test_action(u64::MAX / 2, ActionState::Uncompleted),
+            test_action(u64::MAX / 2, ActionState::Uncompleted),
+        ]);
+        match plan.dry_run().await {
+            Err(NixInstallerError::InsufficientDiskSpace {
+                required,
+                available,
+                path,
+            }) => {
+                assert_eq!(required, u64::MAX);
+                assert!(available < required);
+                assert_eq!(path, std::env::temp_dir());
+            },
+            other => panic!("Expected insufficient disk space, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_override_replaces_matching_descriptions() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(None),
+                StatefulAction {
+                    action: TestGroupAction { children: vec![] },
+                    state: ActionState::Uncompleted,
+                    timeout: None,
+                }
+                .boxed(),
+            ],
+        );
+        plan.describe_override(|action| {
+            (action.typetag_name() == "test_group_action")
+                .then(|| ActionDescription::new("Branded group".to_string(), vec![]))
+        });
+
+        let described = plan.describe_install(false).await?;
+        assert!(described.contains("* Test action"));
+        assert!(described.contains("* Branded group"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_install_json_lists_actions() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let plan = test_plan(planner.boxed(), vec![test_action(None), test_action(None)]);
+
+        let described = plan.describe_install_json(true).await?;
+        assert_eq!(described["planner"], plan.planner.typetag_name());
+        assert_eq!(described["version"], env!("CARGO_PKG_VERSION"));
+        assert!(described["settings"]
+            .as_object()
+            .is_some_and(|settings| !settings.is_empty()));
+        let actions = described["actions"].as_array().unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0]["description"], "Test action");
+        assert!(actions[0]["explanation"].is_array());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn describe_install_notes_unmodified_profiles() -> eyre::Result<()> {
+        use crate::planner::linux::Linux;
+
+        let mut planner = Linux::default().await?;
+        let mut plan = test_plan(planner.clone().boxed(), vec![test_action(None)]);
+        let note = "Shell profiles will not be modified";
+        assert!(!plan.describe_install(false).await?.contains(note));
+
+        planner.settings.modify_profile = false;
+        plan.planner = planner.boxed();
+        let described = plan.describe_install(false).await?;
+        assert!(described.contains(note));
+        assert!(described.contains("profile.d/nix-daemon.sh"));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn describe_install_notes_required_reboot() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, settings::InitSystem};
+
+        let mut planner = Linux::default().await?;
+        planner.init.init = InitSystem::Systemd;
+        planner.init.start_daemon = true;
+        assert!(!planner.requires_reboot_before_use());
+        planner.init.start_daemon = false;
+        assert!(planner.requires_reboot_before_use());
+        planner.init.init = InitSystem::None;
+        assert!(!planner.requires_reboot_before_use());
+
+        let mut plan = test_plan(planner.boxed(), vec![test_action(None)]);
+        let note = "A reboot will be required";
+        assert!(!plan.describe_install(false).await?.contains(note));
+        assert_eq!(
+            plan.describe_install_json(false).await?["requires_reboot_before_use"],
+            false
+        );
+
+        plan.requires_reboot_before_use = true;
+        assert!(plan.describe_install(false).await?.contains(note));
+        assert_eq!(
+            plan.describe_install_json(false).await?["requires_reboot_before_use"],
+            true
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn estimated_duration_skips_completed_actions() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let mut completed = test_action(None);
+        completed.state = ActionState::Completed;
+        let plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(None),
+                completed,
+                StatefulAction {
+                    action: TestGroupAction {
+                        children: vec![
+                            TestAction::default().stateful(),
+                            StatefulAction::completed(TestAction::default()),
+                        ],
+                    },
+                    state: ActionState::Uncompleted,
+                    timeout: None,
+                }
+                .boxed(),
+            ],
+        );
+
+        assert_eq!(
+            plan.estimated_duration(),
+            std::time::Duration::from_millis(200)
+        );
+        assert!(plan
+            .describe_install(false)
+            .await?
+            .contains("Estimated time: ~1s"));
+        Ok(())
+    }
+
+    #[test]
+    fn format_estimated_duration_rounds_up_to_seconds() {
+        use std::time::Duration;
+
+        assert_eq!(format_estimated_duration(Duration::ZERO), "0s");
+        assert_eq!(format_estimated_duration(Duration::from_millis(1500)), "2s");
+        assert_eq!(format_estimated_duration(Duration::from_secs(120)), "2m");
+        assert_eq!(format_estimated_duration(Duration::from_secs(150)), "2m30s");
+    }
+
+    #[tokio::test]
+    async fn to_shell_script_names_unrepresentable_actions() -> eyre::Result<()> {
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = test_plan(
+            planner.boxed(),
+            vec![
+                RemoveDirectory::plan("/nix/temp install dir")
+                    .await?
+                    .boxed(),
+                test_action(None),
+            ],
+        );
+
+        match plan.to_shell_script() {
+            Err(NixInstallerError::NotRepresentableAsShell(name)) => {
+                assert_eq!(name, "test_action")
+            },
+            other => panic!("Expected `NotRepresentableAsShell`, got {other:?}"),
+        }
+
+        // Completed actions are left out, so they need not be representable
+        plan.actions[1].state = ActionState::Completed;
+        let script = plan.to_shell_script()?;
+        assert!(script.starts_with("#!/usr/bin/env bash\n"));
+        assert!(script.contains("\nrm -rf '/nix/temp install dir'\n"));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn resume_from_receipt_only_runs_incomplete_actions() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, planner::Planner};
+
+        let test_action = |state| StatefulAction {
+            action: TestAction::default(),
+            state,
+            timeout: None,
+        };
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = Linux::default().await?;
+        planner.settings.target_root = temp_dir.path().to_path_buf();
+        let plan = test_plan(
+            planner.boxed(),
+            vec![
+                test_action(ActionState::Completed).boxed(),
+                StatefulAction {
+                    action: TestGroupAction {
+                        children: vec![
+                            test_action(ActionState::Completed),
+                            test_action(ActionState::Progress),
+                            test_action(ActionState::Uncompleted),
+                        ],
+                    },
+                    state: ActionState::Progress,
+                    timeout: None,
+                }
+                .boxed(),
+                test_action(ActionState::Uncompleted).boxed(),
+            ],
+        );
+        let receipt_path = temp_dir.path().join("receipt.json");
+        tokio::fs::write(&receipt_path, serde_json::to_string(&plan)?).await?;
+
+        let mut resumed = InstallPlan::resume_from_receipt(&receipt_path).await?;
+        resumed.install(None, None).await?;
+
+        assert!(resumed
+            .actions
+            .iter()
+            .all(|action| action.state == ActionState::Completed));
+        let actions = serde_json::to_value(&resumed.actions)?;
+        let executions = |action: &serde_json::Value| action["action"]["executions"].clone();
+        assert_eq!(executions(&actions[0]), 0);
+        let children = &actions[1]["action"]["children"];
+        assert_eq!(executions(&children[0]), 0);
+        assert_eq!(executions(&children[1]), 1);
+        assert_eq!(executions(&children[2]), 1);
+        assert_eq!(executions(&actions[2]), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forced_revert_reverts_nested_actions() -> eyre::Result<()> {
+        let mut group = StatefulAction::uncompleted(TestGroupAction {
+            children: vec![
+                StatefulAction::uncompleted(TestAction::default()),
+                StatefulAction::completed(TestAction::default()),
+                StatefulAction::skipped(TestAction::default()),
+            ],
+        })
+        .boxed();
+
+        group.try_revert_force().await?;
+
+        let reverts = serde_json::to_value(&group)?["action"]["children"]
+            .as_array()
+            .ok_or_else(|| eyre::eyre!("Expected the children to be serialized"))?
+            .iter()
+            .map(|child| child["action"]["reverts"].as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(reverts, vec![Some(1), Some(1), Some(0)]);
+        assert_eq!(group.state, ActionState::Uncompleted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revert_through_only_reverts_started_actions() -> eyre::Result<()> {
+        let test_action = |state| StatefulAction {
+            action: TestAction::default(),
+            state,
+            timeout: None,
+        };
+        let temp_dir = tempfile::tempdir()?;
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = InstallPlan {
+            receipt_location: Some(temp_dir.path().join("receipt.json")),
+            ..test_plan(
+                planner.boxed(),
+                vec![
+                    test_action(ActionState::Completed).boxed(),
+                    test_action(ActionState::Completed).boxed(),
+                    StatefulAction {
+                        action: TestGroupAction {
+                            children: vec![
+                                test_action(ActionState::Completed),
+                                test_action(ActionState::Skipped),
+                                test_action(ActionState::Uncompleted),
+                            ],
+                        },
+                        state: ActionState::Progress,
+                        timeout: None,
+                    }
+                    .boxed(),
+                    test_action(ActionState::Uncompleted).boxed(),
+                ],
+            )
+        };
+
+        assert!(matches!(
+            plan.revert_through(4).await,
+            Err(NixInstallerError::ActionIndexOutOfRange {
+                index: 4,
+                actions: 4
+            })
+        ));
+
+        plan.revert_through(1).await?;
+        let states = plan
+            .actions
+            .iter()
+            .map(|action| action.state)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                ActionState::Completed,
+                ActionState::Uncompleted,
+                ActionState::Uncompleted,
+                ActionState::Uncompleted,
+            ]
+        );
+        let actions = serde_json::to_value(&plan.actions)?;
+        let children = actions[2]["action"]["children"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|child| child["state"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(children, ["Uncompleted", "Skipped", "Uncompleted"]);
+        assert!(temp_dir.path().join("receipt.json").exists());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn receipt_is_written_to_store_prefix() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, planner::Planner};
+
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = Linux::default().await?;
+        planner.settings.target_root = temp_dir.path().to_path_buf();
+        planner.settings.store_prefix = "/opt/nix".into();
+        let plan = test_plan(BuiltinPlanner::Linux(planner).boxed(), vec![]);
+
+        assert!(plan
+            .describe_install(false)
+            .await?
+            .contains("Store prefix: `/opt/nix` (bind mounted on `/nix`)"));
+        write_receipt(plan).await?;
+        assert!(temp_dir.path().join("opt/nix/receipt.json").is_file());
+        assert!(!temp_dir.path().join("nix").exists());
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn diff_lists_changed_settings_and_actions() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, planner::Planner};
+
+        let plan =
+            |planner: Linux, actions| test_plan(BuiltinPlanner::Linux(planner).boxed(), actions);
+        let mut executed = TestAction::default();
+
+        let before = plan(
+            Linux::default().await?,
+            vec![
+                RemoveDirectory::plan("/kept").await?.boxed(),
+                RemoveDirectory::plan("/removed").await?.boxed(),
+                StatefulAction::uncompleted(executed.clone()).boxed(),
+            ],
+        );
+        let mut planner = Linux::default().await?;
+        planner.settings.nix_build_group_id = 31_000;
+        planner.settings.extra_conf = vec!["keep-outputs = true".into()];
+        executed.executions = 1;
+        let after = plan(
+            planner,
+            vec![
+                RemoveDirectory::plan("/kept").await?.boxed(),
+                StatefulAction::uncompleted(executed).boxed(),
+                RemoveDirectory::plan("/added").await?.boxed(),
+            ],
+        );
+
+        assert_eq!(before.diff(&before), vec![]);
+        assert_eq!(
+            before.diff(&after),
+            vec![
+                PlanDiffEntry::SettingChanged {
+                    name: "extra_conf".into(),
+                    from: serde_json::json!([]),
+                    to: serde_json::json!(["keep-outputs = true"]),
+                },
+                PlanDiffEntry::SettingChanged {
+                    name: "nix_build_group_id".into(),
+                    from: serde_json::json!(30_000),
+                    to: serde_json::json!(31_000),
+                },
+                PlanDiffEntry::ActionChanged {
+                    action: "test_action".into(),
+                    synopsis: "Test action".into(),
+                },
+                PlanDiffEntry::ActionAdded {
+                    action: "remove_directory".into(),
+                    synopsis: "Remove directory `/added`".into(),
+                },
+                PlanDiffEntry::ActionRemoved {
+                    action: "remove_directory".into(),
+                    synopsis: "Remove directory `/removed`".into(),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn diff_ignores_nested_states() -> eyre::Result<()> {
+        use crate::{planner::linux::Linux, settings::InitSystem};
+
+        fn complete(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    if map.get("state") == Some(&serde_json::json!("Uncompleted")) {
+                        map.insert("state".into(), serde_json::json!("Completed"));
+                    }
+                    map.values_mut().for_each(complete);
+                },
+                serde_json::Value::Array(values) => values.iter_mut().for_each(complete),
+                _ => (),
+            }
+        }
+        let planner = || async {
+            let mut planner = Linux::default().await?;
+            planner.init.init = InitSystem::None;
+            planner.init.start_daemon = false;
+            eyre::Result::<_>::Ok(planner)
+        };
+
+        let planned = InstallPlan::plan(planner().await?).await?;
+        let mut receipt = serde_json::to_value(&planned)?;
+        complete(&mut receipt);
+        let installed: InstallPlan = serde_json::from_value(receipt)?;
+        assert!(installed
+            .actions
+            .iter()
+            .all(|action| action.state == ActionState::Completed));
+        assert_eq!(planned.diff(&installed), vec![]);
+
+        let mut changed = planner().await?;
+        changed.settings.nix_build_group_id = 31_000;
+        let changed = InstallPlan::plan(changed).await?;
+        assert!(installed
+            .diff(&changed)
+            .contains(&PlanDiffEntry::SettingChanged {
+                name: "nix_build_group_id".into(),
+                from: serde_json::json!(30_000),
+                to: serde_json::json!(31_000),
+            }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ensure_receipt_schema_version_allows_compatible() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": good_version,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION,
+            "actions": [],
+        });
+        let maybe_plan: Result<InstallPlan, serde_json::Error> = serde_json::from_value(value);
+        maybe_plan.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ensure_receipt_schema_version_ignores_crate_version() -> Result<(), NixInstallerError>
+    {
+        let planner = BuiltinPlanner::default().await?;
+        let other_version = Version::parse("9999999999999.9999999999.99999999")?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": other_version,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION,
+            "actions": [],
+        });
+        let maybe_plan: Result<InstallPlan, serde_json::Error> = serde_json::from_value(value);
+        maybe_plan.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ensure_receipt_schema_version_defaults_legacy_receipts(
+    ) -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": good_version,
+            "actions": [],
+        });
+        let plan: InstallPlan = serde_json::from_value(value).unwrap();
+        assert_eq!(plan.receipt_schema_version, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ensure_receipt_schema_version_denies_incompatible() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
         let value = serde_json::json!({
             "planner": planner.boxed(),
-            "version": bad_version,
+            "version": good_version,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION + 1,
             "actions": [],
         });
         let maybe_plan: Result<InstallPlan, serde_json::Error> = serde_json::from_value(value);
@@ -435,4 +2883,279 @@ mod test {
         assert!(err.is_data());
         Ok(())
     }
+
+    #[test]
+    fn receipt_migrations_reach_current_schema() {
+        assert_eq!(RECEIPT_MIGRATIONS.len(), RECEIPT_SCHEMA_VERSION as usize);
+    }
+
+    #[test]
+    fn migrate_receipt_from_v0_renames_planner_and_daemon_action() -> eyre::Result<()> {
+        let mut receipt = serde_json::json!({
+            "version": "0.5.0",
+            "planner": {
+                "planner": "darwin-multi",
+                "settings": { "ssl_cert_file": "/etc/ssl/cert.pem" },
+            },
+            "actions": [
+                {
+                    "action": {
+                        "action": "configure_nix",
+                        "nested": { "action": { "action": "configure_nix_daemon_service" }, "state": "Completed" },
+                    },
+                    "state": "Completed",
+                },
+            ],
+        });
+        assert_eq!(receipt_schema_version_of(&receipt)?, 0);
+        migrate_receipt_from_v0(&mut receipt)?;
+        assert_eq!(receipt["planner"]["planner"], "macos");
+        assert_eq!(
+            receipt["actions"][0]["action"]["nested"]["action"],
+            serde_json::json!({
+                "action": "configure_init_service",
+                "init": "Launchd",
+                "start_daemon": true,
+                "ssl_cert_file": "/etc/ssl/cert.pem",
+            })
+        );
+        assert_eq!(receipt_schema_version_of(&receipt)?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_receipt_denies_newer_schema() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION + 1,
+            "actions": [],
+        });
+        assert!(matches!(
+            super::migrate_receipt(value),
+            Err(NixInstallerError::MigratingReceipt(Some(version), _)) if version == RECEIPT_SCHEMA_VERSION + 1
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconfigure_requires_the_recorded_planner() -> eyre::Result<()> {
+        let planner = BuiltinPlanner::default().await?;
+        let mut plan = super::migrate_receipt(serde_json::json!({
+            "planner": planner.boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "actions": [],
+        }))?;
+        let other = Receipt::from_recorded(None).boxed();
+        assert!(matches!(
+            plan.reconfigure(other.clone()).await,
+            Err(NixInstallerError::Planner(
+                PlannerError::ReconfigurePlannerChanged { .. }
+            ))
+        ));
+
+        plan.planner = other.clone();
+        assert!(matches!(
+            plan.reconfigure(other).await,
+            Err(NixInstallerError::Planner(
+                PlannerError::ReconfigureUnsupported("receipt")
+            ))
+        ));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reconfigure_updates_nix_conf_and_the_receipt_in_place() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        use nix_config_parser::NixConfig;
+
+        use crate::{action::common::PlaceNixConfiguration, planner::linux::Linux};
+
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = Linux::default().await?;
+        planner.settings.target_root = temp_dir.path().join("root");
+        // The install merged its settings into an existing `nix.conf`
+        let nix_conf = planner.settings.target_root.join("etc/nix/nix.conf");
+        tokio::fs::create_dir_all(planner.settings.target_root.join("etc/nix")).await?;
+        tokio::fs::write(&nix_conf, "warn-dirty = false\n").await?;
+        tokio::fs::set_permissions(&nix_conf, PermissionsExt::from_mode(0o664)).await?;
+        let mut place_nix_configuration = PlaceNixConfiguration::plan(&planner.settings).await?;
+        place_nix_configuration.try_execute().await?;
+        let mut plan = super::migrate_receipt(serde_json::json!({
+            "planner": planner.clone().boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "actions": [place_nix_configuration.boxed()],
+        }))?;
+        plan.receipt_location = Some(temp_dir.path().join("receipt.json"));
+
+        for cores in [2, 4] {
+            planner.settings.cores = Some(cores);
+            plan.reconfigure(planner.clone().boxed()).await?;
+
+            let nix_config = NixConfig::parse_file(&nix_conf)?;
+            assert_eq!(nix_config.settings().get("cores"), Some(&cores.to_string()));
+            assert_eq!(
+                nix_config.settings().get("warn-dirty").map(String::as_str),
+                Some("false")
+            );
+            assert_eq!(plan.actions[0].state, ActionState::Completed);
+            let recorded = serde_json::to_string(&plan.actions)?;
+            assert!(recorded.contains(&format!(r#""cores":"{cores}""#)));
+        }
+
+        let receipt =
+            InstallPlan::resume_from_receipt(temp_dir.path().join("receipt.json")).await?;
+        assert_eq!(receipt.planner.settings()?, planner.boxed().settings()?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn install_lock_is_held_by_one_installer() -> eyre::Result<()> {
+        use crate::planner::linux::Linux;
+
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = Linux::default().await?;
+        planner.settings.target_root = temp_dir.path().to_path_buf();
+        assert!(InstallLock::acquire(&planner)?.is_none());
+
+        let lock_path = temp_dir.path().join("var/run/nix-installer.lock");
+        tokio::fs::create_dir_all(temp_dir.path().join("var/run")).await?;
+        let lock = InstallLock::acquire(&planner)?;
+        assert!(lock.is_some());
+        assert!(matches!(
+            InstallLock::acquire(&planner),
+            Err(NixInstallerError::AlreadyRunning(_))
+        ));
+        drop(lock);
+        assert!(InstallLock::acquire(&planner)?.is_some());
+
+        // A symlink planted at the lock is never followed
+        tokio::fs::remove_file(&lock_path).await?;
+        tokio::fs::symlink(temp_dir.path().join("elsewhere"), &lock_path).await?;
+        assert!(matches!(
+            InstallLock::acquire(&planner),
+            Err(NixInstallerError::Locking(_, _))
+        ));
+        assert!(!temp_dir.path().join("elsewhere").exists());
+
+        // Nor is a lock file of another user, which they could hold
+        if nix::unistd::getuid().is_root() {
+            if let Some(nobody) = nix::unistd::User::from_name("nobody")? {
+                tokio::fs::remove_file(&lock_path).await?;
+                tokio::fs::write(&lock_path, "").await?;
+                nix::unistd::chown(&lock_path, Some(nobody.uid), None)?;
+                assert!(matches!(
+                    InstallLock::acquire(&planner),
+                    Err(NixInstallerError::LockNotOwned(_, _))
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uninstall_plan_from_receipt_skips_planner() -> eyre::Result<()> {
+        let mut action = test_action(None);
+        action.state = ActionState::Completed;
+        let temp_dir = tempfile::tempdir()?;
+        let value = serde_json::json!({
+            "planner": {
+                "planner": "not-a-planner",
+                "settings": {
+                    "store_prefix": "/opt/nix",
+                    "target_root": temp_dir.path(),
+                },
+                "unknown": true,
+            },
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION,
+            "actions": [action],
+        });
+        let receipt = temp_dir.path().join("receipt.json");
+        tokio::fs::write(&receipt, serde_json::to_string(&value)?).await?;
+
+        assert!(InstallPlan::resume_from_receipt(&receipt).await.is_err());
+        let mut plan = InstallPlan::uninstall_plan_from_receipt(&receipt).await?;
+        assert_eq!(plan.planner.store_prefix(), PathBuf::from("/opt/nix"));
+        assert!(plan.planner.plan().await.is_err());
+        assert_eq!(plan.actions[0].state, ActionState::Completed);
+
+        // Rewriting the receipt, as a failed uninstall does, keeps the planner it recorded
+        let rewritten = temp_dir.path().join("rewritten.json");
+        plan.receipt_location = Some(rewritten.clone());
+        write_receipt(plan.clone()).await?;
+        let rewritten: serde_json::Value =
+            serde_json::from_str(&tokio::fs::read_to_string(&rewritten).await?)?;
+        assert_eq!(rewritten["planner"], value["planner"]);
+
+        plan.uninstall(None).await?;
+        assert_eq!(plan.actions[0].state, ActionState::Uncompleted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn force_from_receipt_ignores_incompatible_schema() -> eyre::Result<()> {
+        let planner = BuiltinPlanner::default().await?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": good_version,
+            "receipt_schema_version": RECEIPT_SCHEMA_VERSION + 1,
+            "actions": [test_action(None)],
+        });
+        let temp_dir = tempfile::tempdir()?;
+        let receipt = temp_dir.path().join("receipt.json");
+        tokio::fs::write(&receipt, serde_json::to_string(&value)?).await?;
+
+        assert!(InstallPlan::resume_from_receipt(&receipt).await.is_err());
+        let plan = InstallPlan::force_from_receipt(&receipt).await?;
+        assert!(plan.uninstall_force);
+        assert_eq!(plan.receipt_schema_version, RECEIPT_SCHEMA_VERSION);
+        assert_eq!(plan.actions.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plan_round_trips_through_json() -> eyre::Result<()> {
+        use crate::action::base::CreateDirectory;
+
+        let temp_dir = tempfile::tempdir()?;
+        let planner = BuiltinPlanner::default().await?;
+        let plan = test_plan(
+            planner.boxed(),
+            vec![
+                CreateDirectory::plan(temp_dir.path().join("nix"), None, None, 0o0755, false)
+                    .await?
+                    .boxed(),
+                RemoveDirectory::plan(temp_dir.path().join("scratch"))
+                    .await?
+                    .boxed(),
+                test_action(None),
+            ],
+        );
+        let plan_path = temp_dir.path().join("plan.json");
+        tokio::fs::write(&plan_path, serde_json::to_string_pretty(&plan)?).await?;
+
+        let loaded = InstallPlan::from_file(&plan_path).await?;
+        assert_eq!(
+            serde_json::to_value(&loaded.actions)?,
+            serde_json::to_value(&plan.actions)?
+        );
+        assert_eq!(serde_json::to_value(&loaded)?, serde_json::to_value(&plan)?);
+
+        // A plan is not migrated like a receipt, it is made again by the matching version
+        let mut other_version = serde_json::to_value(&plan)?;
+        other_version["version"] = "0.0.1".into();
+        match InstallPlan::from_reader(other_version.to_string().as_bytes()) {
+            Err(NixInstallerError::IncompatiblePlanVersion { plan: version, .. }) => {
+                assert_eq!(version, Version::new(0, 0, 1))
+            },
+            other => panic!("Expected `IncompatiblePlanVersion`, got {other:?}"),
+        }
+        Ok(())
+    }
 }
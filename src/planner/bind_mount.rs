@@ -0,0 +1,85 @@
+/*! The systemd units which bind mount a directory on `/nix`, for the planners keeping the Nix store elsewhere
+*/
+
+use std::path::Path;
+
+use crate::settings::NIX_ROOT;
+
+/// The mount unit of `/nix`, named after the path it mounts as systemd requires
+pub(crate) const NIX_MOUNT_UNIT: &str = "nix.mount";
+
+/// Reloads systemd once `/nix` is mounted at boot, so the units symlinked into it resolve
+pub(crate) const ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT: &str =
+    "ensure-symlinked-units-resolve.service";
+
+/// A mount unit bind mounting `what` on `/nix`, with each of `unit_extra` added to its `[Unit]` section
+pub(crate) fn nix_mount_unit(what: &Path, unit_extra: &[String]) -> String {
+    format!(
+        "\
+        [Unit]\n\
+        Description=Mount `{what}` on `{NIX_ROOT}`\n\
+        PropagatesStopTo=nix-daemon.service\n\
+        {unit_extra}\
+        DefaultDependencies=no\n\
+        \n\
+        [Mount]\n\
+        What={what}\n\
+        Where={NIX_ROOT}\n\
+        Type=none\n\
+        DirectoryMode=0755\n\
+        Options=bind\n\
+        \n\
+        [Install]\n\
+        RequiredBy=nix-daemon.service\n\
+        RequiredBy=nix-daemon.socket\n\
+        ",
+        what = what.display(),
+        unit_extra = unit_lines(unit_extra),
+    )
+}
+
+/// The [`ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT`], with each of `unit_extra` added to its `[Unit]` section
+pub(crate) fn ensure_symlinked_units_resolve_unit(unit_extra: &[String]) -> String {
+    format!(
+        "\
+        [Unit]\n\
+        Description=Ensure Nix related units which are symlinked resolve\n\
+        After={NIX_MOUNT_UNIT}\n\
+        Requires={NIX_MOUNT_UNIT}\n\
+        {unit_extra}\
+        DefaultDependencies=no\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        RemainAfterExit=yes\n\
+        ExecStart=/usr/bin/systemctl daemon-reload\n\
+        ExecStart=/usr/bin/systemctl restart --no-block nix-daemon.socket\n\
+        \n\
+        [Install]\n\
+        WantedBy=sysinit.target\n\
+        ",
+        unit_extra = unit_lines(unit_extra),
+    )
+}
+
+fn unit_lines(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extra_lines_are_added_to_the_unit_section() {
+        let unit = nix_mount_unit(
+            Path::new("/home/nix"),
+            &["ConditionPathIsDirectory=/nix".to_string()],
+        );
+        let (unit_section, mount_section) = unit
+            .split_once("[Mount]")
+            .expect("The mount unit has a `[Mount]` section");
+        assert!(unit_section.contains("ConditionPathIsDirectory=/nix\nDefaultDependencies=no\n"));
+        assert!(mount_section.contains("What=/home/nix\nWhere=/nix\n"));
+    }
+}
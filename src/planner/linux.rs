@@ -1,21 +1,34 @@
 use crate::{
     action::{
-        base::{CreateDirectory, RemoveDirectory},
-        common::{ConfigureInitService, ConfigureNix, ProvisionNix},
-        linux::ProvisionSelinux,
+        base::{CheckMemory, CreateDirectory, CreateFile, RemoveDirectory, VerifyNixOnPath},
+        common::{ConfigureInitService, ConfigureNix, PlaceNixConfiguration, ProvisionNix},
+        linux::{
+            ConfigureDaemonSocket, ProvisionSelinux, RestartSystemdUnit, StartSystemdUnit,
+            StopSystemdUnit,
+        },
         StatefulAction,
     },
     error::HasExpectedErrors,
     planner::{Planner, PlannerError},
-    settings::CommonSettings,
+    settings::{in_target_root, CommonSettings, NIX_ROOT, SCRATCH_DIR},
     settings::{InitSettings, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::process::Command;
 use which::which;
 
-use super::ShellProfileLocations;
+use super::{
+    bind_mount::{
+        ensure_symlinked_units_resolve_unit, nix_mount_unit, ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT,
+        NIX_MOUNT_UNIT,
+    },
+    ShellProfileLocations,
+};
 
 /// A planner for Linux installs
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -38,26 +51,90 @@ impl Planner for Linux {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
-        check_not_nixos()?;
+        let target_root = &self.settings.target_root;
+        let is_target_root_alternate = self.settings.is_target_root_alternate();
 
-        check_nix_not_already_installed().await?;
+        check_not_nixos(target_root)?;
+
+        // An alternate target root is not on the `PATH`, an existing Nix there is caught when provisioning
+        if !is_target_root_alternate {
+            check_nix_not_already_installed().await?;
+        }
 
         check_not_wsl1()?;
 
-        let has_selinux = detect_selinux().await?;
+        let has_selinux = if is_target_root_alternate {
+            tracing::warn!("SELinux is not configured when installing into an alternate target root, if the target uses SELinux, install the Nix policy from within it");
+            false
+        } else {
+            detect_selinux().await?
+        };
+
+        // The daemon of an alternate target root can only start once it is booted
+        let start_daemon = self.init.start_daemon && !is_target_root_alternate;
+        if self.init.start_daemon && is_target_root_alternate {
+            tracing::info!(
+                "Not starting the Nix daemon, it will start when `{}` is booted",
+                target_root.display()
+            );
+        }
+
+        let configure_daemon_socket =
+            self.init.daemon_socket_group.is_some() || self.init.daemon_socket_mode.is_some();
+        if configure_daemon_socket && self.init.init != InitSystem::Systemd {
+            return Err(LinuxErrorKind::DaemonSocketPermissionsUnsupported(self.init.init).into());
+        }
+
+        // The store prefix is bind mounted on `/nix` by a systemd mount unit, started right away
+        let is_store_prefix_alternate = self.settings.is_store_prefix_alternate();
+        if is_store_prefix_alternate {
+            if !self.settings.store_prefix.is_absolute() {
+                return Err(LinuxErrorKind::StorePrefixNotAbsolute(
+                    self.settings.store_prefix.clone(),
+                )
+                .into());
+            }
+            if self.init.init != InitSystem::Systemd || !start_daemon {
+                return Err(LinuxErrorKind::StorePrefixRequiresSystemd(
+                    self.settings.store_prefix.clone(),
+                )
+                .into());
+            }
+        }
 
-        if self.init.init == InitSystem::Systemd && self.init.start_daemon {
-            check_systemd_active()?;
+        match self.init.init {
+            InitSystem::Systemd if start_daemon => check_systemd_active()?,
+            InitSystem::OpenRc if start_daemon => check_openrc_active()?,
+            _ => (),
         }
 
         let mut plan = vec![];
 
+        // The memory of the host says nothing about the machine an alternate target root will boot on
+        if !is_target_root_alternate {
+            plan.push(
+                CheckMemory::plan(self.settings.minimum_memory_mib)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
         plan.push(
-            CreateDirectory::plan("/nix", None, None, 0o0755, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            CreateDirectory::plan_preserving(
+                self.settings.in_store_prefix(NIX_ROOT),
+                None,
+                None,
+                0o0755,
+                self.settings.resolved_preserve_paths(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
         );
+        if is_store_prefix_alternate {
+            plan.extend(bind_mount_store_prefix(&self.settings).await?);
+        }
 
         plan.push(
             ProvisionNix::plan(&self.settings.clone())
@@ -84,20 +161,60 @@ impl Planner for Linux {
         plan.push(
             ConfigureInitService::plan(
                 self.init.init,
-                self.init.start_daemon,
+                start_daemon,
                 self.settings.ssl_cert_file.clone(),
+                target_root.clone(),
             )
             .await
             .map_err(PlannerError::Action)?
+            .with_daemon_ready_timeout(Duration::from_secs(self.init.daemon_start_timeout))
             .boxed(),
         );
-        plan.push(
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
+        if configure_daemon_socket {
+            plan.push(
+                ConfigureDaemonSocket::plan(
+                    self.init.daemon_socket_group.clone(),
+                    self.init.daemon_socket_mode,
+                    start_daemon,
+                    target_root,
+                )
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            );
+        }
+        if is_store_prefix_alternate {
+            plan.push(
+                StartSystemdUnit::plan(ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT.to_string(), true)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+        plan.push(
+            RemoveDirectory::plan_guarded(
+                self.settings.in_store_prefix(SCRATCH_DIR),
+                false,
+                vec![self.settings.in_store_prefix(NIX_ROOT)],
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
         );
 
+        // Nothing runs in an alternate target root
+        if self.settings.modify_profile
+            && !self.settings.skip_path_check
+            && !is_target_root_alternate
+        {
+            plan.push(
+                VerifyNixOnPath::plan(&self.settings.profile_shells)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
         Ok(plan)
     }
 
@@ -111,6 +228,53 @@ impl Planner for Linux {
         Ok(map)
     }
 
+    fn requires_reboot_before_use(&self) -> bool {
+        // systemd and OpenRC will only start the daemon on the next boot
+        matches!(self.init.init, InitSystem::Systemd | InitSystem::OpenRc)
+            && (!self.init.start_daemon || self.settings.is_target_root_alternate())
+    }
+
+    fn runs_daemon(&self) -> bool {
+        self.init.init != InitSystem::None
+            && self.init.start_daemon
+            && !self.settings.is_target_root_alternate()
+    }
+
+    fn target_root(&self) -> PathBuf {
+        self.settings.target_root.clone()
+    }
+
+    fn store_prefix(&self) -> PathBuf {
+        self.settings.store_prefix.clone()
+    }
+
+    async fn reconfigure(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Only `nix.conf` is rewritten, the rest of `ConfigureNix` needs the unpacked Nix the install removed
+        let mut actions = vec![PlaceNixConfiguration::plan_replacing(&self.settings)
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed()];
+        let start_daemon = self.init.start_daemon && !self.settings.is_target_root_alternate();
+        if self.init.init == InitSystem::Systemd && start_daemon {
+            actions.push(
+                RestartSystemdUnit::plan("nix-daemon.service")
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+        Ok(actions)
+    }
+
+    async fn pre_uninstall(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Nothing runs in an alternate target root
+        if self.init.init == InitSystem::Systemd && !self.settings.is_target_root_alternate() {
+            stop_nix_daemon_units().await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
     async fn configured_settings(
         &self,
     ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
@@ -137,7 +301,9 @@ impl Planner for Linux {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
-        )?)
+            self.settings.user_agent.clone(),
+        )?
+        .with_redacted(self.settings.diagnostic_redact.clone()))
     }
 }
 
@@ -148,9 +314,9 @@ impl Into<BuiltinPlanner> for Linux {
 }
 
 // If on NixOS, running `nix_installer` is pointless
-fn check_not_nixos() -> Result<(), PlannerError> {
+pub(crate) fn check_not_nixos(target_root: &Path) -> Result<(), PlannerError> {
     // NixOS always sets up this file as part of setting up /etc itself: https://github.com/NixOS/nixpkgs/blob/bdd39e5757d858bd6ea58ed65b4a2e52c8ed11ca/nixos/modules/system/etc/setup-etc.pl#L145
-    if Path::new("/etc/NIXOS").exists() {
+    if in_target_root(target_root, "/etc/NIXOS").exists() {
         return Err(PlannerError::NixOs);
     }
     Ok(())
@@ -179,7 +345,65 @@ async fn detect_selinux() -> Result<bool, PlannerError> {
     }
 }
 
-async fn check_nix_not_already_installed() -> Result<(), PlannerError> {
+/// Mount the store prefix on `/nix` now and on every boot
+async fn bind_mount_store_prefix(
+    settings: &CommonSettings,
+) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    let units = Path::new("/etc/systemd/system");
+    // `What=` is read by the booted system, so the store prefix is not in the target root
+    let nix_mount_buf = nix_mount_unit(
+        &settings.store_prefix,
+        &[format!(
+            "RequiresMountsFor={}",
+            settings.store_prefix.display()
+        )],
+    );
+
+    Ok(vec![
+        // The mount point, the store itself is in the store prefix
+        CreateDirectory::plan(
+            in_target_root(&settings.target_root, NIX_ROOT),
+            None,
+            None,
+            0o0755,
+            false,
+        )
+        .await
+        .map_err(PlannerError::Action)?
+        .boxed(),
+        CreateFile::plan(
+            in_target_root(&settings.target_root, units.join(NIX_MOUNT_UNIT)),
+            None,
+            None,
+            0o0644,
+            nix_mount_buf,
+            false,
+        )
+        .await
+        .map_err(PlannerError::Action)?
+        .boxed(),
+        CreateFile::plan(
+            in_target_root(
+                &settings.target_root,
+                units.join(ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT),
+            ),
+            None,
+            None,
+            0o0644,
+            ensure_symlinked_units_resolve_unit(&[]),
+            false,
+        )
+        .await
+        .map_err(PlannerError::Action)?
+        .boxed(),
+        StartSystemdUnit::plan(NIX_MOUNT_UNIT.to_string(), false)
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+    ])
+}
+
+pub(crate) async fn check_nix_not_already_installed() -> Result<(), PlannerError> {
     // For now, we don't try to repair the user's Nix install or anything special.
     if let Ok(_) = Command::new("nix-env")
         .arg("--version")
@@ -193,6 +417,33 @@ async fn check_nix_not_already_installed() -> Result<(), PlannerError> {
     Ok(())
 }
 
+/// Stop the Nix daemon before its files are removed, otherwise it may hold them open
+pub(crate) async fn stop_nix_daemon_units(
+) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    if which("systemctl").is_err() {
+        return Ok(Vec::new());
+    }
+    // The socket goes first, otherwise it could activate the service again
+    let mut actions = Vec::new();
+    for unit in ["nix-daemon.socket", "nix-daemon.service"] {
+        actions.push(
+            StopSystemdUnit::plan(unit)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+        );
+    }
+    Ok(actions)
+}
+
+fn check_openrc_active() -> Result<(), PlannerError> {
+    if !Path::new("/run/openrc").exists() {
+        return Err(LinuxErrorKind::OpenRcNotActive.into());
+    }
+
+    Ok(())
+}
+
 fn check_systemd_active() -> Result<(), PlannerError> {
     if !Path::new("/run/systemd/system").exists() {
         if std::env::var("WSL_DISTRO_NAME").is_ok() {
@@ -228,6 +479,23 @@ pub enum LinuxErrorKind {
         To use a `root`-only Nix install, consider passing `--init none`."
     )]
     Wsl2SystemdNotActive,
+    #[error(
+        "\
+        OpenRC was not active.\n\
+        \n\
+        If it will be started later consider, passing `--no-start-daemon`.\n\
+        \n\
+        To use a `root`-only Nix install, consider passing `--init none`."
+    )]
+    OpenRcNotActive,
+    #[error(
+        "The permissions of the Nix daemon socket can only be configured with systemd, not `{0}`"
+    )]
+    DaemonSocketPermissionsUnsupported(InitSystem),
+    #[error("The store prefix `{}` must be an absolute path", .0.display())]
+    StorePrefixNotAbsolute(PathBuf),
+    #[error("Keeping the Nix store in `{}` requires systemd to bind mount it on `/nix` and start the Nix daemon, pass `--init systemd` and drop `--no-start-daemon`, or keep the store in `/nix`", .0.display())]
+    StorePrefixRequiresSystemd(PathBuf),
 }
 
 impl HasExpectedErrors for LinuxErrorKind {
@@ -235,6 +503,10 @@ impl HasExpectedErrors for LinuxErrorKind {
         match self {
             LinuxErrorKind::SystemdNotActive => Some(Box::new(self)),
             LinuxErrorKind::Wsl2SystemdNotActive => Some(Box::new(self)),
+            LinuxErrorKind::OpenRcNotActive => Some(Box::new(self)),
+            LinuxErrorKind::DaemonSocketPermissionsUnsupported(_) => Some(Box::new(self)),
+            LinuxErrorKind::StorePrefixNotAbsolute(_) => Some(Box::new(self)),
+            LinuxErrorKind::StorePrefixRequiresSystemd(_) => Some(Box::new(self)),
         }
     }
 }
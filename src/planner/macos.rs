@@ -1,4 +1,8 @@
-use std::{collections::HashMap, io::Cursor};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Component, Path, PathBuf},
+};
 
 #[cfg(feature = "cli")]
 use clap::ArgAction;
@@ -8,16 +12,16 @@ use super::ShellProfileLocations;
 
 use crate::{
     action::{
-        base::RemoveDirectory,
+        base::{CheckMemory, RemoveDirectory, VerifyNixOnPath},
         common::{ConfigureInitService, ConfigureNix, ProvisionNix},
-        macos::CreateNixVolume,
+        macos::{CreateNixVolume, PasswordSource},
         StatefulAction,
     },
     execute_command,
-    os::darwin::DiskUtilInfoOutput,
+    os::darwin::{DiskUtilInfoOutput, MacosVersion},
     planner::{Planner, PlannerError},
     settings::InstallSettingsError,
-    settings::{CommonSettings, InitSystem},
+    settings::{CommonSettings, InitSystem, NIX_ROOT},
     Action, BuiltinPlanner,
 };
 
@@ -39,6 +43,30 @@ pub struct Macos {
         )
     )]
     pub encrypt: Option<bool>,
+    /// A command (and its arguments, comma separated) which outputs the password to encrypt the volume with, a random password is generated if not set
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            num_args = 1..,
+            value_delimiter = ',',
+            env = "NIX_INSTALLER_ENCRYPTION_PASSWORD_COMMAND"
+        )
+    )]
+    #[serde(default)]
+    pub encryption_password_command: Vec<String>,
+    /// Where the password to encrypt the volume with comes from when no `--encryption-password-command` is set
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_enum,
+            default_value_t = EncryptionPasswordSource::Generate,
+            env = "NIX_INSTALLER_ENCRYPTION_PASSWORD_SOURCE"
+        )
+    )]
+    #[serde(default)]
+    pub encryption_password_source: EncryptionPasswordSource,
     /// Use a case sensitive volume
     #[cfg_attr(
         feature = "cli",
@@ -56,11 +84,36 @@ pub struct Macos {
         clap(long, default_value = "Nix Store", env = "NIX_INSTALLER_VOLUME_LABEL")
     )]
     pub volume_label: String,
+    /// Where the APFS volume is mounted, it must be a directory directly under `/` as it is created through `/etc/synthetic.conf`
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, default_value = NIX_ROOT, env = "NIX_INSTALLER_MOUNTPOINT")
+    )]
+    #[serde(default = "default_mountpoint")]
+    pub mountpoint: PathBuf,
     /// The root disk of the target
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_ROOT_DISK"))]
     pub root_disk: Option<String>,
 }
 
+/// Where the password to encrypt the Nix volume with comes from, see [`PasswordSource`]
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EncryptionPasswordSource {
+    /// Generate a random password and store it in the System keychain
+    #[default]
+    Generate,
+    /// Use the password already stored in the System keychain under the volume label, such as one provisioned by an MDM
+    Keychain,
+    /// Ask for the password on the terminal and store it in the System keychain
+    Prompt,
+}
+
+fn default_mountpoint() -> PathBuf {
+    PathBuf::from(NIX_ROOT)
+}
+
 async fn default_root_disk() -> Result<String, PlannerError> {
     let buf = execute_command(
         Command::new("/usr/sbin/diskutil")
@@ -75,6 +128,22 @@ async fn default_root_disk() -> Result<String, PlannerError> {
     Ok(the_plist.parent_whole_disk)
 }
 
+impl Macos {
+    /// Detect the running macOS version with `sw_vers`
+    pub async fn macos_version() -> Result<MacosVersion, PlannerError> {
+        let output = Command::new("/usr/bin/sw_vers")
+            .arg("-productVersion")
+            .stdin(std::process::Stdio::null())
+            .process_group(0)
+            .output()
+            .await
+            .map_err(|e| PlannerError::Custom(Box::new(e)))?;
+        let stdout = String::from_utf8(output.stdout)?;
+
+        MacosVersion::parse(&stdout).ok_or(PlannerError::UnknownMacosVersion(stdout))
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "macos")]
 impl Planner for Macos {
@@ -84,13 +153,40 @@ impl Planner for Macos {
             root_disk: Some(default_root_disk().await?),
             case_sensitive: false,
             encrypt: None,
+            encryption_password_command: Default::default(),
+            encryption_password_source: Default::default(),
             volume_label: "Nix Store".into(),
+            mountpoint: default_mountpoint(),
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if self.settings.is_target_root_alternate() {
+            return Err(PlannerError::TargetRootUnsupported("macos"));
+        }
+        // `/nix` is the mount point of the Nix volume
+        if self.settings.is_store_prefix_alternate() {
+            return Err(PlannerError::StorePrefixUnsupported("macos"));
+        }
+
+        if !is_top_level_directory(&self.mountpoint) {
+            return Err(PlannerError::InvalidMountpoint(self.mountpoint.clone()));
+        }
+
         ensure_not_running_in_rosetta().await?;
 
+        // The Nix volume is mounted through `/etc/synthetic.conf`, which older releases lack
+        let macos_version = Self::macos_version().await?;
+        if macos_version < MacosVersion::CATALINA {
+            return Err(PlannerError::UnsupportedMacosVersion(macos_version));
+        }
+
+        if !self.settings.preserve_paths.is_empty() {
+            tracing::warn!(
+                "Paths cannot be preserved on macOS, the whole Nix volume is deleted on uninstall"
+            );
+        }
+
         let root_disk = match &self.root_disk {
             root_disk @ Some(_) => root_disk.clone(),
             None => {
@@ -129,7 +225,11 @@ impl Planner for Macos {
             false
         };
 
-        Ok(vec![
+        let mut plan = vec![
+            CheckMemory::plan(self.settings.minimum_memory_mib)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
             // Create Volume step:
             //
             // setup_Synthetic -> create_synthetic_objects
@@ -137,8 +237,19 @@ impl Planner for Macos {
             CreateNixVolume::plan(
                 root_disk.unwrap(), /* We just ensured it was populated */
                 self.volume_label.clone(),
+                &self.mountpoint,
                 false,
                 encrypt,
+                if !self.encryption_password_command.is_empty() {
+                    PasswordSource::Command(self.encryption_password_command.clone())
+                } else {
+                    match self.encryption_password_source {
+                        EncryptionPasswordSource::Generate => PasswordSource::Generate,
+                        EncryptionPasswordSource::Keychain => PasswordSource::Keychain,
+                        EncryptionPasswordSource::Prompt => PasswordSource::Prompt,
+                    }
+                },
+                macos_version,
             )
             .await
             .map_err(PlannerError::Action)?
@@ -155,22 +266,41 @@ impl Planner for Macos {
                 InitSystem::Launchd,
                 true,
                 self.settings.ssl_cert_file.clone(),
+                self.settings.target_root.clone(),
             )
             .await
             .map_err(PlannerError::Action)?
             .boxed(),
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        ])
+            RemoveDirectory::plan_guarded(
+                crate::settings::SCRATCH_DIR,
+                false,
+                vec![crate::settings::NIX_ROOT.into()],
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+        ];
+
+        if self.settings.modify_profile && !self.settings.skip_path_check {
+            plan.push(
+                VerifyNixOnPath::plan(&self.settings.profile_shells)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
+        Ok(plan)
     }
 
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
         let Self {
             settings,
             encrypt,
+            encryption_password_command,
+            encryption_password_source,
             volume_label,
+            mountpoint,
             case_sensitive,
             root_disk,
         } = self;
@@ -178,7 +308,16 @@ impl Planner for Macos {
 
         map.extend(settings.settings()?.into_iter());
         map.insert("volume_encrypt".into(), serde_json::to_value(encrypt)?);
+        map.insert(
+            "encryption_password_command".into(),
+            serde_json::to_value(encryption_password_command)?,
+        );
+        map.insert(
+            "encryption_password_source".into(),
+            serde_json::to_value(encryption_password_source)?,
+        );
         map.insert("volume_label".into(), serde_json::to_value(volume_label)?);
+        map.insert("mountpoint".into(), serde_json::to_value(mountpoint)?);
         map.insert("root_disk".into(), serde_json::to_value(root_disk)?);
         map.insert(
             "case_sensitive".into(),
@@ -214,7 +353,9 @@ impl Planner for Macos {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
-        )?)
+            self.settings.user_agent.clone(),
+        )?
+        .with_redacted(self.settings.diagnostic_redact.clone()))
     }
 }
 
@@ -224,7 +365,16 @@ impl Into<BuiltinPlanner> for Macos {
     }
 }
 
-async fn ensure_not_running_in_rosetta() -> Result<(), PlannerError> {
+/// If `path` is a directory directly under `/`, the only kind `/etc/synthetic.conf` can create
+fn is_top_level_directory(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next(), components.next()),
+        (Some(Component::RootDir), Some(Component::Normal(_)), None)
+    )
+}
+
+pub(crate) async fn ensure_not_running_in_rosetta() -> Result<(), PlannerError> {
     use sysctl::{Ctl, Sysctl};
     const CTLNAME: &str = "sysctl.proc_translated";
 
@@ -79,6 +79,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.user_agent.clone(),
         )?)
     }
 }
@@ -86,7 +87,7 @@ impl Planner for MyPlanner {
 # async fn custom_planner_install() -> color_eyre::Result<()> {
 let planner = MyPlanner::default().await?;
 let mut plan = InstallPlan::plan(planner).await?;
-match plan.install(None).await {
+match plan.install(None, None).await {
     Ok(()) => tracing::info!("Done"),
     Err(e) => {
         match e.source() {
@@ -103,20 +104,32 @@ match plan.install(None).await {
 
 */
 #[cfg(target_os = "linux")]
+pub(crate) mod bind_mount;
+#[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "macos")]
 pub mod macos;
+pub(crate) mod receipt;
+#[cfg(target_os = "linux")]
+pub mod single_user;
 #[cfg(target_os = "linux")]
 pub mod steam_deck;
+#[cfg(target_os = "linux")]
+pub mod wsl;
 
-use std::{collections::HashMap, path::PathBuf, string::FromUtf8Error};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    string::FromUtf8Error,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     action::{ActionError, StatefulAction},
     error::HasExpectedErrors,
-    settings::{CommonSettings, InstallSettingsError},
+    settings::{in_target_root, CommonSettings, InstallSettingsError},
     Action, InstallPlan, NixInstallerError,
 };
 
@@ -136,6 +149,39 @@ pub trait Planner: std::fmt::Debug + Send + Sync + dyn_clone::DynClone {
     async fn configured_settings(&self)
         -> Result<HashMap<String, serde_json::Value>, PlannerError>;
 
+    /// If the planned install will require a reboot before Nix can be used
+    fn requires_reboot_before_use(&self) -> bool {
+        false
+    }
+
+    /// If a Nix daemon runs once the planned install completes, rather than Nix using the store directly or the daemon starting on the next boot
+    fn runs_daemon(&self) -> bool {
+        true
+    }
+
+    /// The root directory the planned install places Nix into, where its receipt is also written
+    fn target_root(&self) -> PathBuf {
+        PathBuf::from(crate::settings::HOST_ROOT)
+    }
+
+    /// Where the planned install keeps the Nix store and state, and writes its receipt, usually `/nix`
+    fn store_prefix(&self) -> PathBuf {
+        PathBuf::from(crate::settings::NIX_ROOT)
+    }
+
+    /// [`Action`]s applying the settings of the planner to a completed install, for [`InstallPlan::reconfigure`](crate::InstallPlan::reconfigure)
+    ///
+    /// They are executed on top of the install, such as to rewrite `nix.conf` then restart the Nix
+    /// daemon, so must leave nothing behind which reverting the install would not remove.
+    async fn reconfigure(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        Err(PlannerError::ReconfigureUnsupported(self.typetag_name()))
+    }
+
+    /// [`Action`]s to execute before [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) reverts the plan, such as stopping the Nix daemon
+    async fn pre_uninstall(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        Ok(Vec::new())
+    }
+
     /// A boxed, type erased planner
     fn boxed(self) -> Box<dyn Planner>
     where
@@ -163,24 +209,36 @@ pub enum BuiltinPlanner {
     /// A planner suitable for the Valve Steam Deck running SteamOS
     #[cfg(target_os = "linux")]
     SteamDeck(steam_deck::SteamDeck),
+    /// A planner for WSL2 without systemd, starting the Nix daemon from the WSL boot command
+    #[cfg(target_os = "linux")]
+    Wsl(wsl::Wsl),
+    /// A planner for single-user installs, owned by one user and without a daemon or build users
+    #[cfg(target_os = "linux")]
+    SingleUser(single_user::SingleUser),
 }
 
 impl BuiltinPlanner {
     /// Heuristically determine the default planner for the target system
     pub async fn default() -> Result<Self, PlannerError> {
+        check_platform().await?;
+
         use target_lexicon::{Architecture, OperatingSystem};
         match (Architecture::host(), OperatingSystem::host()) {
             #[cfg(target_os = "linux")]
-            (Architecture::X86_64, OperatingSystem::Linux) => {
-                Ok(Self::Linux(linux::Linux::default().await?))
-            },
-            #[cfg(target_os = "linux")]
-            (Architecture::X86_32(_), OperatingSystem::Linux) => {
-                Ok(Self::Linux(linux::Linux::default().await?))
-            },
-            #[cfg(target_os = "linux")]
-            (Architecture::Aarch64(_), OperatingSystem::Linux) => {
-                Ok(Self::Linux(linux::Linux::default().await?))
+            (Architecture::X86_64, OperatingSystem::Linux)
+            | (Architecture::X86_32(_), OperatingSystem::Linux)
+            | (Architecture::Aarch64(_), OperatingSystem::Linux) => {
+                if steam_deck::detect().await {
+                    // SteamOS has a read-only root, which its updates reset
+                    Ok(Self::SteamDeck(steam_deck::SteamDeck::default().await?))
+                } else if wsl::detect().await == Some(2)
+                    && !std::path::Path::new("/run/systemd/system").exists()
+                {
+                    // WSL2 with systemd enabled works with the `linux` planner
+                    Ok(Self::Wsl(wsl::Wsl::default().await?))
+                } else {
+                    Ok(Self::Linux(linux::Linux::default().await?))
+                }
             },
             #[cfg(target_os = "macos")]
             (Architecture::X86_64, OperatingSystem::MacOSX { .. })
@@ -197,16 +255,137 @@ impl BuiltinPlanner {
     }
 
     pub async fn from_common_settings(settings: CommonSettings) -> Result<Self, PlannerError> {
-        let mut built = Self::default().await?;
-        match &mut built {
+        Ok(Self::default().await?.with_common_settings(settings))
+    }
+
+    /// The same planner, with its [`CommonSettings`] replaced by `settings`
+    pub fn with_common_settings(mut self, settings: CommonSettings) -> Self {
+        match &mut self {
             #[cfg(target_os = "linux")]
             BuiltinPlanner::Linux(inner) => inner.settings = settings,
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(inner) => inner.settings = settings,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(inner) => inner.settings = settings,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(inner) => inner.settings = settings,
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(inner) => inner.settings = settings,
         }
-        Ok(built)
+        self
+    }
+
+    /// Load a planner from a document of its settings, in TOML if `path` ends in `.toml` or in JSON otherwise
+    ///
+    /// The document is laid out like the planner recorded in a receipt, so a team can commit it to
+    /// version control and install the same way everywhere:
+    ///
+    /// ```toml
+    /// planner = "linux"
+    ///
+    /// [settings]
+    /// nix_build_group_id = 3000
+    /// extra_conf = ["max-jobs = 4"]
+    ///
+    /// [init]
+    /// start_daemon = false
+    /// ```
+    ///
+    /// Without `planner`, the [`default`](Self::default) planner for this system is used. Settings
+    /// left out keep their defaults. See [`with_overrides`](Self::with_overrides) to apply settings
+    /// given on the command line on top.
+    pub async fn from_config_file(path: impl AsRef<Path>) -> Result<Self, PlannerError> {
+        let path = path.as_ref();
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| PlannerError::ReadingConfigFile(path.to_path_buf(), e))?;
+        let config: serde_json::Value = if path.extension() == Some(OsStr::new("toml")) {
+            toml::from_str(&contents)
+                .map_err(|e| PlannerError::ParsingConfigFile(path.to_path_buf(), e.to_string()))?
+        } else {
+            serde_json::from_str(&contents)
+                .map_err(|e| PlannerError::ParsingConfigFile(path.to_path_buf(), e.to_string()))?
+        };
+
+        let planner = match config.get("planner") {
+            Some(serde_json::Value::String(name)) => Self::default_named(name).await?,
+            Some(other) => {
+                return Err(PlannerError::ParsingConfigFile(
+                    path.to_path_buf(),
+                    format!("`planner` must be the name of a planner, not `{other}`"),
+                ))
+            },
+            None => Self::default().await?,
+        };
+        planner
+            .merged(config)
+            .map_err(|e| PlannerError::ParsingConfigFile(path.to_path_buf(), e.to_string()))
+    }
+
+    /// The same planner, with the settings `overrides` has changed from their defaults applied on top
+    ///
+    /// This lets settings passed on the command line win over those of a [config file](Self::from_config_file).
+    /// Settings `overrides` leaves at their defaults do not override anything.
+    pub async fn with_overrides(self, overrides: &BuiltinPlanner) -> Result<Self, PlannerError> {
+        if self.typetag_name() != overrides.typetag_name() {
+            return Err(PlannerError::ConfigFilePlannerChanged {
+                config: self.typetag_name(),
+                given: overrides.typetag_name(),
+            });
+        }
+        let configured = overrides.configured_settings().await?;
+
+        let mut overrides = serde_json::to_value(overrides.clone().boxed())
+            .map_err(InstallSettingsError::SerdeJson)?;
+        if let serde_json::Value::Object(fields) = &mut overrides {
+            fields.remove("planner");
+            // Settings are either fields of the planner or grouped in fields like `settings`
+            fields.retain(|name, value| match value {
+                serde_json::Value::Object(settings) => {
+                    settings.retain(|name, _| configured.contains_key(name));
+                    true
+                },
+                _ => configured.contains_key(name),
+            });
+        }
+        Ok(self
+            .merged(overrides)
+            .map_err(InstallSettingsError::SerdeJson)?)
+    }
+
+    /// The default planner with the [`typetag`] name `name`
+    async fn default_named(name: &str) -> Result<Self, PlannerError> {
+        Ok(match name {
+            #[cfg(target_os = "linux")]
+            "linux" => Self::Linux(linux::Linux::default().await?),
+            #[cfg(target_os = "linux")]
+            "steam-deck" => Self::SteamDeck(steam_deck::SteamDeck::default().await?),
+            #[cfg(target_os = "linux")]
+            "wsl" => Self::Wsl(wsl::Wsl::default().await?),
+            #[cfg(target_os = "linux")]
+            "single-user" => Self::SingleUser(single_user::SingleUser::default().await?),
+            #[cfg(target_os = "macos")]
+            "macos" => Self::Macos(macos::Macos::default().await?),
+            _ => return Err(PlannerError::UnknownPlanner(name.to_string())),
+        })
+    }
+
+    /// The same planner, with `overrides` merged over its serialized settings
+    fn merged(self, overrides: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let mut value = serde_json::to_value(self.clone().boxed())?;
+        merge_json(&mut value, overrides);
+        Ok(match self {
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Linux(_) => Self::Linux(serde_json::from_value(value)?),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SteamDeck(_) => Self::SteamDeck(serde_json::from_value(value)?),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(_) => Self::Wsl(serde_json::from_value(value)?),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(_) => Self::SingleUser(serde_json::from_value(value)?),
+            #[cfg(target_os = "macos")]
+            BuiltinPlanner::Macos(_) => Self::Macos(serde_json::from_value(value)?),
+        })
     }
 
     pub async fn configured_settings(
@@ -217,19 +396,123 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(inner) => inner.configured_settings().await,
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(inner) => inner.configured_settings().await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(inner) => inner.configured_settings().await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(inner) => inner.configured_settings().await,
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(inner) => inner.configured_settings().await,
         }
     }
 
+    /// Plan out an [`InstallPlan`], refusing to install over an [`ExistingNixStore`] unless the `install_over_existing_store` setting is set
     pub async fn plan(self) -> Result<InstallPlan, NixInstallerError> {
-        match self {
+        let existing_nix_store = self.existing_nix_store().await?;
+        if let Some(existing_nix_store) = &existing_nix_store {
+            if !self.common_settings().install_over_existing_store {
+                tracing::error!("{existing_nix_store}");
+                return Err(NixInstallerError::ExistingNixStore(
+                    existing_nix_store.path.clone(),
+                ));
+            }
+            tracing::warn!(
+                "{existing_nix_store}, installing over it as `install_over_existing_store` is set"
+            );
+        }
+        let action_timeout = self
+            .common_settings()
+            .action_timeout
+            .map(std::time::Duration::from_secs);
+
+        let mut plan = match self {
             #[cfg(target_os = "linux")]
             BuiltinPlanner::Linux(planner) => InstallPlan::plan(planner).await,
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(planner) => InstallPlan::plan(planner).await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(planner) => InstallPlan::plan(planner).await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(planner) => InstallPlan::plan(planner).await,
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(planner) => InstallPlan::plan(planner).await,
+        }?;
+        plan.existing_nix_store = existing_nix_store;
+        if let Some(action_timeout) = action_timeout {
+            for action in &mut plan.actions {
+                action.timeout.get_or_insert(action_timeout);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Look for a Nix store, from some other install of Nix, where this planner would install one
+    ///
+    /// A `/nix` holding nothing but the [`preserve_paths`](CommonSettings::preserve_paths) of a
+    /// previous uninstall does not count.
+    pub async fn existing_nix_store(&self) -> Result<Option<ExistingNixStore>, PlannerError> {
+        let settings = self.common_settings();
+        let path = settings.in_store_prefix(crate::settings::NIX_ROOT);
+        let preserve_paths = settings.resolved_preserve_paths();
+
+        let mut entries = vec![];
+        match tokio::fs::read_dir(&path).await {
+            Ok(mut read_dir) => {
+                while let Some(entry) = read_dir
+                    .next_entry()
+                    .await
+                    .map_err(|e| PlannerError::ReadingExistingNixStore(path.clone(), e))?
+                {
+                    let entry = entry.path();
+                    if !preserve_paths
+                        .iter()
+                        .any(|preserve_path| preserve_path.starts_with(&entry))
+                    {
+                        entries.push(entry);
+                    }
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(PlannerError::ReadingExistingNixStore(path, e)),
+        }
+        entries.sort();
+
+        #[cfg(target_os = "linux")]
+        let volume = None;
+        #[cfg(target_os = "macos")]
+        let volume = {
+            use crate::action::macos::{create_apfs_volume::volume_exists, CreateApfsVolume};
+            let BuiltinPlanner::Macos(inner) = self;
+            volume_exists(&inner.volume_label)
+                .await
+                .map_err(|e| {
+                    PlannerError::Action(ActionError::new(CreateApfsVolume::action_tag(), e))
+                })?
+                .then(|| inner.volume_label.clone())
+        };
+
+        if entries.is_empty() && volume.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(ExistingNixStore {
+                path,
+                entries,
+                volume,
+            }))
+        }
+    }
+
+    fn common_settings(&self) -> &CommonSettings {
+        match self {
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Linux(inner) => &inner.settings,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SteamDeck(inner) => &inner.settings,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(inner) => &inner.settings,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(inner) => &inner.settings,
+            #[cfg(target_os = "macos")]
+            BuiltinPlanner::Macos(inner) => &inner.settings,
         }
     }
     pub fn boxed(self) -> Box<dyn Planner> {
@@ -238,6 +521,10 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.boxed(),
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(i) => i.boxed(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(i) => i.boxed(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(i) => i.boxed(),
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(i) => i.boxed(),
         }
@@ -249,6 +536,10 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.typetag_name(),
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(i) => i.typetag_name(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(i) => i.typetag_name(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(i) => i.typetag_name(),
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(i) => i.typetag_name(),
         }
@@ -260,6 +551,10 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.settings(),
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(i) => i.settings(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(i) => i.settings(),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(i) => i.settings(),
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(i) => i.settings(),
         }
@@ -274,20 +569,70 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.diagnostic_data().await,
             #[cfg(target_os = "linux")]
             BuiltinPlanner::SteamDeck(i) => i.diagnostic_data().await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Wsl(i) => i.diagnostic_data().await,
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SingleUser(i) => i.diagnostic_data().await,
             #[cfg(target_os = "macos")]
             BuiltinPlanner::Macos(i) => i.diagnostic_data().await,
         }
     }
 }
 
+/// A Nix store found by [`BuiltinPlanner::existing_nix_store`], such as one from a distribution package of Nix
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct ExistingNixStore {
+    /// Where the Nix store was found, usually `/nix` or the store prefix
+    pub path: PathBuf,
+    /// The entries of `path`, besides any preserved paths
+    pub entries: Vec<PathBuf>,
+    /// The APFS volume already holding a Nix store, on macOS
+    pub volume: Option<String>,
+}
+
+impl std::fmt::Display for ExistingNixStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Found an existing Nix store at `{}`",
+            self.path.display()
+        )?;
+        if !self.entries.is_empty() {
+            write!(
+                f,
+                " holding {}",
+                self.entries
+                    .iter()
+                    .map(|entry| format!("`{}`", entry.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        if let Some(volume) = &self.volume {
+            write!(f, " with the APFS volume `{volume}`")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct ShellProfileLocations {
     pub fish: FishShellProfileLocations,
     pub bash: Vec<PathBuf>,
     pub zsh: Vec<PathBuf>,
+    /// Files sourced by zsh login shells, where Nix is appended instead of prepended
+    ///
+    /// On macOS, `/etc/zprofile` runs `path_helper`, which moves the `PATH` entries Nix added
+    /// behind the system ones if Nix came first.
+    #[serde(default)]
+    pub zsh_login: Vec<PathBuf>,
+    /// Files in the vendor autoload directories of nushell, which it loads on startup
+    #[serde(default = "default_nushell_profile_locations")]
+    pub nushell: Vec<PathBuf>,
 }
 
 impl Default for ShellProfileLocations {
+    #[cfg(not(target_os = "macos"))]
     fn default() -> Self {
         Self {
             fish: FishShellProfileLocations::default(),
@@ -301,8 +646,32 @@ impl Default for ShellProfileLocations {
                 "/etc/zshrc".into(),
                 "/etc/zsh/zshrc".into(),
             ],
+            zsh_login: vec![],
+            nushell: default_nushell_profile_locations(),
         }
     }
+
+    /// macOS has no `/etc/profile.d`, and zsh is its default shell, reading `/etc/zprofile` in
+    /// login shells and `/etc/zshrc` in interactive ones
+    #[cfg(target_os = "macos")]
+    fn default() -> Self {
+        Self {
+            fish: FishShellProfileLocations::default(),
+            bash: vec!["/etc/bashrc".into()],
+            zsh: vec!["/etc/zshrc".into()],
+            zsh_login: vec!["/etc/zprofile".into()],
+            nushell: default_nushell_profile_locations(),
+        }
+    }
+}
+
+fn default_nushell_profile_locations() -> Vec<PathBuf> {
+    // https://www.nushell.sh/book/configuration.html#configuring-nu-as-a-login-shell
+    vec![
+        "/usr/share/nushell/vendor/autoload/nix.nu".into(),
+        "/usr/local/share/nushell/vendor/autoload/nix.nu".into(),
+        "/opt/homebrew/share/nushell/vendor/autoload/nix.nu".into(),
+    ]
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
@@ -366,6 +735,21 @@ pub enum PlannerError {
     Sysctl(#[from] sysctl::SysctlError),
     #[error("Detected that this process is running under Rosetta, using Nix in Rosetta is not supported (Please open an issue with your use case)")]
     RosettaDetected,
+    /// There is no Nix package for the architecture and operating system `nix-installer` was built for
+    #[error("Nix is not available for `{arch}` on `{os}`, only for {}", SUPPORTED_PLATFORMS.iter().map(|(arch, os)| format!("`{arch}` on `{os}`")).collect::<Vec<_>>().join(", "))]
+    UnsupportedPlatform { arch: String, os: String },
+    /// `nix-installer` was built for another architecture than the one `uname` reports, so it is being emulated
+    #[error("This `nix-installer` is built for `{binary}`, but the kernel reports a `{machine}` machine, so it is run by an emulator and would install the wrong Nix, use the `nix-installer` built for `{machine}` instead")]
+    EmulatedArchitecture { binary: String, machine: String },
+    /// The running macOS release predates `/etc/synthetic.conf`
+    #[error("macOS {0} is not supported, `nix-installer` requires macOS {min} (Catalina) or later", min = crate::os::darwin::MacosVersion::CATALINA)]
+    UnsupportedMacosVersion(crate::os::darwin::MacosVersion),
+    /// The output of `sw_vers -productVersion` could not be understood
+    #[error("Could not parse the macOS version `{}` reported by `sw_vers`", .0.trim())]
+    UnknownMacosVersion(String),
+    /// The Nix volume cannot be mounted where `/etc/synthetic.conf` cannot create a directory
+    #[error("The mountpoint `{}` of the Nix volume must be a directory directly under `/`, such as `/nix`", .0.display())]
+    InvalidMountpoint(PathBuf),
     /// A Linux SELinux related error
     #[error("Unable to install on an SELinux system without common SELinux tooling, the binaries `restorecon`, and `semodule` are required")]
     SelinuxRequirements,
@@ -379,13 +763,124 @@ pub enum PlannerError {
     NixOs,
     #[error("`nix` is already a valid command, so it is installed")]
     NixExists,
+    /// The entries of an existing Nix store could not be listed
+    #[error("Reading the existing Nix store at `{}`", .0.display())]
+    ReadingExistingNixStore(PathBuf, #[source] std::io::Error),
     #[error("WSL1 is not supported, please upgrade to WSL2: https://learn.microsoft.com/en-us/windows/wsl/install#upgrade-version-from-wsl-1-to-wsl-2")]
     Wsl1,
+    /// A single-user install must be owned by an unprivileged user
+    #[error("The `single-user` planner installs Nix for an unprivileged user, run it with `sudo` from that user or pass `--user`")]
+    SingleUserRoot,
+    /// The planner can only install into the running system
+    #[error("The `{0}` planner does not support installing into an alternate target root, only the `linux` planner does")]
+    TargetRootUnsupported(&'static str),
+    /// The planner can only keep the Nix store in `/nix`
+    #[error("The `{0}` planner does not support keeping the Nix store outside of `/nix`, only the `linux` planner does")]
+    StorePrefixUnsupported(&'static str),
+    /// The planner of a plan loaded with [`InstallPlan::uninstall_plan_from_receipt`](crate::InstallPlan::uninstall_plan_from_receipt) cannot plan
+    #[error(
+        "The `{0}` planner was loaded from a receipt only to uninstall, and cannot plan an install"
+    )]
+    UninstallOnly(String),
+    /// The planner cannot apply its settings to an existing install
+    #[error("The `{0}` planner cannot reconfigure an existing install, try uninstalling and installing again")]
+    ReconfigureUnsupported(&'static str),
+    /// An install can only be reconfigured by the planner which planned it
+    #[error("The install was planned by the `{recorded}` planner, it cannot be reconfigured by the `{given}` planner")]
+    ReconfigurePlannerChanged {
+        recorded: &'static str,
+        given: &'static str,
+    },
+    /// An error while reading a file given to [`BuiltinPlanner::from_config_file`]
+    #[error("Reading the config file `{}`", .0.display())]
+    ReadingConfigFile(PathBuf, #[source] std::io::Error),
+    /// A file given to [`BuiltinPlanner::from_config_file`] does not hold the settings of a planner
+    #[error("The config file `{}` is not valid: {1}", .0.display())]
+    ParsingConfigFile(PathBuf, String),
+    /// A planner named in a config file which is not built into this `nix-installer`
+    #[error("There is no `{0}` planner for this system")]
+    UnknownPlanner(String),
+    /// The settings of one planner cannot override those of another
+    #[error("The config file is for the `{config}` planner, it cannot be combined with settings for the `{given}` planner")]
+    ConfigFilePlannerChanged {
+        config: &'static str,
+        given: &'static str,
+    },
     #[cfg(feature = "diagnostics")]
     #[error(transparent)]
     Diagnostic(#[from] crate::diagnostics::DiagnosticError),
 }
 
+/// Merge `overrides` into `base`, replacing all but the objects, which are merged field by field
+fn merge_json(base: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (base, overrides) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overrides)) => {
+            for (name, value) in overrides {
+                merge_json(base.entry(name).or_insert(serde_json::Value::Null), value);
+            }
+        },
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// The platforms there is a Nix package for, as their [`std::env::consts::ARCH`] and [`std::env::consts::OS`]
+const SUPPORTED_PLATFORMS: &[(&str, &str)] = &[
+    ("x86_64", "linux"),
+    ("x86", "linux"),
+    ("aarch64", "linux"),
+    ("x86_64", "macos"),
+    ("aarch64", "macos"),
+];
+
+/// Refuse to plan on a platform without a Nix package, or when `nix-installer` is emulated on a machine of another architecture
+///
+/// The Nix package is chosen by the architecture `nix-installer` was built for, so an emulated
+/// `nix-installer` would install a Nix which does not match the machine.
+///
+/// On Linux, emulation is only noticed when `uname` reports the real machine, as with Rosetta in a
+/// Linux VM. `qemu-user`, including through `binfmt_misc`, reports the emulated architecture from
+/// `uname`, so an `x86_64` `nix-installer` run by it on an `aarch64` machine is not refused.
+async fn check_platform() -> Result<(), PlannerError> {
+    let (arch, os) = (std::env::consts::ARCH, std::env::consts::OS);
+    if !SUPPORTED_PLATFORMS.contains(&(arch, os)) {
+        return Err(PlannerError::UnsupportedPlatform {
+            arch: arch.to_string(),
+            os: os.to_string(),
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    macos::ensure_not_running_in_rosetta().await?;
+
+    #[cfg(target_os = "linux")]
+    match nix::sys::utsname::uname() {
+        Ok(uname) => {
+            let machine = uname.machine().to_string_lossy();
+            if !runs_natively(arch, &machine) {
+                return Err(PlannerError::EmulatedArchitecture {
+                    binary: arch.to_string(),
+                    machine: machine.to_string(),
+                });
+            }
+        },
+        Err(err) => tracing::debug!("Could not get the machine architecture: {err}"),
+    }
+
+    Ok(())
+}
+
+/// If a binary built for `arch` runs natively on a kernel reporting the `uname -m` of `machine`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn runs_natively(arch: &str, machine: &str) -> bool {
+    match arch {
+        "x86_64" => machine == "x86_64",
+        // 32 bit x86 binaries run natively on 64 bit x86 kernels
+        "x86" => matches!(machine, "i386" | "i486" | "i586" | "i686" | "x86_64"),
+        "aarch64" => matches!(machine, "aarch64" | "arm64"),
+        _ => true,
+    }
+}
+
 impl HasExpectedErrors for PlannerError {
     fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
         match self {
@@ -395,6 +890,11 @@ impl HasExpectedErrors for PlannerError {
             PlannerError::Plist(_) => None,
             PlannerError::Sysctl(_) => None,
             this @ PlannerError::RosettaDetected => Some(Box::new(this)),
+            this @ PlannerError::UnsupportedPlatform { .. } => Some(Box::new(this)),
+            this @ PlannerError::EmulatedArchitecture { .. } => Some(Box::new(this)),
+            this @ PlannerError::UnsupportedMacosVersion(_) => Some(Box::new(this)),
+            PlannerError::UnknownMacosVersion(_) => None,
+            this @ PlannerError::InvalidMountpoint(_) => Some(Box::new(this)),
             PlannerError::Utf8(_) => None,
             PlannerError::SelinuxRequirements => Some(Box::new(self)),
             PlannerError::Custom(e) => {
@@ -406,13 +906,37 @@ impl HasExpectedErrors for PlannerError {
             },
             this @ PlannerError::NixOs => Some(Box::new(this)),
             this @ PlannerError::NixExists => Some(Box::new(this)),
+            PlannerError::ReadingExistingNixStore(_, _) => None,
             this @ PlannerError::Wsl1 => Some(Box::new(this)),
+            this @ PlannerError::SingleUserRoot => Some(Box::new(this)),
+            this @ PlannerError::TargetRootUnsupported(_) => Some(Box::new(this)),
+            this @ PlannerError::StorePrefixUnsupported(_) => Some(Box::new(this)),
+            this @ PlannerError::UninstallOnly(_) => Some(Box::new(this)),
+            this @ PlannerError::ReconfigureUnsupported(_) => Some(Box::new(this)),
+            this @ PlannerError::ReconfigurePlannerChanged { .. } => Some(Box::new(this)),
+            PlannerError::ReadingConfigFile(_, _) => None,
+            this @ PlannerError::ParsingConfigFile(_, _) => Some(Box::new(this)),
+            this @ PlannerError::UnknownPlanner(_) => Some(Box::new(this)),
+            this @ PlannerError::ConfigFilePlannerChanged { .. } => Some(Box::new(this)),
             #[cfg(feature = "diagnostics")]
             PlannerError::Diagnostic(diagnostic_error) => Some(Box::new(diagnostic_error)),
         }
     }
 }
 
+impl PlannerError {
+    /// A stable code for the failure, as in [`ActionError::code`](crate::action::ActionError::code) if an action failed, or `planner.<variant>`, like `planner.nix-exists`
+    pub fn code(&self) -> String {
+        match self {
+            PlannerError::Action(action_error) => action_error.code(),
+            this => {
+                let static_str: &'static str = this.into();
+                format!("planner.{}", crate::error::kebab_case(static_str))
+            },
+        }
+    }
+}
+
 #[cfg(feature = "diagnostics")]
 impl crate::diagnostics::ErrorDiagnostic for PlannerError {
     fn diagnostic(&self) -> String {
@@ -420,3 +944,200 @@ impl crate::diagnostics::ErrorDiagnostic for PlannerError {
         return static_str.to_string();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Default settings pointed at a new target root, which lives as long as the returned `TempDir`
+    async fn scratch_settings() -> eyre::Result<(tempfile::TempDir, CommonSettings)> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = CommonSettings::default().await?;
+        settings.target_root = temp_dir.path().to_path_buf();
+        Ok((temp_dir, settings))
+    }
+
+    #[test]
+    fn emulated_architectures_are_detected() {
+        assert!(runs_natively("x86_64", "x86_64"));
+        assert!(runs_natively("x86", "x86_64"));
+        assert!(runs_natively("aarch64", "arm64"));
+        assert!(!runs_natively("x86_64", "aarch64"));
+        assert!(!runs_natively("aarch64", "x86_64"));
+        assert!(!runs_natively("x86_64", "i686"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn existing_nix_store_ignores_preserved_paths() -> eyre::Result<()> {
+        let (temp_dir, mut settings) = scratch_settings().await?;
+        settings.preserve_paths = vec!["/nix/var/nix/gcroots".into()];
+        let planner = BuiltinPlanner::Linux(linux::Linux {
+            settings,
+            init: crate::settings::InitSettings::default().await?,
+        });
+        assert_eq!(planner.existing_nix_store().await?, None);
+
+        let nix = temp_dir.path().join("nix");
+        std::fs::create_dir_all(nix.join("var/nix/gcroots"))?;
+        assert_eq!(planner.existing_nix_store().await?, None);
+
+        std::fs::create_dir_all(nix.join("store"))?;
+        let existing_nix_store = planner.existing_nix_store().await?;
+        assert_eq!(
+            existing_nix_store,
+            Some(ExistingNixStore {
+                path: nix.clone(),
+                entries: vec![nix.join("store")],
+                volume: None,
+            })
+        );
+        match planner.clone().plan().await {
+            Err(NixInstallerError::ExistingNixStore(path)) => assert_eq!(path, nix),
+            other => return Err(eyre::eyre!("Expected an ExistingNixStore, got {other:?}")),
+        }
+
+        // Forcibly recreating files is not enough to install over it
+        let BuiltinPlanner::Linux(mut linux) = planner else {
+            unreachable!()
+        };
+        linux.settings.force = true;
+        linux.init.init = crate::settings::InitSystem::None;
+        linux.init.start_daemon = false;
+        assert!(matches!(
+            BuiltinPlanner::Linux(linux.clone()).plan().await,
+            Err(NixInstallerError::ExistingNixStore(_))
+        ));
+        linux.settings.install_over_existing_store = true;
+        let plan = BuiltinPlanner::Linux(linux).plan().await?;
+        assert_eq!(plan.existing_nix_store, existing_nix_store);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn action_timeout_applies_to_every_action() -> eyre::Result<()> {
+        let (_temp_dir, mut settings) = scratch_settings().await?;
+        settings.action_timeout = Some(30);
+        let mut init = crate::settings::InitSettings::default().await?;
+        init.init = crate::settings::InitSystem::None;
+        init.start_daemon = false;
+
+        let plan = BuiltinPlanner::Linux(linux::Linux { settings, init })
+            .plan()
+            .await?;
+        assert!(!plan.actions.is_empty());
+        for action in &plan.actions {
+            assert_eq!(
+                action.timeout(),
+                Some(std::time::Duration::from_secs(30)),
+                "`{}` has no timeout",
+                action.tracing_synopsis()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preserve_paths_resolve_under_store_prefix() -> eyre::Result<()> {
+        let (temp_dir, mut settings) = scratch_settings().await?;
+        settings.store_prefix = "/opt/nix".into();
+        settings.preserve_paths = vec![
+            "/nix/var/nix/gcroots".into(),
+            "/opt/nix/var/nix/profiles".into(),
+        ];
+
+        let store_root = temp_dir.path().join("opt/nix");
+        let preserved = settings.resolved_preserve_paths();
+        assert_eq!(
+            preserved,
+            vec![
+                store_root.join("var/nix/gcroots"),
+                store_root.join("var/nix/profiles"),
+            ]
+        );
+        crate::action::base::CreateDirectory::plan_preserving(
+            settings.in_store_prefix(crate::settings::NIX_ROOT),
+            None,
+            None,
+            0o0755,
+            preserved,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn runs_daemon_only_with_a_started_daemon() -> eyre::Result<()> {
+        let mut linux = linux::Linux::default().await?;
+        linux.init.init = crate::settings::InitSystem::Systemd;
+        linux.init.start_daemon = true;
+        assert!(linux.runs_daemon());
+        linux.init.start_daemon = false;
+        assert!(!linux.runs_daemon());
+        linux.init.start_daemon = true;
+        linux.init.init = crate::settings::InitSystem::None;
+        assert!(!linux.runs_daemon());
+
+        let mut wsl = wsl::Wsl::default().await?;
+        assert!(wsl.runs_daemon());
+        wsl.start_daemon = false;
+        assert!(!wsl.runs_daemon());
+
+        assert!(!single_user::SingleUser::default().await?.runs_daemon());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn config_file_round_trips_settings() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut planner = linux::Linux::default().await?;
+        planner.settings.nix_build_group_id = 3000;
+        planner.settings.extra_conf = vec!["max-jobs = 4".to_string()];
+        planner.init.start_daemon = false;
+        let planner = BuiltinPlanner::Linux(planner);
+
+        let json = temp_dir.path().join("install-config.json");
+        std::fs::write(&json, serde_json::to_string(&planner.clone().boxed())?)?;
+        let loaded = BuiltinPlanner::from_config_file(&json).await?;
+        assert_eq!(loaded.typetag_name(), "linux");
+        assert_eq!(loaded.settings()?, planner.settings()?);
+
+        // Only what is written is changed from the defaults
+        let toml = temp_dir.path().join("install-config.toml");
+        std::fs::write(
+            &toml,
+            "planner = \"linux\"\n\n[settings]\nnix_build_group_id = 3000\n",
+        )?;
+        let loaded = BuiltinPlanner::from_config_file(&toml).await?;
+        assert_eq!(
+            loaded
+                .configured_settings()
+                .await?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec!["nix_build_group_id".to_string()]
+        );
+
+        // Settings changed on the command line win over those of the file
+        let mut cli = linux::Linux::default().await?;
+        cli.settings.nix_build_group_id = 4000;
+        let merged = loaded
+            .with_overrides(&BuiltinPlanner::Linux(cli))
+            .await?
+            .settings()?;
+        assert_eq!(merged["nix_build_group_id"], 4000);
+
+        std::fs::write(&toml, "planner = \"macos\"\n")?;
+        assert!(matches!(
+            BuiltinPlanner::from_config_file(&toml).await,
+            Err(PlannerError::UnknownPlanner(_))
+        ));
+        Ok(())
+    }
+}
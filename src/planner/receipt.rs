@@ -0,0 +1,131 @@
+/*! The planner of a plan loaded with [`InstallPlan::uninstall_plan_from_receipt`](crate::InstallPlan::uninstall_plan_from_receipt)
+*/
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    action::{Action, StatefulAction},
+    planner::{Planner, PlannerError},
+    settings::{default_store_prefix, default_target_root, InstallSettingsError},
+};
+
+/// Stands in for the planner recorded in a receipt, keeping its name and settings without loading it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Receipt {
+    /// The name of the recorded planner, like `linux`
+    pub(crate) name: String,
+    pub(crate) settings: HashMap<String, serde_json::Value>,
+    /// The recorded planner as it was, put back by [`restore_recorded`](Self::restore_recorded)
+    #[serde(default)]
+    pub(crate) recorded: serde_json::Value,
+}
+
+impl Receipt {
+    /// Keep the name and settings of the recorded `planner`, whatever its shape
+    pub(crate) fn from_recorded(planner: Option<&serde_json::Value>) -> Self {
+        let name = planner
+            .and_then(|planner| planner.get("planner"))
+            .and_then(|name| name.as_str())
+            .unwrap_or("unknown")
+            .to_string();
+        let mut settings = HashMap::new();
+        if let Some(planner) = planner.and_then(|planner| planner.as_object()) {
+            for (key, value) in planner.iter().filter(|(key, _)| *key != "planner") {
+                // Planners nest their common and init settings, `Planner::settings` lists them together
+                match value.as_object() {
+                    Some(nested) => {
+                        settings.extend(nested.iter().map(|(k, v)| (k.clone(), v.clone())))
+                    },
+                    None => {
+                        settings.insert(key.clone(), value.clone());
+                    },
+                }
+            }
+        }
+        Self {
+            name,
+            settings,
+            recorded: planner.cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Put the recorded planner back into `receipt`, a serialized plan with a [`Receipt`] planner
+    ///
+    /// Otherwise a receipt written again, such as after a failed uninstall, would record this
+    /// placeholder instead of the planner which made the install.
+    pub(crate) fn restore_recorded(receipt: &mut serde_json::Value) {
+        let Some(planner) = receipt.get_mut("planner") else {
+            return;
+        };
+        if planner.get("planner").and_then(|name| name.as_str()) != Some("receipt") {
+            return;
+        }
+        if let Some(recorded) = planner
+            .get_mut("recorded")
+            .map(serde_json::Value::take)
+            .filter(|recorded| !recorded.is_null())
+        {
+            *planner = recorded;
+        }
+    }
+
+    fn path_setting(&self, key: &str) -> Option<PathBuf> {
+        self.settings
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "receipt")]
+impl Planner for Receipt {
+    async fn default() -> Result<Self, PlannerError> {
+        Err(PlannerError::UninstallOnly("receipt".to_string()))
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        Err(PlannerError::UninstallOnly(self.name.clone()))
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        Ok(self.settings.clone())
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        Ok(self.settings.clone())
+    }
+
+    fn target_root(&self) -> PathBuf {
+        self.path_setting("target_root")
+            .unwrap_or_else(default_target_root)
+    }
+
+    fn store_prefix(&self) -> PathBuf {
+        self.path_setting("store_prefix")
+            .unwrap_or_else(default_store_prefix)
+    }
+
+    #[cfg(feature = "diagnostics")]
+    async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+        Ok(crate::diagnostics::DiagnosticData::new(
+            self.settings
+                .get("diagnostic_endpoint")
+                .and_then(|value| value.as_str())
+                .map(String::from),
+            self.name.clone(),
+            self.settings.keys().cloned().collect(),
+            self.path_setting("ssl_cert_file"),
+            self.settings
+                .get("user_agent")
+                .and_then(|value| value.as_str())
+                .map(String::from),
+        )?
+        .with_redacted(
+            self.settings
+                .get("diagnostic_redact")
+                .and_then(|value| serde_json::from_value::<Vec<_>>(value.clone()).ok())
+                .unwrap_or_default(),
+        ))
+    }
+}
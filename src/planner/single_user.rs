@@ -0,0 +1,230 @@
+/*! A planner for single-user installs, without a daemon or build users
+
+Nix is installed owned by one unprivileged user, who then uses the store directly. There is no
+`nixbld` group, no build users, and no init service. Only that user can build or install packages.
+*/
+use std::collections::HashMap;
+
+use nix::unistd::{getuid, User};
+
+use crate::{
+    action::{
+        base::{ChangeOwnership, CheckMemory, CreateDirectory, RemoveDirectory, VerifyNixOnPath},
+        common::{ConfigureNix, ProvisionNix},
+        Action, StatefulAction,
+    },
+    planner::{Planner, PlannerError},
+    settings::{CommonSettings, InstallSettingsError, NIX_ROOT, SCRATCH_DIR},
+    BuiltinPlanner,
+};
+
+use super::{
+    linux::{check_nix_not_already_installed, check_not_nixos},
+    ShellProfileLocations,
+};
+
+/// A planner for single-user installs, owned by one user and without a daemon or build users
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct SingleUser {
+    /// The user to own the Nix store, by default the user who invoked `sudo`
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_USER"))]
+    pub user: Option<String>,
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    pub settings: CommonSettings,
+}
+
+impl SingleUser {
+    /// The user to own the Nix store, `user` if set, or else whoever invoked `sudo`
+    fn owner(&self) -> Result<String, PlannerError> {
+        let owner = match &self.user {
+            Some(user) => user.clone(),
+            None => match std::env::var("SUDO_USER") {
+                Ok(sudo_user) if !sudo_user.is_empty() => sudo_user,
+                _ => User::from_uid(getuid())
+                    .ok()
+                    .flatten()
+                    .map(|user| user.name)
+                    .unwrap_or_default(),
+            },
+        };
+        if owner.is_empty() || owner == "root" {
+            return Err(PlannerError::SingleUserRoot);
+        }
+        Ok(owner)
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "single-user")]
+impl Planner for SingleUser {
+    async fn default() -> Result<Self, PlannerError> {
+        Ok(Self {
+            user: None,
+            settings: CommonSettings::default().await?,
+        })
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if self.settings.is_target_root_alternate() {
+            return Err(PlannerError::TargetRootUnsupported("single-user"));
+        }
+        if self.settings.is_store_prefix_alternate() {
+            return Err(PlannerError::StorePrefixUnsupported("single-user"));
+        }
+
+        check_not_nixos(&self.settings.target_root)?;
+        check_nix_not_already_installed().await?;
+        let owner = self.owner()?;
+
+        // An empty `build-users-group` has Nix build as the user running it
+        let mut settings = self.settings.clone();
+        settings.nix_build_group_name = String::new();
+
+        let mut plan = vec![
+            CheckMemory::plan(settings.minimum_memory_mib)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            CreateDirectory::plan_preserving(
+                settings.in_store_prefix(NIX_ROOT),
+                None,
+                None,
+                0o0755,
+                settings.resolved_preserve_paths(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+            ProvisionNix::plan_single_user(&settings)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            ConfigureNix::plan(ShellProfileLocations::default(), &settings)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            RemoveDirectory::plan_guarded(SCRATCH_DIR, false, vec![NIX_ROOT.into()])
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            // Last, so everything the steps above created as `root` is handed over too
+            ChangeOwnership::plan(NIX_ROOT, owner)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+        ];
+
+        if settings.modify_profile && !settings.skip_path_check {
+            plan.push(
+                VerifyNixOnPath::plan(&settings.profile_shells)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
+        Ok(plan)
+    }
+
+    fn runs_daemon(&self) -> bool {
+        false
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        let Self { settings, user } = self;
+        let mut map = HashMap::default();
+
+        map.extend(settings.settings()?);
+        map.insert("user".to_string(), serde_json::to_value(user)?);
+
+        Ok(map)
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        let default = Self::default().await?.settings()?;
+        let configured = self.settings()?;
+
+        let mut settings: HashMap<String, serde_json::Value> = HashMap::new();
+        for (key, value) in configured.iter() {
+            if default.get(key) != Some(value) {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(settings)
+    }
+
+    #[cfg(feature = "diagnostics")]
+    async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+        Ok(crate::diagnostics::DiagnosticData::new(
+            self.settings.diagnostic_endpoint.clone(),
+            self.typetag_name().into(),
+            self.configured_settings()
+                .await?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            self.settings.ssl_cert_file.clone(),
+            self.settings.user_agent.clone(),
+        )?
+        .with_redacted(self.settings.diagnostic_redact.clone()))
+    }
+}
+
+impl From<SingleUser> for BuiltinPlanner {
+    fn from(single_user: SingleUser) -> Self {
+        BuiltinPlanner::SingleUser(single_user)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn plans_provisioning_without_build_users() -> eyre::Result<()> {
+        // Planning reads the host, which needs an unprivileged user and no Nix
+        if User::from_name("nobody")?.is_none() || std::path::Path::new(NIX_ROOT).exists() {
+            return Ok(());
+        }
+        let mut planner = SingleUser::default().await?;
+        planner.user = Some("nobody".to_string());
+        planner.settings.modify_profile = false;
+
+        let actions = planner
+            .plan()
+            .await?
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let tags = actions
+            .iter()
+            .map(|action| action["action"]["action"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                Some("check_memory"),
+                Some("create_directory"),
+                Some("provision_nix"),
+                Some("configure_nix"),
+                Some("remove_directory"),
+                Some("change_ownership"),
+            ]
+        );
+        assert!(actions[2]["action"]["create_group"].is_null());
+        assert!(actions[2]["action"]["delete_users_in_group"].is_null());
+        assert_eq!(actions[5]["action"]["user"], "nobody");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn root_cannot_own_the_store() -> eyre::Result<()> {
+        let mut planner = SingleUser::default().await?;
+        planner.user = Some("root".to_string());
+        assert!(matches!(planner.owner(), Err(PlannerError::SingleUserRoot)));
+        Ok(())
+    }
+}
@@ -59,21 +59,33 @@ Repeated step:
 3. **Do your testing!** You can `ssh deck@localhost -p 2222` in and use `rsync -e 'ssh -p 2222' result/bin/nix-installer deck@localhost:nix-installer` to send a `nix-installer build.
 4. Delete `steamos-hack.qcow2`
 */
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::{
     action::{
-        base::{CreateDirectory, CreateFile, RemoveDirectory},
+        base::{CheckMemory, CreateDirectory, CreateFile, RemoveDirectory, VerifyNixOnPath},
         common::{ConfigureInitService, ConfigureNix, ProvisionNix},
         linux::StartSystemdUnit,
         Action, StatefulAction,
     },
     planner::{Planner, PlannerError},
-    settings::{CommonSettings, InitSystem, InstallSettingsError},
+    settings::{default_daemon_start_timeout, CommonSettings, InitSystem, InstallSettingsError},
     BuiltinPlanner,
 };
 
-use super::ShellProfileLocations;
+use super::{
+    bind_mount::{
+        ensure_symlinked_units_resolve_unit, nix_mount_unit, ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT,
+        NIX_MOUNT_UNIT,
+    },
+    ShellProfileLocations,
+};
+
+const OS_RELEASE: &str = "/etc/os-release";
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "cli", derive(clap::Parser))]
@@ -87,6 +99,18 @@ pub struct SteamDeck {
         )
     )]
     persistence: PathBuf,
+    /// How many seconds to wait for the Nix daemon to serve after starting it, before failing the install
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 30,
+            value_parser = clap::value_parser!(u64).range(1..),
+            env = "NIX_INSTALLER_DAEMON_START_TIMEOUT"
+        )
+    )]
+    #[serde(default = "default_daemon_start_timeout")]
+    daemon_start_timeout: u64,
     #[cfg_attr(feature = "cli", clap(flatten))]
     pub settings: CommonSettings,
 }
@@ -97,11 +121,20 @@ impl Planner for SteamDeck {
     async fn default() -> Result<Self, PlannerError> {
         Ok(Self {
             persistence: PathBuf::from("/home/nix"),
+            daemon_start_timeout: default_daemon_start_timeout(),
             settings: CommonSettings::default().await?,
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if self.settings.is_target_root_alternate() {
+            return Err(PlannerError::TargetRootUnsupported("steam-deck"));
+        }
+        // The persistence directory already takes the place of a store prefix
+        if self.settings.is_store_prefix_alternate() {
+            return Err(PlannerError::StorePrefixUnsupported("steam-deck"));
+        }
+
         let persistence = &self.persistence;
         if !persistence.is_absolute() {
             return Err(PlannerError::Custom(Box::new(
@@ -144,32 +177,17 @@ impl Planner for SteamDeck {
         .await
         .map_err(PlannerError::Action)?;
 
-        let create_bind_mount_buf = format!(
-            "\
-            [Unit]\n\
-            Description=Mount `{persistence}` on `/nix`\n\
-            PropagatesStopTo=nix-daemon.service\n\
-            PropagatesStopTo=nix-directory.service\n\
-            After=nix-directory.service\n\
-            Requires=nix-directory.service\n\
-            ConditionPathIsDirectory=/nix\n\
-            DefaultDependencies=no\n\
-            \n\
-            [Mount]\n\
-            What={persistence}\n\
-            Where=/nix\n\
-            Type=none\n\
-            DirectoryMode=0755\n\
-            Options=bind\n\
-            \n\
-            [Install]\n\
-            RequiredBy=nix-daemon.service\n\
-            RequiredBy=nix-daemon.socket\n
-        ",
-            persistence = persistence.display(),
+        let create_bind_mount_buf = nix_mount_unit(
+            persistence,
+            &[
+                "PropagatesStopTo=nix-directory.service".to_string(),
+                "After=nix-directory.service".to_string(),
+                "Requires=nix-directory.service".to_string(),
+                "ConditionPathIsDirectory=/nix".to_string(),
+            ],
         );
         let create_bind_mount_unit = CreateFile::plan(
-            "/etc/systemd/system/nix.mount",
+            Path::new("/etc/systemd/system").join(NIX_MOUNT_UNIT),
             None,
             None,
             0o0644,
@@ -179,27 +197,10 @@ impl Planner for SteamDeck {
         .await
         .map_err(PlannerError::Action)?;
 
-        let ensure_symlinked_units_resolve_buf = format!(
-            "\
-            [Unit]\n\
-            Description=Ensure Nix related units which are symlinked resolve\n\
-            After=nix.mount\n\
-            Requires=nix-directory.service\n\
-            Requires=nix.mount\n\
-            DefaultDependencies=no\n\
-            \n\
-            [Service]\n\
-            Type=oneshot\n\
-            RemainAfterExit=yes\n\
-            ExecStart=/usr/bin/systemctl daemon-reload\n\
-            ExecStart=/usr/bin/systemctl restart --no-block nix-daemon.socket\n\
-            \n\
-            [Install]\n\
-            WantedBy=sysinit.target\n\
-        "
-        );
+        let ensure_symlinked_units_resolve_buf =
+            ensure_symlinked_units_resolve_unit(&["Requires=nix-directory.service".to_string()]);
         let ensure_symlinked_units_resolve_unit = CreateFile::plan(
-            "/etc/systemd/system/ensure-symlinked-units-resolve.service",
+            Path::new("/etc/systemd/system").join(ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT),
             None,
             None,
             0o0644,
@@ -223,15 +224,33 @@ impl Planner for SteamDeck {
                 .remove(index);
         }
 
-        Ok(vec![
-            CreateDirectory::plan(&persistence, None, None, 0o0755, true)
+        let mut plan = vec![
+            CheckMemory::plan(self.settings.minimum_memory_mib)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            CreateDirectory::plan_preserving(
+                &persistence,
+                None,
+                None,
+                0o0755,
+                // `/nix` is a bind mount of the persistence directory
+                self.settings
+                    .preserve_paths
+                    .iter()
+                    .map(|path| match path.strip_prefix("/nix") {
+                        Ok(relative) => persistence.join(relative),
+                        Err(_) => path.clone(),
+                    })
+                    .collect(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
             nix_directory_unit.boxed(),
             create_bind_mount_unit.boxed(),
             ensure_symlinked_units_resolve_unit.boxed(),
-            StartSystemdUnit::plan("nix.mount".to_string(), false)
+            StartSystemdUnit::plan(NIX_MOUNT_UNIT.to_string(), false)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
@@ -248,25 +267,43 @@ impl Planner for SteamDeck {
                 InitSystem::Systemd,
                 true,
                 self.settings.ssl_cert_file.clone(),
+                self.settings.target_root.clone(),
             )
             .await
             .map_err(PlannerError::Action)?
+            .with_daemon_ready_timeout(Duration::from_secs(self.daemon_start_timeout))
             .boxed(),
-            StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
+            StartSystemdUnit::plan(ENSURE_SYMLINKED_UNITS_RESOLVE_UNIT.to_string(), true)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
-        ])
+            RemoveDirectory::plan_guarded(
+                crate::settings::SCRATCH_DIR,
+                false,
+                vec![crate::settings::NIX_ROOT.into()],
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+        ];
+
+        if self.settings.modify_profile && !self.settings.skip_path_check {
+            plan.push(
+                VerifyNixOnPath::plan(&self.settings.profile_shells)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
+        Ok(plan)
     }
 
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
         let Self {
             settings,
             persistence,
+            daemon_start_timeout,
         } = self;
         let mut map = HashMap::default();
 
@@ -275,6 +312,10 @@ impl Planner for SteamDeck {
             "persistence".to_string(),
             serde_json::to_value(persistence)?,
         );
+        map.insert(
+            "daemon_start_timeout".to_string(),
+            serde_json::to_value(daemon_start_timeout)?,
+        );
 
         Ok(map)
     }
@@ -295,6 +336,10 @@ impl Planner for SteamDeck {
         Ok(settings)
     }
 
+    async fn pre_uninstall(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        super::linux::stop_nix_daemon_units().await
+    }
+
     #[cfg(feature = "diagnostics")]
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
         Ok(crate::diagnostics::DiagnosticData::new(
@@ -305,7 +350,9 @@ impl Planner for SteamDeck {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
-        )?)
+            self.settings.user_agent.clone(),
+        )?
+        .with_redacted(self.settings.diagnostic_redact.clone()))
     }
 }
 
@@ -315,9 +362,42 @@ impl Into<BuiltinPlanner> for SteamDeck {
     }
 }
 
+/// If this is SteamOS, from the `ID` in `/etc/os-release`
+pub(crate) async fn detect() -> bool {
+    match tokio::fs::read_to_string(OS_RELEASE).await {
+        Ok(os_release) => is_steamos(&os_release),
+        Err(_) => false,
+    }
+}
+
+/// If an `os-release` file such as `ID=steamos` describes SteamOS
+pub(crate) fn is_steamos(os_release: &str) -> bool {
+    os_release
+        .lines()
+        .any(|line| match line.trim().split_once('=') {
+            Some(("ID", id)) => id.trim_matches(|c| c == '"' || c == '\'') == "steamos",
+            _ => false,
+        })
+}
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug)]
 pub enum SteamDeckError {
     #[error("`{0}` is not a path that can be canonicalized into an absolute path, bind mounts require an absolute path")]
     AbsolutePathRequired(PathBuf),
 }
+
+#[cfg(test)]
+mod test {
+    use super::is_steamos;
+
+    #[test]
+    fn steamos_from_os_release() {
+        assert!(is_steamos(
+            "NAME=\"SteamOS\"\nPRETTY_NAME=\"SteamOS\"\nID=steamos\nID_LIKE=arch\n"
+        ));
+        assert!(is_steamos("ID=\"steamos\"\n"));
+        assert!(!is_steamos("NAME=\"Arch Linux\"\nID=arch\n"));
+        assert!(!is_steamos("ID_LIKE=steamos\n"));
+    }
+}
@@ -0,0 +1,214 @@
+/*! A planner for WSL2 without systemd
+
+WSL2 only runs systemd when `systemd=true` is set in the `[boot]` section of `/etc/wsl.conf`, and
+the [`Linux`](super::linux::Linux) planner is preferred when it is. Otherwise, this planner has the
+`[boot]` command of `/etc/wsl.conf` start the Nix daemon whenever the distribution starts.
+*/
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    action::{
+        base::{CheckMemory, CreateDirectory, RemoveDirectory, VerifyNixOnPath},
+        common::{ConfigureNix, ProvisionNix},
+        linux::ConfigureWslDaemon,
+        Action, StatefulAction,
+    },
+    planner::{Planner, PlannerError},
+    settings::{CommonSettings, InstallSettingsError, NIX_ROOT, SCRATCH_DIR},
+    BuiltinPlanner,
+};
+
+use super::{
+    linux::{check_nix_not_already_installed, check_not_nixos},
+    ShellProfileLocations,
+};
+
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+
+/// A planner for WSL2 without systemd, starting the Nix daemon from the WSL boot command
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct Wsl {
+    /// Start the daemon now, instead of the next time WSL starts
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            value_parser,
+            long,
+            action(clap::ArgAction::SetFalse),
+            env = "NIX_INSTALLER_START_DAEMON",
+            default_value_t = true,
+            long = "no-start-daemon"
+        )
+    )]
+    pub start_daemon: bool,
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    pub settings: CommonSettings,
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "wsl")]
+impl Planner for Wsl {
+    async fn default() -> Result<Self, PlannerError> {
+        Ok(Self {
+            start_daemon: true,
+            settings: CommonSettings::default().await?,
+        })
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if self.settings.is_target_root_alternate() {
+            return Err(PlannerError::TargetRootUnsupported("wsl"));
+        }
+        // Without systemd, nothing would bind mount the store prefix on `/nix`
+        if self.settings.is_store_prefix_alternate() {
+            return Err(PlannerError::StorePrefixUnsupported("wsl"));
+        }
+
+        check_not_nixos(&self.settings.target_root)?;
+        check_nix_not_already_installed().await?;
+        if detect().await == Some(1) {
+            return Err(PlannerError::Wsl1);
+        }
+
+        let mut plan = vec![
+            CheckMemory::plan(self.settings.minimum_memory_mib)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            CreateDirectory::plan_preserving(
+                self.settings.in_store_prefix(NIX_ROOT),
+                None,
+                None,
+                0o0755,
+                self.settings.resolved_preserve_paths(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+            ProvisionNix::plan(&self.settings.clone())
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            ConfigureWslDaemon::plan(self.settings.ssl_cert_file.clone(), self.start_daemon)
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+            RemoveDirectory::plan_guarded(SCRATCH_DIR, false, vec![NIX_ROOT.into()])
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed(),
+        ];
+
+        if self.settings.modify_profile && !self.settings.skip_path_check {
+            plan.push(
+                VerifyNixOnPath::plan(&self.settings.profile_shells)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
+        Ok(plan)
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        let Self {
+            settings,
+            start_daemon,
+        } = self;
+        let mut map = HashMap::default();
+
+        map.extend(settings.settings()?);
+        map.insert(
+            "start_daemon".to_string(),
+            serde_json::to_value(start_daemon)?,
+        );
+
+        Ok(map)
+    }
+
+    fn requires_reboot_before_use(&self) -> bool {
+        // The boot command only runs the next time WSL starts
+        !self.start_daemon
+    }
+
+    fn runs_daemon(&self) -> bool {
+        self.start_daemon
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        let default = Self::default().await?.settings()?;
+        let configured = self.settings()?;
+
+        let mut settings: HashMap<String, serde_json::Value> = HashMap::new();
+        for (key, value) in configured.iter() {
+            if default.get(key) != Some(value) {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(settings)
+    }
+
+    #[cfg(feature = "diagnostics")]
+    async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+        Ok(crate::diagnostics::DiagnosticData::new(
+            self.settings.diagnostic_endpoint.clone(),
+            self.typetag_name().into(),
+            self.configured_settings()
+                .await?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            self.settings.ssl_cert_file.clone(),
+            self.settings.user_agent.clone(),
+        )?
+        .with_redacted(self.settings.diagnostic_redact.clone()))
+    }
+}
+
+impl From<Wsl> for BuiltinPlanner {
+    fn from(wsl: Wsl) -> Self {
+        BuiltinPlanner::Wsl(wsl)
+    }
+}
+
+/// The version of WSL running this kernel, if any, from `/proc/sys/kernel/osrelease`
+pub(crate) async fn detect() -> Option<u8> {
+    let osrelease = tokio::fs::read_to_string(PathBuf::from(OSRELEASE))
+        .await
+        .ok()?;
+    wsl_version(&osrelease)
+}
+
+/// The version of WSL from a kernel release such as `5.15.90.1-microsoft-standard-WSL2`
+pub(crate) fn wsl_version(osrelease: &str) -> Option<u8> {
+    // Detection strategies: https://patrickwu.space/wslconf/
+    let osrelease = osrelease.trim().to_lowercase();
+    if !osrelease.contains("microsoft") {
+        None
+    } else if osrelease.contains("wsl2") || osrelease.contains("microsoft-standard") {
+        Some(2)
+    } else {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::wsl_version;
+
+    #[test]
+    fn wsl_version_from_osrelease() {
+        assert_eq!(wsl_version("5.15.90.1-microsoft-standard-WSL2\n"), Some(2));
+        assert_eq!(wsl_version("4.19.104-microsoft-standard"), Some(2));
+        assert_eq!(wsl_version("4.4.0-19041-Microsoft\n"), Some(1));
+        assert_eq!(wsl_version("6.5.0-14-generic"), None);
+    }
+}
@@ -0,0 +1,221 @@
+/*! Progress of an install as newline-delimited JSON
+
+[`InstallPlan::install_with_json_progress`] writes one [`ProgressRecord`] per line as actions
+start, complete, or fail, followed by a final record summarizing the install, for piping into `jq`
+or a wrapper:
+
+```json
+{"ts":1685620800.123,"action":"Create directory `/nix`","state":"started","index":0,"total":12}
+{"ts":1685620800.125,"action":"Create directory `/nix`","state":"completed","index":0,"total":12}
+{"ts":1685620842.501,"state":"plan_completed","total":12,"completed":12,"duration_ms":42378}
+```
+*/
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::broadcast::{error::RecvError, Receiver},
+};
+
+use crate::{action::ActionState, InstallEvent, InstallPlan, NixInstallerError};
+
+/// What a [`ProgressRecord`] reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressState {
+    /// An action started executing
+    Started,
+    /// An action finished executing
+    Completed,
+    /// An action failed
+    Failed,
+    /// The install finished, this is the last record
+    PlanCompleted,
+    /// The install failed, this is the last record
+    PlanFailed,
+}
+
+/// One line written by [`InstallPlan::install_with_json_progress`]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProgressRecord {
+    /// Seconds since the Unix epoch
+    pub ts: f64,
+    /// The synopsis of the action, unset in the final record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    pub state: ProgressState,
+    /// The position of the action in the plan, unset in the final record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    /// The number of actions in the plan
+    pub total: usize,
+    /// How many actions of the plan have completed, only set in the final record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<usize>,
+    /// How long the install took, only set in the final record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The [`NixInstallerError::code`] of a failed install, only set in the final record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+impl ProgressRecord {
+    /// The record of a change in the state of an action, if `event` is one
+    fn from_event(event: InstallEvent, total: usize) -> Option<Self> {
+        let (index, action, state, error) = match event {
+            InstallEvent::ActionStarted {
+                index, synopsis, ..
+            } => (index, synopsis, ProgressState::Started, None),
+            InstallEvent::ActionCompleted { index, synopsis } => {
+                (index, synopsis, ProgressState::Completed, None)
+            },
+            InstallEvent::ActionFailed {
+                index,
+                synopsis,
+                error,
+            } => (index, synopsis, ProgressState::Failed, Some(error)),
+            InstallEvent::PlanCompleted
+            | InstallEvent::DownloadProgress { .. }
+            | InstallEvent::RemovalProgress { .. } => return None,
+        };
+        Some(Self {
+            ts: timestamp(),
+            action: Some(action),
+            state,
+            index: Some(index),
+            total,
+            completed: None,
+            duration_ms: None,
+            error,
+            code: None,
+        })
+    }
+}
+
+impl InstallPlan {
+    /// Run [`install`](Self::install), writing its progress to `writer` as newline-delimited JSON
+    ///
+    /// Each action starting, completing, or failing is written as a [`ProgressRecord`], and a last
+    /// record summarizes the install once it completes or fails. If `writer` fails, the install
+    /// carries on without it.
+    pub async fn install_with_json_progress(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        mut writer: impl AsyncWrite + Unpin + Send,
+    ) -> Result<(), NixInstallerError> {
+        let total = self.actions.len();
+        let start = Instant::now();
+        let (event_channel, mut events) = tokio::sync::broadcast::channel(64);
+
+        let install = self.install(cancel_channel, event_channel);
+        let forward = async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let Some(record) = ProgressRecord::from_event(event, total) else {
+                            continue;
+                        };
+                        if let Err(err) = write_record(&mut writer, &record).await {
+                            tracing::warn!(
+                                "Could not write JSON progress, continuing without it: {err}"
+                            );
+                            return None;
+                        }
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::debug!("Skipped {skipped} progress events");
+                    },
+                    // The install finished and dropped its sender
+                    Err(RecvError::Closed) => return Some(writer),
+                }
+            }
+        };
+        let (result, writer) = tokio::join!(install, forward);
+
+        if let Some(mut writer) = writer {
+            let summary = ProgressRecord {
+                ts: timestamp(),
+                action: None,
+                state: match &result {
+                    Ok(()) => ProgressState::PlanCompleted,
+                    Err(_) => ProgressState::PlanFailed,
+                },
+                index: None,
+                total,
+                completed: Some(
+                    self.actions
+                        .iter()
+                        .filter(|action| action.state == ActionState::Completed)
+                        .count(),
+                ),
+                duration_ms: Some(start.elapsed().as_millis().try_into().unwrap_or(u64::MAX)),
+                error: result.as_ref().err().map(ToString::to_string),
+                code: result.as_ref().err().map(NixInstallerError::code),
+            };
+            if let Err(err) = write_record(&mut writer, &summary).await {
+                tracing::warn!("Could not write JSON progress: {err}");
+            }
+        }
+
+        result
+    }
+}
+
+async fn write_record(
+    writer: &mut (impl AsyncWrite + Unpin),
+    record: &ProgressRecord,
+) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_state_transitions_only() {
+        let started = ProgressRecord::from_event(
+            InstallEvent::ActionStarted {
+                index: 2,
+                synopsis: "Create directory `/nix`".into(),
+                weight: 1,
+                completed_weight: 2,
+                total_weight: 5,
+            },
+            5,
+        )
+        .expect("a started action is recorded");
+        assert_eq!(started.state, ProgressState::Started);
+        assert_eq!(started.index, Some(2));
+        assert_eq!(started.total, 5);
+
+        let json = serde_json::to_value(&started).unwrap();
+        assert_eq!(json["action"], "Create directory `/nix`");
+        assert_eq!(json["state"], "started");
+        assert!(json.get("error").is_none());
+
+        assert!(ProgressRecord::from_event(
+            InstallEvent::RemovalProgress {
+                path: "/nix".into(),
+                files: 1,
+                bytes: 1,
+            },
+            5
+        )
+        .is_none());
+    }
+}
@@ -1,13 +1,25 @@
 /*! Configurable knobs and their related errors
 */
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroU64},
+    path::{Path, PathBuf},
+};
 
 #[cfg(feature = "cli")]
 use clap::ArgAction;
 use url::Url;
 
+use crate::action::base::{FetchOptions, NixSignature};
+
 pub const SCRATCH_DIR: &str = "/nix/temp-install-dir";
 
+/// The root directory of the host system, the default [`CommonSettings::target_root`]
+pub const HOST_ROOT: &str = "/";
+
+/// Where Nix expects its store and state, the default [`CommonSettings::store_prefix`]
+pub const NIX_ROOT: &str = "/nix";
+
 /// Default [`nix_package_url`](CommonSettings::nix_package_url) for Linux x86_64
 pub const NIX_X64_64_LINUX_URL: &str =
     "https://releases.nixos.org/nix/nix-2.15.0/nix-2.15.0-x86_64-linux.tar.xz";
@@ -24,6 +36,116 @@ pub const NIX_X64_64_DARWIN_URL: &str =
 pub const NIX_AARCH64_DARWIN_URL: &str =
     "https://releases.nixos.org/nix/nix-2.15.0/nix-2.15.0-aarch64-darwin.tar.xz";
 
+/// A Nix release tarball a [`nix_version`](CommonSettings::nix_version) can pin, the default [`nix_package_url`](CommonSettings::nix_package_url)s among them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NixRelease {
+    pub version: &'static str,
+    /// The Nix system the tarball is for, like `x86_64-linux`
+    pub system: &'static str,
+    /// The SHA-256 of the tarball in the `sha256-<base64>` form Nix uses, as published next to it on `releases.nixos.org`, `None` until it is recorded
+    pub hash: Option<&'static str>,
+}
+
+impl NixRelease {
+    /// Where the tarball is published
+    pub fn url(&self) -> String {
+        format!(
+            "https://releases.nixos.org/nix/nix-{version}/nix-{version}-{system}.tar.xz",
+            version = self.version,
+            system = self.system
+        )
+    }
+
+    /// The release whose tarball is published at `url`
+    pub fn at(url: &Url) -> Option<&'static NixRelease> {
+        NIX_RELEASES
+            .iter()
+            .find(|release| release.url() == url.as_str())
+    }
+}
+
+/// The Nix releases the installer knows, and the hashes their tarballs are verified against
+pub const NIX_RELEASES: &[NixRelease] = &[
+    NixRelease {
+        version: "2.13.3",
+        system: "x86_64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.13.3",
+        system: "i686-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.13.3",
+        system: "aarch64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.13.3",
+        system: "x86_64-darwin",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.13.3",
+        system: "aarch64-darwin",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.14.1",
+        system: "x86_64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.14.1",
+        system: "i686-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.14.1",
+        system: "aarch64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.14.1",
+        system: "x86_64-darwin",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.14.1",
+        system: "aarch64-darwin",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.15.0",
+        system: "x86_64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.15.0",
+        system: "i686-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.15.0",
+        system: "aarch64-linux",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.15.0",
+        system: "x86_64-darwin",
+        hash: None,
+    },
+    NixRelease {
+        version: "2.15.0",
+        system: "aarch64-darwin",
+        hash: None,
+    },
+];
+
+/// Default [`user_agent`](CommonSettings::user_agent) of HTTP requests
+pub const DEFAULT_USER_AGENT: &str = concat!("nix-installer/", env!("CARGO_PKG_VERSION"));
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum InitSystem {
@@ -31,6 +153,9 @@ pub enum InitSystem {
     None,
     #[cfg(target_os = "linux")]
     Systemd,
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "cli", value(name = "openrc"))]
+    OpenRc,
     #[cfg(target_os = "macos")]
     Launchd,
 }
@@ -42,12 +167,280 @@ impl std::fmt::Display for InitSystem {
             InitSystem::None => write!(f, "none"),
             #[cfg(target_os = "linux")]
             InitSystem::Systemd => write!(f, "systemd"),
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRc => write!(f, "openrc"),
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => write!(f, "launchd"),
         }
     }
 }
 
+/// A shell whose configuration the installer can edit
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Nushell,
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shell::Bash => write!(f, "bash"),
+            Shell::Zsh => write!(f, "zsh"),
+            Shell::Fish => write!(f, "fish"),
+            Shell::Nushell => write!(f, "nushell"),
+        }
+    }
+}
+
+/// How many builds Nix runs at once (`max-jobs` in `/etc/nix/nix.conf`), at least one or `auto` for one per CPU
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum MaxJobs {
+    Auto,
+    Jobs(NonZeroU32),
+}
+
+impl std::str::FromStr for MaxJobs {
+    type Err = InstallSettingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MaxJobs::Auto),
+            _ => s
+                .parse::<NonZeroU32>()
+                .map(MaxJobs::Jobs)
+                .map_err(|_| InstallSettingsError::InvalidMaxJobs(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for MaxJobs {
+    type Error = InstallSettingsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MaxJobs> for String {
+    fn from(value: MaxJobs) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for MaxJobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MaxJobs::Auto => write!(f, "auto"),
+            MaxJobs::Jobs(jobs) => write!(f, "{jobs}"),
+        }
+    }
+}
+
+/// A download rate, given in bytes per second with an optional `K`, `M` or `G` suffix (powers of 1024), like `512K` or `2M`
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BytesPerSec(pub NonZeroU64);
+
+impl BytesPerSec {
+    const UNITS: [(char, u64); 3] = [('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+    pub fn get(self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl std::str::FromStr for BytesPerSec {
+    type Err = InstallSettingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some(suffix) => match Self::UNITS.iter().find(|(unit, _)| *unit == suffix) {
+                Some((_, multiplier)) => (&s[..s.len() - 1], *multiplier),
+                None => (s, 1),
+            },
+            None => (s, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .and_then(NonZeroU64::new)
+            .map(BytesPerSec)
+            .ok_or_else(|| InstallSettingsError::InvalidBytesPerSec(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for BytesPerSec {
+    type Error = InstallSettingsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BytesPerSec> for String {
+    fn from(value: BytesPerSec) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for BytesPerSec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.get();
+        match Self::UNITS
+            .iter()
+            .find(|(_, multiplier)| bytes % multiplier == 0)
+        {
+            Some((unit, multiplier)) => write!(f, "{}{unit}", bytes / multiplier),
+            None => write!(f, "{bytes}"),
+        }
+    }
+}
+
+/// The mode of a directory of the `/nix` tree, given as `<path>=<octal mode>`, like `/nix/var/nix/profiles/per-user=0750`
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct NixTreeMode {
+    pub path: PathBuf,
+    pub mode: u32,
+}
+
+impl std::str::FromStr for NixTreeMode {
+    type Err = InstallSettingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, mode) = s
+            .rsplit_once('=')
+            .ok_or_else(|| InstallSettingsError::InvalidNixTreeMode(s.to_string()))?;
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| InstallSettingsError::InvalidNixTreeMode(s.to_string()))?;
+        Ok(NixTreeMode {
+            path: PathBuf::from(path),
+            mode,
+        })
+    }
+}
+
+impl TryFrom<String> for NixTreeMode {
+    type Error = InstallSettingsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<NixTreeMode> for String {
+    fn from(value: NixTreeMode) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for NixTreeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={:04o}", self.path.display(), self.mode)
+    }
+}
+
+/// A binary cache and the public key its store paths are signed with, given as `<url> <name>:<base64 key>`
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BinaryCache {
+    pub url: String,
+    pub public_key: String,
+}
+
+impl std::str::FromStr for BinaryCache {
+    type Err = InstallSettingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, public_key) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| InstallSettingsError::InvalidBinaryCache(s.to_string()))?;
+        let public_key = public_key.trim();
+        if public_key.contains(char::is_whitespace) {
+            return Err(InstallSettingsError::InvalidBinaryCache(s.to_string()));
+        }
+        Ok(BinaryCache {
+            url: url.to_string(),
+            public_key: public_key.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for BinaryCache {
+    type Error = InstallSettingsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BinaryCache> for String {
+    fn from(value: BinaryCache) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for BinaryCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.url, self.public_key)
+    }
+}
+
+/// Whether Nix builds in a sandbox (`sandbox` in `/etc/nix/nix.conf`)
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SandboxMode {
+    True,
+    False,
+    /// Sandbox builds, except derivations with `__noChroot = true`
+    Relaxed,
+}
+
+impl std::fmt::Display for SandboxMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SandboxMode::True => write!(f, "true"),
+            SandboxMode::False => write!(f, "false"),
+            SandboxMode::Relaxed => write!(f, "relaxed"),
+        }
+    }
+}
+
+/// How the unpacked Nix store paths are moved into the Nix store
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum CopyStrategy {
+    /// Rename them on the same filesystem, otherwise reflink each file where possible and copy the rest
+    #[default]
+    Auto,
+    /// Always copy every byte
+    Copy,
+    /// Always reflink each file, failing if the filesystem cannot
+    Reflink,
+}
+
+impl std::fmt::Display for CopyStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyStrategy::Auto => write!(f, "auto"),
+            CopyStrategy::Copy => write!(f, "copy"),
+            CopyStrategy::Reflink => write!(f, "reflink"),
+        }
+    }
+}
+
 /** Common settings used by all [`BuiltinPlanner`](crate::planner::BuiltinPlanner)s
 
 Settings which only apply to certain [`Planner`](crate::planner::Planner)s should be located in the planner.
@@ -131,18 +524,294 @@ pub struct CommonSettings {
     )]
     pub nix_package_url: Url,
 
+    /// The release of Nix to install, such as `2.15.0`, instead of the one at `nix_package_url`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            env = "NIX_INSTALLER_NIX_VERSION",
+            conflicts_with = "nix_package_url",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub nix_version: Option<semver::Version>,
+
+    /// A local Nix package tarball to install from instead of downloading `nix_package_url`, for machines without network access
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_PATH", global = true)
+    )]
+    #[serde(default)]
+    pub nix_package_path: Option<PathBuf>,
+
+    /// The expected SHA-256 of the Nix package tarball, in the `sha256-<base64>` form Nix uses, verified before it is unpacked
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_HASH", global = true)
+    )]
+    #[serde(default)]
+    pub nix_package_hash: Option<String>,
+
+    /// A trusted minisign public key, in the base64 form of the last line of a `minisign.pub`, the Nix package tarball must be signed by before it is unpacked
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_TRUSTED_PUBKEY", global = true)
+    )]
+    #[serde(default)]
+    pub nix_package_trusted_pubkey: Option<String>,
+
+    /// Where to fetch the minisign signature of the Nix package tarball, by default the package URL with `.minisig` appended
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            env = "NIX_INSTALLER_NIX_PACKAGE_SIGNATURE_URL",
+            requires = "nix_package_trusted_pubkey",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub nix_package_signature_url: Option<Url>,
+
+    /// The number of times to retry fetching the Nix package after a network or server error
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 3,
+            env = "NIX_INSTALLER_MAX_RETRIES",
+            global = true
+        )
+    )]
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// The most ranges of the Nix package fetched at once, if its server accepts range requests (`1` fetches it in a single stream)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u32).range(1..),
+            env = "NIX_INSTALLER_DOWNLOAD_PARALLELISM",
+            global = true
+        )
+    )]
+    #[serde(default = "default_download_parallelism")]
+    pub download_parallelism: u32,
+
+    /// The download speed in megabits per second assumed when estimating how long the install takes
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 50,
+            value_parser = clap::value_parser!(u32).range(1..),
+            env = "NIX_INSTALLER_ASSUMED_BANDWIDTH_MBPS",
+            global = true
+        )
+    )]
+    #[serde(default = "default_assumed_bandwidth_mbps")]
+    pub assumed_bandwidth_mbps: u32,
+
+    /// The most bytes per second to download the Nix package at, shared by all ranges fetched at once, like `512K` or `2M` (unlimited by default)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DOWNLOAD_RATE_LIMIT", global = true)
+    )]
+    #[serde(default)]
+    pub download_rate_limit: Option<BytesPerSec>,
+
+    /// How the unpacked Nix is moved into the Nix store, `auto` renames or reflinks when the filesystem allows it and copies otherwise
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_enum,
+            default_value_t = CopyStrategy::Auto,
+            env = "NIX_INSTALLER_COPY_STRATEGY",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub copy_strategy: CopyStrategy,
+
     /// The proxy to use (if any), valid proxy bases are `https://$URL`, `http://$URL` and `socks5://$URL`
+    ///
+    /// If unset, `HTTPS_PROXY` and `HTTP_PROXY` are used. Hosts in `NO_PROXY` are always fetched directly.
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_PROXY"))]
     pub proxy: Option<Url>,
 
+    /// The `User-Agent` of HTTP requests, such as fetching Nix, for proxies and mirrors which filter or log by it (default `nix-installer/<version>`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_USER_AGENT", global = true)
+    )]
+    #[serde(default)]
+    pub user_agent: Option<String>,
+
     /// An SSL cert to use (if any), used for fetching Nix and sets `NIX_SSL_CERT_FILE` for Nix
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_SSL_CERT_FILE"))]
     pub ssl_cert_file: Option<PathBuf>,
 
-    /// Extra configuration lines for `/etc/nix.conf`
+    /// Paths under `/nix` (or the store prefix) to keep when the Nix store is removed on uninstall, such as store paths still in use
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_PRESERVE_PATHS", global = true))]
+    #[serde(default)]
+    pub preserve_paths: Vec<PathBuf>,
+
+    /// Binary caches to use besides `cache.nixos.org`, such as an internal cache (added to `extra-substituters` in `/etc/nix/nix.conf`)
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_EXTRA_SUBSTITUTERS", global = true))]
+    #[serde(default)]
+    pub extra_substituters: Vec<String>,
+
+    /// Public keys to trust signatures of, in the form `<name>:<base64 key>` (added to `extra-trusted-public-keys` in `/etc/nix/nix.conf`)
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_TRUSTED_PUBLIC_KEYS", global = true))]
+    #[serde(default)]
+    pub trusted_public_keys: Vec<String>,
+
+    /// Binary caches to add together with the public key their store paths are signed with, as `'<url> <name>:<base64 key>'` (added to `extra-substituters` and `extra-trusted-public-keys` in `/etc/nix/nix.conf`)
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_BINARY_CACHES", global = true))]
+    #[serde(default)]
+    pub binary_caches: Vec<BinaryCache>,
+
+    /// Extra `key = value` lines for `/etc/nix/nix.conf`, for settings without an option of their own (values of repeated keys are merged)
     #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_EXTRA_CONF", global = true))]
+    #[serde(default)]
     pub extra_conf: Vec<String>,
 
+    /// The number of times Nix should attempt a download before giving up (`download-attempts` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DOWNLOAD_ATTEMPTS", global = true)
+    )]
+    pub download_attempts: Option<NonZeroU32>,
+
+    /// The maximum number of parallel TCP connections Nix should use (`http-connections` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_HTTP_CONNECTIONS", global = true)
+    )]
+    pub http_connections: Option<NonZeroU32>,
+
+    /// The number of builds Nix runs at once, at least 1 or `auto` for one per CPU (`max-jobs` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_MAX_JOBS", global = true)
+    )]
+    #[serde(default)]
+    pub max_jobs: Option<MaxJobs>,
+
+    /// The number of cores each build may use, 0 for all of them (`cores` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_CORES", global = true)
+    )]
+    #[serde(default)]
+    pub cores: Option<u32>,
+
+    /// Whether Nix builds in a sandbox (`sandbox` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_enum, env = "NIX_INSTALLER_SANDBOX", global = true)
+    )]
+    #[serde(default)]
+    pub sandbox: Option<SandboxMode>,
+
+    /// The number of UIDs Nix may allocate to builds (`id-count` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_BUILD_USER_COUNT", global = true)
+    )]
+    pub nix_build_user_count: Option<NonZeroU32>,
+
+    /// The first UID Nix allocates to builds (`start-id` in `/etc/nix/nix.conf`), for hosts where the default range collides with directory services like LDAP
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_BUILD_USER_ID_BASE", global = true)
+    )]
+    pub nix_build_user_id_base: Option<u32>,
+
+    /// Enable the `nix-command` and `flakes` experimental features, merged with any `experimental-features` set by `extra-conf`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            action(ArgAction::SetFalse),
+            default_value = "true",
+            global = true,
+            env = "NIX_INSTALLER_ENABLE_FLAKES",
+            long = "no-enable-flakes"
+        )
+    )]
+    #[serde(default = "default_enable_flakes")]
+    pub enable_flakes: bool,
+
+    /// Enable the `nix-command` and `flakes` experimental features through `NIX_CONFIG` in the shell profiles, instead of globally in `/etc/nix/nix.conf`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_EXPERIMENTAL_FEATURES_IN_PROFILE"
+        )
+    )]
+    #[serde(default)]
+    pub experimental_features_in_profile: bool,
+
+    /// Set `use-xdg-base-directories` in `/etc/nix/nix.conf`, so Nix stores user state such as profiles under `$XDG_STATE_HOME` instead of `~/.nix-profile` and `~/.nix-defexpr` (requires Nix 2.14 or later)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_USE_XDG_BASE_DIRECTORIES"
+        )
+    )]
+    #[serde(default)]
+    pub use_xdg_base_directories: bool,
+
+    /// The shells whose profiles are modified to load Nix, all detected shells if unset
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, value_delimiter = ',', env = "NIX_INSTALLER_PROFILE_SHELLS", global = true))]
+    #[serde(default)]
+    pub profile_shells: Vec<Shell>,
+
+    /// Hook direnv into the shell profiles, so `.envrc` files (such as `use flake`) load once direnv is installed
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_DIRENV"
+        )
+    )]
+    #[serde(default)]
+    pub direnv: bool,
+
+    /// The shells to hook direnv into with `--direnv`, all supported shells if unset
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, value_delimiter = ',', env = "NIX_INSTALLER_DIRENV_SHELLS", global = true))]
+    #[serde(default)]
+    pub direnv_shells: Vec<Shell>,
+
+    /// Users, or groups as `@<group>`, the Nix daemon should trust to use any substituter and set restricted options (`trusted-users` in `/etc/nix/nix.conf`)
+    ///
+    /// By default `root` and the user who invoked `nix-installer`, pass `--trusted-users` without a value to leave `trusted-users` unset
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., value_delimiter = ',', default_values_t = default_trusted_users(), env = "NIX_INSTALLER_TRUSTED_USERS", global = true))]
+    #[serde(default = "default_trusted_users")]
+    pub trusted_users: Vec<String>,
+
+    /// A group whose members should be trusted users of the Nix daemon (added as `@<group>` to `trusted-users` in `/etc/nix/nix.conf`)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_ADMIN_GROUP", global = true)
+    )]
+    pub admin_group: Option<String>,
+
     /// If `nix-installer` should forcibly recreate files it finds existing
     #[cfg_attr(
         feature = "cli",
@@ -156,6 +825,113 @@ pub struct CommonSettings {
     )]
     pub force: bool,
 
+    /// Install over an existing Nix store, such as one from a distribution package of Nix, instead of refusing to
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_INSTALL_OVER_EXISTING_STORE"
+        )
+    )]
+    #[serde(default)]
+    pub install_over_existing_store: bool,
+
+    /// Remove temporary roots and store locks left behind by dead processes before starting the daemon
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_CLEANUP_STALE_TEMP_ROOTS"
+        )
+    )]
+    #[serde(default)]
+    pub cleanup_stale_temp_roots: bool,
+
+    /// Skip checking the system clock is plausibly correct before fetching Nix over TLS
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_SKIP_CLOCK_CHECK"
+        )
+    )]
+    #[serde(default)]
+    pub skip_clock_check: bool,
+
+    /// Skip checking `nix` is on the `PATH` of a fresh login shell once installed, which otherwise fails the install, for headless environments without a usable shell
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_SKIP_PATH_CHECK"
+        )
+    )]
+    #[serde(default)]
+    pub skip_path_check: bool,
+
+    /// Fail if less than this much memory (in MiB) is available, otherwise only a warning is shown below 1024 MiB
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_MINIMUM_MEMORY_MIB", global = true)
+    )]
+    #[serde(default)]
+    pub minimum_memory_mib: Option<u64>,
+
+    /// Fail the install if any single action takes longer than this many seconds to execute, instead of waiting forever
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_ACTION_TIMEOUT", global = true)
+    )]
+    #[serde(default)]
+    pub action_timeout: Option<u64>,
+
+    /// A directory to install Nix into, instead of the running system, such as a mounted image or chroot
+    ///
+    /// All paths the installer touches are placed under this directory, and commands which support it are run with `--root`. Steps which only make sense on a running system, like starting the Nix daemon, are skipped.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value = HOST_ROOT,
+            env = "NIX_INSTALLER_TARGET_ROOT",
+            global = true
+        )
+    )]
+    #[serde(default = "default_target_root")]
+    pub target_root: PathBuf,
+
+    /// A directory to keep the Nix store and state in instead of `/nix`, such as `/opt/nix` or a mounted volume
+    ///
+    /// Nix still finds them at `/nix`, which is bind mounted from this directory. The receipt is written to `<store-prefix>/receipt.json`. Only supported with systemd on Linux.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value = NIX_ROOT,
+            env = "NIX_INSTALLER_STORE_PREFIX",
+            global = true
+        )
+    )]
+    #[serde(default = "default_store_prefix")]
+    pub store_prefix: PathBuf,
+
+    /// Modes for directories of the `/nix` tree other than the default `0755`, as `<path>=<octal mode>`, like `/nix/var/nix/profiles/per-user=0750`
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, value_delimiter = ',', env = "NIX_INSTALLER_NIX_TREE_MODES", global = true))]
+    #[serde(default)]
+    pub nix_tree_modes: Vec<NixTreeMode>,
+
     #[cfg(feature = "diagnostics")]
     /// The URL or file path for an installation diagnostic to be sent
     ///
@@ -169,6 +945,7 @@ pub struct CommonSettings {
     ///     "os_version": "22.04.1 LTS (Jammy Jellyfish)",
     ///     "triple": "x86_64-unknown-linux-gnu",
     ///     "is_ci": false,
+    ///     "user_agent": "nix-installer/0.4.0",
     ///     "action": "Install",
     ///     "status": "Success"
     /// }
@@ -183,6 +960,12 @@ pub struct CommonSettings {
         default_value = "https://install.determinate.systems/nix/diagnostic"
     )]
     pub diagnostic_endpoint: Option<String>,
+
+    #[cfg(feature = "diagnostics")]
+    /// Fields to leave out of the installation diagnostic, which may identify the machine
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, value_delimiter = ',', env = "NIX_INSTALLER_DIAGNOSTIC_REDACT", global = true))]
+    #[serde(default)]
+    pub diagnostic_redact: Vec<crate::diagnostics::DiagnosticField>,
 }
 
 impl CommonSettings {
@@ -226,12 +1009,53 @@ impl CommonSettings {
             nix_build_group_name: String::from("nixbld"),
             nix_build_group_id: 30_000,
             nix_package_url: url.parse()?,
+            nix_version: Default::default(),
+            nix_package_path: Default::default(),
+            nix_package_hash: Default::default(),
+            nix_package_trusted_pubkey: Default::default(),
+            nix_package_signature_url: Default::default(),
+            max_retries: default_max_retries(),
+            download_parallelism: default_download_parallelism(),
+            assumed_bandwidth_mbps: default_assumed_bandwidth_mbps(),
+            download_rate_limit: Default::default(),
+            copy_strategy: Default::default(),
             proxy: Default::default(),
+            user_agent: Default::default(),
+            preserve_paths: Default::default(),
+            extra_substituters: Default::default(),
+            trusted_public_keys: Default::default(),
+            binary_caches: Default::default(),
             extra_conf: Default::default(),
+            download_attempts: Default::default(),
+            http_connections: Default::default(),
+            max_jobs: Default::default(),
+            cores: Default::default(),
+            sandbox: Default::default(),
+            nix_build_user_count: Default::default(),
+            nix_build_user_id_base: Default::default(),
+            enable_flakes: true,
+            experimental_features_in_profile: false,
+            use_xdg_base_directories: false,
+            profile_shells: Default::default(),
+            direnv: false,
+            direnv_shells: Default::default(),
+            trusted_users: default_trusted_users(),
+            admin_group: Default::default(),
             force: false,
+            install_over_existing_store: false,
+            cleanup_stale_temp_roots: false,
+            skip_clock_check: false,
+            skip_path_check: false,
+            minimum_memory_mib: Default::default(),
+            action_timeout: Default::default(),
+            target_root: default_target_root(),
+            store_prefix: default_store_prefix(),
+            nix_tree_modes: Default::default(),
             ssl_cert_file: Default::default(),
             #[cfg(feature = "diagnostics")]
             diagnostic_endpoint: Some("https://install.determinate.systems/nix/diagnostic".into()),
+            #[cfg(feature = "diagnostics")]
+            diagnostic_redact: Default::default(),
         })
     }
 
@@ -242,12 +1066,53 @@ impl CommonSettings {
             nix_build_group_name,
             nix_build_group_id,
             nix_package_url,
+            nix_version,
+            nix_package_path,
+            nix_package_hash,
+            nix_package_trusted_pubkey,
+            nix_package_signature_url,
+            max_retries,
+            download_parallelism,
+            assumed_bandwidth_mbps,
+            download_rate_limit,
+            copy_strategy,
             proxy,
+            user_agent,
+            preserve_paths,
+            extra_substituters,
+            trusted_public_keys,
+            binary_caches,
             extra_conf,
+            download_attempts,
+            http_connections,
+            max_jobs,
+            cores,
+            sandbox,
+            nix_build_user_count,
+            nix_build_user_id_base,
+            enable_flakes,
+            experimental_features_in_profile,
+            use_xdg_base_directories,
+            profile_shells,
+            direnv,
+            direnv_shells,
+            trusted_users,
+            admin_group,
             force,
+            install_over_existing_store,
+            cleanup_stale_temp_roots,
+            skip_clock_check,
+            skip_path_check,
+            minimum_memory_mib,
+            action_timeout,
+            target_root,
+            store_prefix,
+            nix_tree_modes,
             ssl_cert_file,
             #[cfg(feature = "diagnostics")]
             diagnostic_endpoint,
+            #[cfg(feature = "diagnostics")]
+            diagnostic_redact,
         } = self;
         let mut map = HashMap::default();
 
@@ -267,20 +1132,353 @@ impl CommonSettings {
             "nix_package_url".into(),
             serde_json::to_value(nix_package_url)?,
         );
+        map.insert("nix_version".into(), serde_json::to_value(nix_version)?);
+        map.insert(
+            "nix_package_path".into(),
+            serde_json::to_value(nix_package_path)?,
+        );
+        map.insert(
+            "nix_package_hash".into(),
+            serde_json::to_value(nix_package_hash)?,
+        );
+        map.insert(
+            "nix_package_trusted_pubkey".into(),
+            serde_json::to_value(nix_package_trusted_pubkey)?,
+        );
+        map.insert(
+            "nix_package_signature_url".into(),
+            serde_json::to_value(nix_package_signature_url)?,
+        );
+        map.insert("max_retries".into(), serde_json::to_value(max_retries)?);
+        map.insert(
+            "download_parallelism".into(),
+            serde_json::to_value(download_parallelism)?,
+        );
+        map.insert(
+            "assumed_bandwidth_mbps".into(),
+            serde_json::to_value(assumed_bandwidth_mbps)?,
+        );
+        map.insert(
+            "download_rate_limit".into(),
+            serde_json::to_value(download_rate_limit)?,
+        );
+        map.insert("copy_strategy".into(), serde_json::to_value(copy_strategy)?);
         map.insert("proxy".into(), serde_json::to_value(proxy)?);
+        map.insert("user_agent".into(), serde_json::to_value(user_agent)?);
         map.insert("ssl_cert_file".into(), serde_json::to_value(ssl_cert_file)?);
+        map.insert(
+            "preserve_paths".into(),
+            serde_json::to_value(preserve_paths)?,
+        );
+        map.insert(
+            "extra_substituters".into(),
+            serde_json::to_value(extra_substituters)?,
+        );
+        map.insert(
+            "trusted_public_keys".into(),
+            serde_json::to_value(trusted_public_keys)?,
+        );
+        map.insert("binary_caches".into(), serde_json::to_value(binary_caches)?);
         map.insert("extra_conf".into(), serde_json::to_value(extra_conf)?);
+        map.insert(
+            "download_attempts".into(),
+            serde_json::to_value(download_attempts)?,
+        );
+        map.insert(
+            "http_connections".into(),
+            serde_json::to_value(http_connections)?,
+        );
+        map.insert("max_jobs".into(), serde_json::to_value(max_jobs)?);
+        map.insert("cores".into(), serde_json::to_value(cores)?);
+        map.insert("sandbox".into(), serde_json::to_value(sandbox)?);
+        map.insert(
+            "nix_build_user_count".into(),
+            serde_json::to_value(nix_build_user_count)?,
+        );
+        map.insert(
+            "nix_build_user_id_base".into(),
+            serde_json::to_value(nix_build_user_id_base)?,
+        );
+        map.insert("enable_flakes".into(), serde_json::to_value(enable_flakes)?);
+        map.insert(
+            "experimental_features_in_profile".into(),
+            serde_json::to_value(experimental_features_in_profile)?,
+        );
+        map.insert(
+            "use_xdg_base_directories".into(),
+            serde_json::to_value(use_xdg_base_directories)?,
+        );
+        map.insert(
+            "profile_shells".into(),
+            serde_json::to_value(profile_shells)?,
+        );
+        map.insert("direnv".into(), serde_json::to_value(direnv)?);
+        map.insert("direnv_shells".into(), serde_json::to_value(direnv_shells)?);
+        map.insert("trusted_users".into(), serde_json::to_value(trusted_users)?);
+        map.insert("admin_group".into(), serde_json::to_value(admin_group)?);
         map.insert("force".into(), serde_json::to_value(force)?);
+        map.insert(
+            "install_over_existing_store".into(),
+            serde_json::to_value(install_over_existing_store)?,
+        );
+        map.insert(
+            "cleanup_stale_temp_roots".into(),
+            serde_json::to_value(cleanup_stale_temp_roots)?,
+        );
+        map.insert(
+            "skip_clock_check".into(),
+            serde_json::to_value(skip_clock_check)?,
+        );
+        map.insert(
+            "skip_path_check".into(),
+            serde_json::to_value(skip_path_check)?,
+        );
+        map.insert(
+            "minimum_memory_mib".into(),
+            serde_json::to_value(minimum_memory_mib)?,
+        );
+        map.insert(
+            "action_timeout".into(),
+            serde_json::to_value(action_timeout)?,
+        );
+        map.insert("target_root".into(), serde_json::to_value(target_root)?);
+        map.insert("store_prefix".into(), serde_json::to_value(store_prefix)?);
+        map.insert(
+            "nix_tree_modes".into(),
+            serde_json::to_value(nix_tree_modes)?,
+        );
 
         #[cfg(feature = "diagnostics")]
         map.insert(
             "diagnostic_endpoint".into(),
             serde_json::to_value(diagnostic_endpoint)?,
         );
+        #[cfg(feature = "diagnostics")]
+        map.insert(
+            "diagnostic_redact".into(),
+            serde_json::to_value(diagnostic_redact)?,
+        );
 
         Ok(map)
     }
+
+    /// If Nix is being installed into a [`target_root`](Self::target_root) other than the running system
+    pub fn is_target_root_alternate(&self) -> bool {
+        self.target_root != Path::new(HOST_ROOT)
+    }
+
+    /// If the Nix store is kept in a [`store_prefix`](Self::store_prefix) other than `/nix`
+    pub fn is_store_prefix_alternate(&self) -> bool {
+        self.store_prefix != Path::new(NIX_ROOT)
+    }
+
+    /// The URL of the Nix package tarball, of the release in [`nix_version`](Self::nix_version) if it is set
+    pub fn resolved_nix_package_url(&self) -> Result<Url, InstallSettingsError> {
+        let version = match &self.nix_version {
+            Some(version) => version,
+            None => return Ok(self.nix_package_url.clone()),
+        };
+        let system = host_nix_system()
+            .ok_or_else(|| InstallSettingsError::UnsupportedArchitecture(target_lexicon::HOST))?;
+        let available = NIX_RELEASES
+            .iter()
+            .filter(|release| release.system == system)
+            .collect::<Vec<_>>();
+        match available
+            .iter()
+            .find(|release| release.version == version.to_string())
+        {
+            Some(release) => Ok(release.url().parse()?),
+            None => Err(InstallSettingsError::NixVersionUnavailable {
+                version: version.clone(),
+                system,
+                available: available
+                    .iter()
+                    .map(|release| release.version.to_string())
+                    .collect(),
+            }),
+        }
+    }
+
+    /// The hash the Nix package tarball at `package_url` is verified against
+    ///
+    /// This is [`nix_package_hash`](Self::nix_package_hash) if it is set, otherwise the hash
+    /// [`NIX_RELEASES`] records for `package_url`. A [`nix_version`](Self::nix_version) must have a
+    /// recorded hash, a [`nix_package_url`](Self::nix_package_url) or
+    /// [`nix_package_path`](Self::nix_package_path) of another tarball is not checked.
+    pub(crate) fn resolved_nix_package_hash(
+        &self,
+        package_url: &Url,
+    ) -> Result<Option<String>, InstallSettingsError> {
+        if let Some(nix_package_hash) = &self.nix_package_hash {
+            return Ok(Some(nix_package_hash.clone()));
+        }
+        if self.nix_package_path.is_some() {
+            return Ok(None);
+        }
+        match NixRelease::at(package_url) {
+            Some(NixRelease {
+                hash: Some(hash), ..
+            }) => Ok(Some(hash.to_string())),
+            Some(release) if self.nix_version.is_some() => {
+                Err(InstallSettingsError::NixVersionUnverified {
+                    version: release.version,
+                    system: release.system,
+                })
+            },
+            Some(release) => {
+                tracing::warn!(
+                    "No hash of Nix {} for `{}` is known, `{package_url}` is not verified, pass `--nix-package-hash` to verify it",
+                    release.version,
+                    release.system
+                );
+                Ok(None)
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// The signature the Nix package tarball at `package_url` is verified against, if a [`nix_package_trusted_pubkey`](Self::nix_package_trusted_pubkey) is set
+    pub(crate) fn nix_package_signature(&self, package_url: &Url) -> Option<NixSignature> {
+        let trusted_pubkey = self.nix_package_trusted_pubkey.clone()?;
+        let signature_url = self.nix_package_signature_url.clone().unwrap_or_else(|| {
+            let mut signature_url = package_url.clone();
+            signature_url.set_path(&format!("{}.minisig", package_url.path()));
+            signature_url
+        });
+        Some(NixSignature {
+            signature_url,
+            trusted_pubkey,
+        })
+    }
+
+    /// How the Nix package at `package_url` is fetched and checked, for [`FetchAndUnpackNix`](crate::action::base::FetchAndUnpackNix)
+    pub(crate) fn fetch_options(
+        &self,
+        package_url: &Url,
+    ) -> Result<FetchOptions, InstallSettingsError> {
+        Ok(FetchOptions {
+            proxy: self.proxy.clone(),
+            ssl_cert_file: self.ssl_cert_file.clone(),
+            expected_hash: self.resolved_nix_package_hash(package_url)?,
+            skip_clock_check: self.skip_clock_check,
+            max_retries: self.max_retries,
+            local_tarball: self.nix_package_path.clone(),
+            user_agent: self.user_agent.clone(),
+            parallelism: self.download_parallelism,
+            assumed_bandwidth_mbps: self.assumed_bandwidth_mbps,
+            signature: self.nix_package_signature(package_url),
+            rate_limit: self.download_rate_limit,
+        })
+    }
+
+    /// Resolve an absolute `path` of the installed system to where it is written, under the [`store_prefix`](Self::store_prefix) if it is in `/nix` and the [`target_root`](Self::target_root)
+    pub(crate) fn in_store_prefix(&self, path: impl AsRef<Path>) -> PathBuf {
+        in_target_root(&self.target_root, in_store_prefix(&self.store_prefix, path))
+    }
+
+    /// The [`preserve_paths`](Self::preserve_paths), given in `/nix` or the [`store_prefix`](Self::store_prefix), resolved to where they are written
+    pub(crate) fn resolved_preserve_paths(&self) -> Vec<PathBuf> {
+        self.preserve_paths
+            .iter()
+            .map(|path| match path.strip_prefix(&self.store_prefix) {
+                Ok(_) => in_target_root(&self.target_root, path),
+                Err(_) => self.in_store_prefix(path),
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn default_max_retries() -> u32 {
+    3
+}
+
+pub(crate) fn default_download_parallelism() -> u32 {
+    4
+}
+
+pub(crate) fn default_assumed_bandwidth_mbps() -> u32 {
+    50
+}
+
+fn default_enable_flakes() -> bool {
+    true
+}
+
+/// `root` and the user who invoked `nix-installer`, through `sudo` if it was used
+pub(crate) fn default_trusted_users() -> Vec<String> {
+    let mut trusted_users = vec![String::from("root")];
+    let invoking_user = match std::env::var("SUDO_USER") {
+        Ok(sudo_user) if !sudo_user.is_empty() => Some(sudo_user),
+        _ => nix::unistd::User::from_uid(nix::unistd::getuid())
+            .ok()
+            .flatten()
+            .map(|user| user.name),
+    };
+    if let Some(invoking_user) = invoking_user {
+        if !trusted_users.contains(&invoking_user) {
+            trusted_users.push(invoking_user);
+        }
+    }
+    trusted_users
+}
+
+pub(crate) fn default_store_prefix() -> PathBuf {
+    PathBuf::from(NIX_ROOT)
 }
+
+/// Resolve an absolute `path` in `/nix` to its location in `store_prefix`, other paths are unchanged
+pub(crate) fn in_store_prefix(store_prefix: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match path.strip_prefix(NIX_ROOT) {
+        Ok(relative) if relative.as_os_str().is_empty() => store_prefix.to_path_buf(),
+        Ok(relative) => store_prefix.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+pub(crate) fn default_target_root() -> PathBuf {
+    PathBuf::from(HOST_ROOT)
+}
+
+/// The Nix name of the running system, such as `x86_64-linux`
+fn host_nix_system() -> Option<&'static str> {
+    use target_lexicon::{Architecture, OperatingSystem};
+    match (Architecture::host(), OperatingSystem::host()) {
+        (Architecture::X86_64, OperatingSystem::Linux) => Some("x86_64-linux"),
+        (Architecture::X86_32(_), OperatingSystem::Linux) => Some("i686-linux"),
+        (Architecture::Aarch64(_), OperatingSystem::Linux) => Some("aarch64-linux"),
+        (Architecture::X86_64, OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin) => {
+            Some("x86_64-darwin")
+        },
+        (Architecture::Aarch64(_), OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin) => {
+            Some("aarch64-darwin")
+        },
+        _ => None,
+    }
+}
+
+/// Resolve an absolute `path` of the installed system to its location under `target_root`
+pub(crate) fn in_target_root(target_root: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    target_root.join(path.strip_prefix(HOST_ROOT).unwrap_or(path))
+}
+
+/// The init system of the running Linux system, and if it is started
+#[cfg(target_os = "linux")]
+async fn linux_detect_init() -> (InitSystem, bool) {
+    if linux_detect_systemd_started().await {
+        return (InitSystem::Systemd, true);
+    }
+    if std::path::Path::new("/run/openrc").exists() {
+        return (InitSystem::OpenRc, true);
+    }
+    if std::path::Path::new("/sbin/openrc").exists() {
+        return (InitSystem::OpenRc, false);
+    }
+    (InitSystem::Systemd, false)
+}
+
 #[cfg(target_os = "linux")]
 async fn linux_detect_systemd_started() -> bool {
     use std::process::Stdio;
@@ -304,7 +1502,6 @@ async fn linux_detect_systemd_started() -> bool {
         }
     }
 
-    // TODO: Other inits
     started
 }
 
@@ -337,6 +1534,44 @@ pub struct InitSettings {
         )
     )]
     pub start_daemon: bool,
+
+    /// The group owning the Nix daemon socket, only users in it can use the daemon (systemd only)
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_DAEMON_SOCKET_GROUP"))]
+    #[serde(default)]
+    pub daemon_socket_group: Option<String>,
+
+    /// The octal mode of the Nix daemon socket, such as `0660` (systemd only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DAEMON_SOCKET_MODE", value_parser = parse_octal_mode)
+    )]
+    #[serde(default)]
+    pub daemon_socket_mode: Option<u32>,
+
+    /// How many seconds to wait for the Nix daemon to serve after starting it, before failing the install (systemd only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 30,
+            value_parser = clap::value_parser!(u64).range(1..),
+            env = "NIX_INSTALLER_DAEMON_START_TIMEOUT"
+        )
+    )]
+    #[serde(default = "default_daemon_start_timeout")]
+    pub daemon_start_timeout: u64,
+}
+
+pub(crate) fn default_daemon_start_timeout() -> u64 {
+    30
+}
+
+#[cfg(feature = "cli")]
+fn parse_octal_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("`{mode}` is not an octal mode like `0660`"))
 }
 
 impl InitSettings {
@@ -345,17 +1580,11 @@ impl InitSettings {
         use target_lexicon::{Architecture, OperatingSystem};
         let (init, start_daemon) = match (Architecture::host(), OperatingSystem::host()) {
             #[cfg(target_os = "linux")]
-            (Architecture::X86_64, OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
+            (Architecture::X86_64, OperatingSystem::Linux) => linux_detect_init().await,
             #[cfg(target_os = "linux")]
-            (Architecture::X86_32(_), OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
+            (Architecture::X86_32(_), OperatingSystem::Linux) => linux_detect_init().await,
             #[cfg(target_os = "linux")]
-            (Architecture::Aarch64(_), OperatingSystem::Linux) => {
-                (InitSystem::Systemd, linux_detect_systemd_started().await)
-            },
+            (Architecture::Aarch64(_), OperatingSystem::Linux) => linux_detect_init().await,
             #[cfg(target_os = "macos")]
             (Architecture::X86_64, OperatingSystem::MacOSX { .. })
             | (Architecture::X86_64, OperatingSystem::Darwin) => (InitSystem::Launchd, true),
@@ -369,16 +1598,40 @@ impl InitSettings {
             },
         };
 
-        Ok(Self { init, start_daemon })
+        Ok(Self {
+            init,
+            start_daemon,
+            daemon_socket_group: Default::default(),
+            daemon_socket_mode: Default::default(),
+            daemon_start_timeout: default_daemon_start_timeout(),
+        })
     }
 
     /// A listing of the settings, suitable for [`Planner::settings`](crate::planner::Planner::settings)
     pub fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
-        let Self { init, start_daemon } = self;
+        let Self {
+            init,
+            start_daemon,
+            daemon_socket_group,
+            daemon_socket_mode,
+            daemon_start_timeout,
+        } = self;
         let mut map = HashMap::default();
 
         map.insert("init".into(), serde_json::to_value(init)?);
         map.insert("start_daemon".into(), serde_json::to_value(start_daemon)?);
+        map.insert(
+            "daemon_socket_group".into(),
+            serde_json::to_value(daemon_socket_group)?,
+        );
+        map.insert(
+            "daemon_socket_mode".into(),
+            serde_json::to_value(daemon_socket_mode)?,
+        );
+        map.insert(
+            "daemon_start_timeout".into(),
+            serde_json::to_value(daemon_start_timeout)?,
+        );
         Ok(map)
     }
 
@@ -393,6 +1646,24 @@ impl InitSettings {
         self.start_daemon = toggle;
         self
     }
+
+    /// The group owning the daemon socket
+    pub fn daemon_socket_group(&mut self, group: impl Into<Option<String>>) -> &mut Self {
+        self.daemon_socket_group = group.into();
+        self
+    }
+
+    /// The mode of the daemon socket
+    pub fn daemon_socket_mode(&mut self, mode: impl Into<Option<u32>>) -> &mut Self {
+        self.daemon_socket_mode = mode.into();
+        self
+    }
+
+    /// How many seconds to wait for the daemon to serve after starting it
+    pub fn daemon_start_timeout(&mut self, seconds: u64) -> &mut Self {
+        self.daemon_start_timeout = seconds;
+        self
+    }
 }
 
 /// An error originating from a [`Planner::settings`](crate::planner::Planner::settings)
@@ -418,6 +1689,37 @@ pub enum InstallSettingsError {
     ),
     #[error("No supported init system found")]
     InitNotSupported,
+    /// The pinned Nix release has no tarball for the running system
+    #[error("Nix {version} is not available for `{system}`, available versions are {}", .available.join(", "))]
+    NixVersionUnavailable {
+        version: semver::Version,
+        system: &'static str,
+        available: Vec<String>,
+    },
+    /// The pinned Nix release has no recorded hash to verify its tarball against
+    #[error("No hash of Nix {version} for `{system}` is recorded to verify it with, pass its `nix_package_url` and `nix_package_hash` instead")]
+    NixVersionUnverified {
+        version: &'static str,
+        system: &'static str,
+    },
+    /// `max-jobs` must allow at least one build
+    #[error("`{0}` is not a valid `max-jobs`, pass a number of at least 1 or `auto`")]
+    InvalidMaxJobs(String),
+    /// A mode of a `/nix` tree directory must be `<path>=<octal mode>`
+    #[error("`{0}` is not a valid `/nix` tree mode, pass `<path>=<octal mode>`, like `/nix/var/nix/profiles/per-user=0750`")]
+    InvalidNixTreeMode(String),
+    /// A binary cache must be a URL and a public key separated by a space
+    #[error("`{0}` is not a valid binary cache, pass `'<url> <name>:<base64 key>'`, like `'https://cache.example.com cache.example.com-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY='`")]
+    InvalidBinaryCache(String),
+    /// A download rate must be a number of bytes per second, optionally suffixed with `K`, `M` or `G`
+    #[error("`{0}` is not a valid download rate, pass a number of bytes per second of at least 1, optionally suffixed with `K`, `M` or `G`, like `512K`")]
+    InvalidBytesPerSec(String),
+}
+
+impl From<InstallSettingsError> for crate::action::ActionErrorKind {
+    fn from(v: InstallSettingsError) -> crate::action::ActionErrorKind {
+        crate::action::ActionErrorKind::Custom(Box::new(v))
+    }
 }
 
 #[cfg(feature = "diagnostics")]
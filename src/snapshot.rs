@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use nix::unistd::{chown, Gid, Uid};
+
+/**
+The state of the files an [`InstallPlan`](crate::InstallPlan) touches, captured before it executes
+
+Reverting an [`Action`](crate::action::Action) can only undo what it knows it changed, so a file
+which existed before the install may end up removed, or keep leftover edits. With a snapshot,
+[`InstallPlan::uninstall`](crate::InstallPlan::uninstall) deletes the files which did not exist
+and restores the prior content of the ones which did.
+
+Only files and symlinks are captured, including the init service unit files. Users, groups,
+directories and whether a service is enabled or running are not, those are left to the reverts of
+the actions which changed them, which skip any that already existed before the install.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SystemSnapshot {
+    pub files: BTreeMap<PathBuf, PriorState>,
+}
+
+/// What was at a path of a [`SystemSnapshot`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PriorState {
+    Absent,
+    File {
+        /// The base64 encoded content
+        contents: String,
+        /// The SHA-256 of the content, to tell if it changed without decoding it
+        sha256: String,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+    /// Directories are left for the actions to revert
+    Directory,
+}
+
+impl SystemSnapshot {
+    /// Record the state of each of `paths`
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn capture(
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<Self, SystemSnapshotError> {
+        let mut files = BTreeMap::new();
+        for path in paths {
+            if files.contains_key(&path) {
+                continue;
+            }
+            let prior = prior_state(&path).await?;
+            tracing::trace!(path = %path.display(), ?prior, "Captured");
+            files.insert(path, prior);
+        }
+        Ok(Self { files })
+    }
+
+    /// Put each path back into its captured state, returning an error for each one which could not be
+    ///
+    /// Paths which already match their captured state are not touched.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn restore(&self) -> Vec<SystemSnapshotError> {
+        let mut errors = vec![];
+        for (path, prior) in &self.files {
+            if let Err(err) = restore_path(path, prior).await {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+}
+
+async fn prior_state(path: &Path) -> Result<PriorState, SystemSnapshotError> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PriorState::Absent),
+        Err(e) => return Err(SystemSnapshotError::Capture(path.to_path_buf(), e)),
+    };
+
+    if metadata.is_symlink() {
+        let target = tokio::fs::read_link(path)
+            .await
+            .map_err(|e| SystemSnapshotError::Capture(path.to_path_buf(), e))?;
+        Ok(PriorState::Symlink { target })
+    } else if metadata.is_dir() {
+        Ok(PriorState::Directory)
+    } else {
+        let buf = tokio::fs::read(path)
+            .await
+            .map_err(|e| SystemSnapshotError::Capture(path.to_path_buf(), e))?;
+        Ok(PriorState::File {
+            contents: base64::engine::general_purpose::STANDARD.encode(&buf),
+            sha256: sha256(&buf),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+}
+
+async fn restore_path(path: &Path, prior: &PriorState) -> Result<(), SystemSnapshotError> {
+    let current = prior_state(path).await?;
+    if current == *prior {
+        return Ok(());
+    }
+    let restore_error = |e| SystemSnapshotError::Restore(path.to_path_buf(), e);
+
+    match prior {
+        PriorState::Absent => {
+            if current != PriorState::Directory {
+                tracing::debug!(
+                    "Removing `{}`, which did not exist before the install",
+                    path.display()
+                );
+                tokio::fs::remove_file(path).await.map_err(restore_error)?;
+            }
+        },
+        PriorState::Directory => (),
+        PriorState::Symlink { target } => {
+            tracing::debug!("Restoring the `{}` symlink", path.display());
+            remove_for_restore(path, &current).await?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(restore_error)?;
+            }
+            tokio::fs::symlink(target, path)
+                .await
+                .map_err(restore_error)?;
+        },
+        PriorState::File {
+            contents,
+            sha256,
+            mode,
+            uid,
+            gid,
+        } => {
+            let unchanged = match &current {
+                PriorState::File {
+                    sha256: current_sha256,
+                    ..
+                } => current_sha256 == sha256,
+                _ => false,
+            };
+            if !unchanged {
+                tracing::debug!("Restoring the content of `{}`", path.display());
+                let buf = base64::engine::general_purpose::STANDARD
+                    .decode(contents)
+                    .map_err(|e| SystemSnapshotError::Decode(path.to_path_buf(), e))?;
+                remove_for_restore(path, &current).await?;
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .map_err(restore_error)?;
+                }
+                tokio::fs::write(path, buf).await.map_err(restore_error)?;
+            }
+            chown(path, Some(Uid::from_raw(*uid)), Some(Gid::from_raw(*gid)))
+                .map_err(|e| SystemSnapshotError::Chown(path.to_path_buf(), e))?;
+            tokio::fs::set_permissions(path, PermissionsExt::from_mode(*mode))
+                .await
+                .map_err(restore_error)?;
+        },
+    }
+
+    Ok(())
+}
+
+/// Clear whatever is at `path` so the captured state can be put back, refusing to remove directories
+async fn remove_for_restore(path: &Path, current: &PriorState) -> Result<(), SystemSnapshotError> {
+    match current {
+        PriorState::Absent => Ok(()),
+        PriorState::Directory => Err(SystemSnapshotError::DirectoryInTheWay(path.to_path_buf())),
+        PriorState::File { .. } | PriorState::Symlink { .. } => tokio::fs::remove_file(path)
+            .await
+            .map_err(|e| SystemSnapshotError::Restore(path.to_path_buf(), e)),
+    }
+}
+
+fn sha256(buf: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, buf)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum SystemSnapshotError {
+    #[error("Capturing the state of `{}`", .0.display())]
+    Capture(PathBuf, #[source] std::io::Error),
+    #[error("Restoring `{}` to its state before the install", .0.display())]
+    Restore(PathBuf, #[source] std::io::Error),
+    #[error("Decoding the captured content of `{}`", .0.display())]
+    Decode(PathBuf, #[source] base64::DecodeError),
+    #[error("Restoring the owner of `{}`", .0.display())]
+    Chown(PathBuf, #[source] nix::errno::Errno),
+    #[error("Cannot restore `{}`, a directory is in the way", .0.display())]
+    DirectoryInTheWay(PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn restores_modified_files_and_removes_new_ones() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let modified = temp_dir.path().join("modified");
+        let created = temp_dir.path().join("created");
+        tokio::fs::write(&modified, "before\n").await?;
+        tokio::fs::set_permissions(&modified, PermissionsExt::from_mode(0o640)).await?;
+
+        let snapshot = SystemSnapshot::capture([modified.clone(), created.clone()]).await?;
+        assert_eq!(snapshot.files.get(&created), Some(&PriorState::Absent));
+
+        tokio::fs::write(&modified, "before\nafter\n").await?;
+        tokio::fs::set_permissions(&modified, PermissionsExt::from_mode(0o644)).await?;
+        tokio::fs::write(&created, "new\n").await?;
+
+        let errors = snapshot.restore().await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(tokio::fs::read_to_string(&modified).await?, "before\n");
+        assert_eq!(
+            tokio::fs::metadata(&modified).await?.permissions().mode() & 0o7777,
+            0o640
+        );
+        assert!(!created.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restores_removed_files() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let removed = temp_dir.path().join("removed");
+        tokio::fs::write(&removed, "keep me\n").await?;
+
+        let snapshot = SystemSnapshot::capture([removed.clone()]).await?;
+        tokio::fs::remove_file(&removed).await?;
+
+        let errors = snapshot.restore().await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(tokio::fs::read_to_string(&removed).await?, "keep me\n");
+        Ok(())
+    }
+}
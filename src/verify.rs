@@ -0,0 +1,153 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{
+    execute_command,
+    settings::{in_target_root, HOST_ROOT},
+    InstallPlan, NixInstallerError,
+};
+
+pub(crate) const NIX_BIN: &str = "/nix/var/nix/profiles/default/bin/nix";
+const NIX_CONF: &str = "/etc/nix/nix.conf";
+pub(crate) const NIX_DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+/// One check made by [`InstallPlan::verify`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct VerificationCheck {
+    /// What was checked, such as ``Nix daemon socket `/nix/var/nix/daemon-socket/socket` accepts connections``
+    pub name: String,
+    /// Why the check failed, nothing if it passed
+    pub error: Option<String>,
+}
+
+impl VerificationCheck {
+    fn new(name: String, result: Result<(), String>) -> Self {
+        Self {
+            name,
+            error: result.err(),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/**
+The result of each check made by [`InstallPlan::verify`] that a completed install of Nix works
+
+An install can complete and still leave Nix unusable, such as if the daemon fails to start.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct VerificationReport {
+    pub checks: Vec<VerificationCheck>,
+}
+
+impl VerificationReport {
+    /// If every check passed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(VerificationCheck::passed)
+    }
+
+    /// The checks which failed
+    pub fn failed(&self) -> impl Iterator<Item = &VerificationCheck> {
+        self.checks.iter().filter(|check| !check.passed())
+    }
+}
+
+impl InstallPlan {
+    /// Check that the Nix installed by this plan works, reporting each check rather than stopping at the first failure
+    ///
+    /// Checks that `nix` is executable and `nix.conf` parses. If the planner
+    /// [runs a daemon](crate::planner::Planner::runs_daemon), also checks that the daemon accepts
+    /// connections and answers `nix store ping`. Nothing runs in
+    /// an alternate target root, so only files are checked there.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn verify(&self) -> Result<VerificationReport, NixInstallerError> {
+        let target_root = self.planner.target_root();
+        let nix_bin = in_target_root(&target_root, NIX_BIN);
+        let nix_conf = in_target_root(&target_root, NIX_CONF);
+
+        let mut checks = vec![
+            VerificationCheck::new(
+                format!("`{}` is executable", nix_bin.display()),
+                check_executable(&nix_bin).await,
+            ),
+            VerificationCheck::new(
+                format!("`{}` parses", nix_conf.display()),
+                nix_config_parser::NixConfig::parse_file(&nix_conf)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+            ),
+        ];
+
+        if self.planner.runs_daemon() && target_root == Path::new(HOST_ROOT) {
+            let socket = PathBuf::from(NIX_DAEMON_SOCKET);
+            checks.push(VerificationCheck::new(
+                format!(
+                    "Nix daemon socket `{}` accepts connections",
+                    socket.display()
+                ),
+                tokio::net::UnixStream::connect(&socket)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+            ));
+            checks.push(VerificationCheck::new(
+                "Nix daemon answers `nix store ping`".to_string(),
+                execute_command(
+                    Command::new(&nix_bin)
+                        .args(["--extra-experimental-features", "nix-command"])
+                        .args(["store", "ping", "--store", "daemon"])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            ));
+        }
+
+        Ok(VerificationReport { checks })
+    }
+}
+
+async fn check_executable(path: &Path) -> Result<(), String> {
+    let metadata = tokio::fs::metadata(path).await.map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("Not a file".to_string());
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!(
+            "Not executable, its mode is {:o}",
+            metadata.permissions().mode() & 0o777
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::check_executable;
+
+    #[tokio::test]
+    async fn executable_is_checked() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let nix = temp_dir.path().join("nix");
+        assert!(check_executable(&nix).await.is_err());
+
+        tokio::fs::write(&nix, "#!/bin/sh\n").await?;
+        tokio::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o644)).await?;
+        assert!(check_executable(&nix).await.is_err());
+
+        tokio::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o755)).await?;
+        assert!(check_executable(&nix).await.is_ok());
+        assert!(check_executable(temp_dir.path()).await.is_err());
+        Ok(())
+    }
+}
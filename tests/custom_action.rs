@@ -0,0 +1,141 @@
+// A third party action, registered with `typetag` outside of `nix_installer`, can be planned,
+// executed, recorded in the receipt, and reverted like a built-in one.
+use std::{collections::HashMap, path::PathBuf};
+
+use nix_installer::{
+    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    planner::{Planner, PlannerError},
+    settings::InstallSettingsError,
+    InstallPlan,
+};
+use tracing::{span, Span};
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+struct InstallCorporateCert {
+    path: PathBuf,
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "install_corporate_cert")]
+impl Action for InstallCorporateCert {
+    fn action_tag() -> ActionTag {
+        "install_corporate_cert".into()
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Install the corporate certificate to `{}`",
+            self.path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(tracing::Level::DEBUG, "install_corporate_cert")
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+    }
+
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        tokio::fs::write(&self.path, "-----BEGIN CERTIFICATE-----\n")
+            .await
+            .map_err(|e| ActionErrorKind::Write(self.path.clone(), e))
+            .map_err(Self::error)
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Remove the corporate certificate `{}`", self.path.display()),
+            vec![],
+        )]
+    }
+
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        tokio::fs::remove_file(&self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Remove(self.path.clone(), e))
+            .map_err(Self::error)
+    }
+}
+
+/// Plans nothing itself, so the plan holds only the custom action
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct EmptyPlanner {
+    target_root: PathBuf,
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "empty")]
+impl Planner for EmptyPlanner {
+    async fn default() -> Result<Self, PlannerError> {
+        Ok(Self {
+            target_root: "/".into(),
+        })
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        Ok(vec![])
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        Ok(HashMap::new())
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        Ok(HashMap::new())
+    }
+
+    fn target_root(&self) -> PathBuf {
+        self.target_root.clone()
+    }
+
+    #[cfg(feature = "diagnostics")]
+    async fn diagnostic_data(
+        &self,
+    ) -> Result<nix_installer::diagnostics::DiagnosticData, PlannerError> {
+        Ok(nix_installer::diagnostics::DiagnosticData::new(
+            None,
+            self.typetag_name().into(),
+            vec![],
+            None,
+            None,
+        )?)
+    }
+}
+
+#[tokio::test]
+async fn custom_action_round_trips_through_plan() -> eyre::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let cert = temp_dir.path().join("corporate.pem");
+    let receipt = temp_dir.path().join("receipt.json");
+    let action = InstallCorporateCert { path: cert.clone() };
+
+    let builder = InstallPlan::builder()
+        .receipt_location(&receipt)
+        .extra_action(StatefulAction::from(action).boxed());
+    #[cfg(feature = "diagnostics")]
+    let builder = builder.diagnostics(false);
+    let mut plan = builder
+        .plan(EmptyPlanner {
+            target_root: temp_dir.path().to_path_buf(),
+        })
+        .await?;
+
+    let planned = serde_json::to_value(&plan)?;
+    assert_eq!(
+        planned["actions"][0]["action"]["action"],
+        "install_corporate_cert"
+    );
+
+    plan.install(None, None).await?;
+    assert!(cert.exists());
+
+    // The receipt names the custom action, so a later run can load and revert it
+    let mut recorded = InstallPlan::resume_from_receipt(&receipt).await?;
+    recorded.uninstall(None).await?;
+    assert!(!cert.exists());
+
+    Ok(())
+}
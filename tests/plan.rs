@@ -3,6 +3,8 @@ use nix_installer::InstallPlan;
 #[cfg(target_os = "linux")]
 const LINUX: &str = include_str!("./fixtures/linux/linux.json");
 #[cfg(target_os = "linux")]
+const LINUX_MULTI: &str = include_str!("./fixtures/linux/linux-multi.json");
+#[cfg(target_os = "linux")]
 const STEAM_DECK: &str = include_str!("./fixtures/linux/steam-deck.json");
 #[cfg(target_os = "macos")]
 const MACOS: &str = include_str!("./fixtures/macos/macos.json");
@@ -16,6 +18,21 @@ fn plan_compat_linux() -> eyre::Result<()> {
     Ok(())
 }
 
+// Ensure plans of receipt schema version 0 are still migrated
+#[cfg(target_os = "linux")]
+#[test]
+fn plan_migrate_linux_multi() -> eyre::Result<()> {
+    let plan = nix_installer::migrate_receipt(serde_json::from_str(LINUX_MULTI)?)?;
+    let migrated = serde_json::to_value(&plan)?;
+    assert_eq!(migrated["planner"]["planner"], "linux");
+    assert_eq!(
+        migrated["actions"][3]["action"]["action"],
+        "configure_init_service"
+    );
+    assert_eq!(migrated["actions"][3]["action"]["init"], "Systemd");
+    Ok(())
+}
+
 // Ensure existing plans still parse
 // If this breaks and you need to update the fixture, disable these tests, bump `nix_installer` to a new version, and update the plans.
 #[cfg(target_os = "linux")]